@@ -1,44 +1,289 @@
 //! Block validation functions from Orange Paper Section 5.3 Section 5.3
 
 use crate::types::*;
-// use crate::constants::*;
-use crate::error::Result;
-use crate::transaction::{check_transaction, check_tx_inputs};
-use crate::script::verify_script;
-use crate::economic::get_block_subsidy;
+use crate::constants::*;
+use crate::error::{ConsensusError, Result};
+use crate::transaction::{check_transaction, check_tx_inputs, calculate_tx_id, get_transaction_sigop_cost, is_final_tx};
+use crate::script::{verify_script, count_sigops};
+use crate::serialization::{encode_varint, serialize_transaction};
+use crate::economic::{get_block_subsidy, checked_amount_sum};
+use crate::merkle::merkle_root_from_hashes;
+use crate::params::{ChainParams, Deployment, Network};
+use crate::pow::get_next_work_required_at;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 
-/// ConnectBlock: ℬ × 𝒰𝒮 × ℕ → {valid, invalid} × 𝒰𝒮
+/// ConnectBlock: ℬ × 𝒰𝒮 × ℕ → {valid, invalid} × 𝒰𝒮 × 𝒰𝒟
 /// 
 /// For block b = (h, txs) with UTXO set us at height height:
-/// 1. Validate block header h
-/// 2. For each transaction tx ∈ txs:
-///    - Validate tx structure
+/// 1. If CheckBlock(b) is invalid: return (invalid, us)
+/// 2. Validate block header h against the chain context
+/// 3. If some tx ∈ txs has (txid(tx), i) ∈ us (BIP30): return (invalid, us)
+/// 4. For each transaction tx ∈ txs:
 ///    - Check inputs against us
-///    - Verify scripts
-/// 3. Let fees = Σ_{tx ∈ txs} fee(tx)
-/// 4. Let subsidy = GetBlockSubsidy(height)
-/// 5. If coinbase output > fees + subsidy: return (invalid, us)
-/// 6. Apply all transactions to us: us' = ApplyTransactions(txs, us)
-/// 7. Return (valid, us')
+///    - Add its sigop cost; if the total > MAX_BLOCK_SIGOPS_COST: return (invalid, us)
+///    - Queue its input script checks
+///
+///    Then verify all queued scripts; if any fails: return (invalid, us)
+/// 5. Let fees = Σ_{tx ∈ txs} fee(tx)
+/// 6. Let subsidy = GetBlockSubsidy(height)
+/// 7. If the coinbase output sum overflows or exceeds fees + subsidy,
+///    with fees taken from non-coinbase transactions only: return (invalid, us)
+/// 8. Apply all transactions to us: us' = ApplyTransactions(txs, us),
+///    recording the coins each non-coinbase transaction spends in undo
+/// 9. Return (valid, us', undo)
+///
+/// Invalid blocks return the UTXO set unchanged and empty undo data.
+/// Contextual header rules that need chain history are skipped; use
+/// `connect_block_with_context` to enforce them.
 pub fn connect_block(
     block: &Block,
-    mut utxo_set: UtxoSet,
+    utxo_set: UtxoSet,
     height: Natural
-) -> Result<(ValidationResult, UtxoSet)> {
-    // 1. Validate block header
-    if !validate_block_header(&block.header)? {
-        return Ok((ValidationResult::Invalid("Invalid block header".to_string()), utxo_set));
+) -> Result<(ValidationResult, UtxoSet, BlockUndo)> {
+    connect_block_with_context(block, utxo_set, height, &BlockValidationContext::default())
+}
+
+/// CheckBlock: ℬ → {valid, invalid}
+///
+/// Context-free block validation, independent of the UTXO set and chain:
+/// 1. Validate block header h (version, bits)
+/// 2. If txs = ∅, h.merkle_root ≠ MerkleRoot(txs), or the merkle tree is
+///    mutated: return invalid
+/// 3. If |txs| × 4 or the serialized size × 4 exceeds MAX_BLOCK_SIZE: return invalid
+/// 4. If txs[0] is not a coinbase, or any later tx is: return invalid
+/// 5. If some tx fails CheckTransaction or two txs share a txid: return invalid
+/// 6. If the legacy sigop cost of txs exceeds MAX_BLOCK_SIGOPS_COST: return invalid
+/// 7. Return valid
+///
+/// A block passing CheckBlock can still be invalid in context; headers-first
+/// sync uses it to reject corrupt downloads before their parents connect.
+pub fn check_block(block: &Block) -> Result<ValidationResult> {
+    // 1. Header
+    if !validate_block_header(&block.header, None)? {
+        return Ok(ValidationResult::Invalid("Invalid block header".to_string()));
     }
     
-    // 2. Validate all transactions
-    let mut total_fees = 0i64;
+    // 2. The header commits to exactly these transactions
+    if block.transactions.is_empty() {
+        return Ok(ValidationResult::Invalid("Block must have at least one transaction".to_string()));
+    }
+    
+    let (merkle_root, mutated) = calculate_merkle_root_with_mutation(&block.transactions)?;
+    if merkle_root != block.header.merkle_root {
+        return Ok(ValidationResult::Invalid("Merkle root mismatch".to_string()));
+    }
+    
+    // A root that only matches by repeating transactions is a mutated copy
+    // of some other block (CVE-2012-2459)
+    if mutated {
+        return Ok(ValidationResult::Invalid("Merkle tree mutated by duplicate transactions".to_string()));
+    }
+    
+    // 3. Size limits, measured in weight without witness data
+    let max_weight = MAX_BLOCK_SIZE as Natural;
+    let stripped_size = 80 + encode_varint(block.transactions.len() as u64).len()
+        + block.transactions.iter().map(|tx| serialize_transaction(tx).len()).sum::<usize>();
+    if block.transactions.len() as Natural * WITNESS_SCALE_FACTOR > max_weight
+        || stripped_size as Natural * WITNESS_SCALE_FACTOR > max_weight
+    {
+        return Ok(ValidationResult::Invalid("Block exceeds maximum size".to_string()));
+    }
+    
+    // 4. Exactly one coinbase, in first position
+    if !is_coinbase(&block.transactions[0]) {
+        return Ok(ValidationResult::Invalid("First transaction must be coinbase".to_string()));
+    }
+    if let Some(i) = block.transactions.iter().skip(1).position(is_coinbase) {
+        return Ok(ValidationResult::Invalid(
+            format!("Transaction at index {} is an additional coinbase", i + 1)
+        ));
+    }
     
+    // 5. Transaction structure and unique txids
+    let mut txids = HashSet::with_capacity(block.transactions.len());
+    let mut legacy_sigops: Natural = 0;
     for (i, tx) in block.transactions.iter().enumerate() {
-        // Validate transaction structure
         if !matches!(check_transaction(tx)?, ValidationResult::Valid) {
+            return Ok(ValidationResult::Invalid(format!("Invalid transaction at index {}", i)));
+        }
+        
+        if !txids.insert(calculate_tx_id(tx)) {
+            return Ok(ValidationResult::Invalid(format!("Duplicate transaction id at index {}", i)));
+        }
+        
+        legacy_sigops += tx.inputs.iter().map(|input| count_sigops(&input.script_sig, false) as Natural).sum::<Natural>()
+            + tx.outputs.iter().map(|output| count_sigops(&output.script_pubkey, false) as Natural).sum::<Natural>();
+    }
+    
+    // 6. Legacy sigops, before any UTXO lookups
+    if legacy_sigops * WITNESS_SCALE_FACTOR > MAX_BLOCK_SIGOPS_COST {
+        return Ok(ValidationResult::Invalid("Block legacy sigop cost exceeds limit".to_string()));
+    }
+    
+    Ok(ValidationResult::Valid)
+}
+
+/// Undo data for one transaction: the coins its inputs spent, in input order
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct TxUndo {
+    pub spent: Vec<UTXO>,
+}
+
+/// BlockUndo: 𝒰𝒟 = (𝒰*)*
+///
+/// Everything needed to disconnect a block: one `TxUndo` per non-coinbase
+/// transaction, in block order. Each spent coin keeps its value, script,
+/// creation height and coinbase flag.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct BlockUndo {
+    pub tx_undo: Vec<TxUndo>,
+}
+
+/// Chain context for contextual block validation
+#[derive(Debug, Clone, Default)]
+pub struct BlockValidationContext {
+    /// Headers preceding the block, oldest first (the last one is its parent)
+    pub prev_headers: Vec<BlockHeader>,
+    /// Network-adjusted current time; the future-timestamp rule is skipped when `None`
+    pub adjusted_time: Option<Natural>,
+    /// Chain parameters, used for deployment heights
+    pub params: ChainParams,
+    /// Block whose ancestors' scripts are assumed valid, if any
+    pub assume_valid: Option<AssumeValid>,
+}
+
+/// Assume-valid block: a block known to be valid whose scripts, and those of
+/// all its ancestors, need not be verified again
+///
+/// Only the script checks are skipped; structure, amounts, sigop limits and
+/// UTXO updates are still fully validated. The caller must only connect
+/// blocks from the chain leading to `hash` with this set, as no ancestry
+/// check beyond height is possible without a header index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssumeValid {
+    pub hash: Hash,
+    pub height: Natural,
+}
+
+/// A deferred input script check: everything `verify_script` needs for one input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptCheck {
+    pub tx_index: usize,
+    pub input_index: usize,
+    pub script_sig: ByteString,
+    pub script_pubkey: ByteString,
+    pub witness: Option<ByteString>,
+    pub flags: u32,
+}
+
+impl ScriptCheck {
+    /// Run the check
+    pub fn verify(&self) -> Result<bool> {
+        verify_script(&self.script_sig, &self.script_pubkey, self.witness.as_ref(), self.flags)
+    }
+}
+
+/// Run script checks and return the first failing one in block order
+///
+/// With the `parallel` feature the checks run on the rayon thread pool;
+/// all results are collected before being scanned in order, so the reported
+/// failure (or error) is the same as for serial verification.
+pub fn first_failed_script_check(checks: &[ScriptCheck]) -> Result<Option<&ScriptCheck>> {
+    #[cfg(feature = "parallel")]
+    let results: Vec<Result<bool>> = {
+        use rayon::prelude::*;
+        checks.par_iter().map(ScriptCheck::verify).collect()
+    };
+    #[cfg(not(feature = "parallel"))]
+    let results: Vec<Result<bool>> = checks.iter().map(ScriptCheck::verify).collect();
+    
+    for (check, result) in checks.iter().zip(results) {
+        if !result? {
+            return Ok(Some(check));
+        }
+    }
+    Ok(None)
+}
+
+/// SkipScripts: ℋ × ℕ × 𝒞 → {true, false}
+///
+/// True iff c.assume_valid = (hash, h_av) and either height < h_av, or
+/// height = h_av and BlockHash(header) = hash.
+pub fn skip_script_verification(
+    header: &BlockHeader,
+    height: Natural,
+    context: &BlockValidationContext,
+) -> bool {
+    match context.assume_valid {
+        Some(assume_valid) if height < assume_valid.height => true,
+        Some(assume_valid) if height == assume_valid.height => header.block_hash() == assume_valid.hash,
+        _ => false,
+    }
+}
+
+/// ConnectBlockWithContext: ℬ × 𝒰𝒮 × ℕ × 𝒞 → {valid, invalid} × 𝒰𝒮 × 𝒰𝒟
+///
+/// ConnectBlock, additionally requiring for context c:
+/// - h.timestamp > MedianTimePast(c.prev_headers) when c.prev_headers ≠ ∅
+/// - h.timestamp ≤ c.adjusted_time + 2h when c.adjusted_time is known
+/// - every tx is final at height, with time locks compared against
+///   MedianTimePast(c.prev_headers) once BIP113 is active, else h.timestamp
+///
+/// and skipping script verification when SkipScripts(h, height, c).
+pub fn connect_block_with_context(
+    block: &Block,
+    mut utxo_set: UtxoSet,
+    height: Natural,
+    context: &BlockValidationContext,
+) -> Result<(ValidationResult, UtxoSet, BlockUndo)> {
+    // 1. Context-free checks: header, merkle commitment, size, coinbase position
+    if let ValidationResult::Invalid(reason) = check_block(block)? {
+        return Ok((ValidationResult::Invalid(reason), utxo_set, BlockUndo::default()));
+    }
+    
+    // 2. Header timestamp against adjusted time and median time past
+    if !validate_block_header(&block.header, context.adjusted_time)? {
+        return Ok((ValidationResult::Invalid("Invalid block header".to_string()), utxo_set, BlockUndo::default()));
+    }
+    
+    if !context.prev_headers.is_empty()
+        && block.header.timestamp <= median_time_past(&context.prev_headers)
+    {
+        return Ok((ValidationResult::Invalid(
+            "Block timestamp not after median time past".to_string()
+        ), utxo_set, BlockUndo::default()));
+    }
+    
+    // 3. BIP30: no transaction may overwrite an unspent output of an earlier one
+    if enforce_bip30(&block.header, height, &context.params) {
+        if let Some(i) = block.transactions.iter().position(|tx| overwrites_unspent_output(tx, &utxo_set)) {
+            return Ok((ValidationResult::Invalid(
+                format!("Transaction at index {} overwrites an unspent output (BIP30)", i)
+            ), utxo_set, BlockUndo::default()));
+        }
+    }
+    
+    // 4. Validate all transactions
+    let mut total_fees = 0i64;
+    let mut total_sigop_cost: Natural = 0;
+    
+    let script_flags = get_block_script_flags(height, &context.params);
+    let skip_scripts = skip_script_verification(&block.header, height, context);
+    let mut script_checks = Vec::new();
+    
+    // BIP113: time locks are measured against median time past, not block time
+    let lock_time_cutoff = if context.params.is_deployment_active(Deployment::Csv, height) && !context.prev_headers.is_empty() {
+        median_time_past(&context.prev_headers)
+    } else {
+        block.header.timestamp
+    };
+    
+    for (i, tx) in block.transactions.iter().enumerate() {
+        if !is_final_tx(tx, height, lock_time_cutoff) {
             return Ok((ValidationResult::Invalid(
-                format!("Invalid transaction at index {}", i)
-            ), utxo_set));
+                format!("Transaction at index {} is not final", i)
+            ), utxo_set, BlockUndo::default()));
         }
         
         // Check transaction inputs and calculate fees
@@ -46,54 +291,82 @@ pub fn connect_block(
         if !matches!(input_valid, ValidationResult::Valid) {
             return Ok((ValidationResult::Invalid(
                 format!("Invalid transaction inputs at index {}", i)
-            ), utxo_set));
+            ), utxo_set, BlockUndo::default()));
         }
         
-        // Verify scripts for non-coinbase transactions
-        if !is_coinbase(tx) {
+        // Accumulate sigop cost before running any scripts
+        total_sigop_cost += get_transaction_sigop_cost(tx, &utxo_set);
+        if total_sigop_cost > MAX_BLOCK_SIGOPS_COST {
+            return Ok((ValidationResult::Invalid(
+                format!("Block sigop cost exceeds limit at transaction {}", i)
+            ), utxo_set, BlockUndo::default()));
+        }
+        
+        // Queue script checks for non-coinbase transactions
+        if !skip_scripts && !is_coinbase(tx) {
             for (j, input) in tx.inputs.iter().enumerate() {
                 if let Some(utxo) = utxo_set.get(&input.prevout) {
-                    if !verify_script(
-                        &input.script_sig,
-                        &utxo.script_pubkey,
-                        None, // TODO: Add witness support
-                        0
-                    )? {
-                        return Ok((ValidationResult::Invalid(
-                            format!("Invalid script at transaction {}, input {}", i, j)
-                        ), utxo_set));
-                    }
+                    script_checks.push(ScriptCheck {
+                        tx_index: i,
+                        input_index: j,
+                        script_sig: input.script_sig.clone(),
+                        script_pubkey: utxo.script_pubkey.clone(),
+                        witness: None, // TODO: Add witness support
+                        flags: script_flags,
+                    });
                 }
             }
         }
         
-        total_fees += fee;
+        // The coinbase has no inputs and pays no fee
+        if i > 0 {
+            total_fees = match checked_amount_sum([total_fees, fee]) {
+                Some(total) => total,
+                None => return Ok((ValidationResult::Invalid(
+                    "bad-txns-accumulated-fee-outofrange: total fees out of range".to_string()
+                ), utxo_set, BlockUndo::default())),
+            };
+        }
     }
     
-    // 3. Validate coinbase transaction
-    if let Some(coinbase) = block.transactions.first() {
-        if !is_coinbase(coinbase) {
-            return Ok((ValidationResult::Invalid("First transaction must be coinbase".to_string()), utxo_set));
-        }
-        
-        let subsidy = get_block_subsidy(height);
-        let coinbase_output: i64 = coinbase.outputs.iter().map(|o| o.value).sum();
-        
-        if coinbase_output > total_fees + subsidy {
-            return Ok((ValidationResult::Invalid(
-                "Coinbase output exceeds fees + subsidy".to_string()
-            ), utxo_set));
-        }
-    } else {
-        return Ok((ValidationResult::Invalid("Block must have at least one transaction".to_string()), utxo_set));
+    // Run the queued script checks, in parallel with the `parallel` feature
+    if let Some(check) = first_failed_script_check(&script_checks)? {
+        return Ok((ValidationResult::Invalid(
+            format!("Invalid script at transaction {}, input {}", check.tx_index, check.input_index)
+        ), utxo_set, BlockUndo::default()));
+    }
+    
+    // 5. Validate coinbase transaction (its position was checked by check_block)
+    let coinbase = &block.transactions[0];
+    
+    let subsidy = get_block_subsidy(height);
+    let max_coinbase_value = total_fees + subsidy; // Both within MoneyRange, cannot overflow
+    let coinbase_output = checked_amount_sum(coinbase.outputs.iter().map(|o| o.value));
+    
+    if coinbase_output.is_none_or(|value| value > max_coinbase_value) {
+        return Ok((ValidationResult::Invalid(format!(
+            "bad-cb-amount: coinbase pays {} > subsidy {} + fees {}",
+            coinbase_output.map_or_else(|| "out of range".to_string(), |v| v.to_string()),
+            subsidy,
+            total_fees
+        )), utxo_set, BlockUndo::default()));
     }
     
-    // 4. Apply all transactions to UTXO set
+    // 6. Apply all transactions to UTXO set, recording every coin spent
+    let mut undo = BlockUndo::default();
     for tx in &block.transactions {
+        if !is_coinbase(tx) {
+            let spent = tx.inputs.iter()
+                .map(|input| utxo_set.get(&input.prevout).cloned().ok_or_else(|| {
+                    ConsensusError::UtxoNotFound(format!("Spent coin {:?} missing while connecting block", input.prevout))
+                }))
+                .collect::<Result<Vec<UTXO>>>()?;
+            undo.tx_undo.push(TxUndo { spent });
+        }
         utxo_set = apply_transaction(tx, utxo_set, height)?;
     }
     
-    Ok((ValidationResult::Valid, utxo_set))
+    Ok((ValidationResult::Valid, utxo_set, undo))
 }
 
 /// ApplyTransaction: 𝒯𝒳 × 𝒰𝒮 → 𝒰𝒮
@@ -116,6 +389,7 @@ pub fn apply_transaction(
     
     // Add new outputs
     let tx_id = calculate_tx_id(tx);
+    let coinbase = is_coinbase(tx);
     for (i, output) in tx.outputs.iter().enumerate() {
         let outpoint = OutPoint {
             hash: tx_id,
@@ -126,6 +400,7 @@ pub fn apply_transaction(
             value: output.value,
             script_pubkey: output.script_pubkey.clone(),
             height,
+            is_coinbase: coinbase,
         };
         
         utxo_set.insert(outpoint, utxo);
@@ -134,23 +409,259 @@ pub fn apply_transaction(
     Ok(utxo_set)
 }
 
+/// DisconnectBlock: ℬ × 𝒰𝒟 × 𝒰𝒮 → 𝒰𝒮
+///
+/// Inverse of ConnectBlock for block b = (h, txs) with undo data u:
+/// 1. For each tx ∈ txs in reverse order:
+///    - Remove (txid(tx), i) from us for every output i
+///    - If tx is not coinbase, restore each input's prevout from its TxUndo entry
+/// 2. Return us'
+///
+/// Undo data that does not match the block's transactions is an error.
+pub fn disconnect_block(
+    block: &Block,
+    undo: &BlockUndo,
+    mut utxo_set: UtxoSet,
+) -> Result<UtxoSet> {
+    let spending_txs = block.transactions.iter().filter(|tx| !is_coinbase(tx)).count();
+    if undo.tx_undo.len() != spending_txs {
+        return Err(ConsensusError::BlockValidation(format!(
+            "Undo data has {} entries for {} non-coinbase transactions",
+            undo.tx_undo.len(), spending_txs
+        )));
+    }
+    
+    let mut tx_undo = undo.tx_undo.iter().rev();
+    for tx in block.transactions.iter().rev() {
+        // Remove outputs created by this transaction
+        let tx_id = calculate_tx_id(tx);
+        for i in 0..tx.outputs.len() {
+            utxo_set.remove(&OutPoint { hash: tx_id, index: i as Natural });
+        }
+        
+        if is_coinbase(tx) {
+            continue;
+        }
+        
+        // Restore the coins this transaction spent
+        let spent = &tx_undo.next().expect("undo entry count checked above").spent;
+        if spent.len() != tx.inputs.len() {
+            return Err(ConsensusError::BlockValidation(format!(
+                "Undo data has {} coins for {} inputs", spent.len(), tx.inputs.len()
+            )));
+        }
+        for (input, coin) in tx.inputs.iter().zip(spent).rev() {
+            utxo_set.insert(input.prevout.clone(), coin.clone());
+        }
+    }
+    
+    Ok(utxo_set)
+}
+
+/// CheckHeaderContextual: ℋ × ℋ* × 𝒫 → {valid, invalid}
+///
+/// For header h extending prev (the full header chain from genesis, so h is
+/// at height |prev|) under chain params p:
+/// 1. h.prev_block_hash = BlockHash(prev[|prev| - 1]), or 0 when prev = ∅
+/// 2. h.bits = GetNextWorkRequiredAt(|prev|, h.timestamp, prev, p)
+/// 3. h.timestamp > MedianTimePast(prev)
+/// 4. h.version ≥ 2, 3, 4 once BIP34, BIP66, BIP65 are active
+/// 5. h matches any checkpoint p has at its height
+pub fn check_header_contextual(
+    header: &BlockHeader,
+    prev_headers: &[BlockHeader],
+    params: &ChainParams,
+) -> Result<ValidationResult> {
+    let height = prev_headers.len() as Natural;
+    
+    // 1. Linkage to the previous header
+    let expected_prev = prev_headers.last().map(|h| h.block_hash()).unwrap_or([0u8; 32]);
+    if header.prev_block_hash != expected_prev {
+        return Ok(ValidationResult::Invalid("Previous block hash mismatch".to_string()));
+    }
+    
+    // 2. Difficulty
+    let expected_bits = get_next_work_required_at(height, header.timestamp, prev_headers, params)?;
+    if header.bits != expected_bits {
+        return Ok(ValidationResult::Invalid("Incorrect proof of work bits".to_string()));
+    }
+    
+    // 3. Timestamp
+    if !prev_headers.is_empty() && header.timestamp <= median_time_past(prev_headers) {
+        return Ok(ValidationResult::Invalid("Block timestamp not after median time past".to_string()));
+    }
+    
+    // 4. Reject versions superseded by buried soft forks
+    let min_version = if params.is_deployment_active(Deployment::Bip65, height) {
+        4
+    } else if params.is_deployment_active(Deployment::Bip66, height) {
+        3
+    } else if params.is_deployment_active(Deployment::Bip34, height) {
+        2
+    } else {
+        1
+    };
+    if header.version < min_version {
+        return Ok(ValidationResult::Invalid(
+            format!("Obsolete block version {} at height {}", header.version, height)
+        ));
+    }
+    
+    // 5. Checkpoints
+    check_checkpoint(header, height, params)
+}
+
+/// CheckCheckpoint: ℋ × ℕ × 𝒫 → {valid, invalid}
+///
+/// A header at height n is invalid if p has a checkpoint at n with a different hash.
+pub fn check_checkpoint(
+    header: &BlockHeader,
+    height: Natural,
+    params: &ChainParams,
+) -> Result<ValidationResult> {
+    match params.checkpoint_at(height) {
+        Some(hash) if *hash != header.block_hash() => Ok(ValidationResult::Invalid(
+            format!("Block conflicts with checkpoint at height {}", height)
+        )),
+        _ => Ok(ValidationResult::Valid),
+    }
+}
+
+/// MedianTimePast: ℋ* → ℕ
+///
+/// For headers hs ordered oldest first:
+/// 1. Take the timestamps of the last min(|hs|, 11) headers
+/// 2. Return their median (the upper middle element once sorted)
+///
+/// Returns 0 for an empty list.
+pub fn median_time_past(headers: &[BlockHeader]) -> Natural {
+    let start = headers.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps: Vec<Natural> = headers[start..].iter()
+        .map(|h| h.timestamp)
+        .collect();
+    
+    if timestamps.is_empty() {
+        return 0;
+    }
+    
+    timestamps.sort_unstable();
+    timestamps[timestamps.len() / 2]
+}
+
+/// MerkleRoot: 𝒯𝒳* → ℍ
+///
+/// For transactions txs = [tx_0, ..., tx_n]:
+/// 1. Let level = [txid(tx_0), ..., txid(tx_n)]
+/// 2. While |level| > 1: if |level| is odd, duplicate its last hash,
+///    then level = [SHA256d(level[2i] ‖ level[2i+1])]
+/// 3. Return level[0]
+///
+/// An empty transaction list has no merkle root and is an error.
+pub fn calculate_merkle_root(transactions: &[Transaction]) -> Result<Hash> {
+    calculate_merkle_root_with_mutation(transactions).map(|(root, _)| root)
+}
+
+/// MerkleRootMutated: 𝒯𝒳* → ℍ × {true, false}
+///
+/// MerkleRoot together with a mutation flag, set when any level pairs two
+/// identical hashes. Duplicating the trailing transactions of an odd level
+/// yields the same root (CVE-2012-2459), so a flagged block must be rejected
+/// without marking the header itself invalid.
+pub fn calculate_merkle_root_with_mutation(transactions: &[Transaction]) -> Result<(Hash, bool)> {
+    let txids: Vec<Hash> = transactions.iter()
+        .map(calculate_tx_id)
+        .collect();
+    
+    merkle_root_from_hashes(&txids).ok_or_else(|| ConsensusError::BlockValidation(
+        "Cannot calculate merkle root for empty transaction list".to_string()
+    ))
+}
+
+/// Mainnet blocks that violate BIP30 and are grandfathered in, as
+/// (height, block hash in display byte order)
+const BIP30_EXCEPTIONS: [(Natural, &str); 2] = [
+    (91842, "00000000000a4d0a398161ffc163c503763b1f4360639393e0e4c8e300e0caec"),
+    (91880, "00000000000743f190a18c5577a3c2d2a1f610ae9601ac046a38084ccb7cd721"),
+];
+
+/// Whether BIP30 must be checked for a block at `height`
+///
+/// Skipped for the two historical mainnet exception blocks, and between BIP34
+/// activation and the height where BIP34 stops guaranteeing unique coinbases.
+fn enforce_bip30(header: &BlockHeader, height: Natural, params: &ChainParams) -> bool {
+    if (params.bip34_height..BIP34_IMPLIES_BIP30_LIMIT).contains(&height) {
+        return false;
+    }
+    
+    params.network != Network::Mainnet || !BIP30_EXCEPTIONS.iter().any(|&(exception_height, hash)| {
+        height == exception_height && display_hash(&header.block_hash()) == hash
+    })
+}
+
+/// GetBlockScriptFlags: ℕ × 𝒫 → ℕ
+///
+/// Script verification flags for a block at height n:
+/// 1. P2SH, WITNESS and TAPROOT are applied to every block
+/// 2. DERSIG from BIP66, CHECKLOCKTIMEVERIFY from BIP65 and
+///    CHECKSEQUENCEVERIFY from CSV activation
+/// 3. NULLDUMMY from SegWit activation
+///
+/// Applying the first group to all blocks matches Bitcoin Core; its two
+/// historical per-block exceptions on mainnet are not modelled.
+pub fn get_block_script_flags(height: Natural, params: &ChainParams) -> u32 {
+    let mut flags = SCRIPT_VERIFY_P2SH | SCRIPT_VERIFY_WITNESS | SCRIPT_VERIFY_TAPROOT;
+    
+    if params.is_deployment_active(Deployment::Bip66, height) {
+        flags |= SCRIPT_VERIFY_DERSIG;
+    }
+    if params.is_deployment_active(Deployment::Bip65, height) {
+        flags |= SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY;
+    }
+    if params.is_deployment_active(Deployment::Csv, height) {
+        flags |= SCRIPT_VERIFY_CHECKSEQUENCEVERIFY;
+    }
+    if params.is_deployment_active(Deployment::Segwit, height) {
+        flags |= SCRIPT_VERIFY_NULLDUMMY;
+    }
+    
+    flags
+}
+
+/// Whether any output of `tx` would replace an unspent coin with the same outpoint
+fn overwrites_unspent_output(tx: &Transaction, utxo_set: &UtxoSet) -> bool {
+    let tx_id = calculate_tx_id(tx);
+    (0..tx.outputs.len()).any(|i| {
+        utxo_set.contains_key(&OutPoint { hash: tx_id, index: i as Natural })
+    })
+}
+
+/// Hex of a hash in display (reversed) byte order
+pub(crate) fn display_hash(hash: &Hash) -> String {
+    hash.iter().rev().map(|b| format!("{:02x}", b)).collect()
+}
+
 /// Validate block header
-fn validate_block_header(header: &BlockHeader) -> Result<bool> {
+///
+/// `adjusted_time` is the caller's network-adjusted clock; passing it in keeps
+/// this function pure. With `None` the future-timestamp rule is not applied.
+fn validate_block_header(header: &BlockHeader, adjusted_time: Option<Natural>) -> Result<bool> {
     // Check version is valid
     if header.version < 1 {
         return Ok(false);
     }
     
-    // Check timestamp is reasonable (not too far in future)
-    // TODO: Add proper timestamp validation
+    // Check timestamp is not more than two hours in the future
+    if let Some(now) = adjusted_time {
+        if header.timestamp > now.saturating_add(MAX_FUTURE_BLOCK_TIME) {
+            return Ok(false);
+        }
+    }
     
     // Check bits is valid
     if header.bits == 0 {
         return Ok(false);
     }
     
-    // TODO: Add more header validation (merkle root, etc.)
-    
     Ok(true)
 }
 
@@ -161,21 +672,10 @@ fn is_coinbase(tx: &Transaction) -> bool {
     tx.inputs[0].prevout.index == 0xffffffff
 }
 
-/// Calculate transaction ID (simplified)
-fn calculate_tx_id(tx: &Transaction) -> Hash {
-    // Simplified: use a hash of the transaction data
-    // In real implementation, this would be SHA256(SHA256(serialized_tx))
-    let mut hash = [0u8; 32];
-    hash[0] = (tx.version & 0xff) as u8;
-    hash[1] = (tx.inputs.len() & 0xff) as u8;
-    hash[2] = (tx.outputs.len() & 0xff) as u8;
-    hash[3] = (tx.lock_time & 0xff) as u8;
-    hash
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::serialization::double_sha256;
     
     #[test]
     fn test_connect_block_valid() {
@@ -193,7 +693,7 @@ mod tests {
             lock_time: 0,
         };
         
-        let block = Block {
+        let mut block = Block {
             header: BlockHeader {
                 version: 1,
                 prev_block_hash: [0; 32],
@@ -204,9 +704,10 @@ mod tests {
             },
             transactions: vec![coinbase_tx],
         };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
         
         let utxo_set = UtxoSet::new();
-        let (result, new_utxo_set) = connect_block(&block, utxo_set, 0).unwrap();
+        let (result, new_utxo_set, _) = connect_block(&block, utxo_set, 0).unwrap();
         
         assert_eq!(result, ValidationResult::Valid);
         assert_eq!(new_utxo_set.len(), 1); // One new UTXO from coinbase
@@ -267,7 +768,7 @@ mod tests {
         };
         
         let utxo_set = UtxoSet::new();
-        let (result, _) = connect_block(&block, utxo_set, 0).unwrap();
+        let (result, _, _) = connect_block(&block, utxo_set, 0).unwrap();
         
         assert!(matches!(result, ValidationResult::Invalid(_)));
     }
@@ -287,7 +788,7 @@ mod tests {
         };
         
         let utxo_set = UtxoSet::new();
-        let (result, _) = connect_block(&block, utxo_set, 0).unwrap();
+        let (result, _, _) = connect_block(&block, utxo_set, 0).unwrap();
         
         assert!(matches!(result, ValidationResult::Invalid(_)));
     }
@@ -308,7 +809,7 @@ mod tests {
             lock_time: 0,
         };
         
-        let block = Block {
+        let mut block = Block {
             header: BlockHeader {
                 version: 1,
                 prev_block_hash: [0; 32],
@@ -319,9 +820,10 @@ mod tests {
             },
             transactions: vec![regular_tx], // First tx is not coinbase
         };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
         
         let utxo_set = UtxoSet::new();
-        let (result, _) = connect_block(&block, utxo_set, 0).unwrap();
+        let (result, _, _) = connect_block(&block, utxo_set, 0).unwrap();
         
         assert!(matches!(result, ValidationResult::Invalid(_)));
     }
@@ -342,7 +844,7 @@ mod tests {
             lock_time: 0,
         };
         
-        let block = Block {
+        let mut block = Block {
             header: BlockHeader {
                 version: 1,
                 prev_block_hash: [0; 32],
@@ -353,9 +855,10 @@ mod tests {
             },
             transactions: vec![coinbase_tx],
         };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
         
         let utxo_set = UtxoSet::new();
-        let (result, _) = connect_block(&block, utxo_set, 0).unwrap();
+        let (result, _, _) = connect_block(&block, utxo_set, 0).unwrap();
         
         assert!(matches!(result, ValidationResult::Invalid(_)));
     }
@@ -370,6 +873,7 @@ mod tests {
             value: 1000,
             script_pubkey: vec![0x51], // OP_1
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(prev_outpoint, prev_utxo);
         
@@ -432,7 +936,7 @@ mod tests {
             nonce: 0,
         };
         
-        let result = validate_block_header(&header).unwrap();
+        let result = validate_block_header(&header, None).unwrap();
         assert!(result);
     }
     
@@ -447,7 +951,7 @@ mod tests {
             nonce: 0,
         };
         
-        let result = validate_block_header(&header).unwrap();
+        let result = validate_block_header(&header, None).unwrap();
         assert!(!result);
     }
     
@@ -462,7 +966,7 @@ mod tests {
             nonce: 0,
         };
         
-        let result = validate_block_header(&header).unwrap();
+        let result = validate_block_header(&header, None).unwrap();
         assert!(!result);
     }
     
@@ -570,17 +1074,9 @@ mod tests {
         // Should be a 32-byte hash
         assert_eq!(tx_id.len(), 32);
         
-        // First byte should be version (1)
-        assert_eq!(tx_id[0], 1);
-        
-        // Second byte should be input count (1)
-        assert_eq!(tx_id[1], 1);
-        
-        // Third byte should be output count (1)
-        assert_eq!(tx_id[2], 1);
-        
-        // Fourth byte should be lock time (0)
-        assert_eq!(tx_id[3], 0);
+        // UTXOs are keyed by the real txid: SHA256d of the serialization
+        let serialized = crate::serialization::serialize_transaction(&tx);
+        assert_eq!(tx_id, double_sha256(&serialized));
     }
     
     #[test]
@@ -624,7 +1120,7 @@ mod tests {
         let result = connect_block(&block, utxo_set, 0);
         // The result should be Ok with ValidationResult::Invalid
         assert!(result.is_ok());
-        let (validation_result, _) = result.unwrap();
+        let (validation_result, _, _) = result.unwrap();
         assert!(matches!(validation_result, ValidationResult::Invalid(_)));
     }
     
@@ -644,7 +1140,7 @@ mod tests {
             lock_time: 0,
         };
         
-        let block = Block {
+        let mut block = Block {
             header: BlockHeader {
                 version: 1,
                 prev_block_hash: [0; 32],
@@ -655,12 +1151,13 @@ mod tests {
             },
             transactions: vec![invalid_coinbase],
         };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
         
         let utxo_set = UtxoSet::new();
         let result = connect_block(&block, utxo_set, 0);
         // The result should be Ok with ValidationResult::Invalid
         assert!(result.is_ok());
-        let (validation_result, _) = result.unwrap();
+        let (validation_result, _, _) = result.unwrap();
         assert!(matches!(validation_result, ValidationResult::Invalid(_)));
     }
     
@@ -674,6 +1171,7 @@ mod tests {
             value: 100, // Small value
             script_pubkey: vec![0x51],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(prev_outpoint, prev_utxo);
         
@@ -730,9 +1228,30 @@ mod tests {
             nonce: 0,
         };
         
-        // The simplified implementation doesn't validate timestamps
-        let result = validate_block_header(&header).unwrap();
+        // Without an adjusted time the future rule is not applied
+        let result = validate_block_header(&header, None).unwrap();
         assert!(result);
+        
+        let result = validate_block_header(&header, Some(1231006505)).unwrap();
+        assert!(!result);
+    }
+    
+    #[test]
+    fn test_validate_block_header_max_future_boundary() {
+        let now = 1231006505;
+        let mut header = BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp: now + MAX_FUTURE_BLOCK_TIME,
+            bits: 0x1d00ffff,
+            nonce: 0,
+        };
+        
+        assert!(validate_block_header(&header, Some(now)).unwrap());
+        
+        header.timestamp += 1;
+        assert!(!validate_block_header(&header, Some(now)).unwrap());
     }
     
     #[test]
@@ -747,7 +1266,7 @@ mod tests {
         };
         
         // The simplified implementation doesn't validate timestamps
-        let result = validate_block_header(&header).unwrap();
+        let result = validate_block_header(&header, None).unwrap();
         assert!(result);
     }
     
@@ -767,7 +1286,7 @@ mod tests {
             lock_time: 0,
         };
         
-        let block = Block {
+        let mut block = Block {
             header: BlockHeader {
                 version: 1,
                 prev_block_hash: [0; 32],
@@ -778,12 +1297,13 @@ mod tests {
             },
             transactions: vec![coinbase_tx],
         };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
         
         let utxo_set = UtxoSet::new();
         let result = connect_block(&block, utxo_set, 0);
         // The result should be Ok with ValidationResult::Invalid
         assert!(result.is_ok());
-        let (validation_result, _) = result.unwrap();
+        let (validation_result, _, _) = result.unwrap();
         assert!(matches!(validation_result, ValidationResult::Invalid(_)));
     }
     
@@ -803,7 +1323,7 @@ mod tests {
             lock_time: 0,
         };
         
-        let block = Block {
+        let mut block = Block {
             header: BlockHeader {
                 version: 1,
                 prev_block_hash: [0; 32],
@@ -814,12 +1334,13 @@ mod tests {
             },
             transactions: vec![regular_tx], // First tx is not coinbase
         };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
         
         let utxo_set = UtxoSet::new();
         let result = connect_block(&block, utxo_set, 0);
         // The result should be Ok with ValidationResult::Invalid
         assert!(result.is_ok());
-        let (validation_result, _) = result.unwrap();
+        let (validation_result, _, _) = result.unwrap();
         assert!(matches!(validation_result, ValidationResult::Invalid(_)));
     }
     
@@ -833,6 +1354,7 @@ mod tests {
             value: 500,
             script_pubkey: vec![0x51],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint1, utxo1);
         
@@ -841,6 +1363,7 @@ mod tests {
             value: 300,
             script_pubkey: vec![0x52],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint2, utxo2);
         
@@ -878,6 +1401,7 @@ mod tests {
             value: 1000,
             script_pubkey: vec![0x51],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(prev_outpoint, prev_utxo);
         
@@ -895,4 +1419,717 @@ mod tests {
         let new_utxo_set = apply_transaction(&tx, utxo_set, 1).unwrap();
         assert_eq!(new_utxo_set.len(), 0);
     }
+    
+    #[test]
+    fn test_connect_block_merkle_root_mismatch() {
+        let coinbase_tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 5000000000,
+                script_pubkey: vec![],
+            }],
+            lock_time: 0,
+        };
+        
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash: [0; 32],
+                merkle_root: [0; 32],
+                timestamp: 1231006505,
+                bits: 0x1d00ffff,
+                nonce: 0,
+            },
+            transactions: vec![coinbase_tx],
+        };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
+        block.header.merkle_root[0] ^= 0x01;
+        
+        let utxo_set = UtxoSet::new();
+        let (result, unchanged, _) = connect_block(&block, utxo_set, 0).unwrap();
+        
+        assert_eq!(result, ValidationResult::Invalid("Merkle root mismatch".to_string()));
+        assert!(unchanged.is_empty());
+    }
+    
+    #[test]
+    fn test_calculate_merkle_root_empty() {
+        assert!(calculate_merkle_root(&[]).is_err());
+    }
+    
+    #[test]
+    fn test_calculate_merkle_root_genesis() {
+        // Genesis block coinbase; its merkle root is its txid
+        let mut script_sig = vec![0x04, 0xff, 0xff, 0x00, 0x1d, 0x01, 0x04, 0x45];
+        script_sig.extend_from_slice(b"The Times 03/Jan/2009 Chancellor on brink of second bailout for banks");
+        let mut script_pubkey = vec![0x41];
+        script_pubkey.extend_from_slice(&[
+            0x04, 0x67, 0x8a, 0xfd, 0xb0, 0xfe, 0x55, 0x48, 0x27, 0x19, 0x67, 0xf1, 0xa6, 0x71, 0x30, 0xb7,
+            0x10, 0x5c, 0xd6, 0xa8, 0x28, 0xe0, 0x39, 0x09, 0xa6, 0x79, 0x62, 0xe0, 0xea, 0x1f, 0x61, 0xde,
+            0xb6, 0x49, 0xf6, 0xbc, 0x3f, 0x4c, 0xef, 0x38, 0xc4, 0xf3, 0x55, 0x04, 0xe5, 0x1e, 0xc1, 0x12,
+            0xde, 0x5c, 0x38, 0x4d, 0xf7, 0xba, 0x0b, 0x8d, 0x57, 0x8a, 0x4c, 0x70, 0x2b, 0x6b, 0xf1, 0x1d,
+            0x5f,
+        ]);
+        script_pubkey.push(0xac);
+        
+        let coinbase_tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig,
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 5000000000,
+                script_pubkey,
+            }],
+            lock_time: 0,
+        };
+        
+        let merkle_root = calculate_merkle_root(&[coinbase_tx]).unwrap();
+        
+        // 4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b (display order)
+        let expected: Hash = [
+            0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2, 0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f, 0x61,
+            0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32, 0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e, 0x5e, 0x4a,
+        ];
+        assert_eq!(merkle_root, expected);
+    }
+    
+    #[test]
+    fn test_calculate_merkle_root_odd_count_duplicates_last() {
+        let make_tx = |lock_time| Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [1; 32], index: 0 },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 1000,
+                script_pubkey: vec![],
+            }],
+            lock_time,
+        };
+        let (a, b, c) = (make_tx(1), make_tx(2), make_tx(3));
+        
+        let three = calculate_merkle_root(&[a.clone(), b.clone(), c.clone()]).unwrap();
+        let four = calculate_merkle_root(&[a, b, c.clone(), c]).unwrap();
+        
+        assert_eq!(three, four);
+    }
+    
+    fn header_at(timestamp: Natural) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp,
+            bits: 0x1d00ffff,
+            nonce: 0,
+        }
+    }
+    
+    #[test]
+    fn test_median_time_past_uses_last_eleven() {
+        assert_eq!(median_time_past(&[]), 0);
+        assert_eq!(median_time_past(&[header_at(5)]), 5);
+        
+        // Unsorted input is sorted before taking the median
+        let headers: Vec<_> = [30, 10, 20].iter().map(|&t| header_at(t)).collect();
+        assert_eq!(median_time_past(&headers), 20);
+        
+        // Only the 11 most recent timestamps (100..=110) count
+        let headers: Vec<_> = (0..100).chain(100..111).map(header_at).collect();
+        assert_eq!(median_time_past(&headers), 105);
+    }
+    
+    #[test]
+    fn test_connect_block_timestamp_must_exceed_mtp() {
+        let coinbase_tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 5000000000,
+                script_pubkey: vec![],
+            }],
+            lock_time: 0,
+        };
+        
+        let mut block = Block {
+            header: header_at(1000),
+            transactions: vec![coinbase_tx],
+        };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
+        
+        let context = BlockValidationContext {
+            prev_headers: (990..1001).map(header_at).collect(), // MTP = 995
+            adjusted_time: None,
+            params: ChainParams::mainnet(),
+            ..Default::default()
+        };
+        let (result, _, _) = connect_block_with_context(&block, UtxoSet::new(), 0, &context).unwrap();
+        assert_eq!(result, ValidationResult::Valid);
+        
+        block.header.timestamp = 995;
+        let (result, _, _) = connect_block_with_context(&block, UtxoSet::new(), 0, &context).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(_)));
+    }
+    
+    #[test]
+    fn test_connect_block_bip30_rejects_overwrite() {
+        let coinbase_tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 2500000000, // Within the subsidy at every height used below
+                script_pubkey: vec![],
+            }],
+            lock_time: 0,
+        };
+        
+        let mut block = Block {
+            header: header_at(1231006505),
+            transactions: vec![coinbase_tx],
+        };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
+        
+        // The first connection creates the coinbase output...
+        let (result, utxo_set, _) = connect_block(&block, UtxoSet::new(), 1).unwrap();
+        assert_eq!(result, ValidationResult::Valid);
+        
+        // ...so an identical coinbase cannot be connected while it is unspent
+        let (result, utxo_set, _) = connect_block(&block, utxo_set, 2).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(ref msg) if msg.contains("BIP30")));
+        
+        // Between BIP34 activation and its limit the check is skipped
+        let (result, _, _) = connect_block(&block, utxo_set, ChainParams::mainnet().bip34_height).unwrap();
+        assert_eq!(result, ValidationResult::Valid);
+    }
+    
+    #[test]
+    fn test_enforce_bip30_heights() {
+        let header = header_at(1231006505);
+        let params = ChainParams::mainnet();
+        assert!(enforce_bip30(&header, 0, &params));
+        assert!(enforce_bip30(&header, 91842, &params)); // Only the exact exception block is exempt
+        assert!(!enforce_bip30(&header, params.bip34_height, &params));
+        assert!(enforce_bip30(&header, BIP34_IMPLIES_BIP30_LIMIT, &params));
+    }
+    
+    #[test]
+    fn test_merkle_root_mutation_detected() {
+        let coinbase_tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 5000000000,
+                script_pubkey: vec![],
+            }],
+            lock_time: 0,
+        };
+        let spend = |index| Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [1; 32], index },
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 1000,
+                script_pubkey: vec![0x51],
+            }],
+            lock_time: 0,
+        };
+        
+        let original = vec![coinbase_tx.clone(), spend(0), spend(1)];
+        let duplicated = vec![coinbase_tx, spend(0), spend(1), spend(1)];
+        
+        let (root, mutated) = calculate_merkle_root_with_mutation(&original).unwrap();
+        assert!(!mutated);
+        let (dup_root, dup_mutated) = calculate_merkle_root_with_mutation(&duplicated).unwrap();
+        assert!(dup_mutated);
+        assert_eq!(root, dup_root);
+        
+        let mut block = Block {
+            header: header_at(1231006505),
+            transactions: duplicated,
+        };
+        block.header.merkle_root = dup_root;
+        
+        let (result, _, _) = connect_block(&block, UtxoSet::new(), 0).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(ref msg) if msg.contains("mutated")));
+    }
+    
+    #[test]
+    fn test_connect_block_sigop_cost_limit() {
+        // Each inexact OP_CHECKMULTISIG costs 20 * 4 = 80
+        let per_output = MAX_PUBKEYS_PER_MULTISIG as Natural * WITNESS_SCALE_FACTOR;
+        let outputs_at_limit = (MAX_BLOCK_SIGOPS_COST / per_output) as usize;
+        
+        let coinbase_with_outputs = |count: usize| Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: 0, script_pubkey: vec![0xae] }; count],
+            lock_time: 0,
+        };
+        
+        // Split one output past the limit across transactions to stay under
+        // the per-transaction output limit
+        let mut utxo_set = UtxoSet::new();
+        let mut transactions = vec![coinbase_with_outputs(1000)];
+        let remaining = outputs_at_limit - 1000 + 1;
+        for chunk in 0..remaining.div_ceil(1000) {
+            let prevout = OutPoint { hash: [2; 32], index: chunk as Natural };
+            utxo_set.insert(prevout.clone(), UTXO { value: 1000, script_pubkey: vec![0x51], height: 0, is_coinbase: false });
+            
+            let mut tx = coinbase_with_outputs((remaining - chunk * 1000).min(1000));
+            tx.inputs[0].prevout = prevout;
+            transactions.push(tx);
+        }
+        let mut block = Block { header: header_at(1231006505), transactions };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
+        
+        let (result, _, _) = connect_block(&block, utxo_set, 0).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(ref msg) if msg.contains("sigop")));
+    }
+    
+    #[test]
+    fn test_connect_block_returns_undo() {
+        let coinbase = |tag: u8| Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![tag],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 5000000000,
+                script_pubkey: vec![],
+            }],
+            lock_time: 0,
+        };
+        
+        let mut first = Block { header: header_at(1231006505), transactions: vec![coinbase(1)] };
+        first.header.merkle_root = calculate_merkle_root(&first.transactions).unwrap();
+        let (result, utxo_set, undo) = connect_block(&first, UtxoSet::new(), 1).unwrap();
+        assert_eq!(result, ValidationResult::Valid);
+        assert!(undo.tx_undo.is_empty()); // Coinbase-only blocks spend nothing
+        
+        let spend = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: calculate_tx_id(&first.transactions[0]), index: 0 },
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 4000000000,
+                script_pubkey: vec![],
+            }],
+            lock_time: 0,
+        };
+        let mut second = Block { header: header_at(1231007105), transactions: vec![coinbase(2), spend] };
+        second.header.merkle_root = calculate_merkle_root(&second.transactions).unwrap();
+        
+        let (result, _, undo) = connect_block(&second, utxo_set, 2).unwrap();
+        assert_eq!(result, ValidationResult::Valid);
+        assert_eq!(undo.tx_undo.len(), 1);
+        assert_eq!(undo.tx_undo[0].spent, vec![UTXO {
+            value: 5000000000,
+            script_pubkey: vec![],
+            height: 1,
+            is_coinbase: true,
+        }]);
+    }
+    
+    #[test]
+    fn test_disconnect_block_restores_utxo_set() {
+        let prevout = OutPoint { hash: [1; 32], index: 0 };
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.insert(prevout.clone(), UTXO {
+            value: 10000,
+            script_pubkey: vec![],
+            height: 5,
+            is_coinbase: true,
+        });
+        
+        let coinbase_tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 5000000000,
+                script_pubkey: vec![],
+            }],
+            lock_time: 0,
+        };
+        let spend = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout,
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![
+                TransactionOutput { value: 6000, script_pubkey: vec![] },
+                TransactionOutput { value: 3000, script_pubkey: vec![] },
+            ],
+            lock_time: 0,
+        };
+        let mut block = Block { header: header_at(1231006505), transactions: vec![coinbase_tx, spend] };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
+        
+        let (result, connected, undo) = connect_block(&block, utxo_set.clone(), 10).unwrap();
+        assert_eq!(result, ValidationResult::Valid);
+        assert_eq!(connected.len(), 3);
+        
+        let disconnected = disconnect_block(&block, &undo, connected.clone()).unwrap();
+        assert_eq!(disconnected, utxo_set);
+        
+        // Undo data from a different block is rejected
+        assert!(disconnect_block(&block, &BlockUndo::default(), connected).is_err());
+    }
+    
+    fn chain_of(len: usize) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = Vec::with_capacity(len);
+        for i in 0..len {
+            let mut header = header_at(1231006505 + 600 * i as Natural);
+            header.prev_block_hash = headers.last().map(|h| h.block_hash()).unwrap_or([0; 32]);
+            headers.push(header);
+        }
+        headers
+    }
+    
+    #[test]
+    fn test_check_header_contextual() {
+        let params = ChainParams::mainnet();
+        let prev_headers = chain_of(20);
+        
+        let mut header = header_at(1231006505 + 600 * 20);
+        header.prev_block_hash = prev_headers[19].block_hash();
+        assert_eq!(check_header_contextual(&header, &prev_headers, &params).unwrap(), ValidationResult::Valid);
+        
+        // Genesis must have a zero previous hash and the pow limit
+        let genesis = &prev_headers[0];
+        assert_eq!(check_header_contextual(genesis, &[], &params).unwrap(), ValidationResult::Valid);
+        
+        let mut bad = header.clone();
+        bad.prev_block_hash = [9; 32];
+        assert!(matches!(check_header_contextual(&bad, &prev_headers, &params).unwrap(), ValidationResult::Invalid(_)));
+        
+        let mut bad = header.clone();
+        bad.bits = 0x1c00ffff;
+        assert!(matches!(check_header_contextual(&bad, &prev_headers, &params).unwrap(), ValidationResult::Invalid(_)));
+        
+        let mut bad = header.clone();
+        bad.timestamp = median_time_past(&prev_headers);
+        assert!(matches!(check_header_contextual(&bad, &prev_headers, &params).unwrap(), ValidationResult::Invalid(_)));
+    }
+    
+    #[test]
+    fn test_check_header_contextual_version_rules() {
+        // On regtest BIP34/65/66 are active from height 1
+        let params = ChainParams::regtest();
+        let mut prev = header_at(1296688602);
+        prev.bits = params.pow_limit_bits;
+        
+        let mut header = header_at(1296688603);
+        header.bits = params.pow_limit_bits;
+        header.prev_block_hash = prev.block_hash();
+        
+        for version in 1..4 {
+            header.version = version;
+            assert!(matches!(
+                check_header_contextual(&header, std::slice::from_ref(&prev), &params).unwrap(),
+                ValidationResult::Invalid(_)
+            ));
+        }
+        
+        header.version = 4;
+        assert_eq!(
+            check_header_contextual(&header, std::slice::from_ref(&prev), &params).unwrap(),
+            ValidationResult::Valid
+        );
+    }
+    
+    #[test]
+    fn test_check_header_contextual_checkpoint() {
+        let mut params = ChainParams::mainnet();
+        let prev_headers = chain_of(5);
+        let mut header = header_at(1231006505 + 600 * 5);
+        header.prev_block_hash = prev_headers[4].block_hash();
+        
+        params.checkpoints.insert(5, header.block_hash());
+        assert_eq!(check_header_contextual(&header, &prev_headers, &params).unwrap(), ValidationResult::Valid);
+        
+        params.checkpoints.insert(5, [7; 32]);
+        assert!(matches!(
+            check_header_contextual(&header, &prev_headers, &params).unwrap(),
+            ValidationResult::Invalid(ref msg) if msg.contains("checkpoint")
+        ));
+        
+        // Heights without a checkpoint are unaffected
+        assert_eq!(check_checkpoint(&header, 6, &params).unwrap(), ValidationResult::Valid);
+    }
+    
+    #[test]
+    fn test_connect_block_bip113_uses_median_time_past() {
+        let lock_time = LOCKTIME_THRESHOLD as Natural + 100;
+        let coinbase_tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![],
+                sequence: 0, // Non-final, so the lock time applies
+            }],
+            outputs: vec![TransactionOutput {
+                value: 5000000000,
+                script_pubkey: vec![],
+            }],
+            lock_time,
+        };
+        
+        // Block time is past the lock, median time past (lock - 50) is not
+        let mut block = Block {
+            header: header_at(lock_time + 100),
+            transactions: vec![coinbase_tx],
+        };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
+        let prev_headers: Vec<_> = (0..11).map(|i| header_at(lock_time - 100 + 10 * i)).collect();
+        
+        // Before CSV activation the block timestamp is the cutoff
+        let context = BlockValidationContext {
+            prev_headers,
+            adjusted_time: None,
+            params: ChainParams::mainnet(),
+            ..Default::default()
+        };
+        let (result, _, _) = connect_block_with_context(&block, UtxoSet::new(), 1, &context).unwrap();
+        assert_eq!(result, ValidationResult::Valid);
+        
+        // After activation the transaction is not yet final
+        let context = BlockValidationContext { params: ChainParams::regtest(), ..context };
+        let (result, _, _) = connect_block_with_context(&block, UtxoSet::new(), 1, &context).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(ref msg) if msg.contains("not final")));
+    }
+    
+    #[test]
+    fn test_get_block_script_flags_by_height() {
+        let params = ChainParams::mainnet();
+        let always = SCRIPT_VERIFY_P2SH | SCRIPT_VERIFY_WITNESS | SCRIPT_VERIFY_TAPROOT;
+        
+        assert_eq!(get_block_script_flags(0, &params), always);
+        assert_eq!(get_block_script_flags(params.bip66_height, &params), always | SCRIPT_VERIFY_DERSIG);
+        assert_eq!(
+            get_block_script_flags(params.csv_height, &params),
+            always | SCRIPT_VERIFY_DERSIG | SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY | SCRIPT_VERIFY_CHECKSEQUENCEVERIFY
+        );
+        assert_ne!(get_block_script_flags(params.segwit_height, &params) & SCRIPT_VERIFY_NULLDUMMY, 0);
+        
+        // Regtest enforces everything from height 1
+        let regtest = ChainParams::regtest();
+        assert_eq!(get_block_script_flags(1, &regtest), get_block_script_flags(params.segwit_height, &params));
+    }
+    
+    #[test]
+    fn test_connect_block_bad_cb_amount() {
+        let coinbase_paying = |values: Vec<Integer>| Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: values.into_iter()
+                .map(|value| TransactionOutput { value, script_pubkey: vec![] })
+                .collect(),
+            lock_time: 0,
+        };
+        let connect = |tx: Transaction| {
+            let mut block = Block { header: header_at(1231006505), transactions: vec![tx] };
+            block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
+            connect_block(&block, UtxoSet::new(), 0).unwrap().0
+        };
+        
+        // Exactly the subsidy is fine, one satoshi more is not
+        assert_eq!(connect(coinbase_paying(vec![2500000000, 2500000000])), ValidationResult::Valid);
+        assert!(matches!(
+            connect(coinbase_paying(vec![2500000000, 2500000001])),
+            ValidationResult::Invalid(ref msg) if msg.starts_with("bad-cb-amount")
+        ));
+    }
+    
+    #[test]
+    fn test_connect_block_assume_valid_skips_scripts_only() {
+        let prevout = OutPoint { hash: [1; 32], index: 0 };
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.insert(prevout.clone(), UTXO {
+            value: 1000,
+            script_pubkey: vec![],
+            height: 1,
+            is_coinbase: false,
+        });
+        
+        let block_spending = |value: Integer| {
+            let coinbase = Transaction {
+                version: 1,
+                inputs: vec![TransactionInput {
+                    prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                    script_sig: vec![],
+                    sequence: 0xffffffff,
+                }],
+                outputs: vec![TransactionOutput { value: 5000000000, script_pubkey: vec![] }],
+                lock_time: 0,
+            };
+            // OP_0 leaves false on the stack: the script fails
+            let spend = Transaction {
+                version: 1,
+                inputs: vec![TransactionInput {
+                    prevout: prevout.clone(),
+                    script_sig: vec![0x00],
+                    sequence: 0xffffffff,
+                }],
+                outputs: vec![TransactionOutput { value, script_pubkey: vec![] }],
+                lock_time: 0,
+            };
+            let mut block = Block { header: header_at(1231006505), transactions: vec![coinbase, spend] };
+            block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
+            block
+        };
+        let block = block_spending(900);
+        
+        let mut context = BlockValidationContext {
+            assume_valid: Some(AssumeValid { hash: [0xaa; 32], height: 100 }),
+            ..Default::default()
+        };
+        
+        // Below the assume-valid height the failing script is not run
+        let (result, _, _) = connect_block_with_context(&block, utxo_set.clone(), 50, &context).unwrap();
+        assert_eq!(result, ValidationResult::Valid);
+        
+        // Amounts are still checked
+        let overspend = block_spending(2000);
+        let (result, _, _) = connect_block_with_context(&overspend, utxo_set.clone(), 50, &context).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(_)));
+        
+        // At the assume-valid height only the named block is skipped
+        let (result, _, _) = connect_block_with_context(&block, utxo_set.clone(), 100, &context).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(_)));
+        context.assume_valid = Some(AssumeValid { hash: block.header.block_hash(), height: 100 });
+        let (result, _, _) = connect_block_with_context(&block, utxo_set.clone(), 100, &context).unwrap();
+        assert_eq!(result, ValidationResult::Valid);
+        
+        // Above it, and without assume-valid, scripts are verified
+        let (result, _, _) = connect_block_with_context(&block, utxo_set.clone(), 101, &context).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(_)));
+        let (result, _, _) = connect_block(&block, utxo_set, 50).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(_)));
+    }
+    
+    #[test]
+    fn test_first_failed_script_check_in_block_order() {
+        let check = |tx_index: usize, script_sig: ByteString| ScriptCheck {
+            tx_index,
+            input_index: 0,
+            script_sig,
+            script_pubkey: vec![],
+            witness: None,
+            flags: 0,
+        };
+        
+        let passing: Vec<ScriptCheck> = (0..64).map(|i| check(i, vec![0x51])).collect();
+        assert_eq!(first_failed_script_check(&passing).unwrap(), None);
+        
+        // Two failures: the earliest is reported regardless of scheduling
+        let mut failing = passing.clone();
+        failing[40] = check(40, vec![0x00]);
+        failing[17] = check(17, vec![0x00]);
+        let failed = first_failed_script_check(&failing).unwrap().unwrap();
+        assert_eq!(failed.tx_index, 17);
+    }
+    
+    #[test]
+    fn test_check_block_context_free_rules() {
+        let coinbase = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: 5000000000, script_pubkey: vec![] }],
+            lock_time: 0,
+        };
+        let spend = |tag: u8| Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [tag; 32], index: 0 },
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: 1000, script_pubkey: vec![] }],
+            lock_time: 0,
+        };
+        let check = |transactions: Vec<Transaction>| {
+            let mut block = Block { header: header_at(1231006505), transactions };
+            block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
+            check_block(&block).unwrap()
+        };
+        let is_invalid = |result: ValidationResult, expected: &str| {
+            matches!(result, ValidationResult::Invalid(ref msg) if msg.contains(expected))
+        };
+        
+        assert_eq!(check(vec![coinbase.clone(), spend(1), spend(2)]), ValidationResult::Valid);
+        
+        // Coinbase must come first and only once
+        assert!(is_invalid(check(vec![spend(1), coinbase.clone()]), "First transaction must be coinbase"));
+        let mut second_coinbase = coinbase.clone();
+        second_coinbase.inputs[0].script_sig = vec![0x01, 0x02];
+        assert!(is_invalid(check(vec![coinbase.clone(), second_coinbase]), "additional coinbase"));
+        
+        // Non-adjacent duplicates are not caught as merkle mutation
+        assert!(is_invalid(check(vec![coinbase.clone(), spend(1), spend(2), spend(1)]), "Duplicate transaction id"));
+        
+        // Legacy sigops are counted without the UTXO set: 20 × 4 per OP_CHECKMULTISIG
+        let mut sigop_heavy = spend(1);
+        sigop_heavy.outputs[0].script_pubkey = vec![0xae; 1001];
+        assert!(is_invalid(check(vec![coinbase.clone(), sigop_heavy]), "sigop"));
+        
+        // Stripped size × 4 above the weight limit
+        let mut oversized = spend(1);
+        oversized.outputs[0].script_pubkey = vec![0x6a; 1_000_001];
+        assert!(is_invalid(check(vec![coinbase.clone(), oversized]), "maximum size"));
+        
+        // Context-free: the same block fails connect_block without its inputs
+        let mut block = Block { header: header_at(1231006505), transactions: vec![coinbase, spend(1)] };
+        block.header.merkle_root = calculate_merkle_root(&block.transactions).unwrap();
+        assert_eq!(check_block(&block).unwrap(), ValidationResult::Valid);
+        let (result, _, _) = connect_block(&block, UtxoSet::new(), 1).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(_)));
+    }
 }
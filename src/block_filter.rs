@@ -0,0 +1,274 @@
+//! Compact block filters (BIP158)
+//!
+//! A basic filter is a Golomb-coded set of every script a block pays to
+//! or spends from, so a light client can test it for its own scripts and
+//! download only the blocks that match. Filter headers chain the filters
+//! together the way block headers chain blocks.
+
+use crate::types::*;
+use crate::error::{ConsensusError, Result};
+use crate::block::BlockUndo;
+use crate::serialization::{decode_varint, double_sha256, encode_varint, siphash24};
+use std::collections::BTreeSet;
+
+/// Filter type of the basic filter
+pub const BASIC_FILTER_TYPE: u8 = 0;
+
+/// Golomb-Rice parameter of the basic filter: bits of each remainder
+pub const BASIC_FILTER_P: u8 = 19;
+
+/// False positive parameter of the basic filter: 1/M of absent items match
+pub const BASIC_FILTER_M: u64 = 784_931;
+
+/// BlockFilter: a block's filter type, hash and encoded Golomb-coded set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFilter {
+    pub filter_type: u8,
+    pub block_hash: Hash,
+    /// |set| as a varint followed by the Golomb-Rice coded deltas
+    pub encoded: ByteString,
+}
+
+impl BlockFilter {
+    /// BasicFilter: ℬ × 𝒰𝒟 → BlockFilter
+    ///
+    /// 1. Items: the scriptPubKey of every output except empty and
+    ///    OP_RETURN ones, and of every coin the block spends (from its
+    ///    undo data), without duplicates
+    /// 2. Encode them as a Golomb-coded set keyed by the block hash
+    pub fn basic(block: &Block, undo: &BlockUndo) -> Result<Self> {
+        let spending = block.transactions.len().saturating_sub(1);
+        if undo.tx_undo.len() != spending {
+            return Err(ConsensusError::BlockValidation(format!(
+                "Undo data for {} transactions, block spends in {}", undo.tx_undo.len(), spending
+            )));
+        }
+
+        // 1. Items
+        let mut items: BTreeSet<&[u8]> = BTreeSet::new();
+        for tx in &block.transactions {
+            for output in &tx.outputs {
+                if !output.script_pubkey.is_empty() && output.script_pubkey[0] != 0x6a {
+                    items.insert(&output.script_pubkey);
+                }
+            }
+        }
+        for coin in undo.tx_undo.iter().flat_map(|tx_undo| &tx_undo.spent) {
+            if !coin.script_pubkey.is_empty() {
+                items.insert(&coin.script_pubkey);
+            }
+        }
+
+        // 2. Encode
+        let block_hash = block.header.block_hash();
+        let items: Vec<&[u8]> = items.into_iter().collect();
+        Ok(BlockFilter { filter_type: BASIC_FILTER_TYPE, block_hash, encoded: encode_gcs(&block_hash, &items) })
+    }
+
+    /// Hash256 of the encoded filter
+    pub fn filter_hash(&self) -> Hash {
+        double_sha256(&self.encoded)
+    }
+
+    /// FilterHeader: BlockFilter × ℍ → ℍ
+    ///
+    /// Hash256(filter_hash ‖ prev_header); the header before the genesis
+    /// block's filter is all zeros.
+    pub fn filter_header(&self, prev_header: &Hash) -> Hash {
+        let mut data = [0u8; 64];
+        data[..32].copy_from_slice(&self.filter_hash());
+        data[32..].copy_from_slice(prev_header);
+        double_sha256(&data)
+    }
+
+    /// Number of items in the set
+    pub fn len(&self) -> Result<u64> {
+        Ok(decode_varint(&self.encoded)?.0)
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Whether any of `scripts` may be in the filter; a match can be a
+    /// false positive with probability about 1/M per script
+    pub fn match_any(&self, scripts: &[ByteString]) -> Result<bool> {
+        let (n, start) = decode_varint(&self.encoded)?;
+        if n == 0 || scripts.is_empty() {
+            return Ok(false);
+        }
+        let mut queries: Vec<u64> = scripts.iter()
+            .map(|script| hash_to_range(&self.block_hash, n, script))
+            .collect();
+        queries.sort_unstable();
+
+        // Walk the set and the queries together, both ascending
+        let mut reader = BitReader { data: &self.encoded[start..], pos: 0 };
+        let mut value = 0u64;
+        let mut queries = queries.into_iter().peekable();
+        for _ in 0..n {
+            value += reader.golomb_rice(BASIC_FILTER_P)?;
+            while let Some(&query) = queries.peek() {
+                if query == value {
+                    return Ok(true);
+                }
+                if query > value {
+                    break;
+                }
+                queries.next();
+            }
+            if queries.peek().is_none() {
+                break;
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// SipHash keys from the first 16 bytes of the block hash
+fn filter_keys(block_hash: &Hash) -> (u64, u64) {
+    (
+        u64::from_le_bytes(block_hash[0..8].try_into().unwrap()),
+        u64::from_le_bytes(block_hash[8..16].try_into().unwrap()),
+    )
+}
+
+/// HashToRange: item ↦ ⌊SipHash(item) · N · M / 2⁶⁴⌋
+fn hash_to_range(block_hash: &Hash, n: u64, item: &[u8]) -> u64 {
+    let (k0, k1) = filter_keys(block_hash);
+    let range = n as u128 * BASIC_FILTER_M as u128;
+    ((siphash24(k0, k1, item) as u128 * range) >> 64) as u64
+}
+
+/// EncodeGCS: |items| ‖ Golomb-Rice coded gaps between the sorted hashes
+fn encode_gcs(block_hash: &Hash, items: &[&[u8]]) -> ByteString {
+    let n = items.len() as u64;
+    let mut hashes: Vec<u64> = items.iter().map(|item| hash_to_range(block_hash, n, item)).collect();
+    hashes.sort_unstable();
+
+    let mut writer = BitWriter { data: encode_varint(n), bit: 0 };
+    let mut last = 0;
+    for hash in hashes {
+        writer.golomb_rice(BASIC_FILTER_P, hash - last);
+        last = hash;
+    }
+    writer.data
+}
+
+/// Bit stream written most significant bit first
+struct BitWriter {
+    data: ByteString,
+    /// Bits used in the last byte, 0 when it is full
+    bit: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.bit == 0 {
+            self.data.push(0);
+        }
+        if bit {
+            *self.data.last_mut().unwrap() |= 0x80 >> self.bit;
+        }
+        self.bit = (self.bit + 1) % 8;
+    }
+
+    /// The quotient in unary (ones ended by a zero), then `p` bits of
+    /// remainder
+    fn golomb_rice(&mut self, p: u8, value: u64) {
+        for _ in 0..value >> p {
+            self.write_bit(true);
+        }
+        self.write_bit(false);
+        for i in (0..p).rev() {
+            self.write_bit(value >> i & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read_bit(&mut self) -> Result<bool> {
+        let byte = self.data.get(self.pos / 8).ok_or_else(|| {
+            ConsensusError::Serialization("Block filter ends early".to_string())
+        })?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn golomb_rice(&mut self, p: u8) -> Result<u64> {
+        let mut quotient = 0u64;
+        while self.read_bit()? {
+            quotient += 1;
+        }
+        let mut value = quotient << p;
+        for i in (0..p).rev() {
+            value |= (self.read_bit()? as u64) << i;
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::TxUndo;
+    use crate::genesis::genesis_block;
+    use crate::params::Network;
+
+    fn display_hash(s: &str) -> Hash {
+        let mut hash: Hash = (0..32)
+            .map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap())
+            .collect::<Vec<_>>()
+            .try_into()
+            .unwrap();
+        hash.reverse();
+        hash
+    }
+
+    #[test]
+    fn test_basic_filter_testnet_genesis() {
+        // BIP158 test vector for testnet block 0
+        let block = genesis_block(Network::Testnet);
+        let filter = BlockFilter::basic(&block, &BlockUndo { tx_undo: vec![] }).unwrap();
+        assert_eq!(filter.encoded, vec![0x01, 0x9d, 0xfc, 0xa8]);
+        assert_eq!(
+            filter.filter_header(&[0; 32]),
+            display_hash("21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750")
+        );
+        assert_eq!(filter.len().unwrap(), 1);
+        assert!(filter.match_any(&[block.transactions[0].outputs[0].script_pubkey.clone()]).unwrap());
+        assert!(!filter.match_any(&[vec![0x51]]).unwrap());
+    }
+
+    #[test]
+    fn test_basic_filter_items() {
+        let mut block = genesis_block(Network::Regtest);
+        let spent_script = vec![0x00, 0x14, 0xaa, 0xbb];
+        let mut tx = block.transactions[0].clone();
+        tx.outputs = vec![
+            TransactionOutput { value: 1, script_pubkey: vec![0x6a, 0x01, 0x02] },
+            TransactionOutput { value: 1, script_pubkey: vec![] },
+            TransactionOutput { value: 1, script_pubkey: vec![0x51] },
+            TransactionOutput { value: 1, script_pubkey: vec![0x51] },
+        ];
+        block.transactions.push(tx);
+        let spent = UTXO { value: 5, script_pubkey: spent_script.clone(), height: 1, is_coinbase: false };
+        let undo = BlockUndo { tx_undo: vec![TxUndo { spent: vec![spent] }] };
+
+        // The coinbase script, OP_1 once, and the spent script
+        let filter = BlockFilter::basic(&block, &undo).unwrap();
+        assert_eq!(filter.len().unwrap(), 3);
+        assert!(filter.match_any(&[vec![0x99], spent_script]).unwrap());
+        assert!(filter.match_any(&[vec![0x51]]).unwrap());
+        assert!(!filter.match_any(&[vec![0x6a, 0x01, 0x02], vec![]]).unwrap());
+
+        // Undo data must cover every spending transaction
+        assert!(BlockFilter::basic(&block, &BlockUndo { tx_undo: vec![] }).is_err());
+    }
+}
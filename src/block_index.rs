@@ -0,0 +1,497 @@
+//! Block index: a tree of known headers with heights, cumulative work and
+//! skip pointers for O(log n) ancestor lookups
+
+use crate::types::*;
+use crate::constants::*;
+use crate::error::{ConsensusError, Result};
+use crate::pow::block_proof;
+use std::collections::HashMap;
+
+/// Position of an entry in a `BlockIndex`
+pub type BlockId = usize;
+
+/// Validity of an index entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockStatus {
+    /// Not known to be invalid
+    Valid,
+    /// Found or marked invalid itself
+    Invalid,
+    /// Descends from an invalid block
+    InvalidAncestor,
+}
+
+/// One header in the index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockIndexEntry {
+    pub header: BlockHeader,
+    pub hash: Hash,
+    pub height: Natural,
+    /// Total work of the chain ending at this header, itself included
+    pub chain_work: U256,
+    /// Parent entry; `None` only for the root
+    pub prev: Option<BlockId>,
+    /// Ancestor at height SkipHeight(height), for fast ancestor lookups
+    pub skip: Option<BlockId>,
+    pub status: BlockStatus,
+}
+
+/// Where two chains part: their last common block, and how to move from
+/// the tip of one to the tip of the other
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForkPoint {
+    /// Hash of the last common block
+    pub ancestor: Hash,
+    /// Blocks of the first chain above the ancestor, tip first
+    pub disconnect: Vec<Hash>,
+    /// Blocks of the second chain above the ancestor, oldest first
+    pub connect: Vec<Hash>,
+}
+
+/// Number of most recent blocks a locator lists one by one
+const LOCATOR_DENSE_BLOCKS: usize = 10;
+
+/// BlockIndex: the tree of all known headers, rooted at the first one inserted
+///
+/// Entries are never removed, so a `BlockId` stays valid for the lifetime of
+/// the index. Forks share their common ancestors.
+#[derive(Debug, Clone, Default)]
+pub struct BlockIndex {
+    entries: Vec<BlockIndexEntry>,
+    by_hash: HashMap<Hash, BlockId>,
+}
+
+impl BlockIndex {
+    /// Create an empty index
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of headers in the index
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the index holds no headers
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entry by id
+    pub fn get(&self, id: BlockId) -> Option<&BlockIndexEntry> {
+        self.entries.get(id)
+    }
+
+    /// Id of the header with the given hash
+    pub fn lookup(&self, hash: &Hash) -> Option<BlockId> {
+        self.by_hash.get(hash).copied()
+    }
+
+    /// Add a header and return its id
+    ///
+    /// The first header becomes the root at height 0; every later header
+    /// must extend a header already in the index. Inserting a known header
+    /// returns its existing id.
+    pub fn insert(&mut self, header: BlockHeader) -> Result<BlockId> {
+        let hash = header.block_hash();
+        if let Some(id) = self.lookup(&hash) {
+            return Ok(id);
+        }
+
+        let work = block_proof(&header);
+        let (prev, height, chain_work, skip, status) = if self.entries.is_empty() {
+            (None, 0, work, None, BlockStatus::Valid)
+        } else {
+            let prev = self.lookup(&header.prev_block_hash).ok_or_else(|| {
+                ConsensusError::BlockValidation("Header does not connect to the block index".to_string())
+            })?;
+            let parent = &self.entries[prev];
+            let height = parent.height + 1;
+            let skip = self.ancestor(prev, skip_height(height));
+            let status = match parent.status {
+                BlockStatus::Valid => BlockStatus::Valid,
+                _ => BlockStatus::InvalidAncestor,
+            };
+            (Some(prev), height, parent.chain_work.saturating_add(&work), skip, status)
+        };
+
+        let id = self.entries.len();
+        self.entries.push(BlockIndexEntry { header, hash, height, chain_work, prev, skip, status });
+        self.by_hash.insert(hash, id);
+        Ok(id)
+    }
+
+    /// GetAncestor: BlockId × ℕ → BlockId
+    ///
+    /// The ancestor of `id` at `height` (`id` itself at its own height), or
+    /// `None` if `height` is above `id`. Follows skip pointers whenever they
+    /// do not overshoot, taking O(log n) steps.
+    pub fn ancestor(&self, id: BlockId, height: Natural) -> Option<BlockId> {
+        let mut walk = self.get(id)?;
+        let mut walk_id = id;
+        if height > walk.height {
+            return None;
+        }
+
+        while walk.height > height {
+            let walk_height = walk.height;
+            let skip_target = skip_height(walk_height);
+            let skip_target_prev = skip_height(walk_height - 1);
+
+            // Take the skip unless the parent's skip lands closer without overshooting
+            let take_skip = walk.skip.is_some()
+                && (skip_target == height
+                    || (skip_target > height
+                        && !(skip_target_prev + 2 < skip_target && skip_target_prev >= height)));
+
+            walk_id = if take_skip { walk.skip? } else { walk.prev? };
+            walk = &self.entries[walk_id];
+        }
+
+        Some(walk_id)
+    }
+
+    /// Up to `count` headers ending at `id`, oldest first
+    ///
+    /// The result is suitable as `prev_headers` for the child of `id`.
+    pub fn headers_ending_at(&self, id: BlockId, count: usize) -> Vec<BlockHeader> {
+        let mut headers = Vec::with_capacity(count);
+        let mut walk = self.get(id);
+        while let Some(entry) = walk {
+            if headers.len() == count {
+                break;
+            }
+            headers.push(entry.header.clone());
+            walk = entry.prev.map(|prev| &self.entries[prev]);
+        }
+        headers.reverse();
+        headers
+    }
+
+    /// MedianTimePast of the chain ending at `id`
+    pub fn median_time_past(&self, id: BlockId) -> Natural {
+        crate::block::median_time_past(&self.headers_ending_at(id, MEDIAN_TIME_SPAN))
+    }
+
+    /// Last common ancestor of two entries
+    pub fn fork_point(&self, a: BlockId, b: BlockId) -> Option<BlockId> {
+        let height = self.get(a)?.height.min(self.get(b)?.height);
+        let mut a = self.ancestor(a, height)?;
+        let mut b = self.ancestor(b, height)?;
+        while a != b {
+            a = self.entries[a].prev?;
+            b = self.entries[b].prev?;
+        }
+        Some(a)
+    }
+
+    /// ForkPath: BlockId × BlockId → ForkPoint
+    ///
+    /// The fork point of `from` and `to` with the blocks that moving the
+    /// tip from `from` to `to` disconnects and connects.
+    pub fn fork_path(&self, from: BlockId, to: BlockId) -> Option<ForkPoint> {
+        let ancestor = self.fork_point(from, to)?;
+        let walk_down = |mut id: BlockId| {
+            let mut hashes = Vec::new();
+            while id != ancestor {
+                hashes.push(self.entries[id].hash);
+                id = self.entries[id].prev.expect("the ancestor is below");
+            }
+            hashes
+        };
+        let mut connect = walk_down(to);
+        connect.reverse();
+        Some(ForkPoint { ancestor: self.entries[ancestor].hash, disconnect: walk_down(from), connect })
+    }
+
+    /// Whether `descendant` is `ancestor` or builds on it
+    pub fn is_descendant(&self, descendant: BlockId, ancestor: BlockId) -> bool {
+        self.get(ancestor)
+            .is_some_and(|entry| self.ancestor(descendant, entry.height) == Some(ancestor))
+    }
+
+    /// BestTip: the valid entry with the most chain work
+    ///
+    /// Among equal work the entry inserted first wins, as a node keeps the
+    /// first-seen tip. `None` if every entry is invalid.
+    pub fn best_tip(&self) -> Option<BlockId> {
+        self.entries.iter().enumerate()
+            .filter(|(_, entry)| entry.status == BlockStatus::Valid)
+            .fold(None, |best: Option<(BlockId, &BlockIndexEntry)>, (id, entry)| match best {
+                Some((_, best_entry)) if best_entry.chain_work >= entry.chain_work => best,
+                _ => Some((id, entry)),
+            })
+            .map(|(id, _)| id)
+    }
+
+    /// InvalidateBlock: ℍ → BlockId
+    ///
+    /// Mark the block with `hash` invalid and its descendants as building on
+    /// an invalid block, as Core's `invalidateblock` does, and return the
+    /// new best tip. Fails for an unknown hash.
+    pub fn invalidate_block(&mut self, hash: &Hash) -> Result<Option<BlockId>> {
+        let id = self.lookup_or_err(hash)?;
+        for descendant in 0..self.entries.len() {
+            if descendant != id && self.is_descendant(descendant, id) {
+                self.entries[descendant].status = BlockStatus::InvalidAncestor;
+            }
+        }
+        self.entries[id].status = BlockStatus::Invalid;
+        Ok(self.best_tip())
+    }
+
+    /// ReconsiderBlock: ℍ → BlockId
+    ///
+    /// Undo InvalidateBlock, as Core's `reconsiderblock` does: clear the
+    /// invalid status of the block with `hash`, its descendants and its
+    /// ancestors, and return the new best tip. Blocks on other branches
+    /// keep their status. Fails for an unknown hash.
+    pub fn reconsider_block(&mut self, hash: &Hash) -> Result<Option<BlockId>> {
+        let id = self.lookup_or_err(hash)?;
+        for other in 0..self.entries.len() {
+            if self.is_descendant(other, id) || self.is_descendant(id, other) {
+                self.entries[other].status = BlockStatus::Valid;
+            }
+        }
+        Ok(self.best_tip())
+    }
+
+    fn lookup_or_err(&self, hash: &Hash) -> Result<BlockId> {
+        self.lookup(hash).ok_or_else(|| {
+            ConsensusError::BlockValidation("Block is not in the block index".to_string())
+        })
+    }
+
+    /// BlockLocator: BlockId → ℍ*
+    ///
+    /// Hashes of `id` and its ancestors, densely for the last
+    /// LOCATOR_DENSE_BLOCKS then doubling the step back to the root, which
+    /// is always last. A peer finds the fork point with its own chain in
+    /// O(log n) hashes.
+    pub fn locator(&self, id: BlockId) -> Vec<Hash> {
+        let mut hashes = Vec::new();
+        let mut walk = self.get(id);
+        let mut step = 1;
+        while let Some(entry) = walk {
+            hashes.push(entry.hash);
+            if entry.height == 0 {
+                break;
+            }
+            if hashes.len() >= LOCATOR_DENSE_BLOCKS {
+                step *= 2;
+            }
+            walk = self.ancestor(id, entry.height.saturating_sub(step)).and_then(|next| self.get(next));
+        }
+        hashes
+    }
+
+    /// FindForkInLocator: BlockId × ℍ* → BlockId
+    ///
+    /// The first locator hash on the chain ending at `tip`: the last block
+    /// that chain shares with the chain the locator describes. Falls back
+    /// to the root when no hash matches; `None` only for an unknown `tip`.
+    pub fn find_fork_in_locator(&self, tip: BlockId, locator: &[Hash]) -> Option<BlockId> {
+        self.get(tip)?;
+        let on_chain = locator.iter().filter_map(|hash| self.lookup(hash)).find(|&id| {
+            self.ancestor(tip, self.entries[id].height) == Some(id)
+        });
+        Some(on_chain.unwrap_or(0))
+    }
+}
+
+/// SkipHeight: ℕ → ℕ
+///
+/// Height the skip pointer of a block at `height` points to: `height` with
+/// its lowest set bit cleared, adjusted for odd heights so that skips of
+/// neighbouring blocks differ and lookups stay logarithmic.
+fn skip_height(height: Natural) -> Natural {
+    fn invert_lowest_one(n: Natural) -> Natural {
+        n & n.wrapping_sub(1)
+    }
+
+    if height < 2 {
+        return 0;
+    }
+    if height & 1 == 1 {
+        invert_lowest_one(invert_lowest_one(height - 1)) + 1
+    } else {
+        invert_lowest_one(height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(prev_block_hash: Hash, timestamp: Natural, nonce: Natural) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block_hash,
+            merkle_root: [0; 32],
+            timestamp,
+            bits: 0x1d00ffff,
+            nonce,
+        }
+    }
+
+    /// Extend `tip` by `len` headers, returning the ids in order
+    fn extend(index: &mut BlockIndex, tip: BlockId, len: usize, nonce: Natural) -> Vec<BlockId> {
+        let mut ids = Vec::new();
+        let mut prev = tip;
+        for _ in 0..len {
+            let parent = index.get(prev).unwrap();
+            let next = header(parent.hash, parent.header.timestamp + 600, nonce);
+            prev = index.insert(next).unwrap();
+            ids.push(prev);
+        }
+        ids
+    }
+
+    #[test]
+    fn test_ancestor_matches_linear_walk() {
+        let mut index = BlockIndex::new();
+        let root = index.insert(header([0; 32], 1231006505, 0)).unwrap();
+        let chain: Vec<BlockId> = std::iter::once(root).chain(extend(&mut index, root, 1500, 0)).collect();
+
+        let tip = *chain.last().unwrap();
+        for height in [0, 1, 2, 3, 511, 512, 513, 1000, 1499, 1500] {
+            assert_eq!(index.ancestor(tip, height), Some(chain[height as usize]));
+            assert_eq!(index.get(chain[height as usize]).unwrap().height, height);
+        }
+        assert_eq!(index.ancestor(tip, 1501), None);
+        for (height, &id) in chain.iter().enumerate().step_by(97) {
+            assert_eq!(index.ancestor(id, height as Natural / 2), Some(chain[height / 2]));
+        }
+
+        // Cumulative work grows by one block's work per header
+        let work = block_proof(&index.get(root).unwrap().header);
+        assert_eq!(index.get(tip).unwrap().chain_work, work.checked_mul_u64(1501).unwrap());
+    }
+
+    #[test]
+    fn test_forks_and_fork_point() {
+        let mut index = BlockIndex::new();
+        let root = index.insert(header([0; 32], 1231006505, 0)).unwrap();
+        let main = extend(&mut index, root, 100, 0);
+        let fork = extend(&mut index, main[59], 30, 1);
+
+        assert_eq!(index.len(), 131);
+        assert_eq!(index.get(fork[29]).unwrap().height, 90);
+        assert_eq!(index.ancestor(fork[29], 60), Some(main[59]));
+        assert_eq!(index.fork_point(main[99], fork[29]), Some(main[59]));
+        assert_eq!(index.fork_point(main[99], main[10]), Some(main[10]));
+
+        // Re-inserting is a no-op, unknown parents are rejected
+        let known = index.get(main[5]).unwrap().header.clone();
+        assert_eq!(index.insert(known).unwrap(), main[5]);
+        assert!(index.insert(header([0xee; 32], 1231006505, 0)).is_err());
+    }
+
+    #[test]
+    fn test_fork_path() {
+        let mut index = BlockIndex::new();
+        let root = index.insert(header([0; 32], 1231006505, 0)).unwrap();
+        let main = extend(&mut index, root, 10, 0);
+        let fork = extend(&mut index, main[4], 3, 1);
+        let hash = |id: BlockId| index.get(id).unwrap().hash;
+
+        let path = index.fork_path(main[9], fork[2]).unwrap();
+        assert_eq!(path.ancestor, hash(main[4]));
+        assert_eq!(path.disconnect, main[5..].iter().rev().map(|&id| hash(id)).collect::<Vec<_>>());
+        assert_eq!(path.connect, fork.iter().map(|&id| hash(id)).collect::<Vec<_>>());
+
+        // Moving along one chain only connects, or only disconnects
+        let forward = index.fork_path(main[2], main[4]).unwrap();
+        assert!(forward.disconnect.is_empty());
+        assert_eq!(forward.connect, vec![hash(main[3]), hash(main[4])]);
+        assert!(index.fork_path(main[4], main[4]).unwrap().connect.is_empty());
+        assert_eq!(index.fork_path(main[4], 999), None);
+    }
+
+    #[test]
+    fn test_locator_and_fork_in_locator() {
+        let mut index = BlockIndex::new();
+        let root = index.insert(header([0; 32], 1231006505, 0)).unwrap();
+        let main = extend(&mut index, root, 100, 0);
+        let fork = extend(&mut index, main[69], 20, 1);
+        let height_of = |hash: &Hash| index.get(index.lookup(hash).unwrap()).unwrap().height;
+
+        // Ten dense entries, then doubling steps, ending at the root
+        let locator = index.locator(fork[19]);
+        let heights: Vec<Natural> = locator.iter().map(height_of).collect();
+        assert_eq!(heights, vec![90, 89, 88, 87, 86, 85, 84, 83, 82, 81, 79, 75, 67, 51, 19, 0]);
+        assert_eq!(index.locator(root), vec![index.get(root).unwrap().hash]);
+
+        // The main chain meets the fork's locator below the fork point
+        let fork_point = index.find_fork_in_locator(main[99], &locator).unwrap();
+        assert_eq!(index.get(fork_point).unwrap().height, 67);
+        assert_eq!(index.find_fork_in_locator(fork[19], &locator), Some(fork[19]));
+
+        // Unknown hashes are skipped, and nothing known means the root
+        let shared = index.get(main[69]).unwrap().hash;
+        assert_eq!(index.find_fork_in_locator(main[99], &[[0xee; 32], locator[3], shared]), Some(main[69]));
+        assert_eq!(index.find_fork_in_locator(main[99], &[[0xee; 32]]), Some(root));
+    }
+
+    #[test]
+    fn test_invalidate_and_reconsider_block() {
+        let mut index = BlockIndex::new();
+        let root = index.insert(header([0; 32], 1231006505, 0)).unwrap();
+        let main = extend(&mut index, root, 10, 0);
+        let fork = extend(&mut index, main[4], 3, 1);
+        let hash = |index: &BlockIndex, id: BlockId| index.get(id).unwrap().hash;
+        assert_eq!(index.best_tip(), Some(main[9]));
+
+        // Invalidating the main chain above the fork moves to the fork
+        let bad = hash(&index, main[6]);
+        assert_eq!(index.invalidate_block(&bad).unwrap(), Some(fork[2]));
+        assert_eq!(index.get(main[6]).unwrap().status, BlockStatus::Invalid);
+        assert_eq!(index.get(main[9]).unwrap().status, BlockStatus::InvalidAncestor);
+        assert_eq!(index.get(main[5]).unwrap().status, BlockStatus::Valid);
+
+        // New headers on an invalid block are invalid too
+        let child = extend(&mut index, main[9], 1, 0)[0];
+        assert_eq!(index.get(child).unwrap().status, BlockStatus::InvalidAncestor);
+
+        // Invalidating the fork as well leaves the longest valid prefix
+        let fork_tip = hash(&index, fork[2]);
+        assert_eq!(index.invalidate_block(&fork_tip).unwrap(), Some(fork[1]));
+
+        // Reconsidering restores the block, its descendants and ancestors only
+        assert_eq!(index.reconsider_block(&hash(&index, main[8])).unwrap(), Some(child));
+        assert_eq!(index.get(main[6]).unwrap().status, BlockStatus::Valid);
+        assert_eq!(index.get(fork[2]).unwrap().status, BlockStatus::Invalid);
+
+        assert!(index.invalidate_block(&[0xee; 32]).is_err());
+        let root_hash = hash(&index, root);
+        assert_eq!(index.invalidate_block(&root_hash).unwrap(), None);
+    }
+
+    #[test]
+    fn test_headers_and_median_time_past() {
+        let mut index = BlockIndex::new();
+        let root = index.insert(header([0; 32], 1000, 0)).unwrap();
+        let chain = extend(&mut index, root, 20, 0);
+        let tip = *chain.last().unwrap();
+
+        let headers = index.headers_ending_at(tip, 11);
+        assert_eq!(headers.len(), 11);
+        assert_eq!(headers.last().unwrap(), &index.get(tip).unwrap().header);
+        assert_eq!(index.median_time_past(tip), crate::block::median_time_past(&headers));
+        assert_eq!(index.headers_ending_at(root, 11).len(), 1);
+    }
+
+    #[test]
+    fn test_skip_height() {
+        assert_eq!(skip_height(0), 0);
+        assert_eq!(skip_height(1), 0);
+        assert_eq!(skip_height(2), 0);
+        assert_eq!(skip_height(8), 0);
+        assert_eq!(skip_height(12), 8);
+        assert_eq!(skip_height(13), 1);
+        assert_eq!(skip_height(15), 9);
+        for height in 2..5000 {
+            assert!(skip_height(height) < height);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Chain tree: every known branch of the block tree, with the work and
+//! status of its tip
+//!
+//! A `BlockIndex` records headers; the chain tree adds which blocks have
+//! their data, so it can tell the best chain that is ready to be active
+//! from branches still waiting on downloads or known to be invalid.
+//! Headers that do not connect yet wait in an orphan pool.
+
+pub mod orphans;
+
+use crate::types::*;
+use crate::error::{ConsensusError, Result};
+use crate::block_index::{BlockId, BlockIndex, BlockStatus};
+use std::cmp::Reverse;
+use std::collections::{HashSet, VecDeque};
+use self::orphans::OrphanHeaderPool;
+
+/// Status of a branch tip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TipStatus {
+    /// The tip and all its ancestors have data and are not invalid
+    Valid,
+    /// The tip or an ancestor is invalid
+    Invalid,
+    /// Not invalid, but the data of the tip or an ancestor is missing
+    MissingData,
+}
+
+/// A leaf of the block tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainTip {
+    pub id: BlockId,
+    pub hash: Hash,
+    pub height: Natural,
+    pub chain_work: U256,
+    pub status: TipStatus,
+}
+
+/// ChainTree: the block index plus which blocks have their data
+#[derive(Debug, Clone)]
+pub struct ChainTree {
+    index: BlockIndex,
+    have_data: HashSet<BlockId>,
+    orphans: OrphanHeaderPool,
+}
+
+impl ChainTree {
+    /// Create a tree rooted at `genesis`, whose data is taken as present
+    pub fn new(genesis: BlockHeader) -> Self {
+        let mut index = BlockIndex::new();
+        let root = index.insert(genesis).expect("the first header is the root");
+        ChainTree { index, have_data: HashSet::from([root]), orphans: OrphanHeaderPool::default() }
+    }
+
+    /// Use `orphans` for headers whose parent is unknown
+    pub fn with_orphan_pool(mut self, orphans: OrphanHeaderPool) -> Self {
+        self.orphans = orphans;
+        self
+    }
+
+    /// The underlying block index
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
+    }
+
+    /// Add a header extending a known block; its data starts out missing
+    pub fn add_header(&mut self, header: BlockHeader) -> Result<BlockId> {
+        self.index.insert(header)
+    }
+
+    /// Headers waiting for their parent
+    pub fn orphans(&self) -> &OrphanHeaderPool {
+        &self.orphans
+    }
+
+    /// ProcessHeader: ChainTree × ℋ → ChainTree × [BlockId]
+    ///
+    /// 1. If the parent is unknown, hold the header in the orphan pool
+    /// 2. Otherwise add it to the index
+    /// 3. Connect every orphan descending from it, parents first
+    ///
+    /// Returns the ids of the headers added to the index, empty if the
+    /// header became an orphan.
+    pub fn process_header(&mut self, header: BlockHeader) -> Result<Vec<BlockId>> {
+        let hash = header.block_hash();
+        if let Some(id) = self.index.lookup(&hash) {
+            return Ok(vec![id]);
+        }
+
+        // 1. Unknown parent
+        if self.index.lookup(&header.prev_block_hash).is_none() {
+            self.orphans.add(header);
+            return Ok(Vec::new());
+        }
+
+        // 2. Connect the header
+        let mut added = vec![self.index.insert(header)?];
+
+        // 3. Connect its orphaned descendants, each leaving the pool only
+        //    once the index has accepted or rejected it
+        let mut pending = VecDeque::from([hash]);
+        while let Some(parent) = pending.pop_front() {
+            for orphan in self.orphans.children(&parent) {
+                let orphan_hash = orphan.block_hash();
+                let inserted = self.index.insert(orphan);
+                self.orphans.remove(&orphan_hash);
+                added.push(inserted?);
+                pending.push_back(orphan_hash);
+            }
+        }
+        Ok(added)
+    }
+
+    /// Record that the data of a known block has been received
+    pub fn add_block_data(&mut self, hash: &Hash) -> Result<BlockId> {
+        let id = self.index.lookup(hash).ok_or_else(|| {
+            ConsensusError::BlockValidation("Block is not in the chain tree".to_string())
+        })?;
+        self.have_data.insert(id);
+        Ok(id)
+    }
+
+    /// Mark a block invalid and its descendants as building on it
+    pub fn invalidate_block(&mut self, hash: &Hash) -> Result<()> {
+        self.index.invalidate_block(hash).map(|_| ())
+    }
+
+    /// Clear the invalid status of a block, its descendants and ancestors
+    pub fn reconsider_block(&mut self, hash: &Hash) -> Result<()> {
+        self.index.reconsider_block(hash).map(|_| ())
+    }
+
+    /// Every leaf of the tree with its status, most work first
+    ///
+    /// Among equal work the branch seen first comes first.
+    pub fn tips(&self) -> Vec<ChainTip> {
+        let chain_has_data = self.chain_has_data();
+        let mut has_child = vec![false; self.index.len()];
+        for id in 0..self.index.len() {
+            if let Some(prev) = self.index.get(id).and_then(|entry| entry.prev) {
+                has_child[prev] = true;
+            }
+        }
+
+        let mut tips: Vec<ChainTip> = (0..self.index.len())
+            .filter(|&id| !has_child[id])
+            .map(|id| self.tip(id, &chain_has_data))
+            .collect();
+        tips.sort_by_key(|tip| (Reverse(tip.chain_work), tip.id));
+        tips
+    }
+
+    /// BestTip: the block the active chain should end at
+    ///
+    /// The block with the most work whose chain is valid and has all its
+    /// data, the first seen among equals. This need not be a leaf: a
+    /// branch with more work may still be downloading.
+    pub fn best_tip(&self) -> Option<ChainTip> {
+        let chain_has_data = self.chain_has_data();
+        (0..self.index.len())
+            .filter(|&id| chain_has_data[id] && self.status(id) == BlockStatus::Valid)
+            .min_by_key(|&id| (Reverse(self.index.get(id).map(|entry| entry.chain_work)), id))
+            .map(|id| self.tip(id, &chain_has_data))
+    }
+
+    /// Tips that could still become the best chain: those not invalid,
+    /// most work first
+    ///
+    /// The best candidate with missing data is the branch to download next.
+    pub fn candidate_tips(&self) -> Vec<ChainTip> {
+        self.tips().into_iter().filter(|tip| tip.status != TipStatus::Invalid).collect()
+    }
+
+    fn status(&self, id: BlockId) -> BlockStatus {
+        self.index.get(id).map_or(BlockStatus::Invalid, |entry| entry.status)
+    }
+
+    fn tip(&self, id: BlockId, chain_has_data: &[bool]) -> ChainTip {
+        let entry = self.index.get(id).expect("tips come from the index");
+        let status = if entry.status != BlockStatus::Valid {
+            TipStatus::Invalid
+        } else if !chain_has_data[id] {
+            TipStatus::MissingData
+        } else {
+            TipStatus::Valid
+        };
+        ChainTip { id, hash: entry.hash, height: entry.height, chain_work: entry.chain_work, status }
+    }
+
+    /// Whether each block and all its ancestors have data; parents always
+    /// have lower ids than their children
+    fn chain_has_data(&self) -> Vec<bool> {
+        let mut has_data = vec![false; self.index.len()];
+        for id in 0..self.index.len() {
+            let parent_has_data = self.index.get(id)
+                .and_then(|entry| entry.prev)
+                .is_none_or(|prev| has_data[prev]);
+            has_data[id] = parent_has_data && self.have_data.contains(&id);
+        }
+        has_data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(prev_block_hash: Hash, nonce: Natural) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block_hash,
+            merkle_root: [0; 32],
+            timestamp: 1231006505,
+            bits: 0x1d00ffff,
+            nonce,
+        }
+    }
+
+    /// Add `len` headers on top of `parent`, with their data if `with_data`
+    fn extend(tree: &mut ChainTree, parent: Hash, len: usize, nonce: Natural, with_data: bool) -> Vec<Hash> {
+        let mut hashes = Vec::new();
+        let mut prev = parent;
+        for _ in 0..len {
+            let next = header(prev, nonce);
+            prev = next.block_hash();
+            tree.add_header(next).unwrap();
+            if with_data {
+                tree.add_block_data(&prev).unwrap();
+            }
+            hashes.push(prev);
+        }
+        hashes
+    }
+
+    #[test]
+    fn test_chain_tree_tips() {
+        let genesis = header([0; 32], 0);
+        let mut tree = ChainTree::new(genesis.clone());
+        let main = extend(&mut tree, genesis.block_hash(), 5, 1, true);
+        let fork = extend(&mut tree, main[1], 2, 2, true);
+        let headers_only = extend(&mut tree, main[4], 3, 3, false);
+
+        // The longest branch lacks data, so the best tip is the last block with it
+        let tips = tree.tips();
+        assert_eq!(tips.iter().map(|tip| (tip.hash, tip.status)).collect::<Vec<_>>(), vec![
+            (headers_only[2], TipStatus::MissingData),
+            (fork[1], TipStatus::Valid),
+        ]);
+        assert_eq!(tree.best_tip().unwrap().hash, main[4]);
+        assert_eq!(tree.best_tip().unwrap().height, 5);
+
+        // Data arriving for the whole branch makes it the best chain
+        for hash in &headers_only {
+            tree.add_block_data(hash).unwrap();
+        }
+        assert_eq!(tree.best_tip().unwrap().hash, headers_only[2]);
+
+        // An invalid block drops its branch; the fork now has the most work
+        tree.invalidate_block(&main[3]).unwrap();
+        assert_eq!(tree.tips()[0].status, TipStatus::Invalid);
+        let candidates = tree.candidate_tips();
+        assert_eq!(candidates.iter().map(|tip| tip.hash).collect::<Vec<_>>(), vec![fork[1]]);
+        assert_eq!(tree.best_tip().unwrap().hash, fork[1]);
+
+        tree.reconsider_block(&main[3]).unwrap();
+        assert_eq!(tree.best_tip().unwrap().hash, headers_only[2]);
+        assert!(tree.add_block_data(&[0xee; 32]).is_err());
+    }
+
+    #[test]
+    fn test_process_header_connects_orphans() {
+        let genesis = header([0; 32], 0);
+        let mut tree = ChainTree::new(genesis.clone());
+        let mut chain = vec![header(genesis.block_hash(), 1)];
+        for _ in 0..3 {
+            chain.push(header(chain.last().unwrap().block_hash(), 1));
+        }
+
+        // Headers after a missing one wait in the pool
+        assert!(tree.process_header(chain[3].clone()).unwrap().is_empty());
+        assert!(tree.process_header(chain[2].clone()).unwrap().is_empty());
+        assert_eq!(tree.orphans().len(), 2);
+        assert_eq!(tree.tips().len(), 1);
+
+        // Once the gap fills, the whole chain connects in order
+        assert_eq!(tree.process_header(chain[0].clone()).unwrap(), vec![1]);
+        assert_eq!(tree.process_header(chain[1].clone()).unwrap(), vec![2, 3, 4]);
+        assert!(tree.orphans().is_empty());
+        assert_eq!(tree.tips()[0].hash, chain[3].block_hash());
+        assert_eq!(tree.tips()[0].height, 4);
+        assert_eq!(tree.process_header(chain[3].clone()).unwrap(), vec![4]);
+    }
+}
@@ -0,0 +1,148 @@
+//! Orphan header pool: headers whose parent is not yet known
+//!
+//! Headers can arrive out of order from different peers. Rather than drop
+//! a header that does not connect, the pool holds it until its parent
+//! arrives, up to a fixed number of headers with the oldest evicted first.
+
+use crate::types::*;
+use std::collections::{HashMap, VecDeque};
+
+/// Default number of orphan headers kept
+pub const DEFAULT_MAX_ORPHAN_HEADERS: usize = 1000;
+
+/// OrphanHeaderPool: headers waiting for their parent, oldest first
+#[derive(Debug, Clone)]
+pub struct OrphanHeaderPool {
+    headers: HashMap<Hash, BlockHeader>,
+    by_parent: HashMap<Hash, Vec<Hash>>,
+    arrival: VecDeque<Hash>,
+    max_headers: usize,
+}
+
+impl Default for OrphanHeaderPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ORPHAN_HEADERS)
+    }
+}
+
+impl OrphanHeaderPool {
+    /// Create a pool holding at most `max_headers` headers
+    pub fn new(max_headers: usize) -> Self {
+        OrphanHeaderPool {
+            headers: HashMap::new(),
+            by_parent: HashMap::new(),
+            arrival: VecDeque::new(),
+            max_headers,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    /// AddOrphan: OrphanHeaderPool × ℋ → OrphanHeaderPool × [Hash]
+    ///
+    /// 1. A header already in the pool is ignored
+    /// 2. Store the header under its own hash and its parent's
+    /// 3. While the pool is over its limit, evict the oldest header
+    ///
+    /// Returns the hashes evicted; with a limit of zero this is the header
+    /// itself.
+    pub fn add(&mut self, header: BlockHeader) -> Vec<Hash> {
+        let hash = header.block_hash();
+        // 1. Already held
+        if self.contains(&hash) {
+            return Vec::new();
+        }
+
+        // 2. Store
+        self.by_parent.entry(header.prev_block_hash).or_default().push(hash);
+        self.headers.insert(hash, header);
+        self.arrival.push_back(hash);
+
+        // 3. Evict the oldest
+        let mut evicted = Vec::new();
+        while self.headers.len() > self.max_headers {
+            let Some(oldest) = self.arrival.pop_front() else { break };
+            if self.remove(&oldest).is_some() {
+                evicted.push(oldest);
+            }
+        }
+        evicted
+    }
+
+    /// Remove a header from the pool
+    pub fn remove(&mut self, hash: &Hash) -> Option<BlockHeader> {
+        let header = self.headers.remove(hash)?;
+        if let Some(siblings) = self.by_parent.get_mut(&header.prev_block_hash) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.by_parent.remove(&header.prev_block_hash);
+            }
+        }
+        self.arrival.retain(|queued| queued != hash);
+        Some(header)
+    }
+
+    /// The held headers whose parent is `parent`, oldest first
+    ///
+    /// They stay in the pool; `remove` each once it has been connected or
+    /// rejected.
+    pub fn children(&self, parent: &Hash) -> Vec<BlockHeader> {
+        self.by_parent.get(parent)
+            .map(|children| children.iter().map(|child| self.headers[child].clone()).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(prev_block_hash: Hash, nonce: Natural) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block_hash,
+            merkle_root: [0; 32],
+            timestamp: 1231006505,
+            bits: 0x1d00ffff,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn test_orphan_pool_limit_and_descendants() {
+        let mut pool = OrphanHeaderPool::new(3);
+        let a = header([1; 32], 0);
+        let b = header(a.block_hash(), 0);
+        let c = header(b.block_hash(), 0);
+        let sibling = header(a.block_hash(), 1);
+
+        // Children arriving before their parents are all held
+        assert!(pool.add(c.clone()).is_empty());
+        assert!(pool.add(b.clone()).is_empty());
+        assert!(pool.add(b.clone()).is_empty());
+        assert!(pool.add(a.clone()).is_empty());
+        assert_eq!(pool.len(), 3);
+
+        // Over the limit the oldest goes first
+        assert_eq!(pool.add(sibling.clone()), vec![c.block_hash()]);
+        assert!(!pool.contains(&c.block_hash()));
+
+        // Children are found by parent and stay until removed
+        assert_eq!(pool.children(&[1; 32]), vec![a.clone()]);
+        assert_eq!(pool.children(&a.block_hash()), vec![b.clone(), sibling.clone()]);
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.remove(&b.block_hash()), Some(b));
+        assert_eq!(pool.children(&a.block_hash()), vec![sibling]);
+        assert!(pool.children(&c.block_hash()).is_empty());
+    }
+}
@@ -24,6 +24,37 @@ pub const MAX_STACK_SIZE: usize = 1000;
 /// Maximum number of operations in script
 pub const MAX_SCRIPT_OPS: usize = 201;
 
+/// Maximum public keys counted for an inexact OP_CHECKMULTISIG
+pub const MAX_PUBKEYS_PER_MULTISIG: u32 = 20;
+
+/// Weight units per byte of non-witness data (also scales legacy sigops)
+pub const WITNESS_SCALE_FACTOR: u64 = 4;
+
+/// Maximum total sigop cost per block
+pub const MAX_BLOCK_SIGOPS_COST: u64 = 80_000;
+
+/// Script verification flags (bit values match Bitcoin Core)
+/// Evaluate P2SH subscripts (BIP16)
+pub const SCRIPT_VERIFY_P2SH: u32 = 1 << 0;
+
+/// Require strict DER signature encoding (BIP66)
+pub const SCRIPT_VERIFY_DERSIG: u32 = 1 << 2;
+
+/// Require the OP_CHECKMULTISIG dummy argument to be empty (BIP147)
+pub const SCRIPT_VERIFY_NULLDUMMY: u32 = 1 << 4;
+
+/// Enable OP_CHECKLOCKTIMEVERIFY (BIP65)
+pub const SCRIPT_VERIFY_CHECKLOCKTIMEVERIFY: u32 = 1 << 9;
+
+/// Enable OP_CHECKSEQUENCEVERIFY (BIP112)
+pub const SCRIPT_VERIFY_CHECKSEQUENCEVERIFY: u32 = 1 << 10;
+
+/// Evaluate witness programs (BIP141)
+pub const SCRIPT_VERIFY_WITNESS: u32 = 1 << 11;
+
+/// Evaluate Taproot spends (BIP341/342)
+pub const SCRIPT_VERIFY_TAPROOT: u32 = 1 << 17;
+
 /// Halving interval: 210,000 blocks
 pub const HALVING_INTERVAL: u64 = 210_000;
 
@@ -33,12 +64,25 @@ pub const INITIAL_SUBSIDY: i64 = 50 * 100_000_000;
 /// Satoshis per BTC
 pub const SATOSHIS_PER_BTC: i64 = 100_000_000;
 
+/// Confirmations a coinbase output needs before it can be spent
+pub const COINBASE_MATURITY: u64 = 100;
+
 /// Difficulty adjustment interval: 2016 blocks
 pub const DIFFICULTY_ADJUSTMENT_INTERVAL: u64 = 2016;
 
 /// Target time per block: 10 minutes
 pub const TARGET_TIME_PER_BLOCK: u64 = 600;
 
+/// Number of previous blocks used for median time past
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Maximum seconds a block timestamp may be ahead of network-adjusted time
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// First height at which a pre-BIP34 coinbase could be repeated, so BIP30
+/// must be enforced again even though BIP34 is active
+pub const BIP34_IMPLIES_BIP30_LIMIT: u64 = 1_983_702;
+
 /// Maximum target (minimum difficulty)
 pub const MAX_TARGET: u32 = 0x1d00ffff;
 
@@ -56,5 +100,17 @@ pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
 /// Sequence number for final transaction
 pub const SEQUENCE_FINAL: u32 = 0xffffffff;
 
-/// Sequence number for RBF
-pub const SEQUENCE_RBF: u32 = 0xfffffffe;
+/// Highest sequence number that signals BIP125 replaceability
+pub const SEQUENCE_RBF: u32 = 0xfffffffd;
+
+/// Sequence bit that disables the input's BIP68 relative lock
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+
+/// Sequence bit that makes a BIP68 relative lock time-based
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+
+/// Sequence bits holding the BIP68 relative lock value
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+
+/// Time-based relative locks count units of 2^9 = 512 seconds
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
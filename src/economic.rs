@@ -4,6 +4,8 @@ use crate::types::*;
 use crate::constants::*;
 use crate::error::{Result, ConsensusError};
 
+pub mod fee_estimation;
+
 /// GetBlockSubsidy: ℕ → ℤ
 /// 
 /// Calculate the block subsidy for a given height.
@@ -15,7 +17,7 @@ use crate::error::{Result, ConsensusError};
 /// - H = HALVING_INTERVAL (210,000)
 /// - C = SATOSHIS_PER_BTC (10^8)
 pub fn get_block_subsidy(height: Natural) -> Integer {
-    let halving_period = height / HALVING_INTERVAL;
+    let halving_period = halving_epoch(height);
     
     // After 64 halvings, subsidy becomes 0
     if halving_period >= 64 {
@@ -29,20 +31,107 @@ pub fn get_block_subsidy(height: Natural) -> Integer {
     subsidy
 }
 
+/// HalvingEpoch: ℕ → ℕ
+/// 
+/// Number of halvings that have happened by `height`: ⌊h/H⌋
+pub fn halving_epoch(height: Natural) -> Natural {
+    height / HALVING_INTERVAL
+}
+
+/// Blocks from `height` to the first block of the next halving epoch
+/// 
+/// Always in 1..=HALVING_INTERVAL; halvings past `final_subsidy_height`
+/// are counted too, although the subsidy is already zero.
+pub fn blocks_until_next_halving(height: Natural) -> Natural {
+    HALVING_INTERVAL - height % HALVING_INTERVAL
+}
+
+/// Height of the last block with a non-zero subsidy
+/// 
+/// The subsidy of epoch e is 50 × C ≫ e, which is non-zero for
+/// e ≤ ⌊log₂(50 × C)⌋ = 32, so issuance ends at 33 × H − 1.
+pub fn final_subsidy_height() -> Natural {
+    (INITIAL_SUBSIDY.ilog2() as Natural + 1) * HALVING_INTERVAL - 1
+}
+
 /// TotalSupply: ℕ → ℤ
 /// 
 /// Calculate the total Bitcoin supply at a given height.
-/// This is the sum of all block subsidies up to that height.
+/// This is the sum of all block subsidies up to and including that height,
+/// computed exactly one halving epoch at a time:
+/// 
+/// TotalSupply(h) = Σ_{e=0}^{63} n_e × (50 × C ≫ e)
+/// 
+/// where n_e is the number of heights in 0..=h that fall in epoch e.
 pub fn total_supply(height: Natural) -> Integer {
+    let blocks = height.saturating_add(1);
     let mut total = 0i64;
     
-    for h in 0..=height {
-        total += get_block_subsidy(h);
+    for epoch in 0..64 {
+        let start = epoch * HALVING_INTERVAL;
+        if start >= blocks {
+            break;
+        }
+        let blocks_in_epoch = (blocks - start).min(HALVING_INTERVAL);
+        total += blocks_in_epoch as i64 * (INITIAL_SUBSIDY >> epoch);
     }
     
     total
 }
 
+/// One halving epoch of the issuance schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubsidyEpoch {
+    /// Number of halvings before this epoch
+    pub epoch: Natural,
+    pub start_height: Natural,
+    /// Last height of the epoch, inclusive
+    pub end_height: Natural,
+    /// Subsidy of every block in the epoch
+    pub subsidy: Integer,
+    /// TotalSupply(end_height): everything issued up to the end of the epoch
+    pub cumulative_supply: Integer,
+}
+
+/// Iterator over the halving epochs that issue coins, in order
+///
+/// Ends after the last epoch with a non-zero subsidy (epoch 32); every
+/// later block's subsidy is zero, so `cumulative_supply` of the final item
+/// is the total supply ever issued.
+#[derive(Debug, Clone, Default)]
+pub struct SubsidySchedule {
+    next_epoch: Natural,
+    cumulative_supply: Integer,
+}
+
+impl Iterator for SubsidySchedule {
+    type Item = SubsidyEpoch;
+
+    fn next(&mut self) -> Option<SubsidyEpoch> {
+        let start_height = self.next_epoch * HALVING_INTERVAL;
+        let subsidy = get_block_subsidy(start_height);
+        if subsidy == 0 {
+            return None;
+        }
+
+        self.cumulative_supply += subsidy * HALVING_INTERVAL as Integer;
+        let epoch = SubsidyEpoch {
+            epoch: self.next_epoch,
+            start_height,
+            end_height: start_height + HALVING_INTERVAL - 1,
+            subsidy,
+            cumulative_supply: self.cumulative_supply,
+        };
+        self.next_epoch += 1;
+        Some(epoch)
+    }
+}
+
+/// SubsidySchedule: the issuance schedule as a sequence of halving epochs
+pub fn subsidy_schedule() -> SubsidySchedule {
+    SubsidySchedule::default()
+}
+
 /// Calculate transaction fee
 /// 
 /// Fee = sum of input values - sum of output values
@@ -79,6 +168,122 @@ pub fn validate_supply_limit(height: Natural) -> Result<bool> {
     Ok(current_supply <= MAX_MONEY)
 }
 
+/// Limits beyond which a fee is treated as a mistake rather than a choice
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbsurdFeeLimits {
+    /// Flag fees above this multiple of the fee at the expected fee rate
+    pub max_fee_multiple: Option<Natural>,
+    /// Flag fees above this many satoshis regardless of size
+    pub max_fee: Option<Integer>,
+}
+
+impl Default for AbsurdFeeLimits {
+    /// 100× the expected fee, and never more than 0.1 BTC
+    fn default() -> Self {
+        AbsurdFeeLimits {
+            max_fee_multiple: Some(100),
+            max_fee: Some(SATOSHIS_PER_BTC / 10),
+        }
+    }
+}
+
+/// IsAbsurdFee: ℤ × ℕ × FeeRate × AbsurdFeeLimits → {true, false}
+/// 
+/// For a transaction of `vsize` virtual bytes paying `fee`:
+/// 1. expectedFee = expectedFeeRate × vsize / 1000
+/// 2. Absurd if fee > expectedFee × maxFeeMultiple
+/// 3. Absurd if fee > maxFee
+/// 
+/// Either limit may be disabled. This is policy, not consensus: mempool
+/// acceptance and wallets use it to catch fee-overpayment bugs.
+pub fn is_absurd_fee(
+    fee: Integer,
+    vsize: Natural,
+    expected_fee_rate: fee_estimation::FeeRate,
+    limits: &AbsurdFeeLimits,
+) -> bool {
+    if limits.max_fee.is_some_and(|max_fee| fee > max_fee) {
+        return true;
+    }
+
+    limits.max_fee_multiple.is_some_and(|multiple| {
+        let expected_fee = expected_fee_rate as i128 * vsize as i128 / 1000;
+        fee as i128 > expected_fee * multiple as i128
+    })
+}
+
+/// MoneyRange: ℤ → {true, false}
+/// 
+/// A value is a valid amount iff 0 ≤ value ≤ MAX_MONEY
+pub fn is_money_range(value: Integer) -> bool {
+    (0..=MAX_MONEY).contains(&value)
+}
+
+/// Sum amounts with overflow protection
+/// 
+/// Returns `None` if any value or any partial sum leaves MoneyRange.
+pub fn checked_amount_sum<I: IntoIterator<Item = Integer>>(values: I) -> Option<Integer> {
+    values.into_iter().try_fold(0, |total: Integer, value| {
+        if !is_money_range(value) {
+            return None;
+        }
+        total.checked_add(value).filter(|&sum| is_money_range(sum))
+    })
+}
+
+/// First block at which the UTXO set holds more than was ever issued
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SupplyViolation {
+    pub height: Natural,
+    pub block_hash: Hash,
+    /// Total value of the UTXO set after connecting the block
+    pub utxo_total: i128,
+    /// TotalSupply(height)
+    pub max_supply: Integer,
+}
+
+/// AuditSupply: ℬ* × 𝒰𝒮 × ℕ → SupplyViolation ∪ {⊥}
+/// 
+/// For blocks b₀, b₁, … connected on top of `utxo_set`, with b₀ at
+/// `start_height`:
+/// 1. Apply each block's transactions to the UTXO set in order
+/// 2. After block bᵢ, require Σ value(us) ≤ TotalSupply(start_height + i)
+/// 3. Return the first block violating this, or ⊥
+/// 
+/// Coins can be destroyed (unclaimed subsidy, fees left on the table) but
+/// never created beyond the issuance schedule, so this holds for any chain
+/// of valid blocks. Blocks are not otherwise validated.
+pub fn audit_supply(blocks: &[Block], utxo_set: &UtxoSet, start_height: Natural) -> Result<Option<SupplyViolation>> {
+    let mut utxo_set = utxo_set.clone();
+    let mut utxo_total: i128 = utxo_set.values().map(|utxo| utxo.value as i128).sum();
+
+    for (i, block) in blocks.iter().enumerate() {
+        let height = start_height + i as Natural;
+        for tx in &block.transactions {
+            if !is_coinbase(tx) {
+                utxo_total -= tx.inputs.iter()
+                    .filter_map(|input| utxo_set.get(&input.prevout))
+                    .map(|utxo| utxo.value as i128)
+                    .sum::<i128>();
+            }
+            utxo_total += tx.outputs.iter().map(|output| output.value as i128).sum::<i128>();
+            utxo_set = crate::block::apply_transaction(tx, utxo_set, height)?;
+        }
+
+        let max_supply = total_supply(height);
+        if utxo_total > max_supply as i128 {
+            return Ok(Some(SupplyViolation {
+                height,
+                block_hash: block.header.block_hash(),
+                utxo_total,
+                max_supply,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
 /// Check if transaction is coinbase
 fn is_coinbase(tx: &Transaction) -> bool {
     tx.inputs.len() == 1 && 
@@ -117,13 +322,151 @@ mod tests {
     fn test_total_supply_convergence() {
         // Test that total supply approaches 21M BTC
         let supply_at_halving = total_supply(HALVING_INTERVAL);
-        // At the first halving, we have 210,000 blocks of 50 BTC each
-        let expected_at_halving = (HALVING_INTERVAL as i64) * INITIAL_SUBSIDY;
-        // The difference is due to bit shifting in get_block_subsidy
-        // Allow for much larger rounding differences due to bit operations
-        let difference = (supply_at_halving - expected_at_halving).abs();
-        println!("Supply at halving: {}, Expected: {}, Difference: {}", supply_at_halving, expected_at_halving, difference);
-        assert!(difference <= 3_000_000_000); // Allow for significant rounding differences
+        // 210,000 blocks of 50 BTC, plus the first 25 BTC block at the halving height
+        let expected_at_halving = (HALVING_INTERVAL as i64) * INITIAL_SUBSIDY + INITIAL_SUBSIDY / 2;
+        assert_eq!(supply_at_halving, expected_at_halving);
+        
+        // Every satoshi ever issued
+        assert_eq!(total_supply(Natural::MAX), 2_099_999_997_690_000);
+        assert_eq!(total_supply(HALVING_INTERVAL * 64 - 1), total_supply(Natural::MAX));
+    }
+    
+    #[test]
+    fn test_total_supply_matches_iterative_sum_at_halvings() {
+        // Walk every block of all 64 epochs once, checking each boundary
+        let mut running = 0i64;
+        for height in 0..HALVING_INTERVAL * 64 + 2 {
+            running += get_block_subsidy(height);
+            let offset = height % HALVING_INTERVAL;
+            if offset <= 1 || offset == HALVING_INTERVAL - 1 {
+                assert_eq!(total_supply(height), running, "height {}", height);
+            }
+        }
+        assert!(running <= MAX_MONEY);
+    }
+    
+    #[test]
+    fn test_audit_supply() {
+        let coinbase = |height: u8, value: Integer| Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+                script_sig: vec![height],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        };
+        let block = |transactions: Vec<Transaction>| Block {
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash: [0; 32],
+                merkle_root: [0; 32],
+                timestamp: 1231006505,
+                bits: 0x1d00ffff,
+                nonce: 0,
+            },
+            transactions,
+        };
+        
+        // Block 1 spends block 0's coinbase and moves the value on
+        let first = coinbase(0, INITIAL_SUBSIDY);
+        let spend = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: crate::transaction::calculate_tx_id(&first), index: 0 },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: INITIAL_SUBSIDY, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        };
+        let honest = vec![
+            block(vec![first]),
+            block(vec![coinbase(1, INITIAL_SUBSIDY - 1), spend]),
+        ];
+        assert_eq!(audit_supply(&honest, &UtxoSet::new(), 0).unwrap(), None);
+        
+        // A coinbase claiming one satoshi too many is caught at its own height
+        let mut inflating = honest.clone();
+        inflating.push(block(vec![coinbase(2, INITIAL_SUBSIDY + 2)]));
+        let violation = audit_supply(&inflating, &UtxoSet::new(), 0).unwrap().unwrap();
+        assert_eq!(violation.height, 2);
+        assert_eq!(violation.utxo_total, 3 * INITIAL_SUBSIDY as i128 + 1);
+        assert_eq!(violation.max_supply, 3 * INITIAL_SUBSIDY);
+    }
+    
+    #[test]
+    fn test_halving_epoch_info() {
+        assert_eq!(halving_epoch(0), 0);
+        assert_eq!(halving_epoch(HALVING_INTERVAL - 1), 0);
+        assert_eq!(halving_epoch(HALVING_INTERVAL), 1);
+        assert_eq!(halving_epoch(840_000), 4);
+        
+        assert_eq!(blocks_until_next_halving(0), HALVING_INTERVAL);
+        assert_eq!(blocks_until_next_halving(839_999), 1);
+        assert_eq!(blocks_until_next_halving(840_000), HALVING_INTERVAL);
+        assert_eq!(halving_epoch(850_000 + blocks_until_next_halving(850_000)), 5);
+        
+        let last = final_subsidy_height();
+        assert_eq!(last, 6_929_999);
+        assert_eq!(get_block_subsidy(last), 1);
+        assert_eq!(get_block_subsidy(last + 1), 0);
+        assert_eq!(subsidy_schedule().last().unwrap().end_height, last);
+    }
+    
+    #[test]
+    fn test_subsidy_schedule() {
+        let epochs: Vec<SubsidyEpoch> = subsidy_schedule().collect();
+        assert_eq!(epochs.len(), 33);
+        assert_eq!(epochs[0], SubsidyEpoch {
+            epoch: 0,
+            start_height: 0,
+            end_height: HALVING_INTERVAL - 1,
+            subsidy: INITIAL_SUBSIDY,
+            cumulative_supply: INITIAL_SUBSIDY * HALVING_INTERVAL as i64,
+        });
+        assert_eq!(epochs[32].subsidy, 1);
+        
+        for (i, epoch) in epochs.iter().enumerate() {
+            assert_eq!(epoch.cumulative_supply, total_supply(epoch.end_height));
+            if i > 0 {
+                assert_eq!(epoch.start_height, epochs[i - 1].end_height + 1);
+                assert_eq!(epoch.subsidy, epochs[i - 1].subsidy / 2);
+            }
+        }
+        assert_eq!(epochs[32].cumulative_supply, total_supply(Natural::MAX));
+    }
+    
+    #[test]
+    fn test_is_absurd_fee() {
+        let limits = AbsurdFeeLimits::default();
+        // 200 vB at 10 sat/vB expects 2,000 sat
+        assert!(!is_absurd_fee(2_000, 200, 10_000, &limits));
+        assert!(!is_absurd_fee(200_000, 200, 10_000, &limits));
+        assert!(is_absurd_fee(200_001, 200, 10_000, &limits));
+        
+        // The absolute cap applies whatever the expected rate
+        assert!(is_absurd_fee(SATOSHIS_PER_BTC / 10 + 1, 100_000, 1_000_000, &limits));
+        
+        let no_limits = AbsurdFeeLimits { max_fee_multiple: None, max_fee: None };
+        assert!(!is_absurd_fee(MAX_MONEY, 200, 1_000, &no_limits));
+        let cap_only = AbsurdFeeLimits { max_fee_multiple: None, ..limits };
+        assert!(!is_absurd_fee(1_000_000, 200, 1_000, &cap_only));
+    }
+    
+    proptest::proptest! {
+        #[test]
+        fn prop_total_supply_bounded(height in 0..Natural::MAX) {
+            let supply = total_supply(height);
+            proptest::prop_assert!(supply <= 21_000_000 * SATOSHIS_PER_BTC);
+            proptest::prop_assert!(supply <= MAX_MONEY);
+        }
+        
+        #[test]
+        fn prop_total_supply_increments_by_subsidy(height in 1..HALVING_INTERVAL * 70) {
+            proptest::prop_assert_eq!(total_supply(height) - total_supply(height - 1), get_block_subsidy(height));
+        }
     }
     
     #[test]
@@ -204,11 +547,7 @@ mod tests {
         
         // Test total supply at first halving
         let supply_at_halving = total_supply(HALVING_INTERVAL);
-        assert!(supply_at_halving > 0);
-        // At halving, supply should be close to 210,000 * 50 BTC, but bit shifting causes rounding
-        let expected_approximate = INITIAL_SUBSIDY * HALVING_INTERVAL as i64;
-        // Allow for the fact that bit shifting can cause the supply to be higher due to rounding
-        assert!(supply_at_halving <= expected_approximate + 5000000000); // Allow 50 BTC difference
+        assert_eq!(supply_at_halving, INITIAL_SUBSIDY * HALVING_INTERVAL as i64 + INITIAL_SUBSIDY / 2);
     }
     
     #[test]
@@ -236,6 +575,7 @@ mod tests {
             value: 1000000000, // 10 BTC
             script_pubkey: vec![],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint, utxo);
         
@@ -267,6 +607,7 @@ mod tests {
             value: 500000000, // 5 BTC
             script_pubkey: vec![],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint1, utxo1);
         
@@ -275,6 +616,7 @@ mod tests {
             value: 300000000, // 3 BTC
             script_pubkey: vec![],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint2, utxo2);
         
@@ -343,6 +685,7 @@ mod tests {
             value: 100000000, // 1 BTC
             script_pubkey: vec![],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint, utxo);
         
@@ -451,4 +794,14 @@ mod tests {
         };
         assert!(!is_coinbase(&no_inputs));
     }
+    
+    #[test]
+    fn test_checked_amount_sum() {
+        assert_eq!(checked_amount_sum(vec![1, 2, 3]), Some(6));
+        assert_eq!(checked_amount_sum(Vec::new()), Some(0));
+        assert_eq!(checked_amount_sum(vec![MAX_MONEY]), Some(MAX_MONEY));
+        assert_eq!(checked_amount_sum(vec![MAX_MONEY, 1]), None);
+        assert_eq!(checked_amount_sum(vec![-1, 5]), None);
+        assert_eq!(checked_amount_sum(vec![i64::MAX, i64::MAX]), None);
+    }
 }
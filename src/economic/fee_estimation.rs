@@ -0,0 +1,230 @@
+//! Fee estimation: fee-rate estimates per confirmation target, computed from
+//! the fee rates of recent blocks or from a mempool snapshot
+//!
+//! These are pure-function counterparts of Bitcoin Core's `estimatesmartfee`:
+//! callers keep the history, the functions only summarize it.
+
+use crate::types::*;
+use crate::constants::*;
+
+/// Fee rate in satoshis per 1000 virtual bytes
+pub type FeeRate = Natural;
+
+/// Longest confirmation target an estimate can be asked for (one week)
+pub const MAX_CONFIRMATION_TARGET: usize = 1008;
+
+/// Blocks considered by economical estimates (one day)
+pub const ECONOMICAL_HORIZON: usize = 144;
+
+/// Share of a block's vsize, from the cheapest end, ignored when deciding
+/// what fee rate the block required (CPFP parents, miner-prioritized
+/// transactions)
+const INCLUSION_PERCENTILE: Natural = 10;
+
+/// One transaction (or package) of a fee-rate distribution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeRateSample {
+    pub fee_rate: FeeRate,
+    pub vsize: Natural,
+}
+
+/// Fee-rate distribution of the transactions included in one block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFeeRates {
+    pub height: Natural,
+    pub samples: Vec<FeeRateSample>,
+}
+
+/// How cautious an estimate should be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EstimateMode {
+    /// Succeed in 95% of the observed history, looking at all of it
+    Conservative,
+    /// Succeed in 85% of the last `ECONOMICAL_HORIZON` blocks
+    Economical,
+}
+
+impl EstimateMode {
+    fn success_percent(self) -> usize {
+        match self {
+            EstimateMode::Conservative => 95,
+            EstimateMode::Economical => 85,
+        }
+    }
+
+    fn horizon(self) -> usize {
+        match self {
+            EstimateMode::Conservative => MAX_CONFIRMATION_TARGET,
+            EstimateMode::Economical => ECONOMICAL_HORIZON,
+        }
+    }
+}
+
+/// A fee-rate estimate for a confirmation target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeEstimate {
+    pub fee_rate: FeeRate,
+    /// Confirmation target the estimate is for, after clamping
+    pub target: usize,
+    /// Number of blocks (or, for mempool estimates, block templates) used
+    pub blocks_sampled: usize,
+}
+
+/// BlockInclusionFeeRate: BlockFeeRates → FeeRate
+///
+/// Fee rate a transaction needed to be included in the block: the rate at
+/// the `INCLUSION_PERCENTILE`th percentile of the block's vsize, counted
+/// from the cheapest transaction. `None` for a block without samples.
+pub fn block_inclusion_fee_rate(block: &BlockFeeRates) -> Option<FeeRate> {
+    let mut samples = block.samples.clone();
+    samples.sort_by_key(|sample| sample.fee_rate);
+
+    let total_vsize: Natural = samples.iter().map(|sample| sample.vsize).sum();
+    let skipped_vsize = total_vsize * INCLUSION_PERCENTILE / 100;
+
+    let mut cumulative = 0;
+    for sample in &samples {
+        cumulative += sample.vsize;
+        if cumulative > skipped_vsize {
+            return Some(sample.fee_rate);
+        }
+    }
+    samples.last().map(|sample| sample.fee_rate)
+}
+
+/// EstimateSmartFee: BlockFeeRates* × ℕ × EstimateMode → FeeEstimate
+///
+/// Estimate the fee rate needed to confirm within `target` blocks:
+/// 1. Clamp target to [1, MAX_CONFIRMATION_TARGET]
+/// 2. Keep the most recent blocks within the mode's horizon
+/// 3. For every run of `target` consecutive blocks, the rate that would have
+///    confirmed within it is the lowest BlockInclusionFeeRate of the run
+/// 4. Return the rate that would have succeeded in the mode's share of runs
+///
+/// `blocks` may be in any order. Returns `None` when fewer than `target`
+/// blocks are available.
+pub fn estimate_smart_fee(blocks: &[BlockFeeRates], target: usize, mode: EstimateMode) -> Option<FeeEstimate> {
+    let target = target.clamp(1, MAX_CONFIRMATION_TARGET);
+
+    let mut recent: Vec<&BlockFeeRates> = blocks.iter().collect();
+    recent.sort_by_key(|block| block.height);
+    let recent = &recent[recent.len().saturating_sub(mode.horizon())..];
+    if recent.len() < target {
+        return None;
+    }
+
+    let inclusion_rates: Vec<Option<FeeRate>> = recent.iter()
+        .map(|block| block_inclusion_fee_rate(block))
+        .collect();
+
+    // Runs of empty blocks tell us nothing about the required rate
+    let mut run_rates: Vec<FeeRate> = inclusion_rates.windows(target)
+        .filter_map(|run| run.iter().flatten().min().copied())
+        .collect();
+    if run_rates.is_empty() {
+        return None;
+    }
+    run_rates.sort_unstable();
+
+    let index = (run_rates.len() * mode.success_percent()).div_ceil(100) - 1;
+    Some(FeeEstimate {
+        fee_rate: run_rates[index],
+        target,
+        blocks_sampled: recent.len(),
+    })
+}
+
+/// EstimateFeeFromMempool: FeeRateSample* × ℕ × FeeRate → FeeEstimate
+///
+/// Estimate from a mempool snapshot alone: fill `target` block templates of
+/// MAX_BLOCK_SIZE weight with the best-paying entries first and return the rate
+/// of the last entry that fits, or `min_fee_rate` if the whole mempool fits.
+/// Never returns less than `min_fee_rate`.
+pub fn estimate_fee_from_mempool(mempool: &[FeeRateSample], target: usize, min_fee_rate: FeeRate) -> FeeEstimate {
+    let target = target.clamp(1, MAX_CONFIRMATION_TARGET);
+    let capacity = MAX_BLOCK_SIZE as Natural / WITNESS_SCALE_FACTOR * target as Natural;
+
+    let mut samples = mempool.to_vec();
+    samples.sort_by_key(|sample| std::cmp::Reverse(sample.fee_rate));
+
+    let mut used = 0;
+    let mut marginal_rate = min_fee_rate;
+    for sample in &samples {
+        used += sample.vsize;
+        if used > capacity {
+            break;
+        }
+        marginal_rate = sample.fee_rate;
+    }
+    if used <= capacity {
+        // Everything confirms; only the relay floor matters
+        marginal_rate = min_fee_rate;
+    }
+
+    FeeEstimate {
+        fee_rate: marginal_rate.max(min_fee_rate),
+        target,
+        blocks_sampled: target,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(height: Natural, rates: &[FeeRate]) -> BlockFeeRates {
+        BlockFeeRates {
+            height,
+            samples: rates.iter().map(|&fee_rate| FeeRateSample { fee_rate, vsize: 250 }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_block_inclusion_fee_rate_ignores_cheapest_tail() {
+        // One cheap CPFP parent among nineteen 5 sat/vB transactions
+        let mut rates = vec![5_000; 19];
+        rates.push(100);
+        assert_eq!(block_inclusion_fee_rate(&block(1, &rates)), Some(5_000));
+        assert_eq!(block_inclusion_fee_rate(&block(1, &[2_000])), Some(2_000));
+        assert_eq!(block_inclusion_fee_rate(&block(1, &[])), None);
+    }
+
+    #[test]
+    fn test_estimate_smart_fee_targets_and_modes() {
+        // Every tenth block is expensive; the rest clear at 1 sat/vB
+        let blocks: Vec<BlockFeeRates> = (0..200)
+            .map(|height| block(height, &[if height % 10 == 0 { 50_000 } else { 1_000 }]))
+            .collect();
+
+        let next_block = estimate_smart_fee(&blocks, 1, EstimateMode::Conservative).unwrap();
+        assert_eq!(next_block.fee_rate, 50_000);
+        assert_eq!(next_block.blocks_sampled, 200);
+
+        // Waiting two blocks always includes a cheap one
+        assert_eq!(estimate_smart_fee(&blocks, 2, EstimateMode::Conservative).unwrap().fee_rate, 1_000);
+
+        // 90% of single blocks were cheap: enough for economical, not conservative
+        let economical = estimate_smart_fee(&blocks, 1, EstimateMode::Economical).unwrap();
+        assert_eq!(economical.fee_rate, 1_000);
+        assert_eq!(economical.blocks_sampled, ECONOMICAL_HORIZON);
+
+        // Targets are clamped, and need enough history
+        assert_eq!(estimate_smart_fee(&blocks, 0, EstimateMode::Economical).unwrap().target, 1);
+        assert!(estimate_smart_fee(&blocks[..5], 6, EstimateMode::Conservative).is_none());
+        assert!(estimate_smart_fee(&[block(1, &[])], 1, EstimateMode::Conservative).is_none());
+    }
+
+    #[test]
+    fn test_estimate_fee_from_mempool() {
+        let block_vsize = MAX_BLOCK_SIZE as Natural / WITNESS_SCALE_FACTOR;
+        let mempool = vec![
+            FeeRateSample { fee_rate: 20_000, vsize: block_vsize / 2 },
+            FeeRateSample { fee_rate: 10_000, vsize: block_vsize / 2 },
+            FeeRateSample { fee_rate: 3_000, vsize: block_vsize },
+        ];
+
+        assert_eq!(estimate_fee_from_mempool(&mempool, 1, 1_000).fee_rate, 10_000);
+        assert_eq!(estimate_fee_from_mempool(&mempool, 2, 1_000).fee_rate, 1_000);
+        assert_eq!(estimate_fee_from_mempool(&[], 1, 1_000).fee_rate, 1_000);
+    }
+}
@@ -0,0 +1,198 @@
+//! Genesis blocks of the supported networks
+//!
+//! All four networks share the same coinbase transaction and differ only in
+//! their header timestamp, bits and nonce. Hashes are stored in internal
+//! (little-endian) byte order; the comments give the usual display order.
+
+use crate::types::*;
+use crate::constants::*;
+use crate::error::Result;
+use crate::params::{ChainParams, Network};
+use crate::block::calculate_merkle_root_with_mutation;
+
+/// Genesis coinbase scriptSig: push of 0x1d00ffff, push of 4, and
+/// "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks"
+pub const GENESIS_COINBASE_SCRIPT_SIG: [u8; 77] = [
+    0x04, 0xff, 0xff, 0x00, 0x1d, 0x01, 0x04, 0x45, 0x54, 0x68, 0x65, 0x20, 0x54, 0x69, 0x6d, 0x65,
+    0x73, 0x20, 0x30, 0x33, 0x2f, 0x4a, 0x61, 0x6e, 0x2f, 0x32, 0x30, 0x30, 0x39, 0x20, 0x43, 0x68,
+    0x61, 0x6e, 0x63, 0x65, 0x6c, 0x6c, 0x6f, 0x72, 0x20, 0x6f, 0x6e, 0x20, 0x62, 0x72, 0x69, 0x6e,
+    0x6b, 0x20, 0x6f, 0x66, 0x20, 0x73, 0x65, 0x63, 0x6f, 0x6e, 0x64, 0x20, 0x62, 0x61, 0x69, 0x6c,
+    0x6f, 0x75, 0x74, 0x20, 0x66, 0x6f, 0x72, 0x20, 0x62, 0x61, 0x6e, 0x6b, 0x73,
+];
+
+/// Genesis coinbase output script: <Satoshi's uncompressed pubkey> OP_CHECKSIG
+pub const GENESIS_OUTPUT_SCRIPT: [u8; 67] = [
+    0x41, 0x04, 0x67, 0x8a, 0xfd, 0xb0, 0xfe, 0x55, 0x48, 0x27, 0x19, 0x67, 0xf1, 0xa6, 0x71, 0x30,
+    0xb7, 0x10, 0x5c, 0xd6, 0xa8, 0x28, 0xe0, 0x39, 0x09, 0xa6, 0x79, 0x62, 0xe0, 0xea, 0x1f, 0x61,
+    0xde, 0xb6, 0x49, 0xf6, 0xbc, 0x3f, 0x4c, 0xef, 0x38, 0xc4, 0xf3, 0x55, 0x04, 0xe5, 0x1e, 0xc1,
+    0x12, 0xde, 0x5c, 0x38, 0x4d, 0xf7, 0xba, 0x0b, 0x8d, 0x57, 0x8a, 0x4c, 0x70, 0x2b, 0x6b, 0xf1,
+    0x1d, 0x5f, 0xac,
+];
+
+/// Merkle root of every genesis block: the txid of the genesis coinbase
+///
+/// 4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b
+pub const GENESIS_MERKLE_ROOT: Hash = [
+    0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2, 0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f, 0x61,
+    0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32, 0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e, 0x5e, 0x4a,
+];
+
+/// Mainnet genesis block hash
+///
+/// 000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f
+pub const MAINNET_GENESIS_HASH: Hash = [
+    0x6f, 0xe2, 0x8c, 0x0a, 0xb6, 0xf1, 0xb3, 0x72, 0xc1, 0xa6, 0xa2, 0x46, 0xae, 0x63, 0xf7, 0x4f,
+    0x93, 0x1e, 0x83, 0x65, 0xe1, 0x5a, 0x08, 0x9c, 0x68, 0xd6, 0x19, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Testnet3 genesis block hash
+///
+/// 000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943
+pub const TESTNET_GENESIS_HASH: Hash = [
+    0x43, 0x49, 0x7f, 0xd7, 0xf8, 0x26, 0x95, 0x71, 0x08, 0xf4, 0xa3, 0x0f, 0xd9, 0xce, 0xc3, 0xae,
+    0xba, 0x79, 0x97, 0x20, 0x84, 0xe9, 0x0e, 0xad, 0x01, 0xea, 0x33, 0x09, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Signet genesis block hash
+///
+/// 00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6
+pub const SIGNET_GENESIS_HASH: Hash = [
+    0xf6, 0x1e, 0xee, 0x3b, 0x63, 0xa3, 0x80, 0xa4, 0x77, 0xa0, 0x63, 0xaf, 0x32, 0xb2, 0xbb, 0xc9,
+    0x7c, 0x9f, 0xf9, 0xf0, 0x1f, 0x2c, 0x42, 0x25, 0xe9, 0x73, 0x98, 0x81, 0x08, 0x00, 0x00, 0x00,
+];
+
+/// Regtest genesis block hash
+///
+/// 0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206
+pub const REGTEST_GENESIS_HASH: Hash = [
+    0x06, 0x22, 0x6e, 0x46, 0x11, 0x1a, 0x0b, 0x59, 0xca, 0xaf, 0x12, 0x60, 0x43, 0xeb, 0x5b, 0xbf,
+    0x28, 0xc3, 0x4f, 0x3a, 0x5e, 0x33, 0x2a, 0x1f, 0xc7, 0xb2, 0xb7, 0x3c, 0xf1, 0x88, 0x91, 0x0f,
+];
+
+/// Header timestamp, bits and nonce of a network's genesis block
+fn genesis_header_fields(network: Network) -> (Natural, Natural, Natural) {
+    match network {
+        Network::Mainnet => (1231006505, 0x1d00ffff, 2083236893),
+        Network::Testnet => (1296688602, 0x1d00ffff, 414098458),
+        Network::Signet => (1598918400, 0x1e0377ae, 52613770),
+        Network::Regtest => (1296688602, 0x207fffff, 2),
+    }
+}
+
+/// Hash of a network's genesis block
+pub fn genesis_hash(network: Network) -> Hash {
+    match network {
+        Network::Mainnet => MAINNET_GENESIS_HASH,
+        Network::Testnet => TESTNET_GENESIS_HASH,
+        Network::Signet => SIGNET_GENESIS_HASH,
+        Network::Regtest => REGTEST_GENESIS_HASH,
+    }
+}
+
+/// The genesis coinbase transaction, shared by all networks
+pub fn genesis_coinbase() -> Transaction {
+    Transaction {
+        version: 1,
+        inputs: vec![TransactionInput {
+            prevout: OutPoint { hash: [0; 32], index: 0xffffffff },
+            script_sig: GENESIS_COINBASE_SCRIPT_SIG.to_vec(),
+            sequence: 0xffffffff,
+        }],
+        outputs: vec![TransactionOutput {
+            value: INITIAL_SUBSIDY,
+            script_pubkey: GENESIS_OUTPUT_SCRIPT.to_vec(),
+        }],
+        lock_time: 0,
+    }
+}
+
+/// Full genesis block of a network
+pub fn genesis_block(network: Network) -> Block {
+    let (timestamp, bits, nonce) = genesis_header_fields(network);
+    Block {
+        header: BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: GENESIS_MERKLE_ROOT,
+            timestamp,
+            bits,
+            nonce,
+        },
+        transactions: vec![genesis_coinbase()],
+    }
+}
+
+/// ValidateGenesis: ℬ × ChainParams → {valid, invalid}
+///
+/// For block b on the network of params:
+/// 1. If b.header.prev_block_hash ≠ 0: return invalid
+/// 2. If b ≠ a single coinbase with MerkleRoot(b.txs) = b.header.merkle_root: return invalid
+/// 3. If BlockHash(b.header) ≠ the network's genesis hash: return invalid
+/// 4. Return valid
+///
+/// Matching the known hash implies the header meets its proof of work, so
+/// no separate target check is made. The genesis block is never connected through `connect_block`; its
+/// coinbase output is unspendable by convention.
+pub fn validate_genesis(block: &Block, params: &ChainParams) -> Result<ValidationResult> {
+    if block.header.prev_block_hash != [0; 32] {
+        return Ok(ValidationResult::Invalid("Genesis block must not have a parent".to_string()));
+    }
+
+    if block.transactions.len() != 1 {
+        return Ok(ValidationResult::Invalid(
+            format!("Genesis block must contain exactly one transaction, found {}", block.transactions.len())
+        ));
+    }
+
+    let (merkle_root, _) = calculate_merkle_root_with_mutation(&block.transactions)?;
+    if merkle_root != block.header.merkle_root {
+        return Ok(ValidationResult::Invalid("Genesis merkle root mismatch".to_string()));
+    }
+
+    if block.header.block_hash() != genesis_hash(params.network) {
+        return Ok(ValidationResult::Invalid(
+            format!("Block is not the {:?} genesis block", params.network)
+        ));
+    }
+
+    Ok(ValidationResult::Valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::calculate_tx_id;
+
+    const NETWORKS: [Network; 4] = [Network::Mainnet, Network::Testnet, Network::Signet, Network::Regtest];
+
+    #[test]
+    fn test_genesis_blocks_hash_to_constants() {
+        assert_eq!(calculate_tx_id(&genesis_coinbase()), GENESIS_MERKLE_ROOT);
+
+        for network in NETWORKS {
+            let block = genesis_block(network);
+            assert_eq!(block.header.block_hash(), genesis_hash(network));
+            assert_eq!(block.header.bits, ChainParams::new(network).pow_limit_bits);
+        }
+    }
+
+    #[test]
+    fn test_validate_genesis() {
+        for network in NETWORKS {
+            let params = ChainParams::new(network);
+            let result = validate_genesis(&genesis_block(network), &params).unwrap();
+            assert_eq!(result, ValidationResult::Valid);
+        }
+
+        // Another network's genesis block
+        let testnet_genesis = genesis_block(Network::Testnet);
+        let result = validate_genesis(&testnet_genesis, &ChainParams::mainnet()).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(_)));
+
+        // Tampered coinbase
+        let mut tampered = genesis_block(Network::Mainnet);
+        tampered.transactions[0].outputs[0].value += 1;
+        let result = validate_genesis(&tampered, &ChainParams::mainnet()).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(_)));
+    }
+}
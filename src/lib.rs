@@ -45,14 +45,23 @@ pub mod constants;
 pub mod transaction;
 pub mod script;
 pub mod block;
+pub mod block_index;
+pub mod chain_tree;
+pub mod block_filter;
 pub mod economic;
 pub mod pow;
 pub mod mempool;
+pub mod policy;
+pub mod relay;
 pub mod mining;
 pub mod reorganization;
 pub mod network;
 pub mod segwit;
 pub mod taproot;
+pub mod serialization;
+pub mod merkle;
+pub mod params;
+pub mod genesis;
 pub mod error;
 
 // Re-export commonly used types
@@ -150,6 +159,7 @@ impl ConsensusProof {
     ///     value: 1000000000, // 10 BTC
     ///     script_pubkey: vec![],
     ///     height: 0,
+    ///     is_coinbase: false,
     /// };
     /// utxo_set.insert(outpoint, utxo);
     /// 
@@ -192,7 +202,7 @@ impl ConsensusProof {
     /// let utxo_set = UtxoSet::new();
     /// 
     /// // Create a simple block with coinbase transaction
-    /// let block = Block {
+    /// let mut block = Block {
     ///     header: BlockHeader {
     ///         version: 1,
     ///         prev_block_hash: [0; 32],
@@ -215,6 +225,7 @@ impl ConsensusProof {
     ///         lock_time: 0,
     ///     }],
     /// };
+    /// block.header.merkle_root = consensus_proof::block::calculate_merkle_root(&block.transactions).unwrap();
     /// 
     /// let (result, _new_utxo_set) = consensus.validate_block(&block, utxo_set, 0).unwrap();
     /// assert_eq!(result, ValidationResult::Valid);
@@ -226,6 +237,7 @@ impl ConsensusProof {
         height: Natural
     ) -> Result<(ValidationResult, UtxoSet)> {
         block::connect_block(block, utxo_set, height)
+            .map(|(result, utxo_set, _undo)| (result, utxo_set))
     }
     
     /// Verify script execution
@@ -452,13 +464,20 @@ impl ConsensusProof {
     /// 
     /// let consensus = ConsensusProof::new();
     /// let mempool = Mempool::new();
+    /// let mut utxo_set = UtxoSet::new();
+    /// utxo_set.insert(OutPoint { hash: [1; 32], index: 0 }, UTXO {
+    ///     value: 100010000,
+    ///     script_pubkey: vec![],
+    ///     height: 0,
+    ///     is_coinbase: false,
+    /// });
     /// 
     /// let existing_tx = Transaction {
     ///     version: 1,
     ///     inputs: vec![TransactionInput {
     ///         prevout: OutPoint { hash: [1; 32], index: 0 },
     ///         script_sig: vec![],
-    ///         sequence: 0xfffffffe, // RBF enabled
+    ///         sequence: 0xfffffffd, // RBF enabled
     ///     }],
     ///     outputs: vec![TransactionOutput {
     ///         value: 100000000,
@@ -472,7 +491,7 @@ impl ConsensusProof {
     ///     inputs: vec![TransactionInput {
     ///         prevout: OutPoint { hash: [1; 32], index: 0 },
     ///         script_sig: vec![],
-    ///         sequence: 0xfffffffe, // RBF enabled
+    ///         sequence: 0xfffffffd, // RBF enabled
     ///     }],
     ///     outputs: vec![TransactionOutput {
     ///         value: 90000000, // Higher fee
@@ -481,19 +500,20 @@ impl ConsensusProof {
     ///     lock_time: 0,
     /// };
     /// 
-    /// let can_replace = consensus.replacement_checks(&new_tx, &existing_tx, &mempool).unwrap();
-    /// // Result depends on fee comparison and RBF rules
+    /// let can_replace = consensus.replacement_checks(&new_tx, &existing_tx, &utxo_set, &mempool).unwrap();
+    /// assert!(can_replace);
     /// ```
     pub fn replacement_checks(
         &self,
         new_tx: &Transaction,
         existing_tx: &Transaction,
+        utxo_set: &UtxoSet,
         mempool: &mempool::Mempool
     ) -> Result<bool> {
-        mempool::replacement_checks(new_tx, existing_tx, mempool)
+        mempool::replacement_checks(new_tx, existing_tx, utxo_set, mempool)
     }
     
-    /// Create new block from mempool transactions
+    /// Create new block from mempool transactions at network-adjusted time `adjusted_time`
     /// 
     /// # Examples
     /// 
@@ -526,6 +546,7 @@ impl ConsensusProof {
     ///     &prev_headers,
     ///     &coinbase_script,
     ///     &coinbase_address,
+    ///     prev_header.timestamp + 600,
     /// );
     /// 
     /// // Block creation may succeed or fail depending on difficulty adjustment
@@ -533,6 +554,7 @@ impl ConsensusProof {
     ///     assert_eq!(block.transactions.len(), 1); // Coinbase transaction
     /// }
     /// ```
+    #[allow(clippy::too_many_arguments)]
     pub fn create_new_block(
         &self,
         utxo_set: &UtxoSet,
@@ -542,6 +564,7 @@ impl ConsensusProof {
         prev_headers: &[BlockHeader],
         coinbase_script: &ByteString,
         coinbase_address: &ByteString,
+        adjusted_time: Natural,
     ) -> Result<Block> {
         mining::create_new_block(
             utxo_set,
@@ -551,6 +574,7 @@ impl ConsensusProof {
             prev_headers,
             coinbase_script,
             coinbase_address,
+            adjusted_time,
         )
     }
     
@@ -600,7 +624,9 @@ impl ConsensusProof {
         mining::mine_block(block, max_attempts)
     }
     
-            /// Create block template for mining
+            /// Create block template for mining at network-adjusted time
+            /// `adjusted_time`
+            #[allow(clippy::too_many_arguments)]
             pub fn create_block_template(
                 &self,
                 utxo_set: &UtxoSet,
@@ -610,6 +636,7 @@ impl ConsensusProof {
                 prev_headers: &[BlockHeader],
                 coinbase_script: &ByteString,
                 coinbase_address: &ByteString,
+                adjusted_time: Natural,
             ) -> Result<mining::BlockTemplate> {
                 mining::create_block_template(
                     utxo_set,
@@ -619,10 +646,12 @@ impl ConsensusProof {
                     prev_headers,
                     coinbase_script,
                     coinbase_address,
+                    adjusted_time,
                 )
             }
             
-            /// Reorganize chain when longer chain is found
+            /// Reorganize onto a branch with more work, rolling the current
+            /// branch back with its undo data
             /// 
             /// # Examples
             /// 
@@ -635,17 +664,21 @@ impl ConsensusProof {
             /// let new_chain = vec![];
             /// let current_utxo_set = UtxoSet::new();
             /// 
-            /// let result = consensus.reorganize_chain(&new_chain, &current_chain, current_utxo_set, 0);
+            /// let result = consensus.reorganize_chain(&new_chain, &current_chain, &[], current_utxo_set, 0, U256::zero());
             /// // Result may be an error for empty chains, which is expected
             /// ```
             pub fn reorganize_chain(
                 &self,
                 new_chain: &[Block],
                 current_chain: &[Block],
+                current_undo: &[block::BlockUndo],
                 current_utxo_set: UtxoSet,
                 current_height: Natural,
+                current_chain_work: U256,
             ) -> Result<reorganization::ReorganizationResult> {
-                reorganization::reorganize_chain(new_chain, current_chain, current_utxo_set, current_height)
+                reorganization::reorganize_chain(
+                    new_chain, current_chain, current_undo, current_utxo_set, current_height, current_chain_work,
+                )
             }
             
             /// Check if reorganization is beneficial
@@ -671,7 +704,7 @@ impl ConsensusProof {
                 reorganization::should_reorganize(new_chain, current_chain)
             }
             
-            /// Process incoming network message
+            /// Process incoming network message received at `now`
             /// 
             /// # Examples
             /// 
@@ -684,16 +717,17 @@ impl ConsensusProof {
             /// let chain_state = ChainState::new();
             /// 
             /// let message = NetworkMessage::Ping(PingMessage { nonce: 12345 });
-            /// let response = consensus.process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+            /// let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
             /// // Response will be appropriate for the message type
             /// ```
             pub fn process_network_message(
                 &self,
                 message: &network::NetworkMessage,
                 peer_state: &mut network::PeerState,
-                chain_state: &network::ChainState,
+                chain_state: &dyn network::ChainStateView,
+                now: std::time::SystemTime,
             ) -> Result<network::NetworkResponse> {
-                network::process_network_message(message, peer_state, chain_state)
+                network::process_network_message(message, peer_state, chain_state, now)
             }
             
             /// Calculate transaction weight for SegWit
@@ -852,7 +886,7 @@ impl Default for ConsensusProof {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::network::{NetworkMessage, VersionMessage, NetworkAddress, PeerState, ChainState};
+    use crate::network::{NetworkMessage, VersionMessage, NetworkAddress, PeerState, ChainState, ServiceFlags};
     
     #[test]
     fn test_consensus_proof_new() {
@@ -1022,7 +1056,7 @@ mod tests {
             lock_time: 0,
         };
         let mempool = mempool::Mempool::new();
-        let result = consensus.replacement_checks(&new_tx, &existing_tx, &mempool);
+        let result = consensus.replacement_checks(&new_tx, &existing_tx, &UtxoSet::new(), &mempool);
         assert!(result.is_ok());
     }
     
@@ -1043,7 +1077,7 @@ mod tests {
         let prev_headers = vec![prev_header.clone()];
         let coinbase_script = vec![0x51];
         let coinbase_address = vec![0x51];
-        let result = consensus.create_new_block(&utxo_set, &mempool_txs, height, &prev_header, &prev_headers, &coinbase_script, &coinbase_address);
+        let result = consensus.create_new_block(&utxo_set, &mempool_txs, height, &prev_header, &prev_headers, &coinbase_script, &coinbase_address, prev_header.timestamp + 600);
         // Result may be Ok or Err depending on block creation
         assert!(result.is_ok() || result.is_err());
     }
@@ -1084,7 +1118,7 @@ mod tests {
         let prev_headers = vec![prev_header.clone()];
         let coinbase_script = vec![0x51];
         let coinbase_address = vec![0x51];
-        let result = consensus.create_block_template(&utxo_set, &mempool_txs, height, &prev_header, &prev_headers, &coinbase_script, &coinbase_address);
+        let result = consensus.create_block_template(&utxo_set, &mempool_txs, height, &prev_header, &prev_headers, &coinbase_script, &coinbase_address, prev_header.timestamp + 600);
         // Result may be Ok or Err depending on template creation
         assert!(result.is_ok() || result.is_err());
     }
@@ -1095,7 +1129,7 @@ mod tests {
         let current_chain = vec![];
         let new_chain = vec![];
         let utxo_set = UtxoSet::new();
-        let result = consensus.reorganize_chain(&current_chain, &new_chain, utxo_set, 0);
+        let result = consensus.reorganize_chain(&current_chain, &new_chain, &[], utxo_set, 0, U256::zero());
         // Result may be Ok or Err depending on reorganization
         assert!(result.is_ok() || result.is_err());
     }
@@ -1114,15 +1148,17 @@ mod tests {
         let consensus = ConsensusProof::new();
         let message = NetworkMessage::Version(VersionMessage {
             version: 70015,
-            services: 0,
+            services: ServiceFlags::NONE,
             timestamp: 1234567890,
             addr_recv: NetworkAddress {
-                services: 0,
+                time: 0,
+                services: ServiceFlags::NONE,
                 ip: [0; 16],
                 port: 8333,
             },
             addr_from: NetworkAddress {
-                services: 0,
+                time: 0,
+                services: ServiceFlags::NONE,
                 ip: [0; 16],
                 port: 8333,
             },
@@ -1133,7 +1169,7 @@ mod tests {
         });
         let mut peer_state = PeerState::new();
         let chain_state = ChainState::new();
-        let result = consensus.process_network_message(&message, &mut peer_state, &chain_state);
+        let result = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH);
         assert!(result.is_ok());
     }
     
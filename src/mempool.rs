@@ -2,75 +2,345 @@
 
 use crate::types::*;
 use crate::constants::*;
-use crate::error::Result;
-use crate::transaction::{check_transaction, check_tx_inputs};
+use crate::error::{ConsensusError, Result};
+use crate::transaction::{
+    calculate_sequence_locks, check_sequence_locks, check_transaction, check_tx_inputs,
+    get_transaction_sigop_cost, is_final_tx,
+};
+use crate::block::display_hash;
 use crate::script::verify_script;
-use std::collections::HashSet;
+use crate::policy::{is_dust, transaction_weight, virtual_size, DEFAULT_BYTES_PER_SIGOP};
+use crate::serialization::{
+    decode_varint, deserialize_transaction, double_sha256, encode_varint, serialize_transaction,
+};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+pub use crate::transaction::calculate_tx_id;
+
+pub mod cluster;
 
 /// AcceptToMemoryPool: 𝒯𝒳 × 𝒰𝒮 → {accepted, rejected}
 /// 
 /// For transaction tx and UTXO set us:
 /// 1. Check if tx is already in mempool
-/// 2. Validate transaction structure
-/// 3. Check inputs against UTXO set
+/// 2. Validate transaction structure and reject dust outputs, unless
+///    ephemeral dust is permitted (see `check_ephemeral_dust`)
+/// 3. Check tx is final in the next block, and check inputs against us and
+///    the outputs of mempool transactions with their BIP68 relative locks
+///    satisfied in the next block
 /// 4. Verify scripts
-/// 5. Check mempool-specific rules (size, fee rate, etc.)
-/// 6. Check for conflicts with existing mempool transactions
-/// 7. Return acceptance result
+/// 5. Check ancestor/descendant package limits
+/// 6. If tx conflicts with mempool transactions, check it may replace
+///    them under BIP125
+/// 7. Check mempool-specific rules: fee rate against the rolling minimum,
+///    and outbidding the cheapest package when the mempool is full
+/// 8. Return acceptance result
+/// 
+/// `height` is the height of the current tip. With no time known, time-based
+/// locks are never satisfied; see `accept_to_memory_pool_at_tip`.
+/// 
+/// Uses the default `PackageLimits`. An accepted replacement evicts its
+/// conflicts: call `Mempool::remove_conflicts` before inserting it.
 pub fn accept_to_memory_pool(
     tx: &Transaction,
     utxo_set: &UtxoSet,
     mempool: &Mempool,
     height: Natural
 ) -> Result<MempoolResult> {
+    accept_to_memory_pool_with_limits(tx, utxo_set, mempool, height, &PackageLimits::default())
+}
+
+/// AcceptToMemoryPool with explicit package limits
+pub fn accept_to_memory_pool_with_limits(
+    tx: &Transaction,
+    utxo_set: &UtxoSet,
+    mempool: &Mempool,
+    height: Natural,
+    limits: &PackageLimits,
+) -> Result<MempoolResult> {
+    accept_to_memory_pool_at_tip(tx, utxo_set, mempool, &ChainTip::at_height(height), limits)
+}
+
+/// The active chain's tip, as far as mempool acceptance needs it
+/// 
+/// Transactions are accepted only if they could be mined in the next block:
+/// at height `height + 1`, on top of a block with median time past
+/// `median_time_past`.
+#[derive(Clone, Copy)]
+pub struct ChainTip<'a> {
+    pub height: Natural,
+    pub median_time_past: Natural,
+    /// Median time past of the active-chain block at a height below
+    /// `height`, for time-based relative locks
+    pub median_time_past_at: &'a dyn Fn(Natural) -> Natural,
+}
+
+impl ChainTip<'static> {
+    /// A tip known only by its height: no time-based lock counts as expired
+    pub fn at_height(height: Natural) -> Self {
+        ChainTip { height, median_time_past: 0, median_time_past_at: &|_| 0 }
+    }
+}
+
+/// AcceptToMemoryPool against a fully described chain tip
+pub fn accept_to_memory_pool_at_tip(
+    tx: &Transaction,
+    utxo_set: &UtxoSet,
+    mempool: &Mempool,
+    tip: &ChainTip,
+    limits: &PackageLimits,
+) -> Result<MempoolResult> {
+    let (result, fee, sigop_cost) = check_mempool_transaction(tx, utxo_set, mempool, tip, limits)?;
+    if let ValidationResult::Invalid(reason) = result {
+        return Ok(MempoolResult::Rejected(reason));
+    }
+    let vsize = adjusted_vsize(tx, sigop_cost);
+    
+    // 7. Check mempool-specific rules
+    if !check_mempool_rules(fee, vsize, mempool)? {
+        return Ok(MempoolResult::Rejected("Failed mempool rules".to_string()));
+    }
+    
+    Ok(MempoolResult::Accepted)
+}
+
+/// Steps 1-6 of AcceptToMemoryPool: everything but the fee-rate rules
+/// 
+/// Returns the transaction's fee and sigop cost alongside the result.
+fn check_mempool_transaction(
+    tx: &Transaction,
+    utxo_set: &UtxoSet,
+    mempool: &Mempool,
+    tip: &ChainTip,
+    limits: &PackageLimits,
+) -> Result<(ValidationResult, Integer, Natural)> {
+    let invalid = |reason: String| Ok((ValidationResult::Invalid(reason), 0, 0));
+    
     // 1. Check if transaction is already in mempool
     let tx_id = calculate_tx_id(tx);
     if mempool.contains(&tx_id) {
-        return Ok(MempoolResult::Rejected("Transaction already in mempool".to_string()));
+        return invalid("Transaction already in mempool".to_string());
     }
     
     // 2. Validate transaction structure
     if !matches!(check_transaction(tx)?, ValidationResult::Valid) {
-        return Ok(MempoolResult::Rejected("Invalid transaction structure".to_string()));
+        return invalid("Invalid transaction structure".to_string());
+    }
+    let params = mempool.policy_params();
+    let dust_outputs = tx.outputs.iter().filter(|output| is_dust(output, params.dust_relay_fee_rate)).count();
+    if dust_outputs > 0 && (!params.permit_ephemeral_dust || dust_outputs > MAX_DUST_OUTPUTS_PER_TX) {
+        return invalid("dust".to_string());
     }
     
-    // 3. Check inputs against UTXO set
-    let (input_valid, fee) = check_tx_inputs(tx, utxo_set, height)?;
+    // 3. Check lock times and inputs as of the next block, allowing
+    //    unconfirmed parents, which would confirm in that block too
+    let next_height = tip.height + 1;
+    if !is_final_tx(tx, next_height, tip.median_time_past) {
+        return invalid("non-final".to_string());
+    }
+    let coins = mempool.input_coins(tx, utxo_set, next_height);
+    let (input_valid, fee) = check_tx_inputs(tx, &coins, tip.height)?;
     if !matches!(input_valid, ValidationResult::Valid) {
-        return Ok(MempoolResult::Rejected("Invalid transaction inputs".to_string()));
+        return invalid("Invalid transaction inputs".to_string());
+    }
+    let prev_heights: Vec<Natural> = tx.inputs.iter()
+        .map(|input| coins.get(&input.prevout).map_or(next_height, |coin| coin.height))
+        .collect();
+    let locks = calculate_sequence_locks(tx, &prev_heights, |height| {
+        if height == tip.height { tip.median_time_past } else { (tip.median_time_past_at)(height) }
+    });
+    if !check_sequence_locks(&locks, next_height, tip.median_time_past) {
+        return invalid("non-BIP68-final".to_string());
+    }
+    if let ValidationResult::Invalid(reason) = check_ephemeral_dust(tx, fee, dust_outputs, mempool) {
+        return invalid(reason);
     }
     
     // 4. Verify scripts for non-coinbase transactions
     if !is_coinbase(tx) {
         for (i, input) in tx.inputs.iter().enumerate() {
-            if let Some(utxo) = utxo_set.get(&input.prevout) {
+            if let Some(utxo) = coins.get(&input.prevout) {
                 if !verify_script(
                     &input.script_sig,
                     &utxo.script_pubkey,
                     None, // TODO: Add witness support
                     0
                 )? {
-                    return Ok(MempoolResult::Rejected(
-                        format!("Invalid script at input {}", i)
-                    ));
+                    return invalid(format!("Invalid script at input {}", i));
                 }
             }
         }
     }
     
-    // 5. Check mempool-specific rules
-    if !check_mempool_rules(tx, fee, mempool)? {
-        return Ok(MempoolResult::Rejected("Failed mempool rules".to_string()));
+    // 5. Check ancestor/descendant package limits
+    let sigop_cost = get_transaction_sigop_cost(tx, &coins);
+    let vsize = adjusted_vsize(tx, sigop_cost);
+    if let ValidationResult::Invalid(reason) = check_package_limits(tx, vsize, mempool, limits)? {
+        return invalid(reason);
     }
     
-    // 6. Check for conflicts with existing mempool transactions
+    // 6. Conflicts with existing mempool transactions must be valid replacements
     if has_conflicts(tx, mempool)? {
-        return Ok(MempoolResult::Rejected("Transaction conflicts with mempool".to_string()));
+        if let ValidationResult::Invalid(reason) = check_replacement(tx, fee, vsize, mempool)? {
+            return invalid(format!("Transaction conflicts with mempool: {}", reason));
+        }
+    }
+    
+    Ok((ValidationResult::Valid, fee, sigop_cost))
+}
+
+/// Most dust outputs a transaction may have under the ephemeral dust policy
+pub const MAX_DUST_OUTPUTS_PER_TX: usize = 1;
+
+/// CheckEphemeralDust: 𝒯𝒳 × ℤ × ℕ × Mempool → {valid, invalid}
+/// 
+/// Ephemeral dust lets a transaction carry a dust output, typically a
+/// zero-value anchor, as long as a child spends it in the same package, so
+/// it never lingers in the UTXO set:
+/// 1. A transaction with dust outputs pays no fee itself; its child pays
+///    for both, and nothing rewards mining the parent alone
+/// 2. A transaction spending an in-mempool parent spends all the
+///    parent's dust outputs
+/// 
+/// `dust_outputs` is the number of dust outputs of tx. Transactions with
+/// dust cannot meet the minimum fee rate alone, so they only enter the
+/// mempool through `accept_package`, with their child.
+fn check_ephemeral_dust(tx: &Transaction, fee: Integer, dust_outputs: usize, mempool: &Mempool) -> ValidationResult {
+    // 1. Zero fee
+    if dust_outputs > 0 && fee != 0 {
+        return ValidationResult::Invalid("dust, tx with dust output must be 0-fee".to_string());
+    }
+    
+    // 2. All parent dust is spent
+    let dust_rate = mempool.policy_params().dust_relay_fee_rate;
+    for parent in mempool.parents_of(tx) {
+        let outputs = &mempool.entries[&parent].tx.outputs;
+        for index in (0..outputs.len()).filter(|&index| is_dust(&outputs[index], dust_rate)) {
+            let outpoint = OutPoint { hash: parent, index: index as Natural };
+            if !tx.inputs.iter().any(|input| input.prevout == outpoint) {
+                return ValidationResult::Invalid(format!(
+                    "missing-ephemeral-spends: {} output {} is unspent dust", display_hash(&parent), index
+                ));
+            }
+        }
+    }
+    
+    ValidationResult::Valid
+}
+
+/// Most transactions in a package
+pub const MAX_PACKAGE_COUNT: usize = 25;
+
+/// Largest total virtual size of a package, in bytes
+pub const MAX_PACKAGE_VSIZE: Natural = 101_000;
+
+/// AcceptPackage: 𝒯𝒳* × 𝒰𝒮 → {accepted, rejected}
+/// 
+/// Validate a child and its unconfirmed parents as a unit, so a parent
+/// paying less than the minimum fee rate can be carried by its child
+/// (package CPFP). For package p = [parents…, child]:
+/// 1. 1 ≤ |p| ≤ MAX_PACKAGE_COUNT and Σ vsize ≤ MAX_PACKAGE_VSIZE
+/// 2. p is child-with-parents: the child spends an output of every other
+///    transaction, parents spend none of each other, and no outpoint is
+///    spent twice
+/// 3. Each transaction not yet in the mempool passes AcceptToMemoryPool's
+///    checks except the fee rate, in order, with earlier package
+///    transactions treated as mempool parents
+/// 4. The new transactions' package fee rate Σ fee / Σ vsize meets the
+///    mempool's minimum fee rate
+/// 5. No package transaction would be evicted by TrimToSize
+/// 
+/// Either every transaction is acceptable or the package is rejected.
+pub fn accept_package(
+    txs: &[Transaction],
+    utxo_set: &UtxoSet,
+    mempool: &Mempool,
+    height: Natural,
+) -> Result<MempoolResult> {
+    let rejected = |reason: String| Ok(MempoolResult::Rejected(reason));
+    
+    // 1. Size
+    if txs.is_empty() || txs.len() > MAX_PACKAGE_COUNT {
+        return rejected(format!("package-too-many-transactions: {} transactions", txs.len()));
+    }
+    let package_vsize: Natural = txs.iter().map(|tx| calculate_transaction_size(tx) as Natural).sum();
+    if package_vsize > MAX_PACKAGE_VSIZE {
+        return rejected(format!("package-too-large: {} vbytes", package_vsize));
+    }
+    
+    // 2. Topology
+    if let ValidationResult::Invalid(reason) = check_child_with_parents(txs) {
+        return rejected(reason);
+    }
+    
+    // 3. Validate in order against a mempool that grows with the package
+    let limits = PackageLimits::default();
+    let tip = ChainTip::at_height(height);
+    let mut working = mempool.clone();
+    let (mut new_fees, mut new_vsize) = (0, 0);
+    for (i, tx) in txs.iter().enumerate() {
+        let txid = calculate_tx_id(tx);
+        if working.contains(&txid) {
+            continue;
+        }
+        let (result, fee, sigop_cost) = check_mempool_transaction(tx, utxo_set, &working, &tip, &limits)?;
+        if let ValidationResult::Invalid(reason) = result {
+            return rejected(format!("package transaction {} rejected: {}", i, reason));
+        }
+        
+        let entry = MempoolEntry::new(tx.clone(), fee, sigop_cost, 0, height);
+        new_fees += fee;
+        new_vsize += entry.vsize;
+        working.insert(entry);
+    }
+    
+    // 4. Fees are judged over the package as a whole
+    if new_vsize > 0 && (new_fees as f64) < mempool.min_fee_rate() * new_vsize as f64 {
+        return rejected(format!(
+            "package-fee-too-low: {} sat for {} vbytes", new_fees, new_vsize
+        ));
+    }
+    
+    // 5. The package must survive trimming; the working copy's rolling fee is discarded
+    let evicted = working.trim_to_size(0);
+    if evicted.iter().any(|entry| !mempool.contains(&entry.txid)) {
+        return rejected("mempool full".to_string());
     }
     
     Ok(MempoolResult::Accepted)
 }
 
+/// Whether `txs` is a child (last) with only its direct parents before it
+fn check_child_with_parents(txs: &[Transaction]) -> ValidationResult {
+    let (child, parents) = txs.split_last().expect("package is non-empty");
+    let parent_ids: Vec<Hash> = parents.iter().map(calculate_tx_id).collect();
+    
+    let mut spent = HashSet::new();
+    for tx in txs {
+        for input in &tx.inputs {
+            if !spent.insert(input.prevout.clone()) {
+                return ValidationResult::Invalid("conflict-in-package".to_string());
+            }
+        }
+    }
+    
+    for (parent, parent_id) in parents.iter().zip(&parent_ids) {
+        if !child.inputs.iter().any(|input| input.prevout.hash == *parent_id) {
+            return ValidationResult::Invalid(format!(
+                "package-not-child-with-parents: child does not spend {}", display_hash(parent_id)
+            ));
+        }
+        if parent.inputs.iter().any(|input| parent_ids.contains(&input.prevout.hash)) {
+            return ValidationResult::Invalid(
+                "package-not-child-with-parents: parents depend on each other".to_string()
+            );
+        }
+    }
+    
+    ValidationResult::Valid
+}
+
 /// IsStandardTx: 𝒯𝒳 → {true, false}
 /// 
 /// Check if transaction follows standard rules for mempool acceptance:
@@ -78,6 +348,9 @@ pub fn accept_to_memory_pool(
 /// 2. Script size limits
 /// 3. Standard script types
 /// 4. Fee rate requirements
+/// 
+/// A quick structural check; `policy::check_standard_tx` and
+/// `policy::check_standard_inputs` apply the full, configurable rules.
 pub fn is_standard_tx(tx: &Transaction) -> Result<bool> {
     // 1. Check transaction size
     let tx_size = calculate_transaction_size(tx);
@@ -108,43 +381,1100 @@ pub fn is_standard_tx(tx: &Transaction) -> Result<bool> {
     Ok(true)
 }
 
-/// ReplacementChecks: 𝒯𝒳 × 𝒯𝒳 → {true, false}
+/// Most transactions a single replacement may evict (BIP125 rule 5)
+pub const MAX_REPLACEMENT_CANDIDATES: usize = 100;
+
+/// Default fee rate, in satoshis per virtual byte, a replacement must pay
+/// for its own relay on top of the fees it replaces (BIP125 rule 4)
+pub const INCREMENTAL_RELAY_FEE_RATE: f64 = 1.0;
+
+/// ReplacementChecks: 𝒯𝒳 × 𝒯𝒳 × 𝒰𝒮 × Mempool → {true, false}
 /// 
-/// Check if new transaction can replace existing one (RBF rules):
-/// 1. Both transactions must signal RBF
-/// 2. New transaction must have higher fee rate
-/// 3. New transaction must not create new unconfirmed dependencies
+/// Check if new transaction can replace existing one under the BIP125
+/// rules of `check_replacement`. The replaced set is `existing_tx` plus its
+/// mempool descendants. Fees come from the mempool entry when
+/// `existing_tx` is in the mempool, and otherwise from the coins in the
+/// UTXO set and mempool; a transaction whose fee cannot be computed is
+/// never replaced or replacing.
 pub fn replacement_checks(
     new_tx: &Transaction,
     existing_tx: &Transaction,
+    utxo_set: &UtxoSet,
     mempool: &Mempool
 ) -> Result<bool> {
-    // 1. Check RBF signaling
-    if !signals_rbf(new_tx) || !signals_rbf(existing_tx) {
+    let Some(new_fee) = transaction_fee(new_tx, utxo_set, mempool) else {
         return Ok(false);
+    };
+    
+    let existing_id = calculate_tx_id(existing_tx);
+    let existing = match mempool.get(&existing_id) {
+        Some(entry) => Original { txid: existing_id, tx: &entry.tx, fee: entry.fee, vsize: entry.vsize },
+        None => match transaction_fee(existing_tx, utxo_set, mempool) {
+            Some(fee) => Original {
+                txid: existing_id,
+                tx: existing_tx,
+                fee,
+                vsize: adjusted_vsize(
+                    existing_tx,
+                    get_transaction_sigop_cost(existing_tx, &mempool.input_coins(existing_tx, utxo_set, 0)),
+                ),
+            },
+            None => return Ok(false),
+        },
+    };
+    
+    let coins = mempool.input_coins(new_tx, utxo_set, 0);
+    let new_vsize = adjusted_vsize(new_tx, get_transaction_sigop_cost(new_tx, &coins));
+    let result = evaluate_replacement(new_tx, new_fee, new_vsize, &[existing], mempool);
+    Ok(matches!(result, ValidationResult::Valid))
+}
+
+/// TransactionFee: 𝒯𝒳 × 𝒰𝒮 × Mempool → ℤ
+/// 
+/// Σ inputs − Σ outputs, with inputs looked up in the UTXO set and then
+/// among mempool outputs. `None` if an input is unknown or the outputs
+/// exceed the inputs.
+pub fn transaction_fee(tx: &Transaction, utxo_set: &UtxoSet, mempool: &Mempool) -> Option<Integer> {
+    let coins = mempool.input_coins(tx, utxo_set, 0);
+    if coins.len() != tx.inputs.len() {
+        return None;
     }
+    let input_total: Integer = coins.values().map(|utxo| utxo.value).sum();
+    let output_total: Integer = tx.outputs.iter().map(|output| output.value).sum();
+    let fee = input_total - output_total;
+    (fee >= 0).then_some(fee)
+}
+
+/// ReplaceByFee (BIP125): 𝒯𝒳 × ℤ × ℕ × Mempool → {valid, invalid}
+/// 
+/// For replacement tx of adjusted vsize v paying fee f, with direct conflicts C (the mempool
+/// transactions spending any of its inputs) and replaced set
+/// R = C ∪ descendants(C):
+/// 1. Every c ∈ C signals replaceability (some input sequence ≤ 0xfffffffd)
+/// 2. tx spends no unconfirmed output except those some c ∈ C already spent,
+///    and nothing from R itself
+/// 3. f ≥ Σ_{r ∈ R} fee(r)
+/// 4. f − Σ_{r ∈ R} fee(r) ≥ incrementalRelayFeeRate × v
+/// 5. |R| ≤ MAX_REPLACEMENT_CANDIDATES
+/// 6. feeRate(tx) > feeRate(c) for every c ∈ C
+/// 
+/// A transaction without conflicts replaces nothing and is valid here.
+pub fn check_replacement(
+    new_tx: &Transaction,
+    new_fee: Integer,
+    new_vsize: Natural,
+    mempool: &Mempool,
+) -> Result<ValidationResult> {
+    let conflicts: Vec<Original> = mempool.conflicts_of(new_tx).iter()
+        .map(|txid| &mempool.entries[txid])
+        .map(|entry| Original { txid: entry.txid, tx: &entry.tx, fee: entry.fee, vsize: entry.vsize })
+        .collect();
     
-    // 2. Check fee rate (simplified - in reality would calculate proper fee rate)
-    let new_fee = calculate_fee_rate(new_tx);
-    let existing_fee = calculate_fee_rate(existing_tx);
-    if new_fee <= existing_fee {
-        return Ok(false);
+    Ok(evaluate_replacement(new_tx, new_fee, new_vsize, &conflicts, mempool))
+}
+
+/// A transaction a replacement would evict
+struct Original<'a> {
+    txid: Hash,
+    tx: &'a Transaction,
+    fee: Integer,
+    vsize: Natural,
+}
+
+/// BIP125 rules for `new_tx` against its direct conflicts
+fn evaluate_replacement(
+    new_tx: &Transaction,
+    new_fee: Integer,
+    new_vsize: Natural,
+    conflicts: &[Original],
+    mempool: &Mempool,
+) -> ValidationResult {
+    if conflicts.is_empty() {
+        return ValidationResult::Valid;
     }
     
-    // 3. Check for new unconfirmed dependencies
-    if creates_new_dependencies(new_tx, existing_tx, mempool)? {
-        return Ok(false);
+    // 1. Every direct conflict must opt in, itself or through an ancestor
+    if let Some(conflict) = conflicts.iter().find(|conflict| !is_rbf_opt_in(conflict.tx, mempool)) {
+        return ValidationResult::Invalid(format!(
+            "txn-mempool-conflict: {} does not signal replaceability", display_hash(&conflict.txid)
+        ));
+    }
+    
+    // 5. The replaced set: direct conflicts and all their descendants
+    let mut replaced: HashMap<Hash, Integer> = conflicts.iter()
+        .map(|conflict| (conflict.txid, conflict.fee))
+        .collect();
+    for conflict in conflicts {
+        if let Some(entry) = mempool.get(&conflict.txid) {
+            for descendant in entry.descendants.iter().filter_map(|txid| mempool.get(txid)) {
+                replaced.insert(descendant.txid, descendant.fee);
+            }
+        }
+    }
+    if replaced.len() > MAX_REPLACEMENT_CANDIDATES {
+        return ValidationResult::Invalid(format!(
+            "too many potential replacements: {} > {}", replaced.len(), MAX_REPLACEMENT_CANDIDATES
+        ));
+    }
+    
+    // 2. No new unconfirmed inputs, and nothing spent from what is replaced
+    for (i, input) in new_tx.inputs.iter().enumerate() {
+        let parent = input.prevout.hash;
+        if replaced.contains_key(&parent) {
+            return ValidationResult::Invalid(format!(
+                "bad-txns-spends-conflicting-tx: input {} spends a transaction it replaces", i
+            ));
+        }
+        if mempool.contains(&parent) && !spent_by_any(&parent, conflicts) {
+            return ValidationResult::Invalid(format!(
+                "replacement-adds-unconfirmed: input {} spends a new unconfirmed output", i
+            ));
+        }
+    }
+    
+    // 6. Higher fee rate than every direct conflict
+    let new_fee_rate = new_fee as f64 / new_vsize.max(1) as f64;
+    for conflict in conflicts {
+        let conflict_fee_rate = conflict.fee as f64 / conflict.vsize.max(1) as f64;
+        if new_fee_rate <= conflict_fee_rate {
+            return ValidationResult::Invalid(format!(
+                "insufficient fee: rejecting replacement, new feerate {:.2} <= old feerate {:.2}",
+                new_fee_rate, conflict_fee_rate
+            ));
+        }
+    }
+    
+    // 3. Pay at least everything that is replaced
+    let replaced_fees: Integer = replaced.values().sum();
+    if new_fee < replaced_fees {
+        return ValidationResult::Invalid(format!(
+            "insufficient fee: rejecting replacement, less fees than conflicting txs; {} < {}",
+            new_fee, replaced_fees
+        ));
+    }
+    
+    // 4. And pay for the replacement's own relay
+    let required_extra = mempool.policy_params().incremental_relay_fee_rate * new_vsize as f64;
+    if ((new_fee - replaced_fees) as f64) < required_extra {
+        return ValidationResult::Invalid(format!(
+            "insufficient fee: rejecting replacement, not enough additional fees to relay; {} < {}",
+            new_fee - replaced_fees, required_extra
+        ));
+    }
+    
+    ValidationResult::Valid
+}
+
+/// Whether any of `conflicts` spends an output of `parent`
+fn spent_by_any(parent: &Hash, conflicts: &[Original]) -> bool {
+    conflicts.iter().any(|conflict| {
+        conflict.tx.inputs.iter().any(|input| input.prevout.hash == *parent)
+    })
+}
+
+/// Limits on a transaction's unconfirmed relatives
+/// 
+/// Counts and sizes include the transaction itself; sizes are in virtual bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackageLimits {
+    pub max_ancestor_count: usize,
+    pub max_ancestor_size: Natural,
+    pub max_descendant_count: usize,
+    pub max_descendant_size: Natural,
+}
+
+impl Default for PackageLimits {
+    /// Bitcoin Core's defaults: 25 transactions and 101 kvB either way
+    fn default() -> Self {
+        PackageLimits {
+            max_ancestor_count: 25,
+            max_ancestor_size: 101_000,
+            max_descendant_count: 25,
+            max_descendant_size: 101_000,
+        }
+    }
+}
+
+/// CheckPackageLimits: 𝒯𝒳 × Mempool × PackageLimits → {valid, invalid}
+/// 
+/// For transaction tx of adjusted vsize v with in-mempool ancestors A:
+/// 1. |A| + 1 ≤ maxAncestorCount and Σ_{a ∈ A} vsize(a) + v ≤ maxAncestorSize
+/// 2. For each a ∈ A with descendants D(a):
+///    |D(a)| + 2 ≤ maxDescendantCount and
+///    vsize(a) + Σ_{d ∈ D(a)} vsize(d) + v ≤ maxDescendantSize
+pub fn check_package_limits(
+    tx: &Transaction,
+    vsize: Natural,
+    mempool: &Mempool,
+    limits: &PackageLimits,
+) -> Result<ValidationResult> {
+    let ancestors = mempool.ancestors_of(tx);
+
+    if ancestors.len() + 1 > limits.max_ancestor_count {
+        return Ok(ValidationResult::Invalid(format!(
+            "too-long-mempool-chain: too many unconfirmed ancestors [limit: {}]",
+            limits.max_ancestor_count
+        )));
+    }
+    if mempool.total_vsize(&ancestors) + vsize > limits.max_ancestor_size {
+        return Ok(ValidationResult::Invalid(format!(
+            "too-long-mempool-chain: exceeds ancestor size limit [limit: {}]",
+            limits.max_ancestor_size
+        )));
+    }
+
+    for ancestor in ancestors.iter().filter_map(|txid| mempool.get(txid)) {
+        if ancestor.descendants.len() + 2 > limits.max_descendant_count {
+            return Ok(ValidationResult::Invalid(format!(
+                "too-long-mempool-chain: too many descendants for tx {} [limit: {}]",
+                display_hash(&ancestor.txid), limits.max_descendant_count
+            )));
+        }
+        let descendant_size = ancestor.vsize + mempool.total_vsize(&ancestor.descendants) + vsize;
+        if descendant_size > limits.max_descendant_size {
+            return Ok(ValidationResult::Invalid(format!(
+                "too-long-mempool-chain: exceeds descendant size limit for tx {} [limit: {}]",
+                display_hash(&ancestor.txid), limits.max_descendant_size
+            )));
+        }
+    }
+
+    Ok(ValidationResult::Valid)
+}
+
+/// CheckMempool: Mempool × 𝒰𝒮 → {valid, invalid}
+/// 
+/// Invariants of a consistent mempool m over UTXO set us:
+/// 1. Every input spends an output of us or of another entry of m
+/// 2. No outpoint is spent by two entries
+/// 3. Every entry's fee is non-negative and at most Σ inputs − Σ outputs
+/// 4. Every entry's ancestor set is the transitive closure of its
+///    in-mempool parents, and its descendant set is exactly the entries
+///    listing it as an ancestor
+/// 5. The cached usage is the sum of entry vsizes
+/// 6. The mining-score index holds exactly one current key per entry
+/// 7. The spend index maps exactly the spent outpoints to their spenders
+/// 
+/// Meant for tests and debug assertions; costs O(|m|²) in the worst case.
+pub fn check_mempool(mempool: &Mempool, utxo_set: &UtxoSet) -> Result<ValidationResult> {
+    let invalid = |reason: String| Ok(ValidationResult::Invalid(reason));
+    let mut spent = HashSet::new();
+    
+    for entry in mempool.iter() {
+        let txid = display_hash(&entry.txid);
+        
+        // 1-3. Inputs and fee
+        let mut input_total: Integer = 0;
+        for input in &entry.tx.inputs {
+            let value = match utxo_set.get(&input.prevout) {
+                Some(utxo) => utxo.value,
+                None => match mempool.get_output(&input.prevout) {
+                    Some(output) => output.value,
+                    None => return invalid(format!("{} spends a nonexistent output", txid)),
+                },
+            };
+            if !spent.insert(input.prevout.clone()) {
+                return invalid(format!("{} spends an output already spent in the mempool", txid));
+            }
+            if mempool.spends.get(&input.prevout) != Some(&entry.txid) {
+                return invalid(format!("{} is missing from the spend index", txid));
+            }
+            input_total += value;
+        }
+        let output_total: Integer = entry.tx.outputs.iter().map(|output| output.value).sum();
+        if entry.fee < 0 || entry.fee > input_total - output_total {
+            return invalid(format!("{} has fee {} but pays {}", txid, entry.fee, input_total - output_total));
+        }
+        
+        // 4. Relatives
+        let mut ancestors = HashSet::new();
+        let mut frontier: Vec<Hash> = mempool.parents_of(&entry.tx).into_iter().collect();
+        while let Some(ancestor) = frontier.pop() {
+            if ancestors.insert(ancestor) {
+                frontier.extend(mempool.parents_of(&mempool.entries[&ancestor].tx));
+            }
+        }
+        if ancestors != entry.ancestors {
+            return invalid(format!("{} has a stale ancestor set", txid));
+        }
+        let descendants: HashSet<Hash> = mempool.iter()
+            .filter(|other| other.ancestors.contains(&entry.txid))
+            .map(|other| other.txid)
+            .collect();
+        if descendants != entry.descendants {
+            return invalid(format!("{} has a stale descendant set", txid));
+        }
+    }
+    
+    // 5. Usage
+    if mempool.total_vsize(mempool.entries.keys()) != mempool.usage() {
+        return invalid("Mempool usage does not match its entries".to_string());
+    }
+    
+    // 6. Mining-score index
+    if mempool.by_mining_score.len() != mempool.len() || mempool.mining_scores.len() != mempool.len() {
+        return invalid("Mining-score index does not match the entries".to_string());
+    }
+    for (txid, score) in &mempool.mining_scores {
+        let current = mempool.ancestor_package(txid);
+        if current != Some((score.fee, score.vsize)) || !mempool.by_mining_score.contains(score) {
+            return invalid(format!("{} has a stale mining score", display_hash(txid)));
+        }
+    }
+    
+    // 7. Spend index (entries were checked against it above)
+    if mempool.spends.len() != spent.len() {
+        return invalid("Spend index holds outpoints no entry spends".to_string());
+    }
+    
+    Ok(ValidationResult::Valid)
+}
+
+/// UpdateMempoolForReorg: Mempool × ℬ* × 𝒰𝒮 × ℕ × ℕ → Mempool
+/// 
+/// After disconnecting `disconnected_blocks` (tip first) and connecting the
+/// new branch, leaving UTXO set us at tip height h with median time past t:
+/// 1. Re-add the non-coinbase transactions of the disconnected blocks,
+///    oldest block first, whose inputs still exist in us or the mempool
+///    and which conflict with nothing in the mempool
+/// 2. Remove, with their descendants, entries that are no longer valid in
+///    block h + 1:
+///    - an input is neither in us nor created by another entry
+///    - an input is a coinbase output with fewer than COINBASE_MATURITY
+///      confirmations
+///    - the transaction is not final at height h + 1 and time t
+/// 
+/// Re-added transactions are linked to the entries already spending them,
+/// so ancestor and descendant state stays consistent. Returns the removed
+/// entries.
+pub fn update_mempool_for_reorg(
+    mempool: &mut Mempool,
+    disconnected_blocks: &[Block],
+    new_utxo_set: &UtxoSet,
+    new_height: Natural,
+    median_time_past: Natural,
+) -> Vec<MempoolEntry> {
+    // 1. Re-add disconnected transactions, parents before children
+    for block in disconnected_blocks.iter().rev() {
+        for tx in block.transactions.iter().filter(|tx| !is_coinbase(tx)) {
+            if mempool.contains(&calculate_tx_id(tx)) || !mempool.conflicts_of(tx).is_empty() {
+                continue;
+            }
+            if let Some(fee) = transaction_fee(tx, new_utxo_set, mempool) {
+                let sigop_cost = get_transaction_sigop_cost(tx, &mempool.input_coins(tx, new_utxo_set, 0));
+                let entry = MempoolEntry::new(tx.clone(), fee, sigop_cost, block.header.timestamp, new_height);
+                mempool.insert(entry);
+            }
+        }
+    }
+    
+    // 2. Remove what the new chain invalidated
+    let spend_height = new_height + 1;
+    let stale: Vec<Hash> = mempool.iter()
+        .filter(|entry| {
+            let spendable = entry.tx.inputs.iter().all(|input| match new_utxo_set.get(&input.prevout) {
+                Some(utxo) => !utxo.is_coinbase || spend_height.saturating_sub(utxo.height) >= COINBASE_MATURITY,
+                None => mempool.get_output(&input.prevout).is_some(),
+            });
+            !spendable || !is_final_tx(&entry.tx, spend_height, median_time_past)
+        })
+        .map(|entry| entry.txid)
+        .collect();
+    
+    let mut removed = Vec::new();
+    for txid in stale {
+        removed.extend(mempool.remove_recursive(&txid));
+    }
+    removed
+}
+
+// ============================================================================
+// HELPER FUNCTIONS
+// ============================================================================
+
+/// A transaction in the mempool, with the data policy decisions need
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub txid: Hash,
+    /// Fee paid, in satoshis
+    pub fee: Integer,
+    /// Prioritisation added to the fee for eviction and mining; see
+    /// `Mempool::prioritise_transaction`
+    pub fee_delta: Integer,
+    /// Sigop-adjusted virtual size, in bytes; see `adjusted_vsize`
+    pub vsize: Natural,
+    /// Time the transaction entered the mempool
+    pub time: Natural,
+    /// Chain height when the transaction entered the mempool
+    pub height: Natural,
+    /// Mempool transactions this one spends from, directly or indirectly
+    pub ancestors: HashSet<Hash>,
+    /// Mempool transactions spending from this one, directly or indirectly
+    pub descendants: HashSet<Hash>,
+}
+
+impl MempoolEntry {
+    /// Entry for `tx` with no known relatives; `Mempool::insert` fills those in
+    ///
+    /// The vsize is charged for `sigop_cost`, the cost acceptance counts
+    /// with the spent coins at hand; see `get_transaction_sigop_cost`.
+    pub fn new(tx: Transaction, fee: Integer, sigop_cost: Natural, time: Natural, height: Natural) -> Self {
+        let txid = calculate_tx_id(&tx);
+        let vsize = adjusted_vsize(&tx, sigop_cost);
+        MempoolEntry {
+            tx,
+            txid,
+            fee,
+            fee_delta: 0,
+            vsize,
+            time,
+            height,
+            ancestors: HashSet::new(),
+            descendants: HashSet::new(),
+        }
+    }
+
+    /// Replace the vsize, e.g. with one read back from a dump
+    pub fn with_vsize(mut self, vsize: Natural) -> Self {
+        self.vsize = vsize;
+        self
+    }
+
+    /// Fee with the prioritisation delta applied
+    pub fn modified_fee(&self) -> Integer {
+        self.fee + self.fee_delta
+    }
+
+    /// Fee rate in satoshis per virtual byte
+    pub fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.vsize.max(1) as f64
+    }
+}
+
+/// Default limit on the total vsize of the mempool, in bytes
+pub const DEFAULT_MAX_MEMPOOL_SIZE: Natural = 300_000_000;
+
+/// Time for the rolling minimum fee rate to halve while the mempool is
+/// at least half full, in seconds
+pub const ROLLING_FEE_HALFLIFE: Natural = 12 * 60 * 60;
+
+/// How long a transaction may stay in the mempool, in seconds (two weeks)
+pub const DEFAULT_MEMPOOL_EXPIRY: Natural = 336 * 60 * 60;
+
+/// Lower bounds of the fee histogram buckets, in satoshis per virtual byte
+pub const FEE_HISTOGRAM_BOUNDS: [Natural; 45] = [
+    1, 2, 3, 4, 5, 6, 7, 8, 10, 12, 14, 17, 20, 25, 30, 40, 50, 60, 70, 80, 100,
+    120, 140, 170, 200, 250, 300, 400, 500, 600, 700, 800, 1000, 1200, 1400,
+    1700, 2000, 2500, 3000, 4000, 5000, 6000, 7000, 8000, 10000,
+];
+
+/// Mempool transactions whose fee rate falls in one histogram bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeHistogramBucket {
+    /// Lowest fee rate of the bucket, in satoshis per virtual byte
+    pub min_fee_rate: Natural,
+    pub count: usize,
+    pub vsize: Natural,
+    pub fees: Integer,
+}
+
+/// Summary of the mempool, as reported by getmempoolinfo
+#[derive(Debug, Clone, PartialEq)]
+pub struct MempoolInfo {
+    /// Number of transactions
+    pub size: usize,
+    /// Total vsize, in bytes
+    pub bytes: Natural,
+    /// Total fees, in satoshis
+    pub total_fee: Integer,
+    pub max_size: Natural,
+    /// Fee rate a new transaction must pay, in satoshis per virtual byte
+    pub min_fee_rate: f64,
+    /// One bucket per entry of FEE_HISTOGRAM_BOUNDS, cheapest first
+    pub fee_histogram: Vec<FeeHistogramBucket>,
+}
+
+/// Version of the `Mempool::serialize` format
+pub const MEMPOOL_DUMP_VERSION: u64 = 1;
+
+/// Mempool: unconfirmed transactions by txid
+///
+/// Keeps every entry's ancestor and descendant sets consistent as
+/// transactions are added and removed. Once the total vsize exceeds
+/// `max_size`, `trim_to_size` evicts the cheapest packages and raises a
+/// rolling minimum fee rate that decays back as the pool drains.
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    entries: HashMap<Hash, MempoolEntry>,
+    /// Total vsize of all entries
+    usage: Natural,
+    max_size: Natural,
+    /// Fee rate floor raised by evictions, in satoshis per virtual byte
+    rolling_minimum_fee_rate: f64,
+    /// Time `rolling_minimum_fee_rate` was last raised or decayed
+    last_rolling_fee_update: Natural,
+    /// Every entry's mining score, best first
+    by_mining_score: BTreeSet<MiningScore>,
+    /// Current index key of each entry, to find it again on updates
+    mining_scores: HashMap<Hash, MiningScore>,
+    /// Entry spending each outpoint spent in the mempool
+    spends: HashMap<OutPoint, Hash>,
+    /// Relay fee rates applied to this mempool
+    params: PolicyParams,
+}
+
+/// Mining score of an entry: the modified fee and vsize of the entry
+/// together with its in-mempool ancestors, which must be mined with it
+/// 
+/// Orders by ancestor fee rate, highest first, then by txid.
+#[derive(Debug, Clone, Copy)]
+struct MiningScore {
+    fee: Integer,
+    vsize: Natural,
+    txid: Hash,
+}
+
+impl Ord for MiningScore {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let rate = self.fee as i128 * other.vsize.max(1) as i128;
+        let other_rate = other.fee as i128 * self.vsize.max(1) as i128;
+        other_rate.cmp(&rate).then_with(|| self.txid.cmp(&other.txid))
+    }
+}
+
+impl PartialOrd for MiningScore {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MiningScore {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MiningScore {}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::with_max_size(DEFAULT_MAX_MEMPOOL_SIZE)
+    }
+}
+
+impl Mempool {
+    /// Create an empty mempool
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty mempool holding at most `max_size` vbytes
+    pub fn with_max_size(max_size: Natural) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            usage: 0,
+            max_size,
+            rolling_minimum_fee_rate: 0.0,
+            last_rolling_fee_update: 0,
+            by_mining_score: BTreeSet::new(),
+            mining_scores: HashMap::new(),
+            spends: HashMap::new(),
+            params: PolicyParams::default(),
+        }
+    }
+
+    /// Use `params` instead of the default relay fee rates
+    pub fn with_policy_params(mut self, params: PolicyParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Relay fee rates applied to this mempool
+    pub fn policy_params(&self) -> &PolicyParams {
+        &self.params
+    }
+
+    /// Limit on the total vsize, in bytes
+    pub fn max_size(&self) -> Natural {
+        self.max_size
+    }
+
+    /// Total vsize of all entries, in bytes
+    pub fn usage(&self) -> Natural {
+        self.usage
+    }
+
+    /// Fee rate a new transaction must pay, in satoshis per virtual byte:
+    /// the relay minimum or the rolling minimum, whichever is higher
+    pub fn min_fee_rate(&self) -> f64 {
+        self.rolling_minimum_fee_rate.max(self.params.min_relay_fee_rate)
+    }
+
+    /// Fee rate floor left by past evictions, in satoshis per virtual byte
+    pub fn rolling_minimum_fee_rate(&self) -> f64 {
+        self.rolling_minimum_fee_rate
+    }
+
+    /// Number of transactions
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the mempool holds no transactions
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether a transaction is in the mempool
+    pub fn contains(&self, txid: &Hash) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    /// Entry by txid
+    pub fn get(&self, txid: &Hash) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    /// All entries, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+
+    /// Output `outpoint` if it was created by a mempool transaction
+    pub fn get_output(&self, outpoint: &OutPoint) -> Option<&TransactionOutput> {
+        self.entries.get(&outpoint.hash)?.tx.outputs.get(outpoint.index as usize)
+    }
+
+    /// Mempool transaction spending `outpoint`, if any
+    pub fn spender_of(&self, outpoint: &OutPoint) -> Option<&MempoolEntry> {
+        self.spends.get(outpoint).and_then(|txid| self.get(txid))
+    }
+
+    /// Mempool transactions spending any input of `tx`: the transactions a
+    /// replacement by `tx` would evict directly, in input order
+    pub fn conflicts_of(&self, tx: &Transaction) -> Vec<Hash> {
+        let own_txid = calculate_tx_id(tx);
+        let mut conflicts: Vec<Hash> = Vec::new();
+        for input in &tx.inputs {
+            if let Some(txid) = self.spends.get(&input.prevout) {
+                if *txid != own_txid && !conflicts.contains(txid) {
+                    conflicts.push(*txid);
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Mempool transactions `tx` spends directly
+    pub fn parents_of(&self, tx: &Transaction) -> HashSet<Hash> {
+        tx.inputs.iter()
+            .map(|input| input.prevout.hash)
+            .filter(|hash| self.contains(hash))
+            .collect()
+    }
+
+    /// Mempool transactions spending `txid` directly
+    pub fn children_of(&self, txid: &Hash) -> HashSet<Hash> {
+        self.entries.values()
+            .filter(|entry| entry.tx.inputs.iter().any(|input| input.prevout.hash == *txid))
+            .map(|entry| entry.txid)
+            .collect()
+    }
+
+    /// Mempool transactions `tx` spends from, directly or indirectly
+    pub fn ancestors_of(&self, tx: &Transaction) -> HashSet<Hash> {
+        let mut ancestors = HashSet::new();
+        for parent in self.parents_of(tx) {
+            ancestors.extend(self.entries[&parent].ancestors.iter().copied());
+            ancestors.insert(parent);
+        }
+        ancestors
+    }
+
+    /// Modified fee rate of a transaction together with all its descendants
+    ///
+    /// This is the rate eviction judges a transaction by: removing it
+    /// removes its descendants too.
+    pub fn descendant_fee_rate(&self, txid: &Hash) -> Option<f64> {
+        let entry = self.get(txid)?;
+        let fees: Integer = entry.modified_fee() + entry.descendants.iter()
+            .filter_map(|descendant| self.get(descendant))
+            .map(|descendant| descendant.modified_fee())
+            .sum::<Integer>();
+        let vsize = entry.vsize + self.total_vsize(&entry.descendants);
+        Some(fees as f64 / vsize.max(1) as f64)
+    }
+
+    /// Counts, totals and a fee-rate histogram of the current contents
+    ///
+    /// Each entry is bucketed by its own fee rate under the highest bound
+    /// not above it; the first bucket also takes anything cheaper.
+    pub fn info(&self) -> MempoolInfo {
+        let mut fee_histogram: Vec<FeeHistogramBucket> = FEE_HISTOGRAM_BOUNDS.iter()
+            .map(|&min_fee_rate| FeeHistogramBucket { min_fee_rate, count: 0, vsize: 0, fees: 0 })
+            .collect();
+
+        for entry in self.entries.values() {
+            let fee_rate = entry.fee_rate();
+            let bucket = FEE_HISTOGRAM_BOUNDS.iter()
+                .rposition(|&bound| bound as f64 <= fee_rate)
+                .unwrap_or(0);
+            let bucket = &mut fee_histogram[bucket];
+            bucket.count += 1;
+            bucket.vsize += entry.vsize;
+            bucket.fees += entry.fee;
+        }
+
+        MempoolInfo {
+            size: self.len(),
+            bytes: self.usage,
+            total_fee: self.entries.values().map(|entry| entry.fee).sum(),
+            max_size: self.max_size,
+            min_fee_rate: self.min_fee_rate(),
+            fee_histogram,
+        }
+    }
+
+    /// Transaction with the lowest descendant fee rate, and that rate
+    pub fn lowest_descendant_fee_rate(&self) -> Option<(Hash, f64)> {
+        self.entries.keys()
+            .filter_map(|txid| Some((*txid, self.descendant_fee_rate(txid)?)))
+            .min_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)))
+    }
+
+    /// Total vsize of the given mempool transactions; unknown txids count as 0
+    pub fn total_vsize<'a>(&self, txids: impl IntoIterator<Item = &'a Hash>) -> Natural {
+        txids.into_iter()
+            .filter_map(|txid| self.get(txid))
+            .map(|entry| entry.vsize)
+            .sum()
+    }
+
+    /// Coins spent by `tx`, from the UTXO set or from mempool transactions
+    ///
+    /// Unconfirmed coins are reported at `height`, the height of the next block.
+    pub fn input_coins(&self, tx: &Transaction, utxo_set: &UtxoSet, height: Natural) -> UtxoSet {
+        let mut coins = UtxoSet::new();
+        for input in &tx.inputs {
+            if let Some(utxo) = utxo_set.get(&input.prevout) {
+                coins.insert(input.prevout.clone(), utxo.clone());
+            } else if let Some(output) = self.get_output(&input.prevout) {
+                coins.insert(input.prevout.clone(), UTXO {
+                    value: output.value,
+                    script_pubkey: output.script_pubkey.clone(),
+                    height,
+                    is_coinbase: false,
+                });
+            }
+        }
+        coins
+    }
+
+    /// Add an entry, linking it to its relatives already in the mempool
+    ///
+    /// Returns false, leaving the mempool unchanged, if the transaction is
+    /// already present. No policy is checked here; see `accept_to_memory_pool`.
+    pub fn insert(&mut self, mut entry: MempoolEntry) -> bool {
+        if self.contains(&entry.txid) {
+            return false;
+        }
+
+        let ancestors = self.ancestors_of(&entry.tx);
+
+        // Children can already be present when a transaction is re-added
+        let mut descendants = HashSet::new();
+        for child in self.children_of(&entry.txid) {
+            descendants.extend(self.entries[&child].descendants.iter().copied());
+            descendants.insert(child);
+        }
+
+        for ancestor in &ancestors {
+            let ancestor = self.entries.get_mut(ancestor).expect("ancestor is in the mempool");
+            ancestor.descendants.insert(entry.txid);
+            ancestor.descendants.extend(descendants.iter().copied());
+        }
+        for descendant in &descendants {
+            let descendant = self.entries.get_mut(descendant).expect("descendant is in the mempool");
+            descendant.ancestors.insert(entry.txid);
+            descendant.ancestors.extend(ancestors.iter().copied());
+        }
+
+        entry.ancestors = ancestors;
+        entry.descendants = descendants;
+        self.usage += entry.vsize;
+        let txid = entry.txid;
+        for input in &entry.tx.inputs {
+            self.spends.insert(input.prevout.clone(), txid);
+        }
+        self.entries.insert(txid, entry);
+        self.rescore_with_descendants(&txid);
+        true
+    }
+
+    /// Remove one transaction, unlinking it from its relatives
+    ///
+    /// Meant for transactions leaving because they were mined, when their
+    /// ancestors have left too. Use `remove_recursive` for evictions and
+    /// conflicts, which must take the descendants along.
+    pub fn remove(&mut self, txid: &Hash) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        self.usage -= entry.vsize;
+        for input in &entry.tx.inputs {
+            if self.spends.get(&input.prevout) == Some(txid) {
+                self.spends.remove(&input.prevout);
+            }
+        }
+        for ancestor in &entry.ancestors {
+            if let Some(ancestor) = self.entries.get_mut(ancestor) {
+                ancestor.descendants.remove(txid);
+            }
+        }
+        for descendant in &entry.descendants {
+            if let Some(descendant) = self.entries.get_mut(descendant) {
+                descendant.ancestors.remove(txid);
+            }
+        }
+        self.rescore(txid);
+        for descendant in &entry.descendants {
+            self.rescore(descendant);
+        }
+        Some(entry)
+    }
+
+    /// Entries by mining score (ancestor fee rate, with prioritisation),
+    /// highest first
+    /// 
+    /// The index is kept up to date on every change, so block assembly can
+    /// walk it without sorting the mempool.
+    pub fn iter_by_mining_score(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.by_mining_score.iter().map(|score| &self.entries[&score.txid])
+    }
+
+    /// Modified fee and vsize of a transaction together with its ancestors
+    pub fn ancestor_package(&self, txid: &Hash) -> Option<(Integer, Natural)> {
+        let entry = self.get(txid)?;
+        let fee = entry.modified_fee() + entry.ancestors.iter()
+            .filter_map(|ancestor| self.get(ancestor))
+            .map(|ancestor| ancestor.modified_fee())
+            .sum::<Integer>();
+        Some((fee, entry.vsize + self.total_vsize(&entry.ancestors)))
+    }
+
+    /// Recompute the index key of `txid`, dropping it if the entry is gone
+    fn rescore(&mut self, txid: &Hash) {
+        if let Some(old) = self.mining_scores.remove(txid) {
+            self.by_mining_score.remove(&old);
+        }
+        if let Some((fee, vsize)) = self.ancestor_package(txid) {
+            let score = MiningScore { fee, vsize, txid: *txid };
+            self.by_mining_score.insert(score);
+            self.mining_scores.insert(*txid, score);
+        }
+    }
+
+    /// Recompute the index keys of `txid` and of everything whose ancestor
+    /// package contains it
+    fn rescore_with_descendants(&mut self, txid: &Hash) {
+        let descendants: Vec<Hash> = self.get(txid)
+            .map(|entry| entry.descendants.iter().copied().collect())
+            .unwrap_or_default();
+        self.rescore(txid);
+        for descendant in &descendants {
+            self.rescore(descendant);
+        }
+    }
+
+    /// Add `delta` satoshis to the fee a transaction is judged by for
+    /// eviction and mining, without changing the fee it pays
+    ///
+    /// Returns false if the transaction is not in the mempool.
+    pub fn prioritise_transaction(&mut self, txid: &Hash, delta: Integer) -> bool {
+        match self.entries.get_mut(txid) {
+            Some(entry) => {
+                entry.fee_delta += delta;
+                self.rescore_with_descendants(txid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Serialize the entries for persistence across restarts
+    ///
+    /// version (u64) ‖ |entries| (varint) ‖ entries ‖ checksum, where each
+    /// entry is tx ‖ fee (i64) ‖ fee_delta (i64) ‖ vsize (u64) ‖ time (u64)
+    /// ‖ height (u64), integers little-endian, parents before children, and
+    /// the checksum is the first 4 bytes of Hash256 of everything before it.
+    /// Limits and the rolling minimum fee are not saved.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| (entry.ancestors.len(), entry.txid));
+
+        let mut data = MEMPOOL_DUMP_VERSION.to_le_bytes().to_vec();
+        data.extend(encode_varint(entries.len() as u64));
+        for entry in entries {
+            data.extend(serialize_transaction(&entry.tx));
+            data.extend(entry.fee.to_le_bytes());
+            data.extend(entry.fee_delta.to_le_bytes());
+            data.extend(entry.vsize.to_le_bytes());
+            data.extend(entry.time.to_le_bytes());
+            data.extend(entry.height.to_le_bytes());
+        }
+        let checksum = double_sha256(&data);
+        data.extend(&checksum[..4]);
+        data
+    }
+
+    /// Rebuild a mempool from `serialize` output
+    ///
+    /// Fails on an unknown version, a checksum mismatch, truncated or
+    /// trailing data, or a duplicate transaction. Entries are restored as
+    /// saved, not revalidated: run `check_mempool` or re-accept them against
+    /// the current UTXO set if the chain may have moved.
+    pub fn deserialize(data: &[u8]) -> Result<Mempool> {
+        let corrupt = |reason: &str| ConsensusError::Serialization(format!("Mempool dump: {}", reason));
+
+        let (payload, checksum) = data.split_at_checked(data.len().saturating_sub(4))
+            .filter(|(payload, _)| payload.len() >= 8)
+            .ok_or_else(|| corrupt("truncated"))?;
+        if double_sha256(payload)[..4] != *checksum {
+            return Err(corrupt("checksum mismatch"));
+        }
+
+        let version = u64::from_le_bytes(payload[..8].try_into().unwrap());
+        if version != MEMPOOL_DUMP_VERSION {
+            return Err(corrupt(&format!("unsupported version {}", version)));
+        }
+        let (count, mut pos) = decode_varint(&payload[8..])?;
+        pos += 8;
+
+        let mut mempool = Mempool::new();
+        for _ in 0..count {
+            let (tx, len) = deserialize_transaction(&payload[pos..])?;
+            pos += len;
+            let fields = payload.get(pos..pos + 40).ok_or_else(|| corrupt("truncated"))?;
+            pos += 40;
+            let field = |i: usize| -> [u8; 8] { fields[i * 8..i * 8 + 8].try_into().unwrap() };
+
+            let mut entry = MempoolEntry::new(
+                tx,
+                Integer::from_le_bytes(field(0)),
+                0,
+                Natural::from_le_bytes(field(3)),
+                Natural::from_le_bytes(field(4)),
+            ).with_vsize(Natural::from_le_bytes(field(2)));
+            entry.fee_delta = Integer::from_le_bytes(field(1));
+            if !mempool.insert(entry) {
+                return Err(corrupt("duplicate transaction"));
+            }
+        }
+        if pos != payload.len() {
+            return Err(corrupt("trailing data"));
+        }
+
+        Ok(mempool)
+    }
+
+    /// Remove every transaction spending an input of `tx`, with its
+    /// descendants, as an accepted replacement requires
+    pub fn remove_conflicts(&mut self, tx: &Transaction) -> Vec<MempoolEntry> {
+        let mut removed = Vec::new();
+        for conflict in self.conflicts_of(tx) {
+            removed.extend(self.remove_recursive(&conflict));
+        }
+        removed
+    }
+
+    /// Remove a transaction and all its descendants, returning them
+    pub fn remove_recursive(&mut self, txid: &Hash) -> Vec<MempoolEntry> {
+        let descendants = match self.get(txid) {
+            Some(entry) => entry.descendants.clone(),
+            None => return Vec::new(),
+        };
+
+        let mut removed: Vec<MempoolEntry> = descendants.iter()
+            .filter_map(|descendant| self.remove(descendant))
+            .collect();
+        removed.extend(self.remove(txid));
+        removed
+    }
+
+    /// TrimToSize: Mempool × ℕ → Mempool × 𝒯𝒳*
+    ///
+    /// While usage exceeds max_size:
+    /// 1. Find the transaction with the lowest descendant fee rate r
+    /// 2. Evict it with all its descendants
+    /// 3. Raise the rolling minimum fee rate to r + the incremental relay fee rate,
+    ///    so the next transaction must outbid what was evicted
+    ///
+    /// Returns the evicted entries.
+    pub fn trim_to_size(&mut self, time: Natural) -> Vec<MempoolEntry> {
+        let mut evicted = Vec::new();
+        while self.usage > self.max_size {
+            let Some((txid, fee_rate)) = self.lowest_descendant_fee_rate() else {
+                break;
+            };
+            evicted.extend(self.remove_recursive(&txid));
+
+            let floor = fee_rate + self.params.incremental_relay_fee_rate;
+            if floor > self.rolling_minimum_fee_rate {
+                self.rolling_minimum_fee_rate = floor;
+            }
+            self.last_rolling_fee_update = time;
+        }
+        evicted
     }
-    
-    Ok(true)
-}
 
-// ============================================================================
-// HELPER FUNCTIONS
-// ============================================================================
+    /// Expire: Mempool × ℕ × ℕ → Mempool × 𝒯𝒳*
+    ///
+    /// Remove every transaction that entered the mempool more than `expiry`
+    /// seconds before `now`, together with its descendants, and return the
+    /// removed entries. Descendants go even if they are younger: they
+    /// cannot be mined without their ancestors.
+    pub fn expire(&mut self, now: Natural, expiry: Natural) -> Vec<MempoolEntry> {
+        let cutoff = now.saturating_sub(expiry);
+        let stale: Vec<Hash> = self.entries.values()
+            .filter(|entry| entry.time < cutoff)
+            .map(|entry| entry.txid)
+            .collect();
+
+        let mut removed = Vec::new();
+        for txid in stale {
+            removed.extend(self.remove_recursive(&txid));
+        }
+        removed
+    }
+
+    /// DecayMinimumFee: Mempool × ℕ → Mempool
+    ///
+    /// Let the rolling minimum fee rate fall as time passes:
+    /// 1. Halve it every ROLLING_FEE_HALFLIFE, or every quarter (half) of
+    ///    that while usage is below a quarter (half) of max_size
+    /// 2. Drop it to zero once below half of the incremental relay fee rate
+    ///
+    /// `time` is the current time; earlier times are ignored.
+    pub fn decay_minimum_fee(&mut self, time: Natural) {
+        if time <= self.last_rolling_fee_update || self.rolling_minimum_fee_rate == 0.0 {
+            return;
+        }
+
+        let halflife = if self.usage < self.max_size / 4 {
+            ROLLING_FEE_HALFLIFE / 4
+        } else if self.usage < self.max_size / 2 {
+            ROLLING_FEE_HALFLIFE / 2
+        } else {
+            ROLLING_FEE_HALFLIFE
+        };
+        let elapsed = (time - self.last_rolling_fee_update) as f64;
+        self.rolling_minimum_fee_rate /= 2f64.powf(elapsed / halflife as f64);
+        self.last_rolling_fee_update = time;
 
-/// Mempool data structure
-pub type Mempool = HashSet<Hash>;
+        if self.rolling_minimum_fee_rate < self.params.incremental_relay_fee_rate / 2.0 {
+            self.rolling_minimum_fee_rate = 0.0;
+        }
+    }
+}
 
 /// Result of mempool acceptance
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -153,20 +1483,56 @@ pub enum MempoolResult {
     Rejected(String),
 }
 
-/// Check mempool-specific rules
-fn check_mempool_rules(tx: &Transaction, fee: Integer, mempool: &Mempool) -> Result<bool> {
-    // Check minimum fee rate (simplified)
-    let tx_size = calculate_transaction_size(tx);
-    let fee_rate = (fee as f64) / (tx_size as f64);
-    let min_fee_rate = 1.0; // 1 sat/byte minimum
+/// Default minimum fee rate for relay, in satoshis per virtual byte
+pub const MIN_RELAY_FEE_RATE: f64 = 1.0;
+
+/// Default fee rate, in satoshis per virtual byte, below which spending an
+/// output costs more than it is worth
+pub const DUST_RELAY_FEE_RATE: f64 = 3.0;
+
+/// Relay fee rates of a mempool, in satoshis per virtual byte
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PolicyParams {
+    /// Lowest fee rate accepted into the mempool
+    pub min_relay_fee_rate: f64,
+    /// Fee rate replacements pay for their own relay, and the step the
+    /// rolling minimum rises by on eviction
+    pub incremental_relay_fee_rate: f64,
+    /// Fee rate outputs are priced at to decide whether they are dust
+    pub dust_relay_fee_rate: f64,
+    /// Accept one dust output per transaction when a child in the same
+    /// package spends it; see `check_ephemeral_dust`
+    pub permit_ephemeral_dust: bool,
+}
+
+impl Default for PolicyParams {
+    fn default() -> Self {
+        PolicyParams {
+            min_relay_fee_rate: MIN_RELAY_FEE_RATE,
+            incremental_relay_fee_rate: INCREMENTAL_RELAY_FEE_RATE,
+            dust_relay_fee_rate: DUST_RELAY_FEE_RATE,
+            permit_ephemeral_dust: false,
+        }
+    }
+}
+
+/// Check mempool-specific rules for a transaction of adjusted size `vsize`
+fn check_mempool_rules(fee: Integer, vsize: Natural, mempool: &Mempool) -> Result<bool> {
+    // Check minimum fee rate, including the rolling minimum after evictions
+    let fee_rate = (fee as f64) / (vsize.max(1) as f64);
     
-    if fee_rate < min_fee_rate {
+    if fee_rate < mempool.min_fee_rate() {
         return Ok(false);
     }
     
-    // Check mempool size limits (simplified)
-    if mempool.len() > 10000 { // Arbitrary limit
-        return Ok(false);
+    // A full mempool only takes transactions that outbid the cheapest
+    // package, which trimming would otherwise evict in their place
+    if mempool.usage() + vsize > mempool.max_size() {
+        let outbids = mempool.lowest_descendant_fee_rate()
+            .is_some_and(|(_, lowest)| fee_rate > lowest);
+        if !outbids {
+            return Ok(false);
+        }
     }
     
     Ok(true)
@@ -175,61 +1541,31 @@ fn check_mempool_rules(tx: &Transaction, fee: Integer, mempool: &Mempool) -> Res
 /// Check for transaction conflicts
 fn has_conflicts(tx: &Transaction, mempool: &Mempool) -> Result<bool> {
     // Check if any input is already spent by mempool transaction
-    for input in &tx.inputs {
-        // In a real implementation, we'd check if this input is already spent
-        // by another transaction in the mempool
-        // For now, we'll do a simplified check
-        if mempool.contains(&input.prevout.hash) {
-            return Ok(true);
-        }
-    }
-    
-    Ok(false)
+    Ok(!mempool.conflicts_of(tx).is_empty())
 }
 
 /// Check if transaction signals RBF
+/// 
+/// BIP125: some input has a sequence number of at most 0xfffffffd.
 fn signals_rbf(tx: &Transaction) -> bool {
-    for input in &tx.inputs {
-        if input.sequence < SEQUENCE_FINAL as u64 {
-            return true;
-        }
-    }
-    false
+    tx.inputs.iter().any(|input| input.sequence <= SEQUENCE_RBF as u64)
 }
 
-/// Calculate fee rate (simplified)
-fn calculate_fee_rate(tx: &Transaction) -> f64 {
-    let tx_size = calculate_transaction_size(tx);
-    // Simplified fee calculation - in reality would use actual fee
-    1000.0 / (tx_size as f64) // 1000 sats / size
-}
-
-/// Check if new transaction creates new unconfirmed dependencies
-fn creates_new_dependencies(
-    new_tx: &Transaction,
-    existing_tx: &Transaction,
-    mempool: &Mempool
-) -> Result<bool> {
-    // Check if new transaction spends outputs that weren't spent by existing transaction
-    // and aren't in the UTXO set (i.e., they're unconfirmed)
-    for input in &new_tx.inputs {
-        let mut found = false;
-        
-        // Check if this input was spent by existing transaction
-        for existing_input in &existing_tx.inputs {
-            if existing_input.prevout == input.prevout {
-                found = true;
-                break;
-            }
-        }
-        
-        // If not found in existing transaction and not in mempool, it's a new dependency
-        if !found && !mempool.contains(&input.prevout.hash) {
-            return Ok(true);
-        }
+/// IsRBFOptIn: 𝒯𝒳 × Mempool → {true, false}
+/// 
+/// BIP125: tx is replaceable if it signals itself or any of its unconfirmed
+/// ancestors in the mempool does.
+pub fn is_rbf_opt_in(tx: &Transaction, mempool: &Mempool) -> bool {
+    if signals_rbf(tx) {
+        return true;
     }
-    
-    Ok(false)
+    let ancestors = match mempool.get(&calculate_tx_id(tx)) {
+        Some(entry) => entry.ancestors.clone(),
+        None => mempool.ancestors_of(tx),
+    };
+    ancestors.iter()
+        .filter_map(|ancestor| mempool.get(ancestor))
+        .any(|ancestor| signals_rbf(&ancestor.tx))
 }
 
 /// Check if script is standard
@@ -255,23 +1591,20 @@ fn is_standard_script(script: &ByteString) -> Result<bool> {
     Ok(true)
 }
 
-/// Calculate transaction ID (simplified)
-pub fn calculate_tx_id(tx: &Transaction) -> Hash {
-    // Simplified: use a hash of the transaction data
-    let mut hash = [0u8; 32];
-    hash[0] = (tx.version & 0xff) as u8;
-    hash[1] = (tx.inputs.len() & 0xff) as u8;
-    hash[2] = (tx.outputs.len() & 0xff) as u8;
-    hash[3] = (tx.lock_time & 0xff) as u8;
-    hash
+/// AdjustedVsize: 𝒯𝒳 × ℕ → ℕ
+/// 
+/// Virtual size of a transaction with sigop cost `sigop_cost`, charging
+/// DEFAULT_BYTES_PER_SIGOP per unit of cost when that exceeds its weight.
+/// Fee rates, package limits, eviction and mining all use this size.
+pub fn adjusted_vsize(tx: &Transaction, sigop_cost: Natural) -> Natural {
+    virtual_size(transaction_weight(tx, &[]), sigop_cost, DEFAULT_BYTES_PER_SIGOP)
 }
 
-/// Calculate transaction size (simplified)
+/// Serialized transaction size in bytes
+///
+/// Transactions here carry no witness, so this is also the virtual size.
 fn calculate_transaction_size(tx: &Transaction) -> usize {
-    4 + // version
-    tx.inputs.len() * (32 + 4 + 1 + 4) + // inputs (OutPoint + script_sig_len + sequence) - simplified
-    tx.outputs.len() * (8 + 1) + // outputs (value + script_pubkey_len) - simplified
-    4 // lock_time
+    serialize_transaction(tx).len()
 }
 
 /// Check if transaction is coinbase
@@ -302,7 +1635,7 @@ mod tests {
         let tx = create_valid_transaction();
         let utxo_set = create_test_utxo_set();
         let mut mempool = Mempool::new();
-        mempool.insert(calculate_tx_id(&tx));
+        mempool.insert(MempoolEntry::new(tx.clone(), 1000, 0, 0, 100));
         
         let result = accept_to_memory_pool(&tx, &utxo_set, &mempool, 100).unwrap();
         assert!(matches!(result, MempoolResult::Rejected(_)));
@@ -337,9 +1670,15 @@ mod tests {
         let mut existing_tx_rbf = existing_tx.clone();
         existing_tx_rbf.inputs[0].sequence = SEQUENCE_RBF as u64;
         
-        // This will fail due to fee rate calculation, but that's expected for now
-        let result = replacement_checks(&new_tx_rbf, &existing_tx_rbf, &mempool).unwrap();
-        assert!(!result); // Expected to fail due to simplified fee calculation
+        // Equal fees cannot replace; a higher fee can
+        let utxo_set = create_test_utxo_set();
+        let result = replacement_checks(&new_tx_rbf, &existing_tx_rbf, &utxo_set, &mempool).unwrap();
+        assert!(!result);
+        new_tx_rbf.outputs[0].value = 500;
+        assert!(replacement_checks(&new_tx_rbf, &existing_tx_rbf, &utxo_set, &mempool).unwrap());
+        
+        // Fees that cannot be computed never allow a replacement
+        assert!(!replacement_checks(&new_tx_rbf, &existing_tx_rbf, &UtxoSet::new(), &mempool).unwrap());
     }
     
     #[test]
@@ -349,7 +1688,7 @@ mod tests {
         let mempool = Mempool::new();
         
         // Neither transaction signals RBF
-        assert!(!replacement_checks(&new_tx, &existing_tx, &mempool).unwrap());
+        assert!(!replacement_checks(&new_tx, &existing_tx, &create_test_utxo_set(), &mempool).unwrap());
     }
     
     // ============================================================================
@@ -393,34 +1732,42 @@ mod tests {
         let mut existing_tx = create_valid_transaction();
         
         // Make both transactions signal RBF
-        new_tx.inputs[0].sequence = 0xfffffffe;
-        existing_tx.inputs[0].sequence = 0xfffffffe;
+        new_tx.inputs[0].sequence = SEQUENCE_RBF as u64;
+        existing_tx.inputs[0].sequence = SEQUENCE_RBF as u64;
         
         let mempool = Mempool::new();
-        let result = replacement_checks(&new_tx, &existing_tx, &mempool).unwrap();
-        // The simplified fee calculation may cause this to fail, which is expected
-        assert!(matches!(result, true | false));
+        let utxo_set = create_test_utxo_set();
+        
+        // Equal fees pay nothing for the replacement's own relay
+        assert!(!replacement_checks(&new_tx, &existing_tx, &utxo_set, &mempool).unwrap());
+        
+        // The extra fee must cover the incremental relay fee for its vsize
+        let vsize = adjusted_vsize(&new_tx, 0) as Integer;
+        new_tx.outputs[0].value -= vsize - 1;
+        assert!(!replacement_checks(&new_tx, &existing_tx, &utxo_set, &mempool).unwrap());
+        new_tx.outputs[0].value -= 1;
+        assert!(replacement_checks(&new_tx, &existing_tx, &utxo_set, &mempool).unwrap());
     }
     
     #[test]
     fn test_replacement_checks_new_tx_no_rbf() {
         let new_tx = create_valid_transaction(); // No RBF
         let mut existing_tx = create_valid_transaction();
-        existing_tx.inputs[0].sequence = 0xfffffffe; // RBF
+        existing_tx.inputs[0].sequence = SEQUENCE_RBF as u64; // RBF
         
         let mempool = Mempool::new();
-        let result = replacement_checks(&new_tx, &existing_tx, &mempool).unwrap();
+        let result = replacement_checks(&new_tx, &existing_tx, &create_test_utxo_set(), &mempool).unwrap();
         assert!(!result);
     }
     
     #[test]
     fn test_replacement_checks_existing_tx_no_rbf() {
         let mut new_tx = create_valid_transaction();
-        new_tx.inputs[0].sequence = 0xfffffffe; // RBF
+        new_tx.inputs[0].sequence = SEQUENCE_RBF as u64; // RBF
         let existing_tx = create_valid_transaction(); // No RBF
         
         let mempool = Mempool::new();
-        let result = replacement_checks(&new_tx, &existing_tx, &mempool).unwrap();
+        let result = replacement_checks(&new_tx, &existing_tx, &create_test_utxo_set(), &mempool).unwrap();
         assert!(!result);
     }
     
@@ -430,7 +1777,7 @@ mod tests {
         let fee = 1; // Very low fee
         let mempool = Mempool::new();
         
-        let result = check_mempool_rules(&tx, fee, &mempool).unwrap();
+        let result = check_mempool_rules(fee, vsize_of(&tx), &mempool).unwrap();
         assert!(!result);
     }
     
@@ -440,7 +1787,7 @@ mod tests {
         let fee = 10000; // High fee
         let mempool = Mempool::new();
         
-        let result = check_mempool_rules(&tx, fee, &mempool).unwrap();
+        let result = check_mempool_rules(fee, vsize_of(&tx), &mempool).unwrap();
         assert!(result);
     }
     
@@ -448,23 +1795,24 @@ mod tests {
     fn test_check_mempool_rules_full_mempool() {
         let tx = create_valid_transaction();
         let fee = 10000;
-        let mut mempool = Mempool::new();
+        let tx_size = calculate_transaction_size(&tx) as Natural;
+        let mut mempool = Mempool::with_max_size(tx_size * 10);
         
-        // Fill mempool beyond limit with unique hashes
-        for i in 0..10001 {
-            let mut hash = [0u8; 32];
-            hash[0] = (i & 0xff) as u8;
-            hash[1] = ((i >> 8) & 0xff) as u8;
-            hash[2] = ((i >> 16) & 0xff) as u8;
-            hash[3] = ((i >> 24) & 0xff) as u8;
-            mempool.insert(hash);
+        // Fill mempool to its limit with unique, better-paying transactions
+        for i in 0..10u64 {
+            let mut filler = create_valid_transaction();
+            filler.inputs[0].prevout.index = i + 1;
+            mempool.insert(MempoolEntry::new(filler, 100_000, 0, 0, 100));
         }
         
         // Verify mempool is actually full
-        assert!(mempool.len() > 10000);
+        assert_eq!(mempool.usage(), mempool.max_size());
         
-        let result = check_mempool_rules(&tx, fee, &mempool).unwrap();
+        let result = check_mempool_rules(fee, vsize_of(&tx), &mempool).unwrap();
         assert!(!result);
+        
+        // Outbidding the cheapest package gets in
+        assert!(check_mempool_rules(200_000, vsize_of(&tx), &mempool).unwrap());
     }
     
     #[test]
@@ -482,18 +1830,81 @@ mod tests {
         let mut mempool = Mempool::new();
         
         // Add a conflicting transaction to mempool
-        mempool.insert(tx.inputs[0].prevout.hash);
+        let mut conflicting = tx.clone();
+        conflicting.outputs[0].value = 900;
+        mempool.insert(MempoolEntry::new(conflicting, 100, 0, 0, 100));
         
         let result = has_conflicts(&tx, &mempool).unwrap();
         assert!(result);
     }
     
+    #[test]
+    fn test_conflicts_match_outpoints_not_txids() {
+        let mut parent = create_valid_transaction();
+        parent.outputs.push(parent.outputs[0].clone());
+        let parent_id = calculate_tx_id(&parent);
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(parent, 0, 0, 0, 100));
+        let first = spend(&mempool.get(&parent_id).unwrap().tx, 500);
+        mempool.insert(MempoolEntry::new(first.clone(), 0, 0, 0, 100));
+        
+        // A sibling spending the other output of the same parent conflicts with nothing
+        let mut sibling = first.clone();
+        sibling.inputs[0].prevout.index = 1;
+        assert!(!has_conflicts(&sibling, &mempool).unwrap());
+        
+        // One spending both conflicts with the first child, once
+        let mut both = sibling.clone();
+        both.inputs.push(first.inputs[0].clone());
+        both.inputs.push(first.inputs[0].clone());
+        assert_eq!(mempool.conflicts_of(&both), vec![calculate_tx_id(&first)]);
+        assert!(mempool.conflicts_of(&first).is_empty());
+        
+        // Removal frees the outpoint again
+        mempool.remove_conflicts(&both);
+        assert!(mempool.spender_of(&first.inputs[0].prevout).is_none());
+        assert!(!has_conflicts(&both, &mempool).unwrap());
+        assert_eq!(mempool.spender_of(&OutPoint { hash: [1; 32], index: 0 }).unwrap().txid, parent_id);
+    }
+    
     #[test]
     fn test_signals_rbf_true() {
         let mut tx = create_valid_transaction();
-        tx.inputs[0].sequence = 0xfffffffe; // RBF signal
+        tx.inputs[0].sequence = SEQUENCE_RBF as u64; // RBF signal
         
         assert!(signals_rbf(&tx));
+        
+        // Enables lock time without opting in to replacement
+        tx.inputs[0].sequence = 0xfffffffe;
+        assert!(!signals_rbf(&tx));
+    }
+    
+    #[test]
+    fn test_is_rbf_opt_in_inherited() {
+        let mut parent = create_valid_transaction();
+        let child = spend(&parent, 500);
+        let grandchild = spend(&child, 400);
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(parent.clone(), 0, 0, 0, 100));
+        mempool.insert(MempoolEntry::new(child.clone(), 0, 0, 0, 100));
+        assert!(!is_rbf_opt_in(&child, &mempool));
+        assert!(!is_rbf_opt_in(&grandchild, &mempool));
+        
+        // A signaling grandparent makes the whole chain replaceable
+        parent.inputs[0].sequence = SEQUENCE_RBF as u64;
+        let child = spend(&parent, 500);
+        let grandchild = spend(&child, 400);
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(parent, 0, 0, 0, 100));
+        mempool.insert(MempoolEntry::new(child.clone(), 0, 0, 0, 100));
+        assert!(is_rbf_opt_in(&child, &mempool));
+        assert!(is_rbf_opt_in(&grandchild, &mempool));
+        
+        // So a conflict with the non-signaling child may be replaced
+        let mut replacement = child.clone();
+        replacement.outputs[0].value = 100;
+        let vsize = vsize_of(&replacement);
+        assert_eq!(check_replacement(&replacement, 1000, vsize, &mempool).unwrap(), ValidationResult::Valid);
     }
     
     #[test]
@@ -504,34 +1915,125 @@ mod tests {
     }
     
     #[test]
-    fn test_calculate_fee_rate() {
+    fn test_transaction_fee() {
+        let utxo_set = create_test_utxo_set();
+        let mut mempool = Mempool::new();
         let tx = create_valid_transaction();
-        let fee_rate = calculate_fee_rate(&tx);
+        assert_eq!(transaction_fee(&tx, &utxo_set, &mempool), Some(9000));
         
-        assert!(fee_rate > 0.0);
+        // Unconfirmed parents count, unknown coins and overspends do not
+        let child = spend(&tx, 400);
+        assert_eq!(transaction_fee(&child, &utxo_set, &mempool), None);
+        mempool.insert(MempoolEntry::new(tx.clone(), 9000, 0, 0, 100));
+        assert_eq!(transaction_fee(&child, &utxo_set, &mempool), Some(600));
+        let mut overspend = tx.clone();
+        overspend.outputs[0].value = 10001;
+        assert_eq!(transaction_fee(&overspend, &utxo_set, &mempool), None);
     }
     
     #[test]
-    fn test_creates_new_dependencies_no_new() {
-        let new_tx = create_valid_transaction();
-        let existing_tx = create_valid_transaction();
-        let mempool = Mempool::new();
+    fn test_accept_replacement() {
+        let mut utxo_set = create_test_utxo_set();
+        utxo_set.get_mut(&create_dummy_input().prevout).unwrap().script_pubkey = vec![0x51, 0x87];
+        let mut original = create_valid_transaction();
+        original.inputs[0].sequence = SEQUENCE_RBF as u64;
+        original.outputs[0].value = 9000;
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(original.clone(), 1000, 0, 0, 100));
         
-        let result = creates_new_dependencies(&new_tx, &existing_tx, &mempool).unwrap();
-        assert!(!result);
+        // Paying the same fee again is not enough
+        let mut replacement = original.clone();
+        replacement.outputs[0].script_pubkey = vec![0x52];
+        assert!(matches!(
+            accept_to_memory_pool(&replacement, &utxo_set, &mempool, 100).unwrap(),
+            MempoolResult::Rejected(reason) if reason.contains("insufficient fee")
+        ));
+        
+        replacement.outputs[0].value = 5000;
+        assert_eq!(accept_to_memory_pool(&replacement, &utxo_set, &mempool, 100).unwrap(), MempoolResult::Accepted);
+        let removed = mempool.remove_conflicts(&replacement);
+        assert_eq!(removed.len(), 1);
+        assert!(mempool.insert(MempoolEntry::new(replacement, 5000, 0, 0, 100)));
+        assert_eq!(check_mempool(&mempool, &utxo_set).unwrap(), ValidationResult::Valid);
+        
+        // Without the opt-in signal the conflict stands
+        let mut final_original = original.clone();
+        final_original.inputs[0].sequence = 0xffffffff;
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(final_original, 1000, 0, 0, 100));
+        let mut replacement = original.clone();
+        replacement.outputs[0].value = 5000;
+        assert!(matches!(
+            accept_to_memory_pool(&replacement, &utxo_set, &mempool, 100).unwrap(),
+            MempoolResult::Rejected(reason) if reason.contains("does not signal")
+        ));
     }
     
     #[test]
-    fn test_creates_new_dependencies_with_new() {
-        let mut new_tx = create_valid_transaction();
-        let existing_tx = create_valid_transaction();
-        let mempool = Mempool::new();
+    fn test_check_replacement_bip125_rules() {
+        let mut mempool = Mempool::new();
+        let mut original = create_valid_transaction();
+        original.inputs[0].sequence = SEQUENCE_RBF as u64;
+        let original_id = calculate_tx_id(&original);
+        mempool.insert(MempoolEntry::new(original.clone(), 1000, 0, 0, 100));
+        let child = spend(&original, 500);
+        mempool.insert(MempoolEntry::new(child.clone(), 300, 0, 0, 100));
         
-        // Make new_tx spend a different input
-        new_tx.inputs[0].prevout.hash = [2; 32];
+        let mut replacement = original.clone();
+        replacement.outputs[0].value = 500;
+        let vsize = calculate_transaction_size(&replacement) as Integer;
         
-        let result = creates_new_dependencies(&new_tx, &existing_tx, &mempool).unwrap();
-        assert!(result);
+        // 3/4: must cover the original and its child (1,300) plus its own size
+        assert_eq!(check_replacement(&replacement, 1300 + vsize, vsize_of(&replacement), &mempool).unwrap(), ValidationResult::Valid);
+        assert!(matches!(check_replacement(&replacement, 1300 + vsize - 1, vsize_of(&replacement), &mempool).unwrap(), ValidationResult::Invalid(_)));
+        assert!(matches!(check_replacement(&replacement, 1200, vsize_of(&replacement), &mempool).unwrap(), ValidationResult::Invalid(_)));
+        
+        // 6: the fee rate must beat the direct conflict's
+        let mut bloated = replacement.clone();
+        bloated.outputs.extend(std::iter::repeat_n(create_dummy_output(), 200));
+        assert!(matches!(check_replacement(&bloated, 1300 + 3 * vsize, vsize_of(&bloated), &mempool).unwrap(), ValidationResult::Invalid(_)));
+        
+        // 2: no new unconfirmed inputs, and nothing spent from the replaced set
+        let unrelated = create_valid_transaction_with_prevout([7; 32]);
+        mempool.insert(MempoolEntry::new(unrelated.clone(), 100, 0, 0, 100));
+        let mut adds_unconfirmed = replacement.clone();
+        adds_unconfirmed.inputs.push(spend(&unrelated, 1).inputs[0].clone());
+        assert!(matches!(check_replacement(&adds_unconfirmed, 100_000, vsize_of(&adds_unconfirmed), &mempool).unwrap(), ValidationResult::Invalid(_)));
+        let mut spends_replaced = replacement.clone();
+        spends_replaced.inputs.push(TransactionInput { prevout: OutPoint { hash: original_id, index: 0 }, ..child.inputs[0].clone() });
+        assert!(matches!(check_replacement(&spends_replaced, 100_000, vsize_of(&spends_replaced), &mempool).unwrap(), ValidationResult::Invalid(_)));
+        
+        // 1: the conflict must signal
+        let mut final_mempool = Mempool::new();
+        let mut final_original = original.clone();
+        final_original.inputs[0].sequence = 0xffffffff;
+        final_mempool.insert(MempoolEntry::new(final_original, 1000, 0, 0, 100));
+        assert!(matches!(check_replacement(&replacement, 100_000, vsize_of(&replacement), &final_mempool).unwrap(), ValidationResult::Invalid(_)));
+        
+        // No conflicts: nothing to check
+        assert_eq!(check_replacement(&unrelated, 0, vsize_of(&unrelated), &Mempool::new()).unwrap(), ValidationResult::Valid);
+    }
+    
+    #[test]
+    fn test_check_replacement_max_candidates() {
+        let mut mempool = Mempool::new();
+        let mut original = create_valid_transaction();
+        original.inputs[0].sequence = SEQUENCE_RBF as u64;
+        original.outputs = vec![create_dummy_output(); MAX_REPLACEMENT_CANDIDATES];
+        mempool.insert(MempoolEntry::new(original.clone(), 1000, 0, 0, 100));
+        
+        // One child per output puts 101 transactions in the replaced set
+        let original_id = calculate_tx_id(&original);
+        for index in 0..MAX_REPLACEMENT_CANDIDATES {
+            let mut child = spend(&original, 100);
+            child.inputs[0].prevout.index = index as Natural;
+            mempool.insert(MempoolEntry::new(child, 10, 0, 0, 100));
+        }
+        assert_eq!(mempool.get(&original_id).unwrap().descendants.len(), MAX_REPLACEMENT_CANDIDATES);
+        
+        let replacement = create_valid_transaction();
+        let result = check_replacement(&replacement, MAX_MONEY, vsize_of(&replacement), &mempool).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(reason) if reason.contains("too many")));
     }
     
     #[test]
@@ -621,7 +2123,600 @@ mod tests {
         assert!(!is_coinbase(&regular_tx));
     }
     
+    #[test]
+    fn test_mempool_tracks_ancestors_and_descendants() {
+        let mut mempool = Mempool::new();
+        let parent = create_valid_transaction();
+        let child = spend(&parent, 900);
+        let grandchild = spend(&child, 800);
+        let (parent_id, child_id, grandchild_id) =
+            (calculate_tx_id(&parent), calculate_tx_id(&child), calculate_tx_id(&grandchild));
+        
+        // Insert out of order: the grandchild links up once its parent arrives
+        assert!(mempool.insert(MempoolEntry::new(parent.clone(), 100, 0, 0, 100)));
+        assert!(mempool.insert(MempoolEntry::new(grandchild, 100, 0, 0, 100)));
+        assert!(mempool.insert(MempoolEntry::new(child.clone(), 100, 0, 0, 100)));
+        assert!(!mempool.insert(MempoolEntry::new(child, 100, 0, 0, 100)));
+        
+        assert_eq!(mempool.get(&parent_id).unwrap().descendants, HashSet::from([child_id, grandchild_id]));
+        assert_eq!(mempool.get(&grandchild_id).unwrap().ancestors, HashSet::from([parent_id, child_id]));
+        assert_eq!(mempool.parents_of(&mempool.get(&grandchild_id).unwrap().tx), HashSet::from([child_id]));
+        assert_eq!(mempool.spender_of(&OutPoint { hash: parent_id, index: 0 }).unwrap().txid, child_id);
+        
+        // Mining the parent leaves the rest linked to each other only
+        let mined = mempool.remove(&parent_id).unwrap();
+        assert_eq!(mined.txid, parent_id);
+        assert_eq!(mempool.get(&grandchild_id).unwrap().ancestors, HashSet::from([child_id]));
+        
+        // Evicting the child takes the grandchild with it
+        assert_eq!(mempool.remove_recursive(&child_id).len(), 2);
+        assert!(mempool.is_empty());
+    }
+    
+    #[test]
+    fn test_accept_to_memory_pool_spends_unconfirmed_parent() {
+        // The parent pays to OP_1 OP_EQUAL, which a scriptSig of OP_1 satisfies
+        let mut parent = create_valid_transaction();
+        parent.outputs[0].script_pubkey = vec![0x51, 0x87];
+        let mut utxo_set = create_test_utxo_set();
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(parent.clone(), 9000, 0, 0, 100));
+        utxo_set.remove(&parent.inputs[0].prevout);
+        
+        let child = spend(&parent, 500);
+        assert_eq!(accept_to_memory_pool(&child, &utxo_set, &mempool, 100).unwrap(), MempoolResult::Accepted);
+        
+        // A second spend of the parent's output conflicts with the first
+        mempool.insert(MempoolEntry::new(child, 500, 0, 0, 100));
+        let double_spend = spend(&parent, 480);
+        assert!(matches!(
+            accept_to_memory_pool(&double_spend, &utxo_set, &mempool, 100).unwrap(),
+            MempoolResult::Rejected(reason) if reason.starts_with("Transaction conflicts with mempool")
+        ));
+    }
+    
+    #[test]
+    fn test_policy_params() {
+        let mut utxo_set = create_test_utxo_set();
+        utxo_set.values_mut().for_each(|utxo| utxo.script_pubkey = vec![0x51, 0x87]);
+        let mut tx = create_valid_transaction();
+        let default = Mempool::new();
+        assert_eq!(accept_to_memory_pool(&tx, &utxo_set, &default, 100).unwrap(), MempoolResult::Accepted);
+        
+        // 9000 sat over ~60 vbytes clears 1 sat/vB but not 1000 sat/vB
+        let strict = Mempool::new().with_policy_params(PolicyParams { min_relay_fee_rate: 1000.0, ..PolicyParams::default() });
+        assert!(matches!(accept_to_memory_pool(&tx, &utxo_set, &strict, 100).unwrap(), MempoolResult::Rejected(_)));
+        
+        // 100 sat outputs are dust at the default rate only
+        tx.outputs[0].value = 100;
+        assert_eq!(accept_to_memory_pool(&tx, &utxo_set, &default, 100).unwrap(), MempoolResult::Rejected("dust".to_string()));
+        let no_dust = Mempool::new().with_policy_params(PolicyParams { dust_relay_fee_rate: 0.0, ..PolicyParams::default() });
+        assert_eq!(accept_to_memory_pool(&tx, &utxo_set, &no_dust, 100).unwrap(), MempoolResult::Accepted);
+        
+        // Evictions raise the rolling minimum by the configured increment
+        let mut small = Mempool::with_max_size(0).with_policy_params(PolicyParams { incremental_relay_fee_rate: 5.0, ..PolicyParams::default() });
+        small.insert(MempoolEntry::new(create_valid_transaction(), 0, 0, 0, 100));
+        small.trim_to_size(0);
+        assert_eq!(small.rolling_minimum_fee_rate(), 5.0);
+    }
+    
+    #[test]
+    fn test_accept_to_memory_pool_lock_times() {
+        let mut utxo_set = create_test_utxo_set();
+        utxo_set.values_mut().for_each(|utxo| {
+            utxo.script_pubkey = vec![0x51, 0x87];
+            utxo.height = 90;
+        });
+        let mempool = Mempool::new();
+        let accepted = |tx: &Transaction, tip: &ChainTip| {
+            accept_to_memory_pool_at_tip(tx, &utxo_set, &mempool, tip, &PackageLimits::default()).unwrap()
+        };
+        
+        // Locked to height 100: minable in block 101, so from tip 100 on
+        let mut tx = create_valid_transaction();
+        tx.inputs[0].sequence = 0;
+        tx.lock_time = 100;
+        assert_eq!(accepted(&tx, &ChainTip::at_height(99)), MempoolResult::Rejected("non-final".to_string()));
+        assert_eq!(accepted(&tx, &ChainTip::at_height(100)), MempoolResult::Accepted);
+        
+        // Time locks compare against the tip's median time past
+        tx.lock_time = LOCKTIME_THRESHOLD as Natural + 1000;
+        let mtp = |height: Natural| LOCKTIME_THRESHOLD as Natural + height * 10;
+        let tip = |height| ChainTip { height, median_time_past: mtp(height), median_time_past_at: &mtp };
+        assert_eq!(accepted(&tx, &tip(100)), MempoolResult::Rejected("non-final".to_string()));
+        assert_eq!(accepted(&tx, &tip(101)), MempoolResult::Accepted);
+        
+        // BIP68: 20 blocks after confirmation at 90, so in block 110
+        tx.lock_time = 0;
+        tx.version = 2;
+        tx.inputs[0].sequence = 20;
+        assert_eq!(accepted(&tx, &tip(108)), MempoolResult::Rejected("non-BIP68-final".to_string()));
+        assert_eq!(accepted(&tx, &tip(109)), MempoolResult::Accepted);
+        
+        // 512 seconds after the median time past of block 89
+        tx.inputs[0].sequence = SEQUENCE_LOCKTIME_TYPE_FLAG as Natural | 1;
+        assert_eq!(accepted(&tx, &tip(139)), MempoolResult::Rejected("non-BIP68-final".to_string()));
+        assert_eq!(accepted(&tx, &tip(141)), MempoolResult::Accepted);
+    }
+    
+    #[test]
+    fn test_ephemeral_dust_package() {
+        let mut utxo_set = create_test_utxo_set();
+        utxo_set.values_mut().for_each(|utxo| utxo.script_pubkey = vec![0x51, 0x87]);
+        let permissive = Mempool::new().with_policy_params(PolicyParams { permit_ephemeral_dust: true, ..PolicyParams::default() });
+        
+        // A zero-fee parent with a zero-value anchor, and a child spending both outputs
+        let mut parent = create_valid_transaction();
+        parent.outputs = vec![
+            TransactionOutput { value: 10000, script_pubkey: vec![0x51, 0x87] },
+            TransactionOutput { value: 0, script_pubkey: vec![0x51, 0x87] },
+        ];
+        let mut child = spend(&parent, 8000);
+        let mut anchor_spend = child.inputs[0].clone();
+        anchor_spend.prevout.index = 1;
+        child.inputs.push(anchor_spend);
+        let package = [parent.clone(), child.clone()];
+        
+        assert_eq!(accept_package(&package, &utxo_set, &permissive, 100).unwrap(), MempoolResult::Accepted);
+        assert!(matches!(
+            accept_package(&package, &utxo_set, &Mempool::new(), 100).unwrap(),
+            MempoolResult::Rejected(reason) if reason.ends_with("dust")
+        ));
+        
+        // The child must spend the anchor
+        let lazy_child = spend(&parent, 8000);
+        assert!(matches!(
+            accept_package(&[parent.clone(), lazy_child], &utxo_set, &permissive, 100).unwrap(),
+            MempoolResult::Rejected(reason) if reason.contains("missing-ephemeral-spends")
+        ));
+        
+        // The parent must not pay a fee itself, nor carry a second dust output
+        let mut paying = parent.clone();
+        paying.outputs[0].value = 9000;
+        let mut paying_child = child.clone();
+        paying_child.inputs.iter_mut().for_each(|input| input.prevout.hash = calculate_tx_id(&paying));
+        assert!(matches!(
+            accept_package(&[paying, paying_child], &utxo_set, &permissive, 100).unwrap(),
+            MempoolResult::Rejected(reason) if reason.contains("must be 0-fee")
+        ));
+        let mut double_dust = parent.clone();
+        double_dust.outputs.push(double_dust.outputs[1].clone());
+        assert!(matches!(
+            accept_to_memory_pool(&double_dust, &utxo_set, &permissive, 100).unwrap(),
+            MempoolResult::Rejected(reason) if reason == "dust"
+        ));
+    }
+    
+    #[test]
+    fn test_package_limits() {
+        let mut mempool = Mempool::new();
+        let mut chain = vec![create_valid_transaction()];
+        for i in 1..25 {
+            chain.push(spend(&chain[i - 1], 1000 - i as Integer));
+        }
+        for tx in &chain {
+            mempool.insert(MempoolEntry::new(tx.clone(), 100, 0, 0, 100));
+        }
+        
+        // A 26th link exceeds both the ancestor limit and the root's descendant limit
+        let too_long = spend(chain.last().unwrap(), 900);
+        let result = check_package_limits(&too_long, vsize_of(&too_long), &mempool, &PackageLimits::default()).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(reason) if reason.contains("ancestors")));
+        
+        // Spending the 24th link directly still has 24 ancestors, but the root would have 25 descendants
+        let limits = PackageLimits { max_ancestor_count: 100, ..PackageLimits::default() };
+        let sibling = spend(&chain[23], 800);
+        let result = check_package_limits(&sibling, vsize_of(&sibling), &mempool, &limits).unwrap();
+        assert!(matches!(result, ValidationResult::Invalid(reason) if reason.contains("descendants")));
+        
+        // With room for descendants a shorter branch is fine, and sizes are limited as well
+        let limits = PackageLimits { max_descendant_count: 100, ..PackageLimits::default() };
+        let short = spend(&chain[10], 700);
+        assert_eq!(check_package_limits(&short, vsize_of(&short), &mempool, &limits).unwrap(), ValidationResult::Valid);
+        let tiny = PackageLimits { max_ancestor_size: 500, ..limits };
+        assert!(matches!(check_package_limits(&short, vsize_of(&short), &mempool, &tiny).unwrap(), ValidationResult::Invalid(_)));
+        
+        let result = accept_to_memory_pool(&too_long, &UtxoSet::new(), &mempool, 100).unwrap();
+        assert!(matches!(result, MempoolResult::Rejected(_)));
+    }
+    
+    #[test]
+    fn test_accept_package_cpfp() {
+        // A parent paying nothing, and a child paying for both
+        let mut parent = create_valid_transaction();
+        parent.outputs = vec![TransactionOutput { value: 10000, script_pubkey: vec![0x51, 0x87] }];
+        let mut utxo_set = create_test_utxo_set();
+        utxo_set.get_mut(&parent.inputs[0].prevout).unwrap().script_pubkey = vec![0x51, 0x87];
+        let mempool = Mempool::new();
+        let rich_child = spend(&parent, 9000);
+        let poor_child = spend(&parent, 9990);
+        
+        assert!(matches!(
+            accept_to_memory_pool(&parent, &utxo_set, &mempool, 100).unwrap(),
+            MempoolResult::Rejected(_)
+        ));
+        assert_eq!(
+            accept_package(&[parent.clone(), rich_child.clone()], &utxo_set, &mempool, 100).unwrap(),
+            MempoolResult::Accepted
+        );
+        assert!(matches!(
+            accept_package(&[parent.clone(), poor_child], &utxo_set, &mempool, 100).unwrap(),
+            MempoolResult::Rejected(reason) if reason.starts_with("package-fee-too-low")
+        ));
+        
+        // Parents already in the mempool are skipped
+        let mut with_parent = Mempool::new();
+        with_parent.insert(MempoolEntry::new(parent.clone(), 0, 0, 0, 100));
+        assert_eq!(
+            accept_package(&[parent.clone(), rich_child.clone()], &utxo_set, &with_parent, 100).unwrap(),
+            MempoolResult::Accepted
+        );
+    }
+    
+    #[test]
+    fn test_accept_package_topology() {
+        let parent = create_valid_transaction();
+        let child = spend(&parent, 500);
+        let utxo_set = create_test_utxo_set();
+        let mempool = Mempool::new();
+        
+        // Wrong order: the child must come last
+        assert!(matches!(
+            accept_package(&[child.clone(), parent.clone()], &utxo_set, &mempool, 100).unwrap(),
+            MempoolResult::Rejected(reason) if reason.starts_with("package-not-child-with-parents")
+        ));
+        // Two transactions spending the same outpoint
+        let mut double_spend = parent.clone();
+        double_spend.outputs[0].value = 1;
+        assert!(matches!(
+            accept_package(&[parent.clone(), double_spend, child], &utxo_set, &mempool, 100).unwrap(),
+            MempoolResult::Rejected(reason) if reason == "conflict-in-package"
+        ));
+        assert!(matches!(accept_package(&[], &utxo_set, &mempool, 100).unwrap(), MempoolResult::Rejected(_)));
+    }
+    
+    #[test]
+    fn test_trim_to_size_evicts_lowest_descendant_fee_rate() {
+        let tx_size = calculate_transaction_size(&create_valid_transaction()) as Natural;
+        let mut mempool = Mempool::with_max_size(tx_size * 3);
+        
+        // A cheap parent carried by a rich child outranks a lone mid-rate transaction
+        let parent = create_valid_transaction();
+        let child = spend(&parent, 500);
+        let mut lone = create_valid_transaction();
+        lone.inputs[0].prevout.index = 7;
+        let mut rich = create_valid_transaction();
+        rich.inputs[0].prevout.index = 8;
+        mempool.insert(MempoolEntry::new(parent.clone(), 0, 0, 0, 100));
+        mempool.insert(MempoolEntry::new(child.clone(), 50 * tx_size as Integer, 0, 0, 100));
+        mempool.insert(MempoolEntry::new(lone.clone(), 5 * tx_size as Integer, 0, 0, 100));
+        mempool.insert(MempoolEntry::new(rich.clone(), 40 * tx_size as Integer, 0, 0, 100));
+        assert!(mempool.usage() > mempool.max_size());
+        
+        let evicted = mempool.trim_to_size(1000);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].txid, calculate_tx_id(&lone));
+        assert!(mempool.usage() <= mempool.max_size());
+        assert_eq!(mempool.rolling_minimum_fee_rate(), 5.0 + INCREMENTAL_RELAY_FEE_RATE);
+        assert_eq!(mempool.min_fee_rate(), 6.0);
+        
+        // Shrinking further evicts the whole parent-child package together
+        mempool.max_size = tx_size;
+        let evicted = mempool.trim_to_size(1000);
+        assert_eq!(evicted.len(), 2);
+        assert!(mempool.contains(&calculate_tx_id(&rich)));
+        assert_eq!(mempool.usage(), tx_size);
+    }
+    
+    #[test]
+    fn test_rolling_minimum_fee_decays() {
+        let mut mempool = Mempool::with_max_size(1000);
+        mempool.rolling_minimum_fee_rate = 8.0;
+        mempool.last_rolling_fee_update = 1000;
+        
+        // Earlier times change nothing
+        mempool.decay_minimum_fee(500);
+        assert_eq!(mempool.rolling_minimum_fee_rate(), 8.0);
+        
+        // An empty mempool decays with a quarter of the halflife
+        mempool.decay_minimum_fee(1000 + ROLLING_FEE_HALFLIFE / 4);
+        assert_eq!(mempool.rolling_minimum_fee_rate(), 4.0);
+        mempool.decay_minimum_fee(1000 + ROLLING_FEE_HALFLIFE / 2);
+        assert_eq!(mempool.rolling_minimum_fee_rate(), 2.0);
+        
+        // Below half the incremental fee it snaps to zero
+        mempool.decay_minimum_fee(1000 + ROLLING_FEE_HALFLIFE * 2);
+        assert_eq!(mempool.rolling_minimum_fee_rate(), 0.0);
+        assert_eq!(mempool.min_fee_rate(), MIN_RELAY_FEE_RATE);
+    }
+    
+    #[test]
+    fn test_expire_removes_stale_transactions_and_descendants() {
+        let day = 24 * 60 * 60;
+        let now = 30 * day;
+        let mut mempool = Mempool::new();
+        
+        let old_parent = create_valid_transaction();
+        let young_child = spend(&old_parent, 500);
+        let mut young = create_valid_transaction();
+        young.inputs[0].prevout.index = 7;
+        mempool.insert(MempoolEntry::new(old_parent.clone(), 1000, 0, now - 15 * day, 100));
+        mempool.insert(MempoolEntry::new(young_child.clone(), 1000, 0, now - day, 100));
+        mempool.insert(MempoolEntry::new(young.clone(), 1000, 0, now - 13 * day, 100));
+        
+        let removed = mempool.expire(now, DEFAULT_MEMPOOL_EXPIRY);
+        assert_eq!(removed.len(), 2);
+        assert!(!mempool.contains(&calculate_tx_id(&young_child)));
+        assert!(mempool.contains(&calculate_tx_id(&young)));
+        
+        // Nothing expires before the clock has run past the expiry
+        assert!(mempool.expire(day, DEFAULT_MEMPOOL_EXPIRY).is_empty());
+        assert_eq!(mempool.expire(now + 2 * day, DEFAULT_MEMPOOL_EXPIRY).len(), 1);
+        assert!(mempool.is_empty());
+    }
+    
+    #[test]
+    fn test_info_and_fee_histogram() {
+        let mut mempool = Mempool::new();
+        let info = mempool.info();
+        assert_eq!((info.size, info.bytes, info.total_fee), (0, 0, 0));
+        assert_eq!(info.min_fee_rate, MIN_RELAY_FEE_RATE);
+        assert_eq!(info.fee_histogram.len(), FEE_HISTOGRAM_BOUNDS.len());
+        
+        let tx_size = calculate_transaction_size(&create_valid_transaction()) as Integer;
+        for (i, rate) in [0, 1, 3, 11, 11, 20_000].into_iter().enumerate() {
+            let mut tx = create_valid_transaction();
+            tx.inputs[0].prevout.index = i as Natural;
+            mempool.insert(MempoolEntry::new(tx, rate * tx_size, 0, 0, 100));
+        }
+        
+        let info = mempool.info();
+        assert_eq!(info.size, 6);
+        assert_eq!(info.bytes, 6 * tx_size as Natural);
+        assert_eq!(info.total_fee, 20_026 * tx_size);
+        let count_at = |bound: Natural| {
+            info.fee_histogram.iter().find(|bucket| bucket.min_fee_rate == bound).unwrap().count
+        };
+        assert_eq!(count_at(1), 2);
+        assert_eq!(count_at(3), 1);
+        assert_eq!(count_at(10), 2);
+        assert_eq!(count_at(10000), 1);
+        assert_eq!(info.fee_histogram.iter().map(|bucket| bucket.count).sum::<usize>(), 6);
+    }
+    
+    #[test]
+    fn test_check_mempool_invariants() {
+        let mut utxo_set = create_test_utxo_set();
+        let parent = create_valid_transaction();
+        let child = spend(&parent, 500);
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(parent.clone(), 9000, 0, 0, 100));
+        mempool.insert(MempoolEntry::new(child.clone(), 500, 0, 0, 100));
+        assert_eq!(check_mempool(&mempool, &utxo_set).unwrap(), ValidationResult::Valid);
+        
+        // Removing mined transactions keeps the caches accurate
+        let mut mined = mempool.clone();
+        mined.remove(&calculate_tx_id(&parent));
+        utxo_set.insert(child.inputs[0].prevout.clone(), UTXO {
+            value: parent.outputs[0].value,
+            script_pubkey: parent.outputs[0].script_pubkey.clone(),
+            height: 100,
+            is_coinbase: false,
+        });
+        assert_eq!(check_mempool(&mined, &utxo_set).unwrap(), ValidationResult::Valid);
+        
+        let broken = |mempool: &Mempool, utxo_set: &UtxoSet| {
+            matches!(check_mempool(mempool, utxo_set).unwrap(), ValidationResult::Invalid(_))
+        };
+        let utxo_set = create_test_utxo_set();
+        
+        // Overstated fee
+        let mut bad = mempool.clone();
+        bad.entries.get_mut(&calculate_tx_id(&child)).unwrap().fee = 600;
+        assert!(broken(&bad, &utxo_set));
+        
+        // Stale ancestor cache
+        let mut bad = mempool.clone();
+        bad.entries.get_mut(&calculate_tx_id(&child)).unwrap().ancestors.clear();
+        assert!(broken(&bad, &utxo_set));
+        
+        // Double spend of the parent's input
+        let mut bad = mempool.clone();
+        let mut double = parent.clone();
+        double.outputs[0].value = 1;
+        bad.entries.insert(calculate_tx_id(&double), MempoolEntry::new(double, 0, 0, 0, 100));
+        assert!(broken(&bad, &utxo_set));
+        
+        // Missing coin
+        assert!(broken(&mempool, &UtxoSet::new()));
+    }
+    
+    #[test]
+    fn test_adjusted_vsize_charges_sigops() {
+        let mut tx = create_valid_transaction();
+        let size = calculate_transaction_size(&tx) as Natural;
+        assert_eq!(adjusted_vsize(&tx, 0), size);
+        assert_eq!(MempoolEntry::new(tx.clone(), 1000, 0, 0, 100).vsize, size);
+        
+        // Bare multisig outputs count 20 sigops each, weighing far more than their bytes
+        tx.outputs[0].script_pubkey = vec![0xae];
+        let sigop_cost = get_transaction_sigop_cost(&tx, &UtxoSet::new());
+        assert_eq!(sigop_cost, 80);
+        let entry = MempoolEntry::new(tx.clone(), 1000, sigop_cost, 0, 100);
+        assert_eq!(entry.vsize, 80 * DEFAULT_BYTES_PER_SIGOP / 4);
+        assert!(entry.fee_rate() < 1000.0 / size as f64);
+        
+        // A fee enough for the bytes is not enough for the sigops
+        let fee = 2 * size as Integer;
+        assert!(check_mempool_rules(fee, size, &Mempool::new()).unwrap());
+        assert!(!check_mempool_rules(fee, entry.vsize, &Mempool::new()).unwrap());
+    }
+    
+    #[test]
+    fn test_serialize_round_trip() {
+        let parent = create_valid_transaction();
+        let child = spend(&parent, 500);
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(child.clone(), 500, 0, 1_700_000_100, 101).with_vsize(300));
+        mempool.insert(MempoolEntry::new(parent.clone(), 9000, 0, 1_700_000_000, 100));
+        assert!(mempool.prioritise_transaction(&calculate_tx_id(&parent), -2000));
+        assert!(!mempool.prioritise_transaction(&[9; 32], 1));
+        
+        let data = mempool.serialize();
+        let restored = Mempool::deserialize(&data).unwrap();
+        assert_eq!(restored.len(), 2);
+        for entry in mempool.iter() {
+            assert_eq!(restored.get(&entry.txid), Some(entry));
+        }
+        assert_eq!(restored.get(&calculate_tx_id(&parent)).unwrap().modified_fee(), 7000);
+        assert_eq!(restored.usage(), mempool.usage());
+        assert_eq!(Mempool::deserialize(&Mempool::new().serialize()).unwrap().len(), 0);
+    }
+    
+    #[test]
+    fn test_deserialize_rejects_corruption() {
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(create_valid_transaction(), 9000, 0, 0, 100));
+        let data = mempool.serialize();
+        let error = |data: &[u8]| Mempool::deserialize(data).unwrap_err().to_string();
+        
+        let mut flipped = data.clone();
+        flipped[20] ^= 1;
+        assert!(error(&flipped).contains("checksum"));
+        assert!(error(&data[..data.len() - 1]).contains("checksum"));
+        assert!(error(&data[..3]).contains("truncated"));
+        
+        // A future version with a valid checksum
+        let mut payload = data[..data.len() - 4].to_vec();
+        payload[0] = 2;
+        let checksum = double_sha256(&payload);
+        payload.extend(&checksum[..4]);
+        assert!(error(&payload).contains("unsupported version 2"));
+        
+        // Two copies of the same entry
+        let mut payload = data[..data.len() - 4].to_vec();
+        let entry = payload[9..].to_vec();
+        payload[8] = 2;
+        payload.extend(entry);
+        let checksum = double_sha256(&payload);
+        payload.extend(&checksum[..4]);
+        assert!(error(&payload).contains("duplicate"));
+    }
+    
+    #[test]
+    fn test_mining_score_index() {
+        let order = |mempool: &Mempool| -> Vec<Hash> {
+            mempool.iter_by_mining_score().map(|entry| entry.txid).collect()
+        };
+        let mut utxo_set = create_test_utxo_set();
+        let coin = utxo_set[&OutPoint { hash: [1; 32], index: 0 }].clone();
+        utxo_set.insert(OutPoint { hash: [1; 32], index: 7 }, coin);
+        let mut mempool = Mempool::new();
+        
+        // parent (0 sat) ← child (high fee); an unrelated mid-fee transaction
+        let parent = create_valid_transaction();
+        let child = spend(&parent, 100);
+        let mut other = create_valid_transaction();
+        other.inputs[0].prevout.index = 7;
+        let (parent_id, child_id, other_id) = (calculate_tx_id(&parent), calculate_tx_id(&child), calculate_tx_id(&other));
+        mempool.insert(MempoolEntry::new(parent.clone(), 0, 0, 0, 100));
+        mempool.insert(MempoolEntry::new(other.clone(), 3000, 0, 0, 100));
+        assert_eq!(order(&mempool), vec![other_id, parent_id]);
+        
+        // The child lifts its own package above the mid-fee transaction
+        mempool.insert(MempoolEntry::new(child, 9000, 0, 0, 100));
+        assert_eq!(order(&mempool), vec![child_id, other_id, parent_id]);
+        let parent_vsize = mempool.get(&parent_id).unwrap().vsize;
+        let child_vsize = mempool.get(&child_id).unwrap().vsize;
+        assert_eq!(mempool.ancestor_package(&child_id), Some((9000, parent_vsize + child_vsize)));
+        
+        // Prioritisation and removal update the index in place
+        mempool.prioritise_transaction(&other_id, -3000);
+        assert_eq!(order(&mempool).last(), Some(&other_id));
+        mempool.remove(&parent_id);
+        assert_eq!(order(&mempool), vec![child_id, other_id]);
+        assert_eq!(mempool.ancestor_package(&child_id), Some((9000, child_vsize)));
+        
+        let mut full = Mempool::new();
+        full.insert(MempoolEntry::new(parent, 0, 0, 0, 100));
+        full.insert(MempoolEntry::new(other, 3000, 0, 0, 100));
+        assert_eq!(check_mempool(&full, &utxo_set).unwrap(), ValidationResult::Valid);
+    }
+    
+    #[test]
+    fn test_update_mempool_for_reorg() {
+        let block = |transactions: Vec<Transaction>| Block {
+            header: BlockHeader {
+                version: 1, prev_block_hash: [0; 32], merkle_root: [0; 32],
+                timestamp: 1000, bits: 0x1d00ffff, nonce: 0,
+            },
+            transactions,
+        };
+        let mut utxo_set = create_test_utxo_set();
+        let coin = utxo_set[&OutPoint { hash: [1; 32], index: 0 }].clone();
+        
+        // Block 10 confirmed `confirmed`; the mempool holds its child
+        let confirmed = create_valid_transaction();
+        let child = spend(&confirmed, 500);
+        let mut orphaned = create_valid_transaction();
+        orphaned.inputs[0].prevout.index = 1;
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(child.clone(), 500, 0, 0, 10));
+        
+        // Spending a coinbase mined at height 5 is fine at height 105 only
+        let mut matures = create_valid_transaction();
+        matures.inputs[0].prevout.index = 2;
+        utxo_set.insert(matures.inputs[0].prevout.clone(), UTXO { is_coinbase: true, height: 5, ..coin.clone() });
+        mempool.insert(MempoolEntry::new(matures.clone(), 1000, 0, 0, 10));
+        
+        // Height-locked to 104: final in block 105, not in block 100
+        let mut locked = create_valid_transaction();
+        locked.inputs[0].prevout.index = 3;
+        locked.inputs[0].sequence = 0;
+        locked.lock_time = 104;
+        utxo_set.insert(locked.inputs[0].prevout.clone(), coin.clone());
+        mempool.insert(MempoolEntry::new(locked.clone(), 1000, 0, 0, 10));
+        
+        // Disconnecting block 10 leaves the coin `orphaned` spends spent
+        let disconnected = vec![block(vec![create_coinbase_transaction(), confirmed.clone(), orphaned.clone()])];
+        let removed = update_mempool_for_reorg(&mut mempool, &disconnected, &utxo_set, 99, 0);
+        
+        let removed: HashSet<Hash> = removed.iter().map(|entry| entry.txid).collect();
+        assert_eq!(removed, [calculate_tx_id(&matures), calculate_tx_id(&locked)].into_iter().collect());
+        assert!(!mempool.contains(&calculate_tx_id(&orphaned)));
+        assert!(!mempool.contains(&calculate_tx_id(&create_coinbase_transaction())));
+        
+        // The confirmed transaction is back, as the child's ancestor
+        let confirmed_id = calculate_tx_id(&confirmed);
+        assert_eq!(mempool.get(&confirmed_id).unwrap().fee, 10000 - 1000);
+        assert!(mempool.get(&calculate_tx_id(&child)).unwrap().ancestors.contains(&confirmed_id));
+        assert_eq!(check_mempool(&mempool, &utxo_set).unwrap(), ValidationResult::Valid);
+        
+        // On a longer branch both survive
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(matures, 1000, 0, 0, 10));
+        mempool.insert(MempoolEntry::new(locked, 1000, 0, 0, 10));
+        assert!(update_mempool_for_reorg(&mut mempool, &[], &utxo_set, 104, 0).is_empty());
+    }
+    
+    fn vsize_of(tx: &Transaction) -> Natural {
+        adjusted_vsize(tx, get_transaction_sigop_cost(tx, &UtxoSet::new()))
+    }
+    
     // Helper functions for tests
+    fn spend(parent: &Transaction, value: Integer) -> Transaction {
+        Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: calculate_tx_id(parent), index: 0 },
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        }
+    }
+    
     fn create_valid_transaction() -> Transaction {
         Transaction {
             version: 1,
@@ -631,6 +2726,12 @@ mod tests {
         }
     }
     
+    fn create_valid_transaction_with_prevout(hash: Hash) -> Transaction {
+        let mut tx = create_valid_transaction();
+        tx.inputs[0].prevout.hash = hash;
+        tx
+    }
+    
     fn create_dummy_input() -> TransactionInput {
         TransactionInput {
             prevout: OutPoint { hash: [1; 32], index: 0 },
@@ -653,6 +2754,7 @@ mod tests {
             value: 10000,
             script_pubkey: vec![0x51], // OP_1 for valid script
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint, utxo);
         utxo_set
@@ -0,0 +1,350 @@
+//! Clusters and linearization: grouping mempool transactions that depend on
+//! each other, and ordering each group for mining
+//!
+//! A cluster is a connected component of the spend graph. Its linearization
+//! is a topological order; chunking the linearization into groups of
+//! non-increasing fee rate gives the fee-rate diagram that mining selection
+//! and replacement (diagram) checks compare.
+
+use crate::types::*;
+use super::Mempool;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+/// A run of a linearization mined together: its combined fee rate is at
+/// least that of every later chunk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub txids: Vec<Hash>,
+    /// Sum of modified fees
+    pub fee: Integer,
+    pub vsize: Natural,
+}
+
+impl Chunk {
+    /// Fee rate in satoshis per virtual byte
+    pub fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.vsize.max(1) as f64
+    }
+}
+
+/// Cumulative (vsize, fee) at each chunk boundary, starting at (0, 0)
+pub type FeerateDiagram = Vec<(Natural, Integer)>;
+
+/// Cluster of `txid`: every mempool transaction connected to it through
+/// spends in either direction, sorted by txid
+///
+/// Empty if `txid` is not in the mempool.
+pub fn cluster_of(mempool: &Mempool, txid: &Hash) -> Vec<Hash> {
+    let mut cluster = HashSet::new();
+    let mut frontier = vec![*txid];
+    while let Some(next) = frontier.pop() {
+        let Some(entry) = mempool.get(&next) else {
+            continue;
+        };
+        if cluster.insert(next) {
+            frontier.extend(entry.ancestors.iter().chain(&entry.descendants).copied());
+        }
+    }
+
+    let mut cluster: Vec<Hash> = cluster.into_iter().collect();
+    cluster.sort_unstable();
+    cluster
+}
+
+/// All clusters of the mempool, each sorted by txid, ordered by their
+/// smallest txid
+pub fn clusters(mempool: &Mempool) -> Vec<Vec<Hash>> {
+    let mut txids: Vec<Hash> = mempool.iter().map(|entry| entry.txid).collect();
+    txids.sort_unstable();
+
+    let mut seen = HashSet::new();
+    let mut clusters = Vec::new();
+    for txid in txids {
+        if seen.contains(&txid) {
+            continue;
+        }
+        let cluster = cluster_of(mempool, &txid);
+        seen.extend(cluster.iter().copied());
+        clusters.push(cluster);
+    }
+    clusters
+}
+
+/// Linearize: Mempool × ℍ* → ℍ*
+///
+/// Ancestor-set linearization of `txids` (normally one cluster):
+/// 1. Among the remaining transactions, find the one whose remaining
+///    ancestors together with itself have the highest modified fee rate
+///    (ties: smaller set, then smaller txid)
+/// 2. Append that set, parents before children, and remove it
+/// 3. Repeat until nothing remains
+///
+/// The result is topologically ordered and is the order ancestor-fee-rate
+/// mining would pick the cluster in. It is optimal for most real clusters
+/// but not all: a better set may combine transactions with no common
+/// descendant.
+pub fn linearize(mempool: &Mempool, txids: &[Hash]) -> Vec<Hash> {
+    let mut remaining: HashSet<Hash> = txids.iter()
+        .filter(|txid| mempool.contains(txid))
+        .copied()
+        .collect();
+    let mut linearization = Vec::with_capacity(remaining.len());
+
+    while !remaining.is_empty() {
+        let mut best: Option<(Vec<Hash>, Integer, Natural)> = None;
+        let mut candidates: Vec<&Hash> = remaining.iter().collect();
+        candidates.sort_unstable();
+        for txid in candidates {
+            let entry = mempool.get(txid).expect("remaining transactions are in the mempool");
+            let mut set: Vec<Hash> = entry.ancestors.iter()
+                .filter(|ancestor| remaining.contains(*ancestor))
+                .copied()
+                .collect();
+            set.push(*txid);
+            let fee: Integer = set.iter().map(|txid| mempool.get(txid).unwrap().modified_fee()).sum();
+            let vsize = mempool.total_vsize(&set);
+
+            let better = match &best {
+                None => true,
+                Some((best_set, best_fee, best_vsize)) => {
+                    match compare_fee_rates(fee, vsize, *best_fee, *best_vsize) {
+                        Ordering::Greater => true,
+                        Ordering::Equal => set.len() < best_set.len(),
+                        Ordering::Less => false,
+                    }
+                }
+            };
+            if better {
+                best = Some((set, fee, vsize));
+            }
+        }
+
+        let (mut set, _, _) = best.expect("remaining is non-empty");
+        // Fewer ancestors first puts every parent before its children
+        set.sort_by_key(|txid| (mempool.get(txid).unwrap().ancestors.len(), *txid));
+        for txid in &set {
+            remaining.remove(txid);
+        }
+        linearization.extend(set);
+    }
+
+    linearization
+}
+
+/// Chunk: Mempool × ℍ* → Chunk*
+///
+/// Split a linearization into chunks: start a new chunk per transaction,
+/// then merge each chunk into its predecessor while it pays a higher fee
+/// rate, so the resulting fee rates are non-increasing.
+pub fn chunk(mempool: &Mempool, linearization: &[Hash]) -> Vec<Chunk> {
+    let mut chunks: Vec<Chunk> = Vec::new();
+    for txid in linearization {
+        let Some(entry) = mempool.get(txid) else {
+            continue;
+        };
+        let mut current = Chunk { txids: vec![*txid], fee: entry.modified_fee(), vsize: entry.vsize };
+        while let Some(previous) = chunks.last() {
+            if compare_fee_rates(current.fee, current.vsize, previous.fee, previous.vsize) != Ordering::Greater {
+                break;
+            }
+            let mut previous = chunks.pop().unwrap();
+            previous.txids.extend(current.txids);
+            previous.fee += current.fee;
+            previous.vsize += current.vsize;
+            current = previous;
+        }
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Fee-rate diagram of a chunking: the concave curve through the
+/// cumulative (vsize, fee) after each chunk
+pub fn feerate_diagram(chunks: &[Chunk]) -> FeerateDiagram {
+    let mut diagram = vec![(0, 0)];
+    let (mut vsize, mut fee) = (0, 0);
+    for chunk in chunks {
+        vsize += chunk.vsize;
+        fee += chunk.fee;
+        diagram.push((vsize, fee));
+    }
+    diagram
+}
+
+/// CompareDiagrams: FeerateDiagram × FeerateDiagram → {<, =, >, incomparable}
+///
+/// `Greater` if `a` collects at least as much fee as `b` at every size and
+/// strictly more at some, `Equal` if the same everywhere, `None` if each is
+/// better somewhere. Past its total size a diagram stays flat.
+pub fn compare_diagrams(a: &FeerateDiagram, b: &FeerateDiagram) -> Option<Ordering> {
+    let (mut a_better, mut b_better) = (false, false);
+    let sizes = a.iter().chain(b).map(|&(vsize, _)| vsize);
+    for vsize in sizes {
+        match fee_at(a, vsize).cmp(&fee_at(b, vsize)) {
+            Ordering::Greater => a_better = true,
+            Ordering::Less => b_better = true,
+            Ordering::Equal => {}
+        }
+    }
+    match (a_better, b_better) {
+        (false, false) => Some(Ordering::Equal),
+        (true, false) => Some(Ordering::Greater),
+        (false, true) => Some(Ordering::Less),
+        (true, true) => None,
+    }
+}
+
+/// Fee of a diagram at `vsize`, interpolated between points, as an exact
+/// fraction
+fn fee_at(diagram: &FeerateDiagram, vsize: Natural) -> Fraction {
+    for pair in diagram.windows(2) {
+        let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+        if vsize <= x1 {
+            let (dx, offset) = ((x1 - x0) as i128, (vsize - x0) as i128);
+            if dx == 0 {
+                return Fraction(y1 as i128, 1);
+            }
+            return Fraction(y0 as i128 * dx + (y1 - y0) as i128 * offset, dx);
+        }
+    }
+    Fraction(diagram.last().map_or(0, |&(_, fee)| fee) as i128, 1)
+}
+
+/// Non-negative-denominator fraction, ordered by value
+#[derive(Debug, Clone, Copy)]
+struct Fraction(i128, i128);
+
+impl PartialEq for Fraction {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Fraction {}
+
+impl PartialOrd for Fraction {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Fraction {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0 * other.1).cmp(&(other.0 * self.1))
+    }
+}
+
+/// Compare fee rates fee_a/vsize_a and fee_b/vsize_b exactly
+fn compare_fee_rates(fee_a: Integer, vsize_a: Natural, fee_b: Integer, vsize_b: Natural) -> Ordering {
+    (fee_a as i128 * vsize_b.max(1) as i128).cmp(&(fee_b as i128 * vsize_a.max(1) as i128))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mempool::{calculate_tx_id, MempoolEntry};
+
+    fn tx(prevouts: &[(Hash, Natural)], outputs: usize) -> Transaction {
+        Transaction {
+            version: 1,
+            inputs: prevouts.iter()
+                .map(|&(hash, index)| TransactionInput {
+                    prevout: OutPoint { hash, index },
+                    script_sig: vec![0x51],
+                    sequence: 0xffffffff,
+                })
+                .collect(),
+            outputs: vec![TransactionOutput { value: 1000, script_pubkey: vec![0x51] }; outputs],
+            lock_time: 0,
+        }
+    }
+
+    /// Insert `tx` with a vsize of 100 and the given fee
+    fn add(mempool: &mut Mempool, tx: &Transaction, fee: Integer) -> Hash {
+        mempool.insert(MempoolEntry::new(tx.clone(), fee, 0, 0, 100).with_vsize(100));
+        calculate_tx_id(tx)
+    }
+
+    #[test]
+    fn test_clusters() {
+        let mut mempool = Mempool::new();
+        let a = tx(&[([1; 32], 0)], 2);
+        let a_id = add(&mut mempool, &a, 100);
+        let b_id = add(&mut mempool, &tx(&[(a_id, 0)], 1), 100);
+        let c_id = add(&mut mempool, &tx(&[(a_id, 1)], 1), 100);
+        let lone_id = add(&mut mempool, &tx(&[([2; 32], 0)], 1), 100);
+
+        let mut family = vec![a_id, b_id, c_id];
+        family.sort_unstable();
+        assert_eq!(cluster_of(&mempool, &b_id), family);
+        assert_eq!(cluster_of(&mempool, &lone_id), vec![lone_id]);
+        assert!(cluster_of(&mempool, &[9; 32]).is_empty());
+        assert_eq!(clusters(&mempool).len(), 2);
+    }
+
+    #[test]
+    fn test_linearize_child_pays_for_parent() {
+        // parent (1 sat/vB) ← rich child (50 sat/vB); middle (10 sat/vB) alone
+        let mut mempool = Mempool::new();
+        let parent = tx(&[([1; 32], 0)], 2);
+        let parent_id = add(&mut mempool, &parent, 100);
+        let rich_id = add(&mut mempool, &tx(&[(parent_id, 0)], 1), 5000);
+        let middle_id = add(&mut mempool, &tx(&[(parent_id, 1)], 1), 1000);
+
+        let cluster = cluster_of(&mempool, &parent_id);
+        let linearization = linearize(&mempool, &cluster);
+        assert_eq!(linearization, vec![parent_id, rich_id, middle_id]);
+
+        let chunks = chunk(&mempool, &linearization);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].txids, vec![parent_id, rich_id]);
+        assert_eq!((chunks[0].fee, chunks[0].vsize), (5100, 200));
+        assert_eq!(chunks[1].fee_rate(), 10.0);
+        assert_eq!(feerate_diagram(&chunks), vec![(0, 0), (200, 5100), (300, 6100)]);
+
+        // Prioritisation counts as fee
+        mempool.prioritise_transaction(&middle_id, 10_000);
+        let linearization = linearize(&mempool, &cluster);
+        assert_eq!(linearization[..2], [parent_id, middle_id]);
+    }
+
+    #[test]
+    fn test_linearize_diamond_is_topological() {
+        //     top
+        //    /   \
+        //  left  right
+        //    \   /
+        //    bottom
+        let mut mempool = Mempool::new();
+        let top_id = add(&mut mempool, &tx(&[([1; 32], 0)], 2), 100);
+        let left_id = add(&mut mempool, &tx(&[(top_id, 0)], 1), 200);
+        let right_id = add(&mut mempool, &tx(&[(top_id, 1)], 1), 300);
+        let bottom_id = add(&mut mempool, &tx(&[(left_id, 0), (right_id, 0)], 1), 10_000);
+
+        let linearization = linearize(&mempool, &cluster_of(&mempool, &top_id));
+        assert_eq!(linearization.len(), 4);
+        let position = |txid: &Hash| linearization.iter().position(|t| t == txid).unwrap();
+        assert!(position(&top_id) < position(&left_id));
+        assert!(position(&top_id) < position(&right_id));
+        assert!(position(&left_id) < position(&bottom_id));
+        assert!(position(&right_id) < position(&bottom_id));
+        assert_eq!(chunk(&mempool, &linearization).len(), 1);
+    }
+
+    #[test]
+    fn test_compare_diagrams() {
+        let old: FeerateDiagram = vec![(0, 0), (100, 1000), (200, 1500)];
+        let better: FeerateDiagram = vec![(0, 0), (100, 1200), (200, 1600)];
+        let shorter: FeerateDiagram = vec![(0, 0), (150, 1600)];
+        let crossing: FeerateDiagram = vec![(0, 0), (200, 1700)];
+
+        assert_eq!(compare_diagrams(&better, &old), Some(Ordering::Greater));
+        assert_eq!(compare_diagrams(&old, &better), Some(Ordering::Less));
+        assert_eq!(compare_diagrams(&old, &old), Some(Ordering::Equal));
+        // Flat past its end, but ahead everywhere before
+        assert_eq!(compare_diagrams(&shorter, &old), Some(Ordering::Greater));
+        // Behind at 100 vbytes (850 < 1000), ahead at 200
+        assert_eq!(compare_diagrams(&crossing, &old), None);
+    }
+}
@@ -0,0 +1,339 @@
+//! Merkle trees over transaction ids: roots, branches and BIP37 partial
+//! merkle trees for SPV proofs
+
+use crate::types::*;
+use crate::constants::*;
+use crate::error::{ConsensusError, Result};
+use crate::serialization::double_sha256;
+
+/// Smallest possible serialized transaction, bounding the leaf count of a block
+const MIN_TRANSACTION_SIZE: usize = 60;
+
+/// MerkleNode: ℍ × ℍ → ℍ
+///
+/// SHA256d(left ‖ right)
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut combined = [0u8; 64];
+    combined[..32].copy_from_slice(left);
+    combined[32..].copy_from_slice(right);
+    double_sha256(&combined)
+}
+
+/// MerkleRootMutated: ℍ* → ℍ × {true, false}
+///
+/// Root of the tree over `hashes`, duplicating the last hash of every odd
+/// level, together with a flag set when any level pairs two identical
+/// hashes (CVE-2012-2459). Returns `None` for an empty list.
+pub fn merkle_root_from_hashes(hashes: &[Hash]) -> Option<(Hash, bool)> {
+    if hashes.is_empty() {
+        return None;
+    }
+
+    let mut level = hashes.to_vec();
+    let mut mutated = false;
+
+    while level.len() > 1 {
+        level = level.chunks(2)
+            .map(|chunk| {
+                if chunk.len() == 2 && chunk[0] == chunk[1] {
+                    mutated = true;
+                }
+                // Odd number: the last hash is paired with itself
+                hash_pair(&chunk[0], chunk.get(1).unwrap_or(&chunk[0]))
+            })
+            .collect();
+    }
+
+    Some((level[0], mutated))
+}
+
+/// MerkleBranch: ℍ* × ℕ → ℍ*
+///
+/// Sibling hashes from leaf `index` up to (excluding) the root, or `None`
+/// if `index` is out of range.
+pub fn merkle_branch(hashes: &[Hash], index: usize) -> Option<Vec<Hash>> {
+    if index >= hashes.len() {
+        return None;
+    }
+
+    let mut branch = Vec::new();
+    let mut level = hashes.to_vec();
+    let mut position = index;
+
+    while level.len() > 1 {
+        let sibling = (position ^ 1).min(level.len() - 1);
+        branch.push(level[sibling]);
+
+        level = level.chunks(2)
+            .map(|chunk| hash_pair(&chunk[0], chunk.get(1).unwrap_or(&chunk[0])))
+            .collect();
+        position /= 2;
+    }
+
+    Some(branch)
+}
+
+/// MerkleRootFromBranch: ℍ × ℍ* × ℕ → ℍ
+///
+/// Fold a leaf with its branch; bit i of `index` selects whether the leaf
+/// side is the right (1) or left (0) child at level i.
+pub fn merkle_root_from_branch(leaf: &Hash, branch: &[Hash], index: usize) -> Hash {
+    branch.iter().enumerate().fold(*leaf, |node, (level, sibling)| {
+        if (index >> level) & 1 == 1 {
+            hash_pair(sibling, &node)
+        } else {
+            hash_pair(&node, sibling)
+        }
+    })
+}
+
+/// PartialMerkleTree (BIP37)
+///
+/// A depth-first encoding of the parts of a merkle tree needed to prove a
+/// subset of transactions: one flag bit per visited node (set when the node
+/// has a matched leaf below it) and the hashes of the unexpanded nodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialMerkleTree {
+    pub num_transactions: u32,
+    pub bits: Vec<bool>,
+    pub hashes: Vec<Hash>,
+}
+
+impl PartialMerkleTree {
+    /// Build a partial tree over `txids` proving the entries where `matches` is set
+    ///
+    /// `matches` must have one flag per txid.
+    pub fn from_txids(txids: &[Hash], matches: &[bool]) -> Result<Self> {
+        if txids.is_empty() || txids.len() != matches.len() {
+            return Err(ConsensusError::BlockValidation(
+                "Partial merkle tree needs one match flag per transaction".to_string()
+            ));
+        }
+
+        let mut tree = PartialMerkleTree {
+            num_transactions: txids.len() as u32,
+            bits: Vec::new(),
+            hashes: Vec::new(),
+        };
+        let height = tree.tree_height();
+        tree.traverse_and_build(height, 0, txids, matches);
+        Ok(tree)
+    }
+
+    /// ExtractMatches: PartialMerkleTree → ℍ × (ℕ × ℍ)*
+    ///
+    /// Recompute the merkle root and return it with the matched txids and
+    /// their positions in the block. Fails on any malformed encoding:
+    /// no transactions, more hashes than transactions, unused bits or
+    /// hashes, or identical sibling hashes (CVE-2012-2459).
+    pub fn extract_matches(&self) -> Result<(Hash, Vec<(usize, Hash)>)> {
+        let num_transactions = self.num_transactions as usize;
+        if num_transactions == 0 {
+            return Err(bad_tree("no transactions"));
+        }
+        if num_transactions > MAX_BLOCK_SIZE / MIN_TRANSACTION_SIZE {
+            return Err(bad_tree("too many transactions"));
+        }
+        if self.hashes.len() > num_transactions {
+            return Err(bad_tree("more hashes than transactions"));
+        }
+        if self.bits.len() < self.hashes.len() {
+            return Err(bad_tree("fewer flag bits than hashes"));
+        }
+
+        let mut cursor = ExtractCursor { bits_used: 0, hashes_used: 0, matches: Vec::new() };
+        let root = self.traverse_and_extract(self.tree_height(), 0, &mut cursor)?;
+
+        // Every hash must be used, and flag bits only up to byte padding
+        if cursor.bits_used.div_ceil(8) != self.bits.len().div_ceil(8) {
+            return Err(bad_tree("unused flag bits"));
+        }
+        if cursor.hashes_used != self.hashes.len() {
+            return Err(bad_tree("unused hashes"));
+        }
+
+        Ok((root, cursor.matches))
+    }
+
+    /// Number of nodes at `height` (leaves are at height 0)
+    fn tree_width(&self, height: u32) -> usize {
+        (self.num_transactions as usize + (1 << height) - 1) >> height
+    }
+
+    /// Height of the root
+    fn tree_height(&self) -> u32 {
+        let mut height = 0;
+        while self.tree_width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    fn calc_hash(&self, height: u32, pos: usize, txids: &[Hash]) -> Hash {
+        if height == 0 {
+            return txids[pos];
+        }
+        let left = self.calc_hash(height - 1, pos * 2, txids);
+        let right = if pos * 2 + 1 < self.tree_width(height - 1) {
+            self.calc_hash(height - 1, pos * 2 + 1, txids)
+        } else {
+            left
+        };
+        hash_pair(&left, &right)
+    }
+
+    fn traverse_and_build(&mut self, height: u32, pos: usize, txids: &[Hash], matches: &[bool]) {
+        let start = pos << height;
+        let end = ((pos + 1) << height).min(txids.len());
+        let parent_of_match = matches[start..end].iter().any(|&m| m);
+        self.bits.push(parent_of_match);
+
+        if height == 0 || !parent_of_match {
+            let hash = self.calc_hash(height, pos, txids);
+            self.hashes.push(hash);
+        } else {
+            self.traverse_and_build(height - 1, pos * 2, txids, matches);
+            if pos * 2 + 1 < self.tree_width(height - 1) {
+                self.traverse_and_build(height - 1, pos * 2 + 1, txids, matches);
+            }
+        }
+    }
+
+    fn traverse_and_extract(&self, height: u32, pos: usize, cursor: &mut ExtractCursor) -> Result<Hash> {
+        let parent_of_match = *self.bits.get(cursor.bits_used)
+            .ok_or_else(|| bad_tree("ran out of flag bits"))?;
+        cursor.bits_used += 1;
+
+        if height == 0 || !parent_of_match {
+            let hash = *self.hashes.get(cursor.hashes_used)
+                .ok_or_else(|| bad_tree("ran out of hashes"))?;
+            cursor.hashes_used += 1;
+            if height == 0 && parent_of_match {
+                cursor.matches.push((pos, hash));
+            }
+            return Ok(hash);
+        }
+
+        let left = self.traverse_and_extract(height - 1, pos * 2, cursor)?;
+        let right = if pos * 2 + 1 < self.tree_width(height - 1) {
+            let right = self.traverse_and_extract(height - 1, pos * 2 + 1, cursor)?;
+            if right == left {
+                return Err(bad_tree("identical sibling hashes"));
+            }
+            right
+        } else {
+            left
+        };
+        Ok(hash_pair(&left, &right))
+    }
+}
+
+struct ExtractCursor {
+    bits_used: usize,
+    hashes_used: usize,
+    matches: Vec<(usize, Hash)>,
+}
+
+fn bad_tree(reason: &str) -> ConsensusError {
+    ConsensusError::BlockValidation(format!("Invalid partial merkle tree: {}", reason))
+}
+
+/// VerifyMerkleProof: ℋ × PartialMerkleTree → ℍ*
+///
+/// Matched txids of `tree` if it is well formed and commits to
+/// `header.merkle_root`; an error otherwise.
+pub fn verify_merkle_proof(header: &BlockHeader, tree: &PartialMerkleTree) -> Result<Vec<Hash>> {
+    let (root, matches) = tree.extract_matches()?;
+    if root != header.merkle_root {
+        return Err(ConsensusError::BlockValidation(
+            "Partial merkle tree does not match the block's merkle root".to_string()
+        ));
+    }
+    Ok(matches.into_iter().map(|(_, txid)| txid).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txids(n: u8) -> Vec<Hash> {
+        (0..n).map(|i| [i + 1; 32]).collect()
+    }
+
+    #[test]
+    fn test_merkle_branch_roundtrip() {
+        for n in 1..=9 {
+            let leaves = txids(n);
+            let (root, mutated) = merkle_root_from_hashes(&leaves).unwrap();
+            assert!(!mutated);
+
+            for (index, leaf) in leaves.iter().enumerate() {
+                let branch = merkle_branch(&leaves, index).unwrap();
+                assert_eq!(merkle_root_from_branch(leaf, &branch, index), root);
+            }
+            assert!(merkle_branch(&leaves, leaves.len()).is_none());
+        }
+        assert!(merkle_root_from_hashes(&[]).is_none());
+    }
+
+    #[test]
+    fn test_partial_merkle_tree_roundtrip() {
+        for n in 1..=13u8 {
+            let leaves = txids(n);
+            let (root, _) = merkle_root_from_hashes(&leaves).unwrap();
+
+            // Match every third transaction
+            let matches: Vec<bool> = (0..n as usize).map(|i| i % 3 == 0).collect();
+            let tree = PartialMerkleTree::from_txids(&leaves, &matches).unwrap();
+            let (extracted_root, found) = tree.extract_matches().unwrap();
+
+            assert_eq!(extracted_root, root);
+            let expected: Vec<(usize, Hash)> = leaves.iter().copied().enumerate()
+                .filter(|(i, _)| matches[*i])
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_verify_merkle_proof() {
+        let leaves = txids(5);
+        let header = BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: merkle_root_from_hashes(&leaves).unwrap().0,
+            timestamp: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 0,
+        };
+        let tree = PartialMerkleTree::from_txids(&leaves, &[false, true, false, false, true]).unwrap();
+        assert_eq!(verify_merkle_proof(&header, &tree).unwrap(), vec![leaves[1], leaves[4]]);
+
+        let mut wrong_header = header.clone();
+        wrong_header.merkle_root = [0xff; 32];
+        assert!(verify_merkle_proof(&wrong_header, &tree).is_err());
+    }
+
+    #[test]
+    fn test_partial_merkle_tree_malformed() {
+        let leaves = txids(4);
+        let tree = PartialMerkleTree::from_txids(&leaves, &[true, false, false, false]).unwrap();
+
+        let mut extra_hash = tree.clone();
+        extra_hash.hashes.push([0xaa; 32]);
+        assert!(extra_hash.extract_matches().is_err());
+
+        let mut missing_bits = tree.clone();
+        missing_bits.bits.truncate(1);
+        assert!(missing_bits.extract_matches().is_err());
+
+        let mut extra_bits = tree.clone();
+        extra_bits.bits.extend([false; 8]);
+        assert!(extra_bits.extract_matches().is_err());
+
+        let empty = PartialMerkleTree { num_transactions: 0, bits: vec![], hashes: vec![] };
+        assert!(empty.extract_matches().is_err());
+
+        assert!(PartialMerkleTree::from_txids(&leaves, &[true]).is_err());
+    }
+}
@@ -1,10 +1,21 @@
 //! Mining and block creation functions from Orange Paper Section 10.1
 
 use crate::types::*;
-use crate::error::Result;
-use crate::transaction::{check_transaction, is_coinbase};
+use crate::error::{ConsensusError, Result};
+use crate::constants::{MAX_BLOCK_SIGOPS_COST, MAX_BLOCK_SIZE, MAX_FUTURE_BLOCK_TIME, WITNESS_SCALE_FACTOR};
+use crate::transaction::{calculate_tx_id, calculate_wtxid, check_transaction, get_transaction_sigop_cost};
+use crate::block::{calculate_merkle_root, median_time_past};
+use crate::merkle::{merkle_branch, merkle_root_from_branch, merkle_root_from_hashes};
+use crate::segwit::Witness;
+use crate::serialization::double_sha256;
 use crate::economic::get_block_subsidy;
-use crate::pow::get_next_work_required;
+use crate::pow::{get_next_work_required_at, expand_target, check_proof_of_work_with_params};
+use crate::params::{ChainParams, Deployment};
+use crate::policy::transaction_weight;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+#[cfg(feature = "stratum-v2")]
+pub mod stratum_v2;
 
 /// CreateNewBlock: 𝒰𝒮 × 𝒯𝒳* → ℬ
 /// 
@@ -14,73 +25,451 @@ use crate::pow::get_next_work_required;
 /// 3. Calculate merkle root
 /// 4. Create block header with appropriate difficulty
 /// 5. Return new block
+/// 
+/// The block is built at network-adjusted time `adjusted_time`. The
+/// transactions are taken to have no witness data, and the default
+/// `AssemblerOptions` (mainnet) apply otherwise; see `assemble_block`.
+#[allow(clippy::too_many_arguments)]
 pub fn create_new_block(
-    _utxo_set: &UtxoSet,
+    utxo_set: &UtxoSet,
     mempool_txs: &[Transaction],
     height: Natural,
     prev_header: &BlockHeader,
     prev_headers: &[BlockHeader],
     coinbase_script: &ByteString,
     coinbase_address: &ByteString,
+    adjusted_time: Natural,
 ) -> Result<Block> {
-    // 1. Create coinbase transaction
-    let coinbase_tx = create_coinbase_transaction(
+    let (block, _, _) = assemble_block(
+        utxo_set,
+        mempool_txs,
+        &[],
         height,
-        get_block_subsidy(height),
+        prev_header,
+        prev_headers,
         coinbase_script,
         coinbase_address,
+        adjusted_time,
+        &AssemblerOptions::default(),
     )?;
-    
-    // 2. Select transactions from mempool (simplified: take all for now)
-    let mut selected_txs = Vec::new();
-    for tx in mempool_txs {
-        if check_transaction(tx)? == ValidationResult::Valid {
-            selected_txs.push(tx.clone());
+    Ok(block)
+}
+
+/// Weight kept free by default for the header, transaction count and coinbase
+pub const DEFAULT_COINBASE_RESERVED_WEIGHT: Natural = 4000;
+
+/// Sigop cost kept free by default for the coinbase
+pub const DEFAULT_COINBASE_RESERVED_SIGOPS: Natural = 400;
+
+/// Longest coinbase scriptSig consensus accepts; the shortest is two bytes
+pub const MAX_COINBASE_SCRIPT_SIG_SIZE: usize = 100;
+
+/// Block assembly limits and chain
+/// 
+/// The block limits are capped at the consensus limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblerOptions {
+    /// Chain the block is built for: decides its version and difficulty
+    pub params: ChainParams,
+    /// Most weight of the whole block
+    pub max_block_weight: Natural,
+    /// Most sigop cost of the whole block
+    pub max_block_sigops_cost: Natural,
+    /// Weight left for the header, transaction count and coinbase
+    pub coinbase_reserved_weight: Natural,
+    /// Sigop cost left for the coinbase
+    pub coinbase_reserved_sigops: Natural,
+    /// How the coinbase splits the subsidy and fees; empty pays it all to
+    /// the coinbase address
+    pub coinbase_payouts: Vec<CoinbasePayout>,
+}
+
+impl Default for AssemblerOptions {
+    fn default() -> Self {
+        AssemblerOptions {
+            params: ChainParams::mainnet(),
+            max_block_weight: MAX_BLOCK_SIZE as Natural,
+            max_block_sigops_cost: MAX_BLOCK_SIGOPS_COST,
+            coinbase_reserved_weight: DEFAULT_COINBASE_RESERVED_WEIGHT,
+            coinbase_reserved_sigops: DEFAULT_COINBASE_RESERVED_SIGOPS,
+            coinbase_payouts: Vec::new(),
         }
     }
+}
+
+/// What `assemble_block` selected, leaving out the coinbase
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AssemblerStats {
+    /// Fees of the selected transactions; those spending coins found
+    /// neither in the UTXO set nor in the block count as paying none
+    pub total_fees: Integer,
+    /// Weight of the selected transactions
+    pub weight: Natural,
+    /// Sigop cost of the selected transactions
+    pub sigops_cost: Natural,
+    /// Number of selected transactions
+    pub transactions: usize,
+    /// Number of packages: groups of selected transactions linked by
+    /// spending one another
+    pub packages: usize,
+    /// Lowest package fee rate, in satoshis per virtual byte
+    pub min_fee_rate: Option<f64>,
+    /// Highest package fee rate, in satoshis per virtual byte
+    pub max_fee_rate: Option<f64>,
+}
+
+/// AssembleBlock: 𝒰𝒮 × 𝒯𝒳* × (𝒲*)* × ℕ × AssemblerOptions → ℬ × (𝒲*)* × AssemblerStats
+/// 
+/// CreateNewBlock with witness data and explicit limits:
+/// 1. Take the transactions in TopologicalOrder, skipping any that fail
+///    CheckTransaction, would push the block's weight or sigop cost past
+///    the limits less the coinbase reservations, or spend a skipped one
+/// 2. Pay the subsidy and the selected fees to the coinbase address, or
+///    split them by `options.coinbase_payouts` (see `split_coinbase_reward`)
+/// 3. Commit to the wtxids in the coinbase when a selected transaction
+///    has witness data (see `add_witness_commitment`)
+/// 4. Time the header max(MedianTimePast(prev_headers) + 1, adjusted_time)
+/// 5. Set the version to ComputeBlockVersion(height) and the bits to
+///    GetNextWorkRequiredAt(height, header time, prev_headers), both under
+///    `options.params`
+/// 
+/// `prev_headers` are the headers ending at `prev_header`, oldest first,
+/// and `adjusted_time` is the network-adjusted time. `mempool_witnesses[i]` holds the per-input witness stacks of
+/// `mempool_txs[i]`; missing entries mean no witness. Sigop costs count
+/// P2SH sigops for inputs found in `utxo_set`. Returns the block with the
+/// witness stacks of its transactions, coinbase first, and what was selected.
+#[allow(clippy::too_many_arguments)]
+pub fn assemble_block(
+    utxo_set: &UtxoSet,
+    mempool_txs: &[Transaction],
+    mempool_witnesses: &[Vec<Witness>],
+    height: Natural,
+    prev_header: &BlockHeader,
+    prev_headers: &[BlockHeader],
+    coinbase_script: &ByteString,
+    coinbase_address: &ByteString,
+    adjusted_time: Natural,
+    options: &AssemblerOptions,
+) -> Result<(Block, Vec<Vec<Witness>>, AssemblerStats)> {
+    // 1. Select transactions from mempool, parents first, within the budgets
+    let Selection { txs: selected_txs, mut witnesses, sizes: selected_sizes, weight, sigops } =
+        select_transactions(utxo_set, mempool_txs, mempool_witnesses, options)?;
+    let stats = assembler_stats(&selected_txs, &selected_sizes, weight, sigops);
+    
+    // 2. Create coinbase transaction claiming the subsidy and the fees
+    let reward = get_block_subsidy(height) + stats.total_fees;
+    let coinbase_tx = if options.coinbase_payouts.is_empty() {
+        create_coinbase_transaction(height, reward, coinbase_script, coinbase_address)?
+    } else {
+        let outputs = split_coinbase_reward(reward, &options.coinbase_payouts)?;
+        create_coinbase_with_outputs(height, coinbase_script, outputs)?
+    };
     
-    // 3. Build transaction list (coinbase first)
+    // 3. Build transaction list (coinbase first), committing to witnesses
     let mut transactions = vec![coinbase_tx];
     transactions.extend(selected_txs);
+    let mut block = Block { header: prev_header.clone(), transactions };
+    if let Some(coinbase_witness) = add_witness_commitment(&mut block, &witnesses)? {
+        witnesses[0] = vec![coinbase_witness];
+    }
+    let transactions = block.transactions;
     
     // 4. Calculate merkle root
     let merkle_root = calculate_merkle_root(&transactions)?;
     
-    // 5. Get next work required
-    let next_work = get_next_work_required(prev_header, prev_headers)?;
+    // 5. Get next work required, which on testnet depends on the header time
+    let timestamp = block_time_range(prev_headers, adjusted_time).1;
+    let next_work = get_next_work_required_at(height, timestamp, prev_headers, &options.params)?;
     
     // 6. Create block header
     let header = BlockHeader {
-        version: 1,
-        prev_block_hash: calculate_block_hash(prev_header),
+        version: compute_block_version(height, &options.params),
+        prev_block_hash: prev_header.block_hash(),
         merkle_root,
-        timestamp: get_current_timestamp(),
+        timestamp,
         bits: next_work,
         nonce: 0, // Will be set during mining
     };
     
-    Ok(Block { header, transactions })
+    Ok((Block { header, transactions }, witnesses, stats))
+}
+
+/// Transactions `select_transactions` took, in block order
+struct Selection {
+    txs: Vec<Transaction>,
+    /// Per-input witness stacks, with an empty entry for the coinbase first
+    witnesses: Vec<Vec<Witness>>,
+    /// Fee and vsize of each transaction
+    sizes: Vec<(Integer, Natural)>,
+    weight: Natural,
+    sigops: Natural,
+}
+
+/// Step 1 of `assemble_block`: the mempool transactions a block takes
+fn select_transactions(
+    utxo_set: &UtxoSet,
+    mempool_txs: &[Transaction],
+    mempool_witnesses: &[Vec<Witness>],
+    options: &AssemblerOptions,
+) -> Result<Selection> {
+    let weight_budget = options.max_block_weight.min(MAX_BLOCK_SIZE as Natural)
+        .saturating_sub(options.coinbase_reserved_weight);
+    let sigops_budget = options.max_block_sigops_cost.min(MAX_BLOCK_SIGOPS_COST)
+        .saturating_sub(options.coinbase_reserved_sigops);
+    let mut selection = Selection { txs: Vec::new(), witnesses: vec![Vec::new()], sizes: Vec::new(), weight: 0, sigops: 0 };
+    let mut skipped = HashSet::new();
+    let mut block_coins: HashMap<OutPoint, Integer> = HashMap::new();
+    for i in topological_order(mempool_txs) {
+        let tx = &mempool_txs[i];
+        let tx_witnesses = mempool_witnesses.get(i).cloned().unwrap_or_default();
+        let tx_weight = transaction_weight(tx, &tx_witnesses);
+        let tx_sigops = get_transaction_sigop_cost(tx, utxo_set);
+        let fits = selection.weight + tx_weight <= weight_budget && selection.sigops + tx_sigops <= sigops_budget;
+        let parent_skipped = tx.inputs.iter().any(|input| skipped.contains(&input.prevout.hash));
+        if !fits || parent_skipped || check_transaction(tx)? != ValidationResult::Valid {
+            skipped.insert(calculate_tx_id(tx));
+            continue;
+        }
+        selection.weight += tx_weight;
+        selection.sigops += tx_sigops;
+        
+        let input_values: Option<Integer> = tx.inputs.iter()
+            .map(|input| utxo_set.get(&input.prevout).map(|utxo| utxo.value)
+                .or_else(|| block_coins.get(&input.prevout).copied()))
+            .sum();
+        let output_value: Integer = tx.outputs.iter().map(|output| output.value).sum();
+        let fee = input_values.map_or(0, |inputs| (inputs - output_value).max(0));
+        let txid = calculate_tx_id(tx);
+        for (index, output) in tx.outputs.iter().enumerate() {
+            block_coins.insert(OutPoint { hash: txid, index: index as Natural }, output.value);
+        }
+        
+        selection.sizes.push((fee, tx_weight.div_ceil(WITNESS_SCALE_FACTOR)));
+        selection.txs.push(tx.clone());
+        selection.witnesses.push(tx_witnesses);
+    }
+    Ok(selection)
+}
+
+/// Summarize the selected transactions, given each one's fee and vsize
+fn assembler_stats(
+    selected: &[Transaction],
+    sizes: &[(Integer, Natural)],
+    weight: Natural,
+    sigops_cost: Natural,
+) -> AssemblerStats {
+    // Union the selected transactions with the in-block parents they spend
+    let index_of: HashMap<Hash, usize> = selected.iter().enumerate()
+        .map(|(i, tx)| (calculate_tx_id(tx), i))
+        .collect();
+    let mut package_of: Vec<usize> = (0..selected.len()).collect();
+    fn find(package_of: &mut [usize], mut i: usize) -> usize {
+        while package_of[i] != i {
+            package_of[i] = package_of[package_of[i]];
+            i = package_of[i];
+        }
+        i
+    }
+    for (i, tx) in selected.iter().enumerate() {
+        for input in &tx.inputs {
+            if let Some(&parent) = index_of.get(&input.prevout.hash) {
+                let (a, b) = (find(&mut package_of, i), find(&mut package_of, parent));
+                package_of[a] = b;
+            }
+        }
+    }
+    
+    let mut packages: HashMap<usize, (Integer, Natural)> = HashMap::new();
+    for (i, &(fee, vsize)) in sizes.iter().enumerate() {
+        let package = packages.entry(find(&mut package_of, i)).or_default();
+        package.0 += fee;
+        package.1 += vsize;
+    }
+    let fee_rates: Vec<f64> = packages.values()
+        .map(|&(fee, vsize)| fee as f64 / vsize as f64)
+        .collect();
+    
+    AssemblerStats {
+        total_fees: sizes.iter().map(|&(fee, _)| fee).sum(),
+        weight,
+        sigops_cost,
+        transactions: selected.len(),
+        packages: packages.len(),
+        min_fee_rate: fee_rates.iter().copied().reduce(f64::min),
+        max_fee_rate: fee_rates.iter().copied().reduce(f64::max),
+    }
+}
+
+/// TopologicalOrder: 𝒯𝒳* → ℕ*
+/// 
+/// Indices of `txs` ordered so every transaction comes after the ones in
+/// `txs` it spends, as a block requires:
+/// 1. A transaction is ready once all its in-list parents are placed
+/// 2. Repeatedly place the ready transaction with the lowest index
+/// 
+/// Lists that are already ordered come back unchanged, and otherwise the
+/// given order (say, by fee rate) is kept as far as the dependencies allow.
+/// Duplicate txids depend on, and are depended on as, the first copy.
+pub fn topological_order(txs: &[Transaction]) -> Vec<usize> {
+    let mut index_of: HashMap<Hash, usize> = HashMap::new();
+    for (i, tx) in txs.iter().enumerate() {
+        index_of.entry(calculate_tx_id(tx)).or_insert(i);
+    }
+    
+    let mut pending_parents = vec![0; txs.len()];
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); txs.len()];
+    for (i, tx) in txs.iter().enumerate() {
+        let parents: HashSet<usize> = tx.inputs.iter()
+            .filter_map(|input| index_of.get(&input.prevout.hash).copied())
+            .filter(|&parent| parent != i)
+            .collect();
+        pending_parents[i] = parents.len();
+        for parent in parents {
+            children[parent].push(i);
+        }
+    }
+    
+    let mut ready: BTreeSet<usize> = (0..txs.len()).filter(|&i| pending_parents[i] == 0).collect();
+    let mut order = Vec::with_capacity(txs.len());
+    while let Some(i) = ready.pop_first() {
+        order.push(i);
+        for &child in &children[i] {
+            pending_parents[child] -= 1;
+            if pending_parents[child] == 0 {
+                ready.insert(child);
+            }
+        }
+    }
+    order
+}
+
+/// Version bits a BIP9 version must have set (and the two above clear)
+pub const VERSIONBITS_TOP_BITS: Integer = 0x20000000;
+
+/// ComputeBlockVersion: ℕ × ChainParams → ℕ
+/// 
+/// Version for a new block at `height`:
+/// 1. Once BIP65 is active: VERSIONBITS_TOP_BITS, signalling no deployment
+/// 2. Otherwise the least version the active buried deployments allow:
+///    3 after BIP66, 2 after BIP34, else 1
+/// 
+/// Every deployment this crate knows is buried, so no bit is ever set.
+pub fn compute_block_version(height: Natural, params: &ChainParams) -> Integer {
+    if params.is_deployment_active(Deployment::Bip65, height) {
+        VERSIONBITS_TOP_BITS
+    } else if params.is_deployment_active(Deployment::Bip66, height) {
+        3
+    } else if params.is_deployment_active(Deployment::Bip34, height) {
+        2
+    } else {
+        1
+    }
+}
+
+/// First bytes of a BIP141 witness commitment, after OP_RETURN and its push
+pub const WITNESS_COMMITMENT_HEADER: [u8; 4] = [0xaa, 0x21, 0xa9, 0xed];
+
+/// WitnessCommitment: 𝒯𝒳* × (𝒲*)* × ℍ → ℍ
+/// 
+/// SHA256d(WitnessRoot ‖ reserved), where WitnessRoot is the merkle root of
+/// the wtxids of txs with the coinbase's taken to be zero, `witnesses[i]`
+/// holds the per-input stacks of txs[i], and reserved is the coinbase's
+/// witness reserved value.
+pub fn witness_commitment(
+    transactions: &[Transaction],
+    witnesses: &[Vec<Witness>],
+    witness_reserved_value: &Hash,
+) -> Result<Hash> {
+    let wtxids: Vec<Hash> = transactions.iter().enumerate()
+        .map(|(i, tx)| match i {
+            0 => [0u8; 32],
+            _ => calculate_wtxid(tx, witnesses.get(i).map(Vec::as_slice).unwrap_or_default()),
+        })
+        .collect();
+    let (witness_root, _) = merkle_root_from_hashes(&wtxids).ok_or_else(|| ConsensusError::BlockValidation(
+        "Cannot compute a witness commitment without transactions".to_string()
+    ))?;
+    
+    let mut preimage = witness_root.to_vec();
+    preimage.extend_from_slice(witness_reserved_value);
+    Ok(double_sha256(&preimage))
+}
+
+/// Coinbase output script carrying a witness commitment:
+/// OP_RETURN ‖ push(36) ‖ WITNESS_COMMITMENT_HEADER ‖ commitment
+pub fn witness_commitment_script(commitment: &Hash) -> ByteString {
+    let mut script = vec![0x6a, 0x24];
+    script.extend_from_slice(&WITNESS_COMMITMENT_HEADER);
+    script.extend_from_slice(commitment);
+    script
+}
+
+/// AddWitnessCommitment: ℬ × (𝒲*)* → ℬ × 𝒲
+/// 
+/// For block b whose transaction i has per-input stacks witnesses[i]:
+/// 1. If no transaction but the coinbase has witness data: leave b alone
+/// 2. Replace any witness commitment output of the coinbase by one for
+///    WitnessCommitment(b.txs, witnesses, 0³²)
+/// 3. Recompute the merkle root, as the coinbase txid changed
+/// 4. Return the coinbase input's witness: the zero reserved value
+pub fn add_witness_commitment(block: &mut Block, witnesses: &[Vec<Witness>]) -> Result<Option<Witness>> {
+    let has_witness = witnesses.iter().skip(1)
+        .any(|stacks| stacks.iter().any(|stack| !stack.is_empty()));
+    if !has_witness {
+        return Ok(None);
+    }
+    
+    let reserved_value = [0u8; 32];
+    let commitment = witness_commitment(&block.transactions, witnesses, &reserved_value)?;
+    
+    let coinbase = block.transactions.first_mut().ok_or_else(|| ConsensusError::BlockValidation(
+        "Block has no coinbase to commit to witnesses".to_string()
+    ))?;
+    coinbase.outputs.retain(|output| !is_witness_commitment_script(&output.script_pubkey));
+    coinbase.outputs.push(TransactionOutput { value: 0, script_pubkey: witness_commitment_script(&commitment) });
+    
+    block.header.merkle_root = calculate_merkle_root(&block.transactions)?;
+    Ok(Some(vec![reserved_value.to_vec()]))
+}
+
+/// Whether `script` is a witness commitment output script
+fn is_witness_commitment_script(script: &ByteString) -> bool {
+    script.len() >= 38 && script[..2] == [0x6a, 0x24] && script[2..6] == WITNESS_COMMITMENT_HEADER
 }
 
 /// MineBlock: ℬ × ℕ → ℬ × {success, failure}
 /// 
-/// Attempt to mine a block by finding a valid nonce:
-/// 1. Try different nonce values
-/// 2. Check if resulting hash meets difficulty target
-/// 3. Return mined block or failure
+/// Attempt to mine a mainnet block; see `mine_block_with_params`.
 pub fn mine_block(
+    block: Block,
+    max_attempts: Natural,
+) -> Result<(Block, MiningResult)> {
+    mine_block_with_params(block, max_attempts, &ChainParams::mainnet())
+}
+
+/// MineBlock: ℬ × ℕ × ChainParams → ℬ × {success, failure}
+/// 
+/// Attempt to mine a block of a chain by finding a valid nonce:
+/// 1. Reject targets no nonce can meet (zero or above the pow limit)
+/// 2. Try different nonce values
+/// 3. Check if resulting hash meets difficulty target
+/// 4. Return mined block or failure
+pub fn mine_block_with_params(
     mut block: Block,
     max_attempts: Natural,
+    params: &ChainParams,
 ) -> Result<(Block, MiningResult)> {
     let target = expand_target(block.header.bits)?;
+    if target.is_zero() || target > params.pow_limit() {
+        return Err(ConsensusError::InvalidProofOfWork(
+            format!("Target {:#x} is outside the pow limit", block.header.bits)
+        ));
+    }
     
     for nonce in 0..max_attempts {
         block.header.nonce = nonce;
         
-        let block_hash = calculate_block_hash(&block.header);
-        let hash_u128 = u128::from_le_bytes(block_hash[..16].try_into().unwrap());
-        
-        if hash_u128 <= target {
+        if check_proof_of_work_with_params(&block.header, params)? {
             return Ok((block, MiningResult::Success));
         }
     }
@@ -95,17 +484,104 @@ pub fn mine_block(
 /// 2. Coinbase transaction template
 /// 3. Selected transactions
 /// 4. Mining parameters
+/// 
+/// The coinbase scriptSig holds an EXTRANONCE_SIZE-byte extranonce push
+/// right after the BIP34 height; rolling it gives a fresh merkle root, and
+/// so a fresh nonce space, once the header nonces are exhausted.
 #[derive(Debug, Clone)]
 pub struct BlockTemplate {
     pub header: BlockHeader,
     pub coinbase_tx: Transaction,
     pub transactions: Vec<Transaction>,
-    pub target: u128,
+    pub target: U256,
     pub height: Natural,
     pub timestamp: Natural,
+    /// Earliest valid header time: the median time past plus one
+    pub min_time: Natural,
+    /// Latest header time peers accept: adjusted time plus MAX_FUTURE_BLOCK_TIME
+    pub max_time: Natural,
+    pub extranonce: u64,
+    /// Merkle branch of the coinbase position, so a coinbase change only
+    /// costs one hash per tree level; call `update_coinbase_branch` after
+    /// changing `transactions`
+    pub coinbase_branch: Vec<Hash>,
+    /// What the assembler selected
+    pub stats: AssemblerStats,
+}
+
+/// Bytes of the extranonce pushed in template coinbases
+pub const EXTRANONCE_SIZE: usize = 8;
+
+impl BlockTemplate {
+    /// Write `extranonce` into the coinbase and update the merkle root
+    /// 
+    /// The header nonce starts over, as the header is a new one.
+    pub fn set_extranonce(&mut self, extranonce: u64) -> Result<()> {
+        let start = self.extranonce_offset();
+        let script_sig = &mut self.coinbase_tx.inputs[0].script_sig;
+        if script_sig.len() < start + EXTRANONCE_SIZE {
+            return Err(ConsensusError::BlockValidation(
+                "Coinbase scriptSig has no extranonce region".to_string()
+            ));
+        }
+        script_sig[start..start + EXTRANONCE_SIZE].copy_from_slice(&extranonce.to_le_bytes());
+        self.extranonce = extranonce;
+        self.update_merkle_root();
+        Ok(())
+    }
+
+    /// Replace the coinbase and update the merkle root from the cached
+    /// branch
+    /// 
+    /// The header nonce starts over. `extranonce` is left as is; the new
+    /// coinbase is expected to keep the extranonce region in place.
+    pub fn set_coinbase(&mut self, coinbase_tx: Transaction) {
+        self.coinbase_tx = coinbase_tx;
+        self.update_merkle_root();
+    }
+
+    /// Recompute `coinbase_branch` from the coinbase and `transactions`, and
+    /// the merkle root with it
+    pub fn update_coinbase_branch(&mut self) {
+        let mut txids = vec![calculate_tx_id(&self.coinbase_tx)];
+        txids.extend(self.transactions.iter().map(calculate_tx_id));
+        self.coinbase_branch = merkle_branch(&txids, 0).unwrap_or_default();
+        self.update_merkle_root();
+    }
+
+    /// MerkleRootFromBranch(txid(coinbase), coinbase_branch, 0), resetting
+    /// the header nonce
+    fn update_merkle_root(&mut self) {
+        let coinbase_txid = calculate_tx_id(&self.coinbase_tx);
+        self.header.merkle_root = merkle_root_from_branch(&coinbase_txid, &self.coinbase_branch, 0);
+        self.header.nonce = 0;
+    }
+
+    /// Move on to the next extranonce, returning it
+    pub fn roll_extranonce(&mut self) -> Result<u64> {
+        let next = self.extranonce.wrapping_add(1);
+        self.set_extranonce(next)?;
+        Ok(next)
+    }
+
+    /// The block this template describes, coinbase first
+    pub fn block(&self) -> Block {
+        let mut transactions = Vec::with_capacity(self.transactions.len() + 1);
+        transactions.push(self.coinbase_tx.clone());
+        transactions.extend(self.transactions.iter().cloned());
+        Block { header: self.header.clone(), transactions }
+    }
+
+    /// Start of the extranonce bytes in the coinbase scriptSig: after the
+    /// height push and the extranonce's own push opcode
+    fn extranonce_offset(&self) -> usize {
+        encode_bip34_height(self.height).len() + 1
+    }
 }
 
-/// Create a block template for mining
+/// Create a block template for mining at network-adjusted time
+/// `adjusted_time`
+#[allow(clippy::too_many_arguments)]
 pub fn create_block_template(
     utxo_set: &UtxoSet,
     mempool_txs: &[Transaction],
@@ -114,28 +590,159 @@ pub fn create_block_template(
     prev_headers: &[BlockHeader],
     coinbase_script: &ByteString,
     coinbase_address: &ByteString,
+    adjusted_time: Natural,
+) -> Result<BlockTemplate> {
+    create_block_template_with_options(
+        utxo_set,
+        mempool_txs,
+        &[],
+        height,
+        prev_header,
+        prev_headers,
+        coinbase_script,
+        coinbase_address,
+        adjusted_time,
+        &AssemblerOptions::default(),
+    )
+}
+
+/// Create a block template with witness data and explicit options, as
+/// `assemble_block` does for blocks
+#[allow(clippy::too_many_arguments)]
+pub fn create_block_template_with_options(
+    utxo_set: &UtxoSet,
+    mempool_txs: &[Transaction],
+    mempool_witnesses: &[Vec<Witness>],
+    height: Natural,
+    prev_header: &BlockHeader,
+    prev_headers: &[BlockHeader],
+    coinbase_script: &ByteString,
+    coinbase_address: &ByteString,
+    adjusted_time: Natural,
+    options: &AssemblerOptions,
 ) -> Result<BlockTemplate> {
-    let block = create_new_block(
+    let (min_time, timestamp) = block_time_range(prev_headers, adjusted_time);
+    let (block, _, stats) = assemble_block(
         utxo_set,
         mempool_txs,
+        mempool_witnesses,
         height,
         prev_header,
         prev_headers,
         coinbase_script,
         coinbase_address,
+        adjusted_time,
+        options,
     )?;
     
     let target = expand_target(block.header.bits)?;
     
-    let header = block.header.clone();
-    Ok(BlockTemplate {
-        header: block.header,
-        coinbase_tx: block.transactions[0].clone(),
+    // Open an extranonce region after the height push
+    let mut coinbase_tx = block.transactions[0].clone();
+    let height_push = encode_bip34_height(height).len();
+    let mut extranonce_push = vec![EXTRANONCE_SIZE as u8];
+    extranonce_push.extend_from_slice(&[0; EXTRANONCE_SIZE]);
+    coinbase_tx.inputs[0].script_sig.splice(height_push..height_push, extranonce_push);
+    check_coinbase_script_sig(&coinbase_tx.inputs[0].script_sig)?;
+    
+    let mut template = BlockTemplate {
+        header: block.header.clone(),
+        coinbase_tx,
         transactions: block.transactions[1..].to_vec(),
         target,
         height,
-        timestamp: header.timestamp,
-    })
+        timestamp: block.header.timestamp,
+        min_time,
+        max_time: (adjusted_time + MAX_FUTURE_BLOCK_TIME).max(timestamp),
+        extranonce: 0,
+        coinbase_branch: Vec::new(),
+        stats,
+    };
+    template.update_coinbase_branch();
+    template.set_extranonce(0)?;
+    Ok(template)
+}
+
+/// How a template compares with the current chain tip and mempool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateUpdate {
+    /// Same tip and nothing new to mine: keep working on the template
+    Unchanged,
+    /// Same tip, and assembly would now also take these mempool
+    /// transactions, in the order it takes them; everything in the
+    /// template is still minable
+    NewTransactions(Vec<Transaction>),
+    /// The tip moved, or a template transaction left the mempool (mined,
+    /// replaced or evicted): build a new template from scratch
+    FullRebuild,
+}
+
+/// TemplateUpdate: BlockTemplate × ℍ × 𝒰𝒮 × 𝒯𝒳* × (𝒲*)* × AssemblerOptions → TemplateUpdate
+/// 
+/// For template t, current tip hash tip and mempool transactions txs:
+/// 1. If t.header.prev_block_hash ≠ tip: FullRebuild
+/// 2. If some transaction of t is not in txs: FullRebuild
+/// 3. If `assemble_block` would select some transaction of txs not in t:
+///    NewTransactions
+/// 4. Otherwise: Unchanged
+/// 
+/// Transactions assembly skips (over the budgets, failing
+/// CheckTransaction, or spending a skipped one) do not count as new, so
+/// they never wake a longpolling mining layer. The UTXO set, witnesses
+/// and options should be those the template was built with.
+pub fn template_update(
+    template: &BlockTemplate,
+    tip: &Hash,
+    utxo_set: &UtxoSet,
+    mempool_txs: &[Transaction],
+    mempool_witnesses: &[Vec<Witness>],
+    options: &AssemblerOptions,
+) -> Result<TemplateUpdate> {
+    // 1. New tip
+    if template.header.prev_block_hash != *tip {
+        return Ok(TemplateUpdate::FullRebuild);
+    }
+    
+    // 2. Transactions gone from the mempool
+    let mempool_txids: HashSet<Hash> = mempool_txs.iter().map(calculate_tx_id).collect();
+    let template_txids: HashSet<Hash> = template.transactions.iter().map(calculate_tx_id).collect();
+    if !template_txids.is_subset(&mempool_txids) {
+        return Ok(TemplateUpdate::FullRebuild);
+    }
+    
+    // 3. New transactions assembly would take
+    let new_txs: Vec<Transaction> = select_transactions(utxo_set, mempool_txs, mempool_witnesses, options)?
+        .txs
+        .into_iter()
+        .filter(|tx| !template_txids.contains(&calculate_tx_id(tx)))
+        .collect();
+    if new_txs.is_empty() {
+        Ok(TemplateUpdate::Unchanged)
+    } else {
+        Ok(TemplateUpdate::NewTransactions(new_txs))
+    }
+}
+
+/// AntiFeeSnipingLockTime: ℕ × ℕ → ℕ
+/// 
+/// Recommended nLockTime for a transaction built against a tip at `height`:
+/// 1. Start from the current height, so the transaction cannot be mined in a
+///    re-mined (sniped) copy of an earlier block
+/// 2. With probability 1/10, back-date by a uniform value in [0, 100) to
+///    keep delayed or privacy-sensitive transactions indistinguishable
+/// 3. Never go below zero
+/// 
+/// `entropy` is supplied by the caller so the function stays deterministic:
+/// `entropy % 10` decides whether to back-date and `(entropy / 10) % 100`
+/// gives the back-dating distance. The lock time is only enforced when at
+/// least one input has a non-final sequence number.
+pub fn anti_fee_sniping_locktime(height: Natural, entropy: u64) -> Natural {
+    if !entropy.is_multiple_of(10) {
+        return height;
+    }
+    
+    let back_date = (entropy / 10) % 100;
+    height.saturating_sub(back_date)
 }
 
 // ============================================================================
@@ -149,205 +756,184 @@ pub enum MiningResult {
     Failure,
 }
 
+/// How much of the block reward a coinbase payout gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayoutShare {
+    /// A fixed number of satoshis
+    Amount(Integer),
+    /// A weight: what the fixed amounts leave is split in proportion to
+    /// the weights
+    Proportion(Natural),
+}
+
+/// A coinbase output and its share of the block reward
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinbasePayout {
+    pub script_pubkey: ByteString,
+    pub share: PayoutShare,
+}
+
+/// SplitCoinbaseReward: ℤ × CoinbasePayout* → 𝒯𝒪*
+/// 
+/// For reward r (subsidy plus fees) and payouts p, one output per payout,
+/// in order:
+/// 1. Fixed amounts must be non-negative and sum to at most r
+/// 2. The remainder is split among the proportional payouts by weight,
+///    rounding down; the rounding dust goes to the first of them
+/// 3. With no proportional payout, the remainder is left unclaimed
+/// 
+/// The outputs never pay more than r in total. Fails on an empty list, a
+/// negative amount, amounts exceeding r, or weights that are all zero.
+pub fn split_coinbase_reward(reward: Integer, payouts: &[CoinbasePayout]) -> Result<Vec<TransactionOutput>> {
+    if payouts.is_empty() {
+        return Err(ConsensusError::EconomicValidation("Coinbase needs at least one payout".to_string()));
+    }
+    
+    // 1. Fixed amounts
+    let mut fixed: Integer = 0;
+    let mut total_weight: u128 = 0;
+    for payout in payouts {
+        match payout.share {
+            PayoutShare::Amount(amount) if amount < 0 => {
+                return Err(ConsensusError::EconomicValidation(
+                    format!("Negative coinbase payout {}", amount)
+                ));
+            }
+            PayoutShare::Amount(amount) => fixed = fixed.saturating_add(amount),
+            PayoutShare::Proportion(weight) => total_weight += weight as u128,
+        }
+    }
+    if fixed > reward {
+        return Err(ConsensusError::EconomicValidation(
+            format!("Coinbase payouts of {} exceed the block reward {}", fixed, reward)
+        ));
+    }
+    let has_proportions = payouts.iter().any(|payout| matches!(payout.share, PayoutShare::Proportion(_)));
+    if has_proportions && total_weight == 0 {
+        return Err(ConsensusError::EconomicValidation("Coinbase payout weights are all zero".to_string()));
+    }
+    
+    // 2. Proportional split of the remainder
+    let remainder = (reward - fixed) as u128;
+    let mut outputs: Vec<TransactionOutput> = payouts.iter()
+        .map(|payout| TransactionOutput {
+            value: match payout.share {
+                PayoutShare::Amount(amount) => amount,
+                PayoutShare::Proportion(weight) => (remainder * weight as u128 / total_weight) as Integer,
+            },
+            script_pubkey: payout.script_pubkey.clone(),
+        })
+        .collect();
+    if let Some(first) = payouts.iter().position(|payout| matches!(payout.share, PayoutShare::Proportion(_))) {
+        let paid: Integer = outputs.iter().map(|output| output.value).sum();
+        outputs[first].value += reward - paid;
+    }
+    
+    Ok(outputs)
+}
+
 /// Create coinbase transaction
+/// 
+/// The scriptSig starts with the BIP34 height push, followed by `script`
+/// and padded with OP_0 to the two-byte consensus minimum. A scriptSig
+/// over MAX_COINBASE_SCRIPT_SIG_SIZE bytes is an error.
 fn create_coinbase_transaction(
-    _height: Natural,
+    height: Natural,
     subsidy: Integer,
     script: &ByteString,
     address: &ByteString,
 ) -> Result<Transaction> {
+    // Create coinbase output
+    let coinbase_output = TransactionOutput {
+        value: subsidy,
+        script_pubkey: address.clone(),
+    };
+    
+    create_coinbase_with_outputs(height, script, vec![coinbase_output])
+}
+
+/// Create coinbase transaction paying `outputs`
+fn create_coinbase_with_outputs(
+    height: Natural,
+    script: &ByteString,
+    outputs: Vec<TransactionOutput>,
+) -> Result<Transaction> {
+    let mut script_sig = encode_bip34_height(height);
+    script_sig.extend_from_slice(script);
+    if script_sig.len() < 2 {
+        script_sig.push(0x00);
+    }
+    check_coinbase_script_sig(&script_sig)?;
+    
     // Create coinbase input
     let coinbase_input = TransactionInput {
         prevout: OutPoint {
             hash: [0u8; 32],
             index: 0xffffffff,
         },
-        script_sig: script.clone(),
+        script_sig,
         sequence: 0xffffffff,
     };
     
-    // Create coinbase output
-    let coinbase_output = TransactionOutput {
-        value: subsidy,
-        script_pubkey: address.clone(),
-    };
-    
     Ok(Transaction {
         version: 1,
         inputs: vec![coinbase_input],
-        outputs: vec![coinbase_output],
+        outputs,
         lock_time: 0,
     })
 }
 
-/// Calculate merkle root using proper Bitcoin Merkle tree construction
-fn calculate_merkle_root(transactions: &[Transaction]) -> Result<Hash> {
-    if transactions.is_empty() {
-        return Err(crate::error::ConsensusError::InvalidProofOfWork(
-            "Cannot calculate merkle root for empty transaction list".to_string()
-        ));
-    }
-    
-    // Calculate transaction hashes
-    let mut hashes = Vec::new();
-    for tx in transactions {
-        hashes.push(calculate_tx_hash(tx));
-    }
-    
-    // Build Merkle tree bottom-up
-    while hashes.len() > 1 {
-        let mut next_level = Vec::new();
-        
-        // Process pairs of hashes
-        for chunk in hashes.chunks(2) {
-            if chunk.len() == 2 {
-                // Hash two hashes together
-                let mut combined = Vec::new();
-                combined.extend_from_slice(&chunk[0]);
-                combined.extend_from_slice(&chunk[1]);
-                next_level.push(sha256_hash(&combined));
-            } else {
-                // Odd number: duplicate the last hash
-                let mut combined = Vec::new();
-                combined.extend_from_slice(&chunk[0]);
-                combined.extend_from_slice(&chunk[0]);
-                next_level.push(sha256_hash(&combined));
-            }
-        }
-        
-        hashes = next_level;
-    }
-    
-    Ok(hashes[0])
-}
-
-/// Calculate transaction hash using proper Bitcoin serialization
-fn calculate_tx_hash(tx: &Transaction) -> Hash {
-    let mut data = Vec::new();
-    
-    // Version (4 bytes, little-endian)
-    data.extend_from_slice(&(tx.version as u32).to_le_bytes());
-    
-    // Input count (varint)
-    data.extend_from_slice(&encode_varint(tx.inputs.len() as u64));
-    
-    // Inputs
-    for input in &tx.inputs {
-        // Previous output hash (32 bytes)
-        data.extend_from_slice(&input.prevout.hash);
-        // Previous output index (4 bytes, little-endian)
-        data.extend_from_slice(&(input.prevout.index as u32).to_le_bytes());
-        // Script length (varint)
-        data.extend_from_slice(&encode_varint(input.script_sig.len() as u64));
-        // Script
-        data.extend_from_slice(&input.script_sig);
-        // Sequence (4 bytes, little-endian)
-        data.extend_from_slice(&(input.sequence as u32).to_le_bytes());
-    }
-    
-    // Output count (varint)
-    data.extend_from_slice(&encode_varint(tx.outputs.len() as u64));
-    
-    // Outputs
-    for output in &tx.outputs {
-        // Value (8 bytes, little-endian)
-        data.extend_from_slice(&(output.value as u64).to_le_bytes());
-        // Script length (varint)
-        data.extend_from_slice(&encode_varint(output.script_pubkey.len() as u64));
-        // Script
-        data.extend_from_slice(&output.script_pubkey);
-    }
-    
-    // Lock time (4 bytes, little-endian)
-    data.extend_from_slice(&(tx.lock_time as u32).to_le_bytes());
-    
-    sha256_hash(&data)
-}
-
-/// Encode a number as a Bitcoin varint
-fn encode_varint(value: u64) -> Vec<u8> {
-    if value < 0xfd {
-        vec![value as u8]
-    } else if value <= 0xffff {
-        let mut result = vec![0xfd];
-        result.extend_from_slice(&(value as u16).to_le_bytes());
-        result
-    } else if value <= 0xffffffff {
-        let mut result = vec![0xfe];
-        result.extend_from_slice(&(value as u32).to_le_bytes());
-        result
-    } else {
-        let mut result = vec![0xff];
-        result.extend_from_slice(&value.to_le_bytes());
-        result
+/// Reject a coinbase scriptSig outside the 2 to MAX_COINBASE_SCRIPT_SIG_SIZE
+/// byte range consensus allows
+fn check_coinbase_script_sig(script_sig: &ByteString) -> Result<()> {
+    if !(2..=MAX_COINBASE_SCRIPT_SIG_SIZE).contains(&script_sig.len()) {
+        return Err(ConsensusError::BlockValidation(format!(
+            "Coinbase scriptSig is {} bytes, outside 2..={}",
+            script_sig.len(),
+            MAX_COINBASE_SCRIPT_SIG_SIZE
+        )));
     }
+    Ok(())
 }
 
-/// Calculate block hash using proper Bitcoin header serialization
-fn calculate_block_hash(header: &BlockHeader) -> Hash {
-    let mut data = Vec::new();
-    
-    // Version (4 bytes, little-endian)
-    data.extend_from_slice(&(header.version as u32).to_le_bytes());
-    
-    // Previous block hash (32 bytes)
-    data.extend_from_slice(&header.prev_block_hash);
-    
-    // Merkle root (32 bytes)
-    data.extend_from_slice(&header.merkle_root);
-    
-    // Timestamp (4 bytes, little-endian)
-    data.extend_from_slice(&(header.timestamp as u32).to_le_bytes());
-    
-    // Bits (4 bytes, little-endian)
-    data.extend_from_slice(&(header.bits as u32).to_le_bytes());
-    
-    // Nonce (4 bytes, little-endian)
-    data.extend_from_slice(&(header.nonce as u32).to_le_bytes());
-    
-    sha256_hash(&data)
-}
-
-/// Simple SHA256 hash function
-fn sha256_hash(data: &[u8]) -> Hash {
-    use sha2::{Sha256, Digest};
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    let result = hasher.finalize();
-    let mut hash = [0u8; 32];
-    hash.copy_from_slice(&result);
-    hash
-}
-
-/// Expand target from compact format (simplified)
-fn expand_target(bits: Natural) -> Result<u128> {
-    let exponent = (bits >> 24) as u8;
-    let mantissa = bits & 0x00ffffff;
-    
-    if exponent <= 3 {
-        let shift = 8 * (3 - exponent);
-        Ok((mantissa >> shift) as u128)
-    } else {
-        let shift = 8 * (exponent - 3);
-        if shift >= 104 { // Allow up to 128-bit values (16 bytes - 3 = 13 bytes * 8 = 104)
-            return Err(crate::error::ConsensusError::InvalidProofOfWork(
-                "Target too large".to_string()
-            ));
+/// BIP34 height push: the script `CScript() << height` that must start the
+/// coinbase scriptSig
+/// 
+/// OP_0 for 0, OP_1 … OP_16 for 1 … 16, otherwise a push of the minimal
+/// little-endian script number.
+pub fn encode_bip34_height(height: Natural) -> ByteString {
+    match height {
+        0 => vec![0x00],
+        1..=16 => vec![0x50 + height as u8],
+        _ => {
+            let mut number = Vec::new();
+            let mut rest = height;
+            while rest > 0 {
+                number.push(rest as u8);
+                rest >>= 8;
+            }
+            // Keep the sign bit clear
+            if number.last().is_some_and(|byte| byte & 0x80 != 0) {
+                number.push(0x00);
+            }
+            let mut push = vec![number.len() as u8];
+            push.extend(number);
+            push
         }
-        Ok(((mantissa << shift) as u128).min(u128::MAX))
     }
 }
 
-/// Get current timestamp (simplified)
-fn get_current_timestamp() -> Natural {
-    // In reality, this would get the actual current time
-    // For testing, return a fixed timestamp
-    1231006505
+/// Earliest valid header time after `prev_headers`, and the header time
+/// to use: `adjusted_time`, unless that is earlier
+fn block_time_range(prev_headers: &[BlockHeader], adjusted_time: Natural) -> (Natural, Natural) {
+    let min_time = median_time_past(prev_headers) + 1;
+    (min_time, adjusted_time.max(min_time))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::transaction::{calculate_tx_id, is_coinbase};
     
     #[test]
     fn test_create_new_block() {
@@ -367,12 +953,269 @@ mod tests {
             &prev_headers,
             &coinbase_script,
             &coinbase_address,
+            prev_header.timestamp + 600,
         ).unwrap();
         
         assert_eq!(block.transactions.len(), 2); // coinbase + 1 mempool tx
         assert!(is_coinbase(&block.transactions[0]));
         assert_eq!(block.header.version, 1);
-        assert_eq!(block.header.timestamp, 1231006505);
+        assert_eq!(block.header.timestamp, 1231006505 + 600);
+        
+        // Never at or before the median time past of the previous headers
+        let block = create_new_block(
+            &utxo_set,
+            &mempool_txs,
+            height,
+            &prev_header,
+            &prev_headers,
+            &coinbase_script,
+            &coinbase_address,
+            1231006505 - 600,
+        ).unwrap();
+        assert_eq!(block.header.timestamp, 1231006505 + 1);
+    }
+    
+    #[test]
+    fn test_create_new_block_commits_to_witnesses() {
+        let prev_header = create_valid_block_header();
+        let legacy = create_valid_transaction();
+        let mut segwit = create_valid_transaction();
+        segwit.inputs[0].prevout.index = 1;
+        segwit.inputs[0].script_sig.clear();
+        let segwit_witness = vec![vec![vec![0x30; 71], vec![0x02; 33]]];
+        
+        let prev_headers = vec![prev_header.clone(), prev_header.clone()];
+        let build = |witnesses: &[Vec<Witness>]| assemble_block(
+            &UtxoSet::new(), &[legacy.clone(), segwit.clone()], witnesses, 100,
+            &prev_header, &prev_headers, &vec![0x51], &vec![0x51], prev_header.timestamp + 600,
+            &AssemblerOptions::default(),
+        ).unwrap();
+        
+        // Without witness data the coinbase is unchanged
+        let (block, witnesses, _) = build(&[]);
+        assert_eq!(block.transactions[0].outputs.len(), 1);
+        assert!(witnesses.iter().all(Vec::is_empty));
+        
+        // With it, the coinbase commits to the wtxid root and the reserved value
+        let (block, witnesses, _) = build(&[vec![], segwit_witness.clone()]);
+        let coinbase = &block.transactions[0];
+        assert_eq!(coinbase.outputs.len(), 2);
+        let wtxids = [[0u8; 32], calculate_tx_id(&legacy), calculate_wtxid(&segwit, &segwit_witness)];
+        let mut preimage = merkle_root_from_hashes(&wtxids).unwrap().0.to_vec();
+        preimage.extend_from_slice(&[0; 32]);
+        assert_eq!(coinbase.outputs[1].script_pubkey, witness_commitment_script(&double_sha256(&preimage)));
+        assert_eq!(&coinbase.outputs[1].script_pubkey[..6], &[0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed]);
+        assert_eq!(witnesses, vec![vec![vec![vec![0; 32]]], vec![], segwit_witness]);
+        assert_eq!(block.header.merkle_root, calculate_merkle_root(&block.transactions).unwrap());
+        
+        // Committing again replaces the commitment instead of adding another
+        let mut recommitted = block.clone();
+        add_witness_commitment(&mut recommitted, &witnesses).unwrap();
+        assert_eq!(recommitted, block);
+    }
+    
+    #[test]
+    fn test_assemble_block_respects_budgets() {
+        let prev_header = create_valid_block_header();
+        let prev_headers = vec![prev_header.clone(), prev_header.clone()];
+        let first = create_valid_transaction();
+        let mut heavy = create_valid_transaction();
+        heavy.inputs[0].prevout.index = 1;
+        heavy.outputs[0].script_pubkey = vec![0x51; 400];
+        let mut sigop_heavy = create_valid_transaction();
+        sigop_heavy.inputs[0].prevout.index = 2;
+        sigop_heavy.outputs[0].script_pubkey = vec![0xac; 30];
+        let heavy_child = Transaction {
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: calculate_tx_id(&heavy), index: 0 },
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }],
+            ..create_valid_transaction()
+        };
+        let mut last = create_valid_transaction();
+        last.inputs[0].prevout.index = 3;
+        let txs = [first.clone(), heavy, sigop_heavy, heavy_child, last.clone()];
+        
+        // Room for the small transactions and 100 sigop cost besides the reservations
+        let small_weight = transaction_weight(&first, &[]);
+        let options = AssemblerOptions {
+            max_block_weight: 10_000 + 2 * small_weight + 100,
+            max_block_sigops_cost: 1000 + 100,
+            coinbase_reserved_weight: 10_000,
+            coinbase_reserved_sigops: 1000,
+            ..AssemblerOptions::default()
+        };
+        let (block, _, stats) = assemble_block(
+            &UtxoSet::new(), &txs, &[], 100, &prev_header, &prev_headers, &vec![0x51], &vec![0x51],
+            prev_header.timestamp + 600, &options,
+        ).unwrap();
+        assert_eq!(stats.weight, 2 * small_weight);
+        assert_eq!(stats.transactions, 2);
+        
+        // The heavy transaction, its child and the sigop-heavy one are left out
+        assert_eq!(block.transactions[1..], [first, last]);
+        
+        // Everything fits with the default limits
+        let (block, _, _) = assemble_block(
+            &UtxoSet::new(), &txs, &[], 100, &prev_header, &prev_headers, &vec![0x51], &vec![0x51],
+            prev_header.timestamp + 600, &AssemblerOptions::default(),
+        ).unwrap();
+        assert_eq!(block.transactions.len(), 6);
+    }
+    
+    #[test]
+    fn test_assembler_stats() {
+        let coin = |value| UTXO { value, script_pubkey: vec![0x51], height: 1, is_coinbase: false };
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.insert(OutPoint { hash: [1; 32], index: 0 }, coin(2000));
+        utxo_set.insert(OutPoint { hash: [1; 32], index: 1 }, coin(1500));
+        
+        // A CPFP pair, an unrelated payment and one spending an unknown coin
+        let parent = create_valid_transaction();
+        let child = Transaction {
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: calculate_tx_id(&parent), index: 0 },
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: 500, script_pubkey: vec![0x51] }],
+            ..create_valid_transaction()
+        };
+        let mut payment = create_valid_transaction();
+        payment.inputs[0].prevout.index = 1;
+        payment.outputs[0].value = 1400;
+        let mut unknown = create_valid_transaction();
+        unknown.inputs[0].prevout.index = 2;
+        let txs = [parent.clone(), child.clone(), payment.clone(), unknown];
+        
+        let mut prev_header = create_valid_block_header();
+        prev_header.bits = 0x1d00ffff;
+        let template = create_block_template(
+            &utxo_set, &txs, 300, &prev_header,
+            &[prev_header.clone(), prev_header.clone()], &vec![0x51], &vec![0x51], prev_header.timestamp + 600,
+        ).unwrap();
+        let stats = &template.stats;
+        
+        let vsize = |tx: &Transaction| transaction_weight(tx, &[]).div_ceil(WITNESS_SCALE_FACTOR) as f64;
+        assert_eq!(stats.total_fees, 1000 + 500 + 100);
+        assert_eq!(stats.transactions, 4);
+        assert_eq!(stats.packages, 3);
+        assert_eq!(stats.weight, txs.iter().map(|tx| transaction_weight(tx, &[])).sum::<Natural>());
+        assert_eq!(stats.sigops_cost, 0);
+        assert_eq!(stats.min_fee_rate, Some(0.0));
+        assert_eq!(stats.max_fee_rate, Some(1500.0 / (vsize(&parent) + vsize(&child))));
+        
+        // Nothing selected, no fee rates
+        let empty = create_block_template(
+            &utxo_set, &[], 300, &prev_header,
+            &[prev_header.clone(), prev_header.clone()], &vec![0x51], &vec![0x51], prev_header.timestamp + 600,
+        ).unwrap();
+        assert_eq!(empty.stats, AssemblerStats::default());
+    }
+    
+    #[test]
+    fn test_roll_extranonce() {
+        let mut prev_header = create_valid_block_header();
+        prev_header.bits = 0x1d00ffff;
+        let mut template = create_block_template(
+            &UtxoSet::new(), &[create_valid_transaction()], 300, &prev_header,
+            &[prev_header.clone(), prev_header.clone()], &vec![0x51], &vec![0x51], prev_header.timestamp + 600,
+        ).unwrap();
+        
+        // Height push, the zero extranonce, then the miner's script
+        let mut expected = vec![0x02, 0x2c, 0x01, 0x08];
+        expected.extend([0; 8]);
+        expected.push(0x51);
+        assert_eq!(template.coinbase_tx.inputs[0].script_sig, expected);
+        assert_eq!(template.header.merkle_root, calculate_merkle_root(&template.block().transactions).unwrap());
+        
+        let old_root = template.header.merkle_root;
+        template.header.nonce = 77;
+        assert_eq!(template.roll_extranonce().unwrap(), 1);
+        assert_eq!(template.coinbase_tx.inputs[0].script_sig[4..12], 1u64.to_le_bytes());
+        assert_eq!(template.header.nonce, 0);
+        assert_ne!(template.header.merkle_root, old_root);
+        assert_eq!(template.header.merkle_root, calculate_merkle_root(&template.block().transactions).unwrap());
+        
+        template.set_extranonce(0).unwrap();
+        assert_eq!(template.header.merkle_root, old_root);
+    }
+    
+    #[test]
+    fn test_coinbase_branch_matches_full_root() {
+        let mut prev_header = create_valid_block_header();
+        prev_header.bits = 0x1d00ffff;
+        for count in 0..6 {
+            let txs: Vec<Transaction> = (0..count).map(|index| {
+                let mut tx = create_valid_transaction();
+                tx.inputs[0].prevout.index = index;
+                tx
+            }).collect();
+            let mut template = create_block_template(
+                &UtxoSet::new(), &txs, 300, &prev_header,
+                &[prev_header.clone(), prev_header.clone()], &vec![0x51], &vec![0x51], prev_header.timestamp + 600,
+            ).unwrap();
+            let full_root = |template: &BlockTemplate| calculate_merkle_root(&template.block().transactions).unwrap();
+            assert_eq!(template.coinbase_branch.len(), (count as usize + 1).next_power_of_two().trailing_zeros() as usize);
+            
+            template.roll_extranonce().unwrap();
+            assert_eq!(template.header.merkle_root, full_root(&template));
+            
+            let mut coinbase = template.coinbase_tx.clone();
+            coinbase.outputs[0].value -= 1;
+            template.header.nonce = 5;
+            template.set_coinbase(coinbase);
+            assert_eq!(template.header.merkle_root, full_root(&template));
+            assert_eq!(template.header.nonce, 0);
+            
+            // A changed transaction list needs a fresh branch
+            template.transactions.reverse();
+            template.update_coinbase_branch();
+            assert_eq!(template.header.merkle_root, full_root(&template));
+        }
+    }
+    
+    #[test]
+    fn test_template_update() {
+        let mut prev_header = create_valid_block_header();
+        prev_header.bits = 0x1d00ffff;
+        let first = create_valid_transaction();
+        let mut second = create_valid_transaction();
+        second.inputs[0].prevout.index = 1;
+        let template = create_block_template(
+            &UtxoSet::new(), std::slice::from_ref(&first), 300, &prev_header,
+            &[prev_header.clone(), prev_header.clone()], &vec![0x51], &vec![0x51], prev_header.timestamp + 600,
+        ).unwrap();
+        let tip = prev_header.block_hash();
+        let update = |tip: &Hash, txs: &[Transaction], options: &AssemblerOptions| {
+            template_update(&template, tip, &UtxoSet::new(), txs, &[], options).unwrap()
+        };
+        let options = AssemblerOptions::default();
+        
+        assert_eq!(update(&tip, std::slice::from_ref(&first), &options), TemplateUpdate::Unchanged);
+        assert_eq!(
+            update(&tip, &[second.clone(), first.clone()], &options),
+            TemplateUpdate::NewTransactions(vec![second.clone()])
+        );
+        
+        // A transaction assembly would skip for the weight budget is not new
+        let mut heavy = create_valid_transaction();
+        heavy.inputs[0].prevout.index = 2;
+        heavy.outputs[0].script_pubkey = vec![0x51; 400];
+        let tight = AssemblerOptions {
+            max_block_weight: options.coinbase_reserved_weight + 2 * transaction_weight(&first, &[]),
+            ..options.clone()
+        };
+        assert_eq!(update(&tip, &[first.clone(), heavy.clone()], &tight), TemplateUpdate::Unchanged);
+        assert_eq!(
+            update(&tip, &[first.clone(), heavy.clone(), second.clone()], &tight),
+            TemplateUpdate::NewTransactions(vec![second.clone()])
+        );
+        
+        // The template's transaction was replaced, or the tip moved
+        assert_eq!(update(&tip, &[second], &options), TemplateUpdate::FullRebuild);
+        assert_eq!(update(&[7; 32], &[first], &options), TemplateUpdate::FullRebuild);
     }
     
     #[test]
@@ -397,8 +1240,8 @@ mod tests {
         let coinbase_script = vec![0x51];
         let coinbase_address = vec![0x51];
         
-        // This will fail due to target expansion, but that's expected for now
-        let result = create_block_template(
+        // Away from a retarget boundary the parent's bits carry over
+        let template = create_block_template(
             &utxo_set,
             &mempool_txs,
             height,
@@ -406,10 +1249,85 @@ mod tests {
             &prev_headers,
             &coinbase_script,
             &coinbase_address,
-        );
+            prev_header.timestamp + 600,
+        ).unwrap();
         
-        // Expected to fail due to target expansion issues
-        assert!(result.is_err());
+        assert_eq!(template.header.bits, prev_header.bits);
+        assert_eq!(template.target, expand_target(prev_header.bits).unwrap());
+    }
+    
+    #[test]
+    fn test_topological_order_diamond() {
+        // a funds b and c, which both fund d
+        let mut a = create_valid_transaction();
+        a.outputs.push(a.outputs[0].clone());
+        let child = |parents: &[(&Transaction, Natural)]| Transaction {
+            inputs: parents.iter().map(|&(parent, index)| TransactionInput {
+                prevout: OutPoint { hash: calculate_tx_id(parent), index },
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }).collect(),
+            ..create_valid_transaction()
+        };
+        let b = child(&[(&a, 0)]);
+        let c = child(&[(&a, 1)]);
+        let d = child(&[(&b, 0), (&c, 0)]);
+        
+        let ordered = vec![a.clone(), b.clone(), c.clone(), d.clone()];
+        assert_eq!(topological_order(&ordered), vec![0, 1, 2, 3]);
+        
+        // Reversed, the siblings keep their relative order
+        let reversed = vec![d.clone(), c.clone(), b.clone(), a.clone()];
+        assert_eq!(topological_order(&reversed), vec![3, 1, 2, 0]);
+        
+        // Assembly places every parent before its children
+        let mut prev_header = create_valid_block_header();
+        prev_header.bits = 0x1d00ffff;
+        let block = create_new_block(
+            &UtxoSet::new(), &reversed, 100, &prev_header,
+            &[prev_header.clone(), prev_header.clone()], &vec![0x51], &vec![0x51], prev_header.timestamp + 600,
+        ).unwrap();
+        let txids: Vec<Hash> = block.transactions[1..].iter().map(calculate_tx_id).collect();
+        assert_eq!(txids, [&a, &c, &b, &d].map(calculate_tx_id));
+    }
+    
+    #[test]
+    fn test_assemble_block_for_network() {
+        let regtest = ChainParams::regtest();
+        let mut prev_header = create_valid_block_header();
+        prev_header.bits = regtest.pow_limit_bits;
+        let prev_headers = vec![prev_header.clone()];
+        let assemble = |height, options: &AssemblerOptions| assemble_block(
+            &UtxoSet::new(), &[], &[], height, &prev_header, &prev_headers, &vec![0x51], &vec![0x51],
+            prev_header.timestamp + 600, options,
+        ).unwrap().0;
+        
+        // Regtest: version bits from height 1, no retargeting, instant mining
+        let options = AssemblerOptions { params: regtest.clone(), ..AssemblerOptions::default() };
+        let block = assemble(2016, &options);
+        assert_eq!(block.header.version, VERSIONBITS_TOP_BITS);
+        assert_eq!(block.header.bits, regtest.pow_limit_bits);
+        let (_, result) = mine_block_with_params(block, 1000, &regtest).unwrap();
+        assert_eq!(result, MiningResult::Success);
+        
+        // Testnet: minimum difficulty once the chain stalls for 20 minutes
+        let mut testnet_header = create_valid_block_header();
+        testnet_header.bits = 0x1c00ffff;
+        let testnet_headers = vec![testnet_header.clone()];
+        let testnet = AssemblerOptions { params: ChainParams::testnet(), ..AssemblerOptions::default() };
+        let block_at = |adjusted_time| assemble_block(
+            &UtxoSet::new(), &[], &[], 100, &testnet_header, &testnet_headers, &vec![0x51], &vec![0x51],
+            adjusted_time, &testnet,
+        ).unwrap().0;
+        assert_eq!(block_at(testnet_header.timestamp + 600).header.bits, 0x1c00ffff);
+        assert_eq!(block_at(testnet_header.timestamp + 1201).header.bits, 0x1d00ffff);
+        
+        // Mainnet versions follow the buried deployments
+        let mainnet = ChainParams::mainnet();
+        assert_eq!(compute_block_version(100, &mainnet), 1);
+        assert_eq!(compute_block_version(mainnet.bip34_height, &mainnet), 2);
+        assert_eq!(compute_block_version(mainnet.bip66_height, &mainnet), 3);
+        assert_eq!(compute_block_version(mainnet.bip65_height, &mainnet), VERSIONBITS_TOP_BITS);
     }
     
     #[test]
@@ -472,13 +1390,14 @@ mod tests {
             &prev_headers,
             &coinbase_script,
             &coinbase_address,
+            prev_header.timestamp + 600,
         );
         
         // If get_next_work_required returns a target that's too large, this will fail
         // That's ok for testing the error path
         if let Ok(template) = result {
             assert_eq!(template.height, height);
-            assert!(template.target > 0);
+            assert!(!template.target.is_zero());
             assert!(is_coinbase(&template.coinbase_tx));
             assert_eq!(template.transactions.len(), 1);
         } else {
@@ -525,112 +1444,155 @@ mod tests {
         assert_eq!(coinbase_tx.outputs.len(), 1);
         assert_eq!(coinbase_tx.outputs[0].value, subsidy);
         assert_eq!(coinbase_tx.outputs[0].script_pubkey, address);
-        assert_eq!(coinbase_tx.inputs[0].script_sig, script);
+        assert_eq!(coinbase_tx.inputs[0].script_sig, vec![0x01, 100, 0x51, 0x52]);
         assert_eq!(coinbase_tx.inputs[0].prevout.hash, [0u8; 32]);
         assert_eq!(coinbase_tx.inputs[0].prevout.index, 0xffffffff);
     }
     
     #[test]
-    fn test_calculate_tx_hash() {
-        let tx = create_valid_transaction();
-        let hash = calculate_tx_hash(&tx);
+    fn test_split_coinbase_reward() {
+        let payout = |script: u8, share| CoinbasePayout { script_pubkey: vec![script], share };
+        let values = |outputs: Vec<TransactionOutput>| outputs.iter().map(|output| output.value).collect::<Vec<_>>();
         
-        // Should be a 32-byte hash
-        assert_eq!(hash.len(), 32);
-        
-        // Same transaction should produce same hash
-        let hash2 = calculate_tx_hash(&tx);
-        assert_eq!(hash, hash2);
-    }
-    
-    #[test]
-    fn test_calculate_tx_hash_different_txs() {
-        let tx1 = create_valid_transaction();
-        let mut tx2 = tx1.clone();
-        tx2.version = 2; // Different version
+        // A donation, then a 2:1 pool split of the rest with the dust to the first
+        let payouts = [
+            payout(1, PayoutShare::Amount(1000)),
+            payout(2, PayoutShare::Proportion(2)),
+            payout(3, PayoutShare::Proportion(1)),
+        ];
+        let outputs = split_coinbase_reward(10_001, &payouts).unwrap();
+        assert_eq!(values(outputs.clone()), vec![1000, 6001, 3000]);
+        assert_eq!(outputs[2].script_pubkey, vec![3]);
         
-        let hash1 = calculate_tx_hash(&tx1);
-        let hash2 = calculate_tx_hash(&tx2);
+        // Fixed amounts alone may leave part of the reward unclaimed
+        assert_eq!(values(split_coinbase_reward(10_001, &payouts[..1]).unwrap()), vec![1000]);
         
-        // Different transactions should produce different hashes
-        assert_ne!(hash1, hash2);
-    }
-    
-    #[test]
-    fn test_encode_varint_small() {
-        let encoded = encode_varint(0x42);
-        assert_eq!(encoded, vec![0x42]);
+        assert!(split_coinbase_reward(999, &payouts).is_err());
+        assert!(split_coinbase_reward(10_001, &[]).is_err());
+        assert!(split_coinbase_reward(10_001, &[payout(1, PayoutShare::Amount(-1))]).is_err());
+        assert!(split_coinbase_reward(10_001, &[payout(1, PayoutShare::Proportion(0))]).is_err());
     }
     
     #[test]
-    fn test_encode_varint_medium() {
-        let encoded = encode_varint(0x1234);
-        assert_eq!(encoded.len(), 3);
-        assert_eq!(encoded[0], 0xfd);
+    fn test_assemble_block_pays_fees_to_payouts() {
+        let mut utxo_set = UtxoSet::new();
+        utxo_set.insert(OutPoint { hash: [1; 32], index: 0 }, UTXO {
+            value: 1500,
+            script_pubkey: vec![0x51],
+            height: 1,
+            is_coinbase: false,
+        });
+        let prev_header = create_valid_block_header();
+        let options = AssemblerOptions {
+            coinbase_payouts: vec![
+                CoinbasePayout { script_pubkey: vec![0x52], share: PayoutShare::Amount(100) },
+                CoinbasePayout { script_pubkey: vec![0x53], share: PayoutShare::Proportion(1) },
+            ],
+            ..AssemblerOptions::default()
+        };
+        let (block, _, stats) = assemble_block(
+            &utxo_set, &[create_valid_transaction()], &[], 100, &prev_header,
+            &[prev_header.clone(), prev_header.clone()], &vec![0x51], &vec![0x51], prev_header.timestamp + 600,
+            &options,
+        ).unwrap();
+        
+        let coinbase = &block.transactions[0];
+        assert_eq!(stats.total_fees, 500);
+        assert_eq!(coinbase.outputs.len(), 2);
+        assert_eq!(coinbase.outputs[0].value, 100);
+        assert_eq!(coinbase.outputs[1].value, get_block_subsidy(100) + 500 - 100);
+        assert_eq!(coinbase.outputs[1].script_pubkey, vec![0x53]);
     }
     
     #[test]
-    fn test_encode_varint_large() {
-        let encoded = encode_varint(0x12345678);
-        assert_eq!(encoded.len(), 5);
-        assert_eq!(encoded[0], 0xfe);
+    fn test_encode_bip34_height() {
+        assert_eq!(encode_bip34_height(0), vec![0x00]);
+        assert_eq!(encode_bip34_height(16), vec![0x60]);
+        assert_eq!(encode_bip34_height(17), vec![0x01, 17]);
+        assert_eq!(encode_bip34_height(128), vec![0x02, 0x80, 0x00]);
+        // First BIP34 block on mainnet
+        assert_eq!(encode_bip34_height(227_931), vec![0x03, 0x5b, 0x7a, 0x03]);
+        
+        // Padded to two bytes when nothing follows the height
+        let coinbase = create_coinbase_transaction(1, 0, &vec![], &vec![0x51]).unwrap();
+        assert_eq!(coinbase.inputs[0].script_sig, vec![0x51, 0x00]);
     }
     
     #[test]
-    fn test_encode_varint_huge() {
-        let encoded = encode_varint(0x123456789abcdef0);
-        assert_eq!(encoded.len(), 9);
-        assert_eq!(encoded[0], 0xff);
+    fn test_coinbase_script_sig_size_limit() {
+        let mut prev_header = create_valid_block_header();
+        prev_header.bits = 0x1d00ffff;
+        let prev_headers = [prev_header.clone(), prev_header.clone()];
+        
+        // Height 300 pushes 3 bytes, so 97 more fill the scriptSig exactly
+        let coinbase = create_coinbase_transaction(300, 0, &vec![0x51; 97], &vec![0x51]).unwrap();
+        assert_eq!(coinbase.inputs[0].script_sig.len(), MAX_COINBASE_SCRIPT_SIG_SIZE);
+        assert!(create_coinbase_transaction(300, 0, &vec![0x51; 98], &vec![0x51]).is_err());
+        assert!(create_new_block(
+            &UtxoSet::new(), &[], 300, &prev_header, &prev_headers,
+            &vec![0x51; 98], &vec![0x51], 1231006505 + 600,
+        ).is_err());
+        
+        // Templates also hold the 9-byte extranonce push
+        assert!(create_block_template(
+            &UtxoSet::new(), &[], 300, &prev_header, &prev_headers, &vec![0x51; 88], &vec![0x51],
+            prev_header.timestamp + 600,
+        ).is_ok());
+        assert!(create_block_template(
+            &UtxoSet::new(), &[], 300, &prev_header, &prev_headers, &vec![0x51; 89], &vec![0x51],
+            prev_header.timestamp + 600,
+        ).is_err());
     }
     
     #[test]
-    fn test_calculate_block_hash() {
-        let header = create_valid_block_header();
-        let hash = calculate_block_hash(&header);
+    fn test_calculate_tx_id() {
+        let tx = create_valid_transaction();
+        let hash = calculate_tx_id(&tx);
         
         // Should be a 32-byte hash
         assert_eq!(hash.len(), 32);
         
-        // Same header should produce same hash
-        let hash2 = calculate_block_hash(&header);
+        // Same transaction should produce same hash
+        let hash2 = calculate_tx_id(&tx);
         assert_eq!(hash, hash2);
     }
     
     #[test]
-    fn test_calculate_block_hash_different_headers() {
-        let header1 = create_valid_block_header();
-        let mut header2 = header1.clone();
-        header2.version = 2; // Different version
+    fn test_calculate_tx_id_different_txs() {
+        let tx1 = create_valid_transaction();
+        let mut tx2 = tx1.clone();
+        tx2.version = 2; // Different version
         
-        let hash1 = calculate_block_hash(&header1);
-        let hash2 = calculate_block_hash(&header2);
+        let hash1 = calculate_tx_id(&tx1);
+        let hash2 = calculate_tx_id(&tx2);
         
-        // Different headers should produce different hashes
+        // Different transactions should produce different hashes
         assert_ne!(hash1, hash2);
     }
     
     #[test]
-    fn test_sha256_hash() {
-        let data = b"hello world";
-        let hash = sha256_hash(data);
+    fn test_block_hash() {
+        let header = create_valid_block_header();
+        let hash = header.block_hash();
         
         // Should be a 32-byte hash
         assert_eq!(hash.len(), 32);
         
-        // Same data should produce same hash
-        let hash2 = sha256_hash(data);
+        // Same header should produce same hash
+        let hash2 = header.block_hash();
         assert_eq!(hash, hash2);
     }
     
     #[test]
-    fn test_sha256_hash_different_data() {
-        let data1 = b"hello";
-        let data2 = b"world";
+    fn test_block_hash_different_headers() {
+        let header1 = create_valid_block_header();
+        let mut header2 = header1.clone();
+        header2.version = 2; // Different version
         
-        let hash1 = sha256_hash(data1);
-        let hash2 = sha256_hash(data2);
+        let hash1 = header1.block_hash();
+        let hash2 = header2.block_hash();
         
-        // Different data should produce different hashes
+        // Different headers should produce different hashes
         assert_ne!(hash1, hash2);
     }
     
@@ -638,27 +1600,45 @@ mod tests {
     fn test_expand_target_small() {
         let bits = 0x0300ffff; // exponent = 3
         let target = expand_target(bits).unwrap();
-        assert!(target > 0);
+        assert!(!target.is_zero());
     }
     
     #[test]
     fn test_expand_target_medium() {
         let bits = 0x0600ffff; // exponent = 6 (safe value)
         let target = expand_target(bits).unwrap();
-        assert!(target > 0);
+        assert!(!target.is_zero());
     }
     
     #[test]
     fn test_expand_target_too_large() {
-        let bits = 0x2000ffff; // exponent = 32, would cause shift >= 104
+        let bits = 0x2300ffff; // exponent = 35, beyond 256 bits
         let result = expand_target(bits);
         assert!(result.is_err());
     }
     
     #[test]
-    fn test_get_current_timestamp() {
-        let timestamp = get_current_timestamp();
-        assert_eq!(timestamp, 1231006505);
+    fn test_template_time_range() {
+        let mut prev_header = create_valid_block_header();
+        prev_header.bits = 0x1d00ffff;
+        let prev_headers: Vec<BlockHeader> = (0..11)
+            .map(|i| BlockHeader { timestamp: 1_000_000 + i * 600, ..prev_header.clone() })
+            .collect();
+        let template_at = |adjusted_time| create_block_template_with_options(
+            &UtxoSet::new(), &[], &[], 300, &prev_header, &prev_headers, &vec![0x51], &vec![0x51],
+            adjusted_time, &AssemblerOptions::default(),
+        ).unwrap();
+        
+        // The median of the last eleven is the sixth, at 1_003_000
+        let template = template_at(2_000_000);
+        assert_eq!((template.min_time, template.timestamp), (1_003_001, 2_000_000));
+        assert_eq!(template.header.timestamp, 2_000_000);
+        assert_eq!(template.max_time, 2_000_000 + MAX_FUTURE_BLOCK_TIME);
+        
+        // A clock behind the median time past is overruled
+        let template = template_at(900_000);
+        assert_eq!(template.timestamp, 1_003_001);
+        assert!(template.max_time >= template.timestamp);
     }
     
     #[test]
@@ -719,6 +1699,7 @@ mod tests {
             &prev_headers,
             &coinbase_script,
             &coinbase_address,
+            prev_header.timestamp + 600,
         );
         
         // If get_next_work_required returns a target that's too large, this will fail
@@ -726,7 +1707,7 @@ mod tests {
         if let Ok(template) = result {
             // Test all fields
             assert_eq!(template.height, height);
-            assert!(template.target > 0);
+            assert!(!template.target.is_zero());
             assert!(template.timestamp > 0);
             assert!(is_coinbase(&template.coinbase_tx));
             assert_eq!(template.transactions.len(), 1);
@@ -882,22 +1863,40 @@ mod tests {
     }
     
     #[test]
-    fn test_calculate_tx_hash_different_transactions() {
+    fn test_calculate_tx_id_different_transactions() {
         let tx1 = create_valid_transaction();
         let mut tx2 = create_valid_transaction();
         tx2.version = 2; // Different version
         
-        let hash1 = calculate_tx_hash(&tx1);
-        let hash2 = calculate_tx_hash(&tx2);
+        let hash1 = calculate_tx_id(&tx1);
+        let hash2 = calculate_tx_id(&tx2);
         
         assert_ne!(hash1, hash2);
     }
     
     #[test]
-    fn test_sha256_hash_empty_data() {
-        let data = vec![];
-        let hash = sha256_hash(&data);
-        
-        assert_eq!(hash.len(), 32);
+    fn test_anti_fee_sniping_locktime_current_height() {
+        // Entropy not divisible by 10 never back-dates
+        for entropy in [1, 7, 123, 999_999] {
+            assert_eq!(anti_fee_sniping_locktime(800_000, entropy), 800_000);
+        }
+    }
+    
+    #[test]
+    fn test_anti_fee_sniping_locktime_back_dated() {
+        // (entropy / 10) % 100 = 42
+        assert_eq!(anti_fee_sniping_locktime(800_000, 420), 800_000 - 42);
+        // Back-dating distance is always below 100
+        for entropy in (0..10_000).step_by(10) {
+            let lock_time = anti_fee_sniping_locktime(800_000, entropy);
+            assert!(lock_time <= 800_000 && lock_time > 800_000 - 100);
+        }
+    }
+    
+    #[test]
+    fn test_anti_fee_sniping_locktime_low_height() {
+        // Never underflows below zero
+        assert_eq!(anti_fee_sniping_locktime(5, 990), 0);
+        assert_eq!(anti_fee_sniping_locktime(0, 0), 0);
     }
 }
@@ -0,0 +1,191 @@
+//! Stratum V2 Template Distribution Protocol: the NewTemplate and
+//! SetNewPrevHash messages a template provider sends for a `BlockTemplate`
+//!
+//! Only the message fields are built here; framing, encryption and the
+//! binary encoding are left to the pool software.
+
+use crate::types::*;
+use crate::error::{ConsensusError, Result};
+use crate::serialization::encode_varint;
+use super::{encode_bip34_height, BlockTemplate};
+
+/// Most bytes of coinbase scriptSig a NewTemplate may fix before the
+/// pool's own extranonce
+pub const MAX_COINBASE_PREFIX_SIZE: usize = 8;
+
+/// Most entries of a NewTemplate merkle path (SEQ0_255)
+pub const MAX_MERKLE_PATH_LENGTH: usize = 255;
+
+/// Most bytes of serialized coinbase outputs in a NewTemplate (B0_64K)
+pub const MAX_COINBASE_OUTPUTS_SIZE: usize = 0xffff;
+
+/// NewTemplate: a block template, with the coinbase split into what the
+/// pool must keep and what it fills in itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewTemplate {
+    pub template_id: u64,
+    /// Whether the template is for a block on a tip yet to be announced by
+    /// SetNewPrevHash
+    pub future_template: bool,
+    pub version: u32,
+    pub coinbase_tx_version: u32,
+    /// Start of the coinbase scriptSig: the BIP34 height push
+    pub coinbase_prefix: ByteString,
+    pub coinbase_tx_input_sequence: u32,
+    /// Value the pool's own coinbase outputs may pay out in total
+    pub coinbase_tx_value_remaining: u64,
+    pub coinbase_tx_outputs_count: u32,
+    /// Outputs the coinbase must include, serialized back to back
+    pub coinbase_tx_outputs: ByteString,
+    pub coinbase_tx_locktime: u32,
+    /// Merkle branch of the coinbase, from the leaves up
+    pub merkle_path: Vec<Hash>,
+}
+
+/// SetNewPrevHash: the tip and header fields a template's work builds on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetNewPrevHash {
+    pub template_id: u64,
+    pub prev_hash: Hash,
+    pub header_timestamp: u32,
+    pub n_bits: u32,
+    /// Target as a little-endian 256-bit number
+    pub target: [u8; 32],
+}
+
+impl BlockTemplate {
+    /// NewTemplate: BlockTemplate × ℕ × {true, false} → NewTemplate
+    ///
+    /// 1. coinbase_prefix = EncodeBip34Height(height); the template's
+    ///    extranonce and coinbase script are left for the pool to replace
+    /// 2. Zero-value coinbase outputs (the witness commitment) are required
+    ///    outputs; the value of the others is the value remaining
+    /// 3. merkle_path = the template's cached coinbase branch
+    ///
+    /// Fails if a field exceeds its Stratum V2 size limit.
+    pub fn to_new_template(&self, template_id: u64, future_template: bool) -> Result<NewTemplate> {
+        // 1. Coinbase prefix
+        let coinbase_prefix = encode_bip34_height(self.height);
+        if coinbase_prefix.len() > MAX_COINBASE_PREFIX_SIZE {
+            return Err(ConsensusError::Serialization(
+                format!("Coinbase prefix of {} bytes exceeds {}", coinbase_prefix.len(), MAX_COINBASE_PREFIX_SIZE)
+            ));
+        }
+
+        // 2. Required outputs and the value left for the pool
+        let (required, payouts): (Vec<_>, Vec<_>) = self.coinbase_tx.outputs.iter()
+            .partition(|output| output.value == 0);
+        let mut coinbase_tx_outputs = Vec::new();
+        for output in &required {
+            coinbase_tx_outputs.extend_from_slice(&(output.value as u64).to_le_bytes());
+            coinbase_tx_outputs.extend_from_slice(&encode_varint(output.script_pubkey.len() as u64));
+            coinbase_tx_outputs.extend_from_slice(&output.script_pubkey);
+        }
+        if coinbase_tx_outputs.len() > MAX_COINBASE_OUTPUTS_SIZE {
+            return Err(ConsensusError::Serialization(
+                format!("Coinbase outputs of {} bytes exceed {}", coinbase_tx_outputs.len(), MAX_COINBASE_OUTPUTS_SIZE)
+            ));
+        }
+
+        // 3. Coinbase merkle path
+        let merkle_path = self.coinbase_branch.clone();
+        if merkle_path.len() > MAX_MERKLE_PATH_LENGTH {
+            return Err(ConsensusError::Serialization(
+                format!("Merkle path of {} hashes exceeds {}", merkle_path.len(), MAX_MERKLE_PATH_LENGTH)
+            ));
+        }
+
+        let input = &self.coinbase_tx.inputs[0];
+        Ok(NewTemplate {
+            template_id,
+            future_template,
+            version: self.header.version as u32,
+            coinbase_tx_version: self.coinbase_tx.version as u32,
+            coinbase_prefix,
+            coinbase_tx_input_sequence: input.sequence as u32,
+            coinbase_tx_value_remaining: payouts.iter().map(|output| output.value as u64).sum(),
+            coinbase_tx_outputs_count: required.len() as u32,
+            coinbase_tx_outputs,
+            coinbase_tx_locktime: self.coinbase_tx.lock_time as u32,
+            merkle_path,
+        })
+    }
+
+    /// SetNewPrevHash: BlockTemplate × ℕ → SetNewPrevHash
+    ///
+    /// The parent hash, header time and bits of the template, with the
+    /// expanded target.
+    pub fn to_set_new_prev_hash(&self, template_id: u64) -> SetNewPrevHash {
+        SetNewPrevHash {
+            template_id,
+            prev_hash: self.header.prev_block_hash,
+            header_timestamp: self.header.timestamp as u32,
+            n_bits: self.header.bits as u32,
+            target: self.target.to_le_bytes(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::merkle_root_from_branch;
+    use crate::transaction::calculate_tx_id;
+    use crate::mining::create_block_template_with_options;
+    use crate::mining::AssemblerOptions;
+    use crate::segwit::Witness;
+    use crate::serialization::double_sha256;
+
+    #[test]
+    fn test_template_to_stratum_v2() {
+        let prev_header = BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 0,
+        };
+        let tx = |index| Transaction {
+            version: 2,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [1; 32], index },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: 1000, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        };
+        let witnesses: Vec<Vec<Witness>> = vec![vec![vec![vec![0x51]]]; 3];
+        let template = create_block_template_with_options(
+            &UtxoSet::new(), &[tx(0), tx(1), tx(2)], &witnesses, 300, &prev_header,
+            &[prev_header.clone(), prev_header.clone()], &vec![0x51], &vec![0x51], prev_header.timestamp + 600,
+            &AssemblerOptions::default(),
+        ).unwrap();
+
+        let new_template = template.to_new_template(7, false).unwrap();
+        assert_eq!(new_template.template_id, 7);
+        assert_eq!(new_template.coinbase_prefix, vec![0x02, 0x2c, 0x01]);
+        assert_eq!(new_template.coinbase_tx_value_remaining, template.coinbase_tx.outputs[0].value as u64);
+
+        // The witness commitment is the one required output
+        let commitment = &template.coinbase_tx.outputs[1].script_pubkey;
+        assert_eq!(new_template.coinbase_tx_outputs_count, 1);
+        let mut expected_outputs = vec![0; 8];
+        expected_outputs.push(commitment.len() as u8);
+        expected_outputs.extend_from_slice(commitment);
+        assert_eq!(new_template.coinbase_tx_outputs, expected_outputs);
+
+        // Any coinbase folds with the path into the header's merkle root
+        let coinbase_txid = calculate_tx_id(&template.coinbase_tx);
+        assert_eq!(new_template.merkle_path.len(), 2);
+        assert_eq!(merkle_root_from_branch(&coinbase_txid, &new_template.merkle_path, 0), template.header.merkle_root);
+        assert_ne!(merkle_root_from_branch(&double_sha256(b"pool coinbase"), &new_template.merkle_path, 0), template.header.merkle_root);
+
+        let prev_hash = template.to_set_new_prev_hash(7);
+        assert_eq!(prev_hash.prev_hash, prev_header.block_hash());
+        assert_eq!(prev_hash.n_bits, 0x1d00ffff);
+        assert_eq!(prev_hash.header_timestamp as Natural, template.header.timestamp);
+        assert_eq!(prev_hash.target, template.target.to_le_bytes());
+    }
+}
@@ -2,7 +2,41 @@
 
 use crate::types::*;
 use crate::error::Result;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+pub mod wire;
+pub mod compact_blocks;
+pub mod addrv2;
+pub mod bloom;
+pub mod misbehavior;
+pub mod keepalive;
+pub mod inventory;
+pub mod protocol;
+pub mod addrman;
+pub mod reject;
+pub mod handshake;
+pub mod timedata;
+
+use self::compact_blocks::{block_transactions, reconstruct_block, PartialBlock};
+use self::addrv2::AddrV2Entry;
+use self::addrman::{AddrMan, ADDRMAN_GETADDR_MAX_PCT};
+use self::bloom::{merkle_block, BloomFilter, MAX_FILTER_ADD_SIZE};
+use crate::merkle::{verify_merkle_proof, PartialMerkleTree};
+use crate::block_filter::{BlockFilter, BASIC_FILTER_TYPE};
+use self::misbehavior::Misbehavior;
+use self::wire::{MAX_ADDR_TO_SEND, MAX_HEADERS_RESULTS};
+use self::inventory::InventoryRelay;
+pub use self::reject::{RejectCode, Rejection};
+use self::handshake::HandshakeState;
+pub use self::protocol::ServiceFlags;
+use self::protocol::{
+    FEEFILTER_VERSION, MIN_PEER_PROTO_VERSION, PROTOCOL_VERSION, SHORT_IDS_BLOCKS_VERSION, WTXID_RELAY_VERSION,
+};
+use crate::relay::RecentRejects;
+
+/// Inventory type of a transaction announced or requested by wtxid
+pub const MSG_WTX: u32 = 5;
 
 /// NetworkMessage: 𝒯𝒳 × 𝒰𝒮 → {accepted, rejected}
 /// 
@@ -11,9 +45,11 @@ use std::collections::HashMap;
 pub enum NetworkMessage {
     Version(VersionMessage),
     VerAck,
+    GetAddr,
     Addr(AddrMessage),
     Inv(InvMessage),
     GetData(GetDataMessage),
+    NotFound(NotFoundMessage),
     GetHeaders(GetHeadersMessage),
     Headers(HeadersMessage),
     Block(Block),
@@ -22,13 +58,29 @@ pub enum NetworkMessage {
     Pong(PongMessage),
     MemPool,
     FeeFilter(FeeFilterMessage),
+    SendCmpct(SendCmpctMessage),
+    CmpctBlock(CmpctBlockMessage),
+    GetBlockTxn(GetBlockTxnMessage),
+    BlockTxn(BlockTxnMessage),
+    AddrV2(AddrV2Message),
+    SendAddrV2,
+    WtxidRelay,
+    Reject(RejectMessage),
+    FilterLoad(FilterLoadMessage),
+    FilterAdd(FilterAddMessage),
+    FilterClear,
+    MerkleBlock(MerkleBlockMessage),
+    GetCFilters(GetCFiltersMessage),
+    CFilter(CFilterMessage),
+    GetCFHeaders(GetCFHeadersMessage),
+    CFHeaders(CFHeadersMessage),
 }
 
 /// Version message for initial handshake
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VersionMessage {
     pub version: u32,
-    pub services: u64,
+    pub services: ServiceFlags,
     pub timestamp: i64,
     pub addr_recv: NetworkAddress,
     pub addr_from: NetworkAddress,
@@ -44,12 +96,24 @@ pub struct AddrMessage {
     pub addresses: Vec<NetworkAddress>,
 }
 
+/// AddrV2 message containing peer addresses on any network (BIP155)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrV2Message {
+    pub addresses: Vec<AddrV2Entry>,
+}
+
 /// Inventory message listing available objects
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct InvMessage {
     pub inventory: Vec<InventoryVector>,
 }
 
+/// NotFound message listing requested objects that are not available
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotFoundMessage {
+    pub inventory: Vec<InventoryVector>,
+}
+
 /// GetData message requesting specific objects
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GetDataMessage {
@@ -82,16 +146,126 @@ pub struct PongMessage {
     pub nonce: u64,
 }
 
+/// Reject message telling a peer why its message was refused (BIP61)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectMessage {
+    /// Command of the refused message
+    pub message: String,
+    pub code: u8,
+    pub reason: String,
+    /// Hash of the refused block or transaction
+    pub hash: Option<Hash>,
+}
+
 /// FeeFilter message setting minimum fee rate
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FeeFilterMessage {
     pub feerate: u64,
 }
 
+/// SendCmpct message: whether the peer wants new blocks announced as
+/// compact blocks, and which compact block version it speaks (BIP152)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendCmpctMessage {
+    pub announce: bool,
+    pub version: u64,
+}
+
+/// CmpctBlock message: a header with short ids for most transactions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmpctBlockMessage {
+    pub header: BlockHeader,
+    pub nonce: u64,
+    pub short_ids: Vec<u64>,
+    pub prefilled_txs: Vec<PrefilledTransaction>,
+}
+
+/// Transaction sent in full within a compact block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefilledTransaction {
+    /// Position in the block (differentially encoded on the wire)
+    pub index: u64,
+    pub tx: Transaction,
+}
+
+/// GetBlockTxn message requesting transactions of a block by position
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetBlockTxnMessage {
+    pub block_hash: Hash,
+    /// Positions in the block, increasing (differentially encoded on the wire)
+    pub indexes: Vec<u64>,
+}
+
+/// BlockTxn message answering a GetBlockTxn
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTxnMessage {
+    pub block_hash: Hash,
+    pub transactions: Vec<Transaction>,
+}
+
+/// FilterLoad message setting the peer's bloom filter (BIP37)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterLoadMessage {
+    pub filter: Vec<u8>,
+    pub hash_funcs: u32,
+    pub tweak: u32,
+    pub flags: u8,
+}
+
+/// FilterAdd message adding one element to the peer's bloom filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterAddMessage {
+    pub data: Vec<u8>,
+}
+
+/// MerkleBlock message: a header with a proof of the matched transactions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBlockMessage {
+    pub header: BlockHeader,
+    pub tree: PartialMerkleTree,
+}
+
+/// GetCFilters message requesting the filters of a range of blocks (BIP157)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetCFiltersMessage {
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: Hash,
+}
+
+/// CFilter message carrying one block's filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFilterMessage {
+    pub filter_type: u8,
+    pub block_hash: Hash,
+    pub filter: Vec<u8>,
+}
+
+/// GetCFHeaders message requesting the filter headers of a range of blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetCFHeadersMessage {
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: Hash,
+}
+
+/// CFHeaders message: the filter header before a range and the filter
+/// hashes in it, from which the headers of the range follow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFHeadersMessage {
+    pub filter_type: u8,
+    pub stop_hash: Hash,
+    pub previous_filter_header: Hash,
+    pub filter_hashes: Vec<Hash>,
+}
+
 /// Network address structure
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NetworkAddress {
-    pub services: u64,
+    /// Last time the address was seen, in seconds since the epoch; only
+    /// sent in addr messages, not in version
+    pub time: u32,
+    pub services: ServiceFlags,
     pub ip: [u8; 16], // IPv6 address
     pub port: u16,
 }
@@ -103,18 +277,42 @@ pub struct InventoryVector {
     pub hash: Hash,
 }
 
-/// Process incoming network message
+/// Process incoming network message received at `now`
+///
+/// Offenses add to the peer's misbehavior score; the caller should drop
+/// the connection once `peer_state.should_disconnect()` holds.
+///
+/// Handshake messages out of order are protocol violations; see
+/// `HandshakeState::transition`. Other messages are ignored until the
+/// handshake lets them through; see `HandshakeState::processes`. A peer
+/// whose version is refused is disconnected.
 pub fn process_network_message(
     message: &NetworkMessage,
     peer_state: &mut PeerState,
-    chain_state: &ChainState,
+    chain_state: &dyn ChainStateView,
+    now: std::time::SystemTime,
 ) -> Result<NetworkResponse> {
-    match message {
+    let handshake = match peer_state.handshake.transition(message) {
+        Ok(handshake) => handshake,
+        Err(violation) => {
+            peer_state.misbehaving(Misbehavior::ProtocolViolation);
+            return Ok(NetworkResponse::reject(RejectCode::Invalid, violation.to_string()));
+        }
+    };
+    if !peer_state.handshake.processes(message) {
+        return Ok(NetworkResponse::Ok);
+    }
+
+    let response = match message {
         NetworkMessage::Version(version) => {
             process_version_message(version, peer_state)
         }
         NetworkMessage::VerAck => {
-            process_verack_message(peer_state)
+            // Nothing to do beyond the handshake state
+            Ok(NetworkResponse::Ok)
+        }
+        NetworkMessage::GetAddr => {
+            process_getaddr_message(peer_state, chain_state, now)
         }
         NetworkMessage::Addr(addr) => {
             process_addr_message(addr, peer_state)
@@ -125,6 +323,9 @@ pub fn process_network_message(
         NetworkMessage::GetData(getdata) => {
             process_getdata_message(getdata, peer_state, chain_state)
         }
+        NetworkMessage::NotFound(notfound) => {
+            process_notfound_message(notfound, peer_state)
+        }
         NetworkMessage::GetHeaders(getheaders) => {
             process_getheaders_message(getheaders, peer_state, chain_state)
         }
@@ -141,7 +342,7 @@ pub fn process_network_message(
             process_ping_message(ping, peer_state)
         }
         NetworkMessage::Pong(pong) => {
-            process_pong_message(pong, peer_state)
+            process_pong_message(pong, peer_state, now)
         }
         NetworkMessage::MemPool => {
             process_mempool_message(peer_state, chain_state)
@@ -149,7 +350,60 @@ pub fn process_network_message(
         NetworkMessage::FeeFilter(feefilter) => {
             process_feefilter_message(feefilter, peer_state)
         }
+        NetworkMessage::Reject(_) => {
+            // Informational only
+            Ok(NetworkResponse::Ok)
+        }
+        NetworkMessage::SendCmpct(sendcmpct) => {
+            process_sendcmpct_message(sendcmpct, peer_state)
+        }
+        NetworkMessage::CmpctBlock(cmpctblock) => {
+            process_cmpctblock_message(cmpctblock, peer_state, chain_state)
+        }
+        NetworkMessage::GetBlockTxn(getblocktxn) => {
+            process_getblocktxn_message(getblocktxn, chain_state)
+        }
+        NetworkMessage::BlockTxn(blocktxn) => {
+            process_blocktxn_message(blocktxn, peer_state, chain_state)
+        }
+        NetworkMessage::AddrV2(addrv2) => {
+            process_addrv2_message(addrv2, peer_state)
+        }
+        NetworkMessage::SendAddrV2 => {
+            process_sendaddrv2_message(peer_state)
+        }
+        NetworkMessage::WtxidRelay => {
+            process_wtxidrelay_message(peer_state)
+        }
+        NetworkMessage::FilterLoad(filterload) => {
+            process_filterload_message(filterload, peer_state)
+        }
+        NetworkMessage::FilterAdd(filteradd) => {
+            process_filteradd_message(filteradd, peer_state)
+        }
+        NetworkMessage::FilterClear => {
+            peer_state.bloom_filter = None;
+            Ok(NetworkResponse::Ok)
+        }
+        NetworkMessage::MerkleBlock(merkleblock) => {
+            process_merkleblock_message(merkleblock, peer_state)
+        }
+        NetworkMessage::GetCFilters(getcfilters) => {
+            process_getcfilters_message(getcfilters, chain_state)
+        }
+        NetworkMessage::GetCFHeaders(getcfheaders) => {
+            process_getcfheaders_message(getcfheaders, chain_state)
+        }
+        // Filters are served, not fetched
+        NetworkMessage::CFilter(_) | NetworkMessage::CFHeaders(_) => {
+            Ok(NetworkResponse::Ok)
+        }
+    }?;
+
+    if !matches!(response, NetworkResponse::Reject(_)) {
+        peer_state.handshake = handshake;
     }
+    Ok(response)
 }
 
 /// Process version message
@@ -158,12 +412,13 @@ fn process_version_message(
     peer_state: &mut PeerState,
 ) -> Result<NetworkResponse> {
     // Validate version message
-    if version.version < 70001 {
-        return Ok(NetworkResponse::Reject("Version too old".to_string()));
+    if version.version < MIN_PEER_PROTO_VERSION {
+        peer_state.disconnect = true;
+        return Ok(NetworkResponse::reject(RejectCode::Obsolete, "Version too old"));
     }
     
     // Update peer state
-    peer_state.version = version.version;
+    peer_state.version = version.version.min(PROTOCOL_VERSION);
     peer_state.services = version.services;
     peer_state.user_agent = version.user_agent.clone();
     peer_state.start_height = version.start_height;
@@ -172,10 +427,43 @@ fn process_version_message(
     Ok(NetworkResponse::SendMessage(NetworkMessage::VerAck))
 }
 
-/// Process verack message
-fn process_verack_message(peer_state: &mut PeerState) -> Result<NetworkResponse> {
-    peer_state.handshake_complete = true;
-    Ok(NetworkResponse::Ok)
+/// Process getaddr message
+///
+/// 1. Only inbound peers are answered, so an outbound peer cannot
+///    fingerprint us by the addresses we know
+/// 2. Only the first getaddr of a connection is answered
+/// 3. Sample up to `MAX_ADDR_TO_SEND` addresses, at most
+///    `ADDRMAN_GETADDR_MAX_PCT` percent of those known
+/// 4. Send them as addrv2 if the peer asked for it; otherwise as addr,
+///    leaving out the networks addr cannot describe
+fn process_getaddr_message(
+    peer_state: &mut PeerState,
+    chain_state: &dyn ChainStateView,
+    now: std::time::SystemTime,
+) -> Result<NetworkResponse> {
+    // 1. Inbound only
+    if !peer_state.inbound {
+        return Ok(NetworkResponse::Ok);
+    }
+
+    // 2. Once per connection
+    if peer_state.getaddr_answered {
+        return Ok(NetworkResponse::Ok);
+    }
+    peer_state.getaddr_answered = true;
+
+    // 3. Sample
+    let now = now.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as u32);
+    let addresses = chain_state.get_addresses(MAX_ADDR_TO_SEND, ADDRMAN_GETADDR_MAX_PCT, now);
+
+    // 4. Reply
+    let message = if peer_state.wants_addrv2 {
+        NetworkMessage::AddrV2(AddrV2Message { addresses })
+    } else {
+        let addresses: Vec<NetworkAddress> = addresses.iter().filter_map(NetworkAddress::from_addr_v2).collect();
+        NetworkMessage::Addr(AddrMessage { addresses })
+    };
+    Ok(NetworkResponse::SendMessage(message))
 }
 
 /// Process addr message
@@ -185,7 +473,8 @@ fn process_addr_message(
 ) -> Result<NetworkResponse> {
     // Validate address count
     if addr.addresses.len() > 1000 {
-        return Ok(NetworkResponse::Reject("Too many addresses".to_string()));
+        peer_state.misbehaving(Misbehavior::OversizedMessage);
+        return Ok(NetworkResponse::reject(RejectCode::Malformed, "Too many addresses"));
     }
     
     // Store addresses for future use
@@ -194,21 +483,201 @@ fn process_addr_message(
     Ok(NetworkResponse::Ok)
 }
 
+/// Process addrv2 message
+fn process_addrv2_message(
+    addrv2: &AddrV2Message,
+    peer_state: &mut PeerState,
+) -> Result<NetworkResponse> {
+    // Validate address count
+    if addrv2.addresses.len() > 1000 {
+        peer_state.misbehaving(Misbehavior::OversizedMessage);
+        return Ok(NetworkResponse::reject(RejectCode::Malformed, "Too many addresses"));
+    }
+
+    // Addresses with a legacy form join the others
+    for entry in &addrv2.addresses {
+        match NetworkAddress::from_addr_v2(entry) {
+            Some(address) => peer_state.known_addresses.push(address),
+            None => peer_state.known_addresses_v2.push(entry.clone()),
+        }
+    }
+
+    Ok(NetworkResponse::Ok)
+}
+
+/// Process sendaddrv2 message
+fn process_sendaddrv2_message(peer_state: &mut PeerState) -> Result<NetworkResponse> {
+    peer_state.wants_addrv2 = true;
+    Ok(NetworkResponse::Ok)
+}
+
+/// Process wtxidrelay message
+fn process_wtxidrelay_message(peer_state: &mut PeerState) -> Result<NetworkResponse> {
+    // Only from peers that know it (BIP339)
+    if peer_state.version >= WTXID_RELAY_VERSION {
+        peer_state.wtxid_relay = true;
+    }
+    Ok(NetworkResponse::Ok)
+}
+
+/// Process filterload message
+fn process_filterload_message(
+    filterload: &FilterLoadMessage,
+    peer_state: &mut PeerState,
+) -> Result<NetworkResponse> {
+    match BloomFilter::from_filter_load(filterload) {
+        Ok(filter) => {
+            peer_state.bloom_filter = Some(filter);
+            Ok(NetworkResponse::Ok)
+        }
+        Err(e) => {
+            peer_state.misbehaving(Misbehavior::OversizedMessage);
+            Ok(NetworkResponse::reject(RejectCode::Invalid, format!("Invalid filterload: {}", e)))
+        }
+    }
+}
+
+/// Process filteradd message
+fn process_filteradd_message(
+    filteradd: &FilterAddMessage,
+    peer_state: &mut PeerState,
+) -> Result<NetworkResponse> {
+    if filteradd.data.len() > MAX_FILTER_ADD_SIZE {
+        peer_state.misbehaving(Misbehavior::OversizedMessage);
+        return Ok(NetworkResponse::reject(RejectCode::Malformed, "filteradd element too large"));
+    }
+    match peer_state.bloom_filter.as_mut() {
+        Some(filter) => {
+            filter.insert(&filteradd.data);
+            Ok(NetworkResponse::Ok)
+        }
+        None => {
+            peer_state.misbehaving(Misbehavior::ProtocolViolation);
+            Ok(NetworkResponse::reject(RejectCode::Invalid, "filteradd without a filter"))
+        }
+    }
+}
+
+/// Process merkleblock message
+fn process_merkleblock_message(
+    merkleblock: &MerkleBlockMessage,
+    peer_state: &mut PeerState,
+) -> Result<NetworkResponse> {
+    if let Err(e) = verify_merkle_proof(&merkleblock.header, &merkleblock.tree) {
+        peer_state.misbehaving(Misbehavior::InvalidMerkleBlock);
+        return Ok(NetworkResponse::Reject(
+            Rejection::new(RejectCode::Invalid, format!("Invalid merkle block: {}", e)).with_hash(merkleblock.header.block_hash())
+        ));
+    }
+    Ok(NetworkResponse::Ok)
+}
+
+/// Most filters served for one getcfilters
+pub const MAX_GETCFILTERS_SIZE: usize = 1000;
+
+/// Most filter headers served for one getcfheaders
+pub const MAX_GETCFHEADERS_SIZE: usize = 2000;
+
+/// Process getcfilters message
+fn process_getcfilters_message(
+    getcfilters: &GetCFiltersMessage,
+    chain_state: &dyn ChainStateView,
+) -> Result<NetworkResponse> {
+    if getcfilters.filter_type != BASIC_FILTER_TYPE {
+        return Ok(NetworkResponse::reject(RejectCode::Invalid, "Unsupported filter type"));
+    }
+    let Some(entries) = chain_state.filter_range(getcfilters.start_height, &getcfilters.stop_hash, MAX_GETCFILTERS_SIZE) else {
+        return Ok(NetworkResponse::reject(RejectCode::Invalid, "Invalid getcfilters range"));
+    };
+
+    let responses = entries.into_iter()
+        .map(|entry| NetworkMessage::CFilter(CFilterMessage {
+            filter_type: entry.filter.filter_type,
+            block_hash: entry.filter.block_hash,
+            filter: entry.filter.encoded.clone(),
+        }))
+        .collect();
+    Ok(NetworkResponse::SendMessages(responses))
+}
+
+/// Process getcfheaders message
+fn process_getcfheaders_message(
+    getcfheaders: &GetCFHeadersMessage,
+    chain_state: &dyn ChainStateView,
+) -> Result<NetworkResponse> {
+    if getcfheaders.filter_type != BASIC_FILTER_TYPE {
+        return Ok(NetworkResponse::reject(RejectCode::Invalid, "Unsupported filter type"));
+    }
+    let Some(entries) = chain_state.filter_range(getcfheaders.start_height, &getcfheaders.stop_hash, MAX_GETCFHEADERS_SIZE) else {
+        return Ok(NetworkResponse::reject(RejectCode::Invalid, "Invalid getcfheaders range"));
+    };
+
+    // The header before the range: zero before genesis, else the parent's
+    let previous_filter_header = match entries.first() {
+        Some(first) if first.height > 0 => {
+            let parent = chain_state.header_of(&first.filter.block_hash)
+                .and_then(|header| chain_state.filter_entry(&header.prev_block_hash));
+            match parent {
+                Some(parent) => parent.header,
+                None => return Ok(NetworkResponse::reject(RejectCode::Invalid, "Missing filter header")),
+            }
+        }
+        _ => [0; 32],
+    };
+
+    Ok(NetworkResponse::SendMessage(NetworkMessage::CFHeaders(CFHeadersMessage {
+        filter_type: getcfheaders.filter_type,
+        stop_hash: getcfheaders.stop_hash,
+        previous_filter_header,
+        filter_hashes: entries.iter().map(|entry| entry.filter.filter_hash()).collect(),
+    })))
+}
+
+/// Most blocks requested of one peer and not yet received
+pub const MAX_BLOCKS_IN_TRANSIT_PER_PEER: usize = 16;
+
 /// Process inv message
+///
+/// Blocks already in flight, or beyond `MAX_BLOCKS_IN_TRANSIT_PER_PEER`
+/// of them, are not requested; later announcements bring them up again.
 fn process_inv_message(
     inv: &InvMessage,
-    _peer_state: &mut PeerState,
-    chain_state: &ChainState,
+    peer_state: &mut PeerState,
+    chain_state: &dyn ChainStateView,
 ) -> Result<NetworkResponse> {
     // Validate inventory count
     if inv.inventory.len() > 50000 {
-        return Ok(NetworkResponse::Reject("Too many inventory items".to_string()));
+        peer_state.misbehaving(Misbehavior::OversizedMessage);
+        return Ok(NetworkResponse::reject(RejectCode::Malformed, "Too many inventory items"));
     }
     
     // Check which items we need
     let mut needed_items = Vec::new();
     for item in &inv.inventory {
-        if !chain_state.has_object(&item.hash) {
+        // Transactions come by wtxid from peers that negotiated it and by
+        // txid from the rest; the other kind is ignored
+        let by_wtxid = item.inv_type == MSG_WTX;
+        if (by_wtxid || item.inv_type == 1) && by_wtxid != peer_state.wtxid_relay {
+            continue;
+        }
+        peer_state.inventory.known.insert(item.hash);
+        let known = if by_wtxid {
+            chain_state.get_transaction_by_wtxid(&item.hash).is_some()
+        } else {
+            chain_state.has_object(&item.hash)
+        };
+        // Transactions rejected since the tip changed are not fetched again
+        let known = known || (matches!(item.inv_type, 1 | MSG_WTX) && chain_state.is_recently_rejected(&item.hash));
+        if !known {
+            if item.inv_type == 2 { // MSG_BLOCK
+                // Not asked for twice, nor beyond the in-flight limit
+                if peer_state.requested_blocks.contains(&item.hash)
+                    || peer_state.requested_blocks.len() >= MAX_BLOCKS_IN_TRANSIT_PER_PEER
+                {
+                    continue;
+                }
+                peer_state.requested_blocks.insert(item.hash);
+            }
             needed_items.push(item.clone());
         }
     }
@@ -226,44 +695,75 @@ fn process_inv_message(
 /// Process getdata message
 fn process_getdata_message(
     getdata: &GetDataMessage,
-    _peer_state: &mut PeerState,
-    chain_state: &ChainState,
+    peer_state: &mut PeerState,
+    chain_state: &dyn ChainStateView,
 ) -> Result<NetworkResponse> {
     // Validate request count
     if getdata.inventory.len() > 50000 {
-        return Ok(NetworkResponse::Reject("Too many getdata items".to_string()));
+        peer_state.misbehaving(Misbehavior::OversizedMessage);
+        return Ok(NetworkResponse::reject(RejectCode::Malformed, "Too many getdata items"));
     }
     
     // Send requested objects
     let mut responses = Vec::new();
+    let mut not_found = Vec::new();
     for item in &getdata.inventory {
-        if let Some(obj) = chain_state.get_object(&item.hash) {
-            match item.inv_type {
-                1 => { // MSG_TX
-                    if let Some(tx) = obj.as_transaction() {
-                        responses.push(NetworkMessage::Tx(tx.clone()));
-                    }
-                }
-                2 => { // MSG_BLOCK
-                    if let Some(block) = obj.as_block() {
-                        responses.push(NetworkMessage::Block(block.clone()));
-                    }
-                }
-                _ => {
-                    // Unknown inventory type
+        let object = if item.inv_type == MSG_WTX {
+            chain_state.get_transaction_by_wtxid(&item.hash).map(ChainObject::Transaction)
+        } else {
+            chain_state.get_object(&item.hash)
+        };
+        match (item.inv_type, object) {
+            (1 | MSG_WTX, Some(ChainObject::Transaction(tx))) => { // MSG_TX, MSG_WTX
+                responses.push(NetworkMessage::Tx(tx));
+            }
+            (2, Some(ChainObject::Block(block))) => { // MSG_BLOCK
+                responses.push(NetworkMessage::Block(block));
+            }
+            (3, Some(ChainObject::Block(block))) => { // MSG_FILTERED_BLOCK
+                // Only served to peers that loaded a filter
+                if let Some(filter) = peer_state.bloom_filter.as_mut() {
+                    let (merkleblock, matched) = merkle_block(&block, filter)?;
+                    responses.push(NetworkMessage::MerkleBlock(merkleblock));
+                    responses.extend(matched.into_iter().map(|(_, tx)| NetworkMessage::Tx(tx)));
                 }
             }
+            (1 | 2 | 3 | MSG_WTX, _) => {
+                not_found.push(item.clone());
+            }
+            _ => {
+                // Unknown inventory type
+            }
         }
     }
+
+    // Tell the peer what we do not have, so it can ask elsewhere
+    if !not_found.is_empty() {
+        responses.push(NetworkMessage::NotFound(NotFoundMessage { inventory: not_found }));
+    }
     
     Ok(NetworkResponse::SendMessages(responses))
 }
 
+/// Process notfound message
+fn process_notfound_message(
+    notfound: &NotFoundMessage,
+    peer_state: &mut PeerState,
+) -> Result<NetworkResponse> {
+    // Blocks the peer does not have are no longer expected from it
+    for item in &notfound.inventory {
+        if item.inv_type == 2 { // MSG_BLOCK
+            peer_state.requested_blocks.remove(&item.hash);
+        }
+    }
+    Ok(NetworkResponse::Ok)
+}
+
 /// Process getheaders message
 fn process_getheaders_message(
     getheaders: &GetHeadersMessage,
     _peer_state: &mut PeerState,
-    chain_state: &ChainState,
+    chain_state: &dyn ChainStateView,
 ) -> Result<NetworkResponse> {
     // Find headers to send
     let headers = chain_state.get_headers(&getheaders.block_locator_hashes, &getheaders.hash_stop);
@@ -275,18 +775,32 @@ fn process_getheaders_message(
 /// Process headers message
 fn process_headers_message(
     headers: &HeadersMessage,
-    _peer_state: &mut PeerState,
-    chain_state: &ChainState,
+    peer_state: &mut PeerState,
+    chain_state: &dyn ChainStateView,
 ) -> Result<NetworkResponse> {
     // Validate header count
     if headers.headers.len() > 2000 {
-        return Ok(NetworkResponse::Reject("Too many headers".to_string()));
+        peer_state.misbehaving(Misbehavior::OversizedMessage);
+        return Ok(NetworkResponse::reject(RejectCode::Malformed, "Too many headers"));
+    }
+    
+    // Headers must follow one another from one already known
+    if let Some(first) = headers.headers.first() {
+        let connects = chain_state.header_of(&first.prev_block_hash).is_some()
+            && headers.headers.windows(2).all(|pair| pair[1].prev_block_hash == pair[0].block_hash());
+        if !connects {
+            peer_state.misbehaving(Misbehavior::NonConnectingHeaders);
+            return Ok(NetworkResponse::Ok);
+        }
     }
     
     // Process each header
     for header in &headers.headers {
         if let Err(e) = chain_state.process_header(header) {
-            return Ok(NetworkResponse::Reject(format!("Invalid header: {}", e)));
+            peer_state.misbehaving(Misbehavior::InvalidHeader);
+            return Ok(NetworkResponse::Reject(
+                Rejection::new(RejectCode::Invalid, format!("Invalid header: {}", e)).with_hash(header.block_hash())
+            ));
         }
     }
     
@@ -296,12 +810,28 @@ fn process_headers_message(
 /// Process block message
 fn process_block_message(
     block: &Block,
-    _peer_state: &mut PeerState,
-    chain_state: &ChainState,
+    peer_state: &mut PeerState,
+    chain_state: &dyn ChainStateView,
+) -> Result<NetworkResponse> {
+    // Blocks should only arrive when asked for
+    if !peer_state.requested_blocks.remove(&block.header.block_hash()) {
+        peer_state.misbehaving(Misbehavior::UnrequestedBlock);
+    }
+
+    accept_block(block, peer_state, chain_state)
+}
+
+/// Validate a block from a peer, penalizing it if the block is invalid
+fn accept_block(
+    block: &Block,
+    peer_state: &mut PeerState,
+    chain_state: &dyn ChainStateView,
 ) -> Result<NetworkResponse> {
-    // Validate block
     if let Err(e) = chain_state.process_block(block) {
-        return Ok(NetworkResponse::Reject(format!("Invalid block: {}", e)));
+        peer_state.misbehaving(Misbehavior::InvalidBlock);
+        return Ok(NetworkResponse::Reject(
+            Rejection::new(RejectCode::Invalid, format!("Invalid block: {}", e)).with_hash(block.header.block_hash())
+        ));
     }
     
     Ok(NetworkResponse::Ok)
@@ -310,12 +840,24 @@ fn process_block_message(
 /// Process transaction message
 fn process_tx_message(
     tx: &Transaction,
-    _peer_state: &mut PeerState,
-    chain_state: &ChainState,
+    peer_state: &mut PeerState,
+    chain_state: &dyn ChainStateView,
 ) -> Result<NetworkResponse> {
-    // Validate transaction
+    // The peer has the transaction, so it is never announced back
+    let txid = crate::transaction::calculate_tx_id(tx);
+    peer_state.inventory.known.insert(txid);
+
+    // Rejected since the tip changed: no need to validate it again
+    if chain_state.is_recently_rejected(&txid) {
+        return Ok(NetworkResponse::Ok);
+    }
+
+    // Validate transaction, remembering a rejection until the tip changes
     if let Err(e) = chain_state.process_transaction(tx) {
-        return Ok(NetworkResponse::Reject(format!("Invalid transaction: {}", e)));
+        chain_state.record_rejection(txid);
+        return Ok(NetworkResponse::Reject(
+            Rejection::new(RejectCode::Invalid, format!("Invalid transaction: {}", e)).with_hash(txid)
+        ));
     }
     
     Ok(NetworkResponse::Ok)
@@ -336,26 +878,38 @@ fn process_ping_message(
 fn process_pong_message(
     pong: &PongMessage,
     peer_state: &mut PeerState,
+    now: std::time::SystemTime,
 ) -> Result<NetworkResponse> {
-    // Validate pong nonce matches our ping
-    if peer_state.ping_nonce == Some(pong.nonce) {
-        peer_state.ping_nonce = None;
-        peer_state.last_pong = Some(std::time::SystemTime::now());
-    }
-    
+    // Pongs that do not match our ping are ignored
+    peer_state.receive_pong(pong.nonce, now);
     Ok(NetworkResponse::Ok)
 }
 
 /// Process mempool message
 fn process_mempool_message(
-    _peer_state: &mut PeerState,
-    chain_state: &ChainState,
+    peer_state: &mut PeerState,
+    chain_state: &dyn ChainStateView,
 ) -> Result<NetworkResponse> {
-    // Send all mempool transactions
+    // Send the mempool transactions that clear the peer's fee filter and
+    // match its bloom filter; one whose fee rate is unknown clears only
+    // the absence of a filter
     let mempool_txs = chain_state.get_mempool_transactions();
     let mut responses = Vec::new();
     
     for tx in mempool_txs {
+        let txid = crate::transaction::calculate_tx_id(&tx);
+        let clears_filter = match chain_state.mempool_fee_rate(&txid) {
+            Some(fee_rate) => should_relay_tx(fee_rate, peer_state),
+            None => peer_state.min_fee_rate.is_none(),
+        };
+        if !clears_filter {
+            continue;
+        }
+        if let Some(filter) = peer_state.bloom_filter.as_mut() {
+            if !filter.is_relevant_and_update(&tx) {
+                continue;
+            }
+        }
         responses.push(NetworkMessage::Tx(tx));
     }
     
@@ -367,10 +921,113 @@ fn process_feefilter_message(
     feefilter: &FeeFilterMessage,
     peer_state: &mut PeerState,
 ) -> Result<NetworkResponse> {
-    peer_state.min_fee_rate = Some(feefilter.feerate);
+    // Only from peers that know it (BIP133)
+    if peer_state.version >= FEEFILTER_VERSION {
+        peer_state.min_fee_rate = Some(feefilter.feerate);
+    }
+    Ok(NetworkResponse::Ok)
+}
+
+/// ShouldRelayTx: ℕ × PeerState → {true, false}
+///
+/// Whether a transaction paying `tx_feerate` (sat/kvB, the feefilter unit)
+/// clears the peer's fee filter; peers that sent none take everything.
+pub fn should_relay_tx(tx_feerate: u64, peer_state: &PeerState) -> bool {
+    crate::relay::passes_fee_filter(tx_feerate, peer_state.min_fee_rate)
+}
+
+/// Process sendcmpct message
+fn process_sendcmpct_message(
+    sendcmpct: &SendCmpctMessage,
+    peer_state: &mut PeerState,
+) -> Result<NetworkResponse> {
+    // Only from peers that know it (BIP152), and only version 1 (txid
+    // short ids) is supported; others are ignored
+    if peer_state.version >= SHORT_IDS_BLOCKS_VERSION && sendcmpct.version == 1 {
+        peer_state.compact_block_version = Some(sendcmpct.version);
+        peer_state.announce_compact_blocks = sendcmpct.announce;
+    }
     Ok(NetworkResponse::Ok)
 }
 
+/// Process cmpctblock message
+fn process_cmpctblock_message(
+    cmpctblock: &CmpctBlockMessage,
+    peer_state: &mut PeerState,
+    chain_state: &dyn ChainStateView,
+) -> Result<NetworkResponse> {
+    let partial = match reconstruct_block(cmpctblock, &chain_state.get_mempool_transactions()) {
+        Ok(partial) => partial,
+        Err(e) => {
+            peer_state.misbehaving(Misbehavior::InvalidCompactBlock);
+            return Ok(NetworkResponse::Reject(
+                Rejection::new(RejectCode::Invalid, format!("Invalid compact block: {}", e)).with_hash(cmpctblock.header.block_hash())
+            ));
+        }
+    };
+
+    // Ask for the transactions the mempool could not supply
+    if !partial.missing_indexes().is_empty() {
+        let request = partial.request();
+        peer_state.partial_block = Some(partial);
+        return Ok(NetworkResponse::SendMessage(NetworkMessage::GetBlockTxn(request)));
+    }
+
+    // Fall back to the full block if the reconstruction is wrong
+    let header = partial.header.clone();
+    match partial.into_block() {
+        Ok(block) => accept_block(&block, peer_state, chain_state),
+        Err(_) => Ok(request_full_block(&header, peer_state)),
+    }
+}
+
+/// Process getblocktxn message
+fn process_getblocktxn_message(
+    getblocktxn: &GetBlockTxnMessage,
+    chain_state: &dyn ChainStateView,
+) -> Result<NetworkResponse> {
+    let Some(block) = chain_state.get_block(&getblocktxn.block_hash) else {
+        return Ok(NetworkResponse::Ok);
+    };
+    match block_transactions(&block, getblocktxn) {
+        Ok(blocktxn) => Ok(NetworkResponse::SendMessage(NetworkMessage::BlockTxn(blocktxn))),
+        Err(e) => Ok(NetworkResponse::Reject(
+            Rejection::new(RejectCode::Invalid, format!("Invalid getblocktxn: {}", e)).with_hash(getblocktxn.block_hash)
+        )),
+    }
+}
+
+/// Process blocktxn message
+fn process_blocktxn_message(
+    blocktxn: &BlockTxnMessage,
+    peer_state: &mut PeerState,
+    chain_state: &dyn ChainStateView,
+) -> Result<NetworkResponse> {
+    // Only a response to our own request is of use
+    let partial = match peer_state.partial_block.take() {
+        Some(partial) if partial.header.block_hash() == blocktxn.block_hash => partial,
+        other => {
+            peer_state.partial_block = other;
+            return Ok(NetworkResponse::Ok);
+        }
+    };
+
+    let header = partial.header.clone();
+    match partial.fill(blocktxn) {
+        Ok(block) => accept_block(&block, peer_state, chain_state),
+        Err(_) => Ok(request_full_block(&header, peer_state)),
+    }
+}
+
+/// GetData for a whole block
+fn request_full_block(header: &BlockHeader, peer_state: &mut PeerState) -> NetworkResponse {
+    let hash = header.block_hash();
+    peer_state.requested_blocks.insert(hash);
+    NetworkResponse::SendMessage(NetworkMessage::GetData(GetDataMessage {
+        inventory: vec![InventoryVector { inv_type: 2, hash }],
+    }))
+}
+
 // ============================================================================
 // TYPES
 // ============================================================================
@@ -381,35 +1038,87 @@ pub enum NetworkResponse {
     Ok,
     SendMessage(NetworkMessage),
     SendMessages(Vec<NetworkMessage>),
-    Reject(String),
+    Reject(Rejection),
 }
 
 /// Peer connection state
 #[derive(Debug, Clone)]
 pub struct PeerState {
+    /// Protocol version in common with the peer
     pub version: u32,
-    pub services: u64,
+    pub services: ServiceFlags,
     pub user_agent: String,
     pub start_height: i32,
-    pub handshake_complete: bool,
+    /// Progress of the peer's version handshake
+    pub handshake: HandshakeState,
     pub known_addresses: Vec<NetworkAddress>,
+    /// Addresses from addrv2 messages that have no legacy form
+    pub known_addresses_v2: Vec<AddrV2Entry>,
+    /// Whether the peer asked for addrv2 instead of addr (BIP155)
+    pub wants_addrv2: bool,
+    /// Whether transactions are announced and requested by wtxid (BIP339)
+    pub wtxid_relay: bool,
+    /// Bloom filter the peer loaded (BIP37)
+    pub bloom_filter: Option<BloomFilter>,
     pub ping_nonce: Option<u64>,
+    /// When the last ping was sent
+    pub ping_sent: Option<std::time::SystemTime>,
     pub last_pong: Option<std::time::SystemTime>,
     pub min_fee_rate: Option<u64>,
+    /// Compact block version the peer announced with sendcmpct
+    pub compact_block_version: Option<u64>,
+    /// Whether the peer wants new blocks announced as compact blocks
+    pub announce_compact_blocks: bool,
+    /// Compact block from this peer waiting for a blocktxn response
+    pub partial_block: Option<PartialBlock>,
+    /// Blocks asked of the peer and not yet received
+    pub requested_blocks: HashSet<Hash>,
+    /// Accumulated misbehavior penalties
+    pub misbehavior_score: u32,
+    /// Whether the peer is exempt from disconnection for misbehavior
+    pub noban: bool,
+    /// Whether the peer is to be disconnected whatever its score, e.g.
+    /// for an obsolete version
+    pub disconnect: bool,
+    /// Whether the peer connected to us
+    pub inbound: bool,
+    /// Whether the peer's getaddr has been answered
+    pub getaddr_answered: bool,
+    /// Whether refused messages are answered with BIP61 reject messages;
+    /// off by default, as few peers still use them
+    pub send_reject: bool,
+    /// Inventory the peer knows and inventory queued for it
+    pub inventory: InventoryRelay,
 }
 
 impl PeerState {
     pub fn new() -> Self {
         Self {
             version: 0,
-            services: 0,
+            services: ServiceFlags::NONE,
             user_agent: String::new(),
             start_height: 0,
-            handshake_complete: false,
+            handshake: HandshakeState::AwaitingVersion,
             known_addresses: Vec::new(),
+            known_addresses_v2: Vec::new(),
+            wants_addrv2: false,
+            wtxid_relay: false,
+            bloom_filter: None,
             ping_nonce: None,
+            ping_sent: None,
             last_pong: None,
             min_fee_rate: None,
+            compact_block_version: None,
+            announce_compact_blocks: false,
+            partial_block: None,
+            requested_blocks: HashSet::new(),
+            misbehavior_score: 0,
+            noban: false,
+            disconnect: false,
+            inbound: false,
+            getaddr_answered: false,
+            send_reject: false,
+            inventory: InventoryRelay::new(),
         }
     }
 }
@@ -419,8 +1128,126 @@ impl PeerState {
 pub struct ChainState {
     pub blocks: HashMap<Hash, Block>,
     pub transactions: HashMap<Hash, Transaction>,
+    /// Txids of known transactions by wtxid
+    pub wtxids: HashMap<Hash, Hash>,
     pub headers: HashMap<Hash, BlockHeader>,
     pub mempool: Vec<Transaction>,
+    /// Fee rates of mempool transactions in sat/kvB, by txid
+    pub mempool_fee_rates: HashMap<Hash, u64>,
+    /// Hashes of the main chain by height, genesis first
+    pub main_chain: Vec<Hash>,
+    /// Block filters by block hash
+    pub block_filters: HashMap<Hash, FilterEntry>,
+    /// Known peer addresses, if getaddr should be answered
+    pub addrman: Option<AddrMan>,
+    /// Transactions rejected since the tip last changed; cleared by
+    /// `extend_main_chain`
+    pub recent_rejects: RefCell<RecentRejects>,
+}
+
+/// A block's filter with its height and filter header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterEntry {
+    pub height: Natural,
+    pub filter: BlockFilter,
+    pub header: Hash,
+}
+
+/// Chain and mempool access needed to answer peers
+///
+/// `ChainState` is a simple in-memory implementation; a node implements
+/// this over its own block storage and mempool to use it with
+/// `process_network_message`. Methods that accept data take `&self`, so
+/// implementations that store it synchronize internally.
+pub trait ChainStateView {
+    /// Whether the full block is stored
+    fn has_block(&self, hash: &Hash) -> bool;
+
+    fn get_block(&self, hash: &Hash) -> Option<Block>;
+
+    /// A stored transaction by txid
+    fn get_transaction(&self, txid: &Hash) -> Option<Transaction>;
+
+    /// A stored transaction by wtxid
+    ///
+    /// By default the wtxid is looked up as a txid, which finds the
+    /// transactions without witness data.
+    fn get_transaction_by_wtxid(&self, wtxid: &Hash) -> Option<Transaction> {
+        self.get_transaction(wtxid)
+    }
+
+    /// Header of a known block, whether or not the block is stored
+    fn header_of(&self, hash: &Hash) -> Option<BlockHeader>;
+
+    /// Main chain headers answering a getheaders request
+    fn get_headers(&self, locator_hashes: &[Hash], hash_stop: &Hash) -> Vec<BlockHeader>;
+
+    /// Validate and store a header from a peer
+    fn process_header(&self, header: &BlockHeader) -> Result<()>;
+
+    /// Validate and store a block from a peer
+    fn process_block(&self, block: &Block) -> Result<()>;
+
+    /// Validate a transaction from a peer and add it to the mempool
+    fn process_transaction(&self, tx: &Transaction) -> Result<()>;
+
+    fn get_mempool_transactions(&self) -> Vec<Transaction>;
+
+    /// Fee rate of a mempool transaction in sat/kvB; `None` if unknown
+    fn mempool_fee_rate(&self, txid: &Hash) -> Option<u64>;
+
+    /// Whether a transaction was rejected since the tip last changed, by
+    /// txid or wtxid; such transactions are neither requested nor
+    /// validated again
+    fn is_recently_rejected(&self, _hash: &Hash) -> bool {
+        false
+    }
+
+    /// Remember a transaction `process_transaction` rejected until the tip
+    /// changes; nodes that keep no such cache ignore it
+    fn record_rejection(&self, _txid: Hash) {}
+
+    /// Filter of a block; `None` for nodes that do not index filters
+    fn filter_entry(&self, _block_hash: &Hash) -> Option<FilterEntry> {
+        None
+    }
+
+    /// Random sample of known addresses answering a getaddr, as by
+    /// `AddrMan::get_addr`; none for nodes that keep no address manager
+    fn get_addresses(&self, _max_addresses: usize, _max_pct: usize, _now: u32) -> Vec<AddrV2Entry> {
+        Vec::new()
+    }
+
+    fn has_object(&self, hash: &Hash) -> bool {
+        self.has_block(hash) || self.get_transaction(hash).is_some()
+    }
+
+    fn get_object(&self, hash: &Hash) -> Option<ChainObject> {
+        if let Some(block) = self.get_block(hash) {
+            return Some(ChainObject::Block(block));
+        }
+        self.get_transaction(hash).map(ChainObject::Transaction)
+    }
+
+    /// Filters of the blocks from `start_height` up to `stop_hash`, oldest
+    /// first; `None` if the range is empty, longer than `max` or not
+    /// fully indexed
+    fn filter_range(&self, start_height: u32, stop_hash: &Hash, max: usize) -> Option<Vec<FilterEntry>> {
+        let stop = self.filter_entry(stop_hash)?;
+        let start_height = start_height as Natural;
+        if start_height > stop.height || (stop.height - start_height) as usize >= max {
+            return None;
+        }
+
+        let mut hash = *stop_hash;
+        let mut entries = vec![stop];
+        while entries.last()?.height > start_height {
+            hash = self.header_of(&hash)?.prev_block_hash;
+            entries.push(self.filter_entry(&hash)?);
+        }
+        entries.reverse();
+        Some(entries)
+    }
 }
 
 impl ChainState {
@@ -428,48 +1255,139 @@ impl ChainState {
         Self {
             blocks: HashMap::new(),
             transactions: HashMap::new(),
+            wtxids: HashMap::new(),
             headers: HashMap::new(),
             mempool: Vec::new(),
+            mempool_fee_rates: HashMap::new(),
+            main_chain: Vec::new(),
+            block_filters: HashMap::new(),
+            addrman: None,
+            recent_rejects: RefCell::new(RecentRejects::default()),
         }
     }
-    
-    pub fn has_object(&self, hash: &Hash) -> bool {
-        self.blocks.contains_key(hash) || self.transactions.contains_key(hash)
+
+    /// Add `header` as the new tip of the main chain
+    ///
+    /// Forgets the recent rejects, which the new block may have made
+    /// valid.
+    pub fn extend_main_chain(&mut self, header: BlockHeader) {
+        let hash = header.block_hash();
+        self.headers.insert(hash, header);
+        self.main_chain.push(hash);
+        self.recent_rejects.get_mut().clear();
     }
-    
-    pub fn get_object(&self, hash: &Hash) -> Option<ChainObject> {
-        if let Some(block) = self.blocks.get(hash) {
-            return Some(ChainObject::Block(block.clone()));
+
+    /// Height of `hash` if it is on the main chain
+    ///
+    /// Searches from the tip down, where locators and stop hashes usually
+    /// point.
+    pub fn main_chain_height(&self, hash: &Hash) -> Option<usize> {
+        self.main_chain.iter().rposition(|h| h == hash)
+    }
+}
+
+impl ChainStateView for ChainState {
+    fn has_block(&self, hash: &Hash) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    fn get_block(&self, hash: &Hash) -> Option<Block> {
+        self.blocks.get(hash).cloned()
+    }
+
+    fn get_transaction(&self, txid: &Hash) -> Option<Transaction> {
+        self.transactions.get(txid).cloned()
+    }
+
+    /// Looked up through `wtxids`, falling back to the txid
+    fn get_transaction_by_wtxid(&self, wtxid: &Hash) -> Option<Transaction> {
+        let txid = self.wtxids.get(wtxid).unwrap_or(wtxid);
+        self.get_transaction(txid)
+    }
+
+    fn header_of(&self, hash: &Hash) -> Option<BlockHeader> {
+        self.headers.get(hash).or_else(|| self.blocks.get(hash).map(|block| &block.header)).cloned()
+    }
+
+    /// GetHeaders: ℍ* × ℍ → ℋ*
+    ///
+    /// 1. With no locator, send just the header of `hash_stop`, if known
+    /// 2. Fork point: the first locator hash on the main chain, or the
+    ///    genesis block if none is
+    /// 3. Send the main chain headers after the fork point, in order, up
+    ///    to and including `hash_stop` and at most `MAX_HEADERS_RESULTS`
+    fn get_headers(&self, locator_hashes: &[Hash], hash_stop: &Hash) -> Vec<BlockHeader> {
+        // 1. No locator
+        if locator_hashes.is_empty() {
+            return self.header_of(hash_stop).into_iter().collect();
         }
-        if let Some(tx) = self.transactions.get(hash) {
-            return Some(ChainObject::Transaction(tx.clone()));
+
+        // 2. Fork point
+        let fork = locator_hashes.iter()
+            .find_map(|hash| self.main_chain_height(hash))
+            .unwrap_or(0);
+
+        // 3. Headers after it
+        let mut headers = Vec::new();
+        for hash in self.main_chain.iter().skip(fork + 1).take(MAX_HEADERS_RESULTS) {
+            match self.header_of(hash) {
+                Some(header) => headers.push(header),
+                None => break,
+            }
+            if hash == hash_stop {
+                break;
+            }
         }
-        None
+        headers
     }
     
-    pub fn get_headers(&self, _locator_hashes: &[Hash], _hash_stop: &Hash) -> Vec<BlockHeader> {
-        // Simplified: return all headers
-        self.headers.values().cloned().collect()
-    }
-    
-    pub fn process_header(&self, _header: &BlockHeader) -> Result<()> {
+    fn process_header(&self, _header: &BlockHeader) -> Result<()> {
         // Simplified: always accept
         Ok(())
     }
     
-    pub fn process_block(&self, _block: &Block) -> Result<()> {
+    fn process_block(&self, _block: &Block) -> Result<()> {
         // Simplified: always accept
         Ok(())
     }
     
-    pub fn process_transaction(&self, _tx: &Transaction) -> Result<()> {
+    fn process_transaction(&self, _tx: &Transaction) -> Result<()> {
         // Simplified: always accept
         Ok(())
     }
-    
-    pub fn get_mempool_transactions(&self) -> Vec<Transaction> {
+
+    fn get_mempool_transactions(&self) -> Vec<Transaction> {
         self.mempool.clone()
     }
+
+    fn mempool_fee_rate(&self, txid: &Hash) -> Option<u64> {
+        self.mempool_fee_rates.get(txid).copied()
+    }
+
+    fn is_recently_rejected(&self, hash: &Hash) -> bool {
+        self.recent_rejects.borrow().contains(hash)
+    }
+
+    fn record_rejection(&self, txid: Hash) {
+        self.recent_rejects.borrow_mut().insert(txid);
+    }
+
+    fn filter_entry(&self, block_hash: &Hash) -> Option<FilterEntry> {
+        self.block_filters.get(block_hash).cloned()
+    }
+
+    /// Sampled with randomness from the standard library's hash keys
+    fn get_addresses(&self, max_addresses: usize, max_pct: usize, now: u32) -> Vec<AddrV2Entry> {
+        use std::hash::BuildHasher;
+        let Some(addrman) = &self.addrman else { return Vec::new() };
+        let keys = std::collections::hash_map::RandomState::new();
+        let mut counter = 0u64;
+        let mut rng = || {
+            counter += 1;
+            keys.hash_one(counter)
+        };
+        addrman.get_addr(max_addresses, max_pct, now, &mut rng)
+    }
 }
 
 /// Chain object (block or transaction)
@@ -498,21 +1416,32 @@ impl ChainObject {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::relay::{RelayDecision, SkipReason};
+
+    /// A peer at our protocol version that has completed the handshake
+    fn connected_peer() -> PeerState {
+        let mut peer_state = PeerState::new();
+        peer_state.version = PROTOCOL_VERSION;
+        peer_state.handshake = HandshakeState::Complete;
+        peer_state
+    }
     
     #[test]
     fn test_process_version_message() {
         let mut peer_state = PeerState::new();
         let version = VersionMessage {
             version: 70015,
-            services: 1,
+            services: ServiceFlags::NETWORK,
             timestamp: 1234567890,
             addr_recv: NetworkAddress {
-                services: 1,
+                time: 0,
+                services: ServiceFlags::NETWORK,
                 ip: [0; 16],
                 port: 8333,
             },
             addr_from: NetworkAddress {
-                services: 1,
+                time: 0,
+                services: ServiceFlags::NETWORK,
                 ip: [0; 16],
                 port: 8333,
             },
@@ -525,6 +1454,11 @@ mod tests {
         let response = process_version_message(&version, &mut peer_state).unwrap();
         assert!(matches!(response, NetworkResponse::SendMessage(NetworkMessage::VerAck)));
         assert_eq!(peer_state.version, 70015);
+
+        // Features follow the version both sides speak
+        let newer = VersionMessage { version: PROTOCOL_VERSION + 1, ..version };
+        process_version_message(&newer, &mut peer_state).unwrap();
+        assert_eq!(peer_state.version, PROTOCOL_VERSION);
     }
     
     #[test]
@@ -532,15 +1466,17 @@ mod tests {
         let mut peer_state = PeerState::new();
         let version = VersionMessage {
             version: 60000, // Too old
-            services: 1,
+            services: ServiceFlags::NETWORK,
             timestamp: 1234567890,
             addr_recv: NetworkAddress {
-                services: 1,
+                time: 0,
+                services: ServiceFlags::NETWORK,
                 ip: [0; 16],
                 port: 8333,
             },
             addr_from: NetworkAddress {
-                services: 1,
+                time: 0,
+                services: ServiceFlags::NETWORK,
                 ip: [0; 16],
                 port: 8333,
             },
@@ -552,14 +1488,26 @@ mod tests {
         
         let response = process_version_message(&version, &mut peer_state).unwrap();
         assert!(matches!(response, NetworkResponse::Reject(_)));
+        assert!(peer_state.should_disconnect());
+
+        // Even noban peers are not kept waiting for another version
+        let mut peer_state = PeerState::new();
+        peer_state.noban = true;
+        let message = NetworkMessage::Version(version);
+        let response = process_network_message(&message, &mut peer_state, &ChainState::new(), std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Reject(_)));
+        assert_eq!(peer_state.handshake, HandshakeState::AwaitingVersion);
+        assert!(peer_state.should_disconnect());
     }
     
     #[test]
     fn test_process_verack_message() {
         let mut peer_state = PeerState::new();
-        let response = process_verack_message(&mut peer_state).unwrap();
+        let chain_state = ChainState::new();
+        peer_state.handshake = HandshakeState::AwaitingVerack;
+        let response = process_network_message(&NetworkMessage::VerAck, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::Ok));
-        assert!(peer_state.handshake_complete);
+        assert!(peer_state.handshake.is_complete());
     }
     
     #[test]
@@ -577,16 +1525,17 @@ mod tests {
         peer_state.ping_nonce = Some(12345);
         
         let pong = PongMessage { nonce: 12345 };
-        let response = process_pong_message(&pong, &mut peer_state).unwrap();
+        let response = process_pong_message(&pong, &mut peer_state, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::Ok));
         assert!(peer_state.ping_nonce.is_none());
+        assert_eq!(peer_state.last_pong, Some(std::time::UNIX_EPOCH));
     }
     
     #[test]
     fn test_peer_state_new() {
         let peer_state = PeerState::new();
         assert_eq!(peer_state.version, 0);
-        assert!(!peer_state.handshake_complete);
+        assert_eq!(peer_state.handshake, HandshakeState::AwaitingVersion);
         assert!(peer_state.known_addresses.is_empty());
     }
     
@@ -609,15 +1558,17 @@ mod tests {
         let chain_state = ChainState::new();
         let version = VersionMessage {
             version: 70015,
-            services: 1,
+            services: ServiceFlags::NETWORK,
             timestamp: 1234567890,
             addr_recv: NetworkAddress {
-                services: 1,
+                time: 0,
+                services: ServiceFlags::NETWORK,
                 ip: [0; 16],
                 port: 8333,
             },
             addr_from: NetworkAddress {
-                services: 1,
+                time: 0,
+                services: ServiceFlags::NETWORK,
                 ip: [0; 16],
                 port: 8333,
             },
@@ -628,8 +1579,14 @@ mod tests {
         };
         
         let message = NetworkMessage::Version(version);
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::SendMessage(NetworkMessage::VerAck)));
+        assert_eq!(peer_state.handshake, HandshakeState::AwaitingVerack);
+
+        // A second version is a protocol violation
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Reject(_)));
+        assert!(peer_state.should_disconnect());
     }
     
     #[test]
@@ -637,57 +1594,101 @@ mod tests {
         let mut peer_state = PeerState::new();
         let chain_state = ChainState::new();
         let message = NetworkMessage::VerAck;
-        
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+
+        // Not before version
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Reject(_)));
+        assert_eq!(peer_state.handshake, HandshakeState::AwaitingVersion);
+
+        peer_state.handshake = HandshakeState::AwaitingVerack;
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::Ok));
-        assert!(peer_state.handshake_complete);
+        assert!(peer_state.handshake.is_complete());
     }
     
     #[test]
-    fn test_process_network_message_ping() {
+    fn test_messages_ignored_before_handshake() {
+        let mut chain_state = ChainState::new();
+        chain_state.mempool.push(Transaction {
+            version: 1,
+            inputs: vec![],
+            outputs: vec![TransactionOutput { value: 1, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        });
+        let ping = NetworkMessage::Ping(PingMessage { nonce: 1 });
+        let inv = NetworkMessage::Inv(InvMessage {
+            inventory: vec![InventoryVector { inv_type: 2, hash: [1; 32] }],
+        });
+
+        // Before version nothing else is acted on, nor penalized
         let mut peer_state = PeerState::new();
+        for message in [&ping, &inv, &NetworkMessage::MemPool] {
+            let response = process_network_message(message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+            assert!(matches!(response, NetworkResponse::Ok));
+        }
+        assert!(peer_state.requested_blocks.is_empty());
+        assert_eq!(peer_state.misbehavior_score, 0);
+
+        // Before verack only the negotiation messages are
+        peer_state.handshake = HandshakeState::AwaitingVerack;
+        for message in [&ping, &inv, &NetworkMessage::MemPool] {
+            let response = process_network_message(message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+            assert!(matches!(response, NetworkResponse::Ok));
+        }
+        assert!(peer_state.requested_blocks.is_empty());
+        assert_eq!(peer_state.handshake, HandshakeState::AwaitingVerack);
+
+        process_network_message(&NetworkMessage::VerAck, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        let response = process_network_message(&ping, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::SendMessage(NetworkMessage::Pong(_))));
+    }
+
+    #[test]
+    fn test_process_network_message_ping() {
+        let mut peer_state = connected_peer();
         let chain_state = ChainState::new();
         let ping = PingMessage { nonce: 12345 };
         let message = NetworkMessage::Ping(ping);
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::SendMessage(NetworkMessage::Pong(_))));
     }
     
     #[test]
     fn test_process_network_message_pong() {
-        let mut peer_state = PeerState::new();
+        let mut peer_state = connected_peer();
         peer_state.ping_nonce = Some(12345);
         let chain_state = ChainState::new();
         let pong = PongMessage { nonce: 12345 };
         let message = NetworkMessage::Pong(pong);
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::Ok));
         assert!(peer_state.ping_nonce.is_none());
     }
     
     #[test]
     fn test_process_network_message_addr() {
-        let mut peer_state = PeerState::new();
+        let mut peer_state = connected_peer();
         let chain_state = ChainState::new();
         let addr = AddrMessage {
             addresses: vec![NetworkAddress {
-                services: 1,
+                time: 0,
+                services: ServiceFlags::NETWORK,
                 ip: [192, 168, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
                 port: 8333,
             }],
         };
         let message = NetworkMessage::Addr(addr);
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::Ok));
         assert_eq!(peer_state.known_addresses.len(), 1);
     }
     
     #[test]
     fn test_process_network_message_inv() {
-        let mut peer_state = PeerState::new();
+        let mut peer_state = connected_peer();
         let chain_state = ChainState::new();
         let inv = InvMessage {
             inventory: vec![InventoryVector {
@@ -697,14 +1698,28 @@ mod tests {
         };
         let message = NetworkMessage::Inv(inv);
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         // INV message returns SendMessage when requesting objects we don't have
         assert!(matches!(response, NetworkResponse::SendMessage(_)));
+        
+        // Only so many blocks are in flight at once
+        let inv = InvMessage {
+            inventory: (0..40u8).map(|i| InventoryVector { inv_type: 2, hash: [i; 32] }).collect(),
+        };
+        let response = process_network_message(&NetworkMessage::Inv(inv), &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        let NetworkResponse::SendMessage(NetworkMessage::GetData(getdata)) = response else {
+            panic!("expected getdata");
+        };
+        assert_eq!(getdata.inventory.len(), MAX_BLOCKS_IN_TRANSIT_PER_PEER - 1);
+        assert_eq!(peer_state.requested_blocks.len(), MAX_BLOCKS_IN_TRANSIT_PER_PEER);
+        let inv = InvMessage { inventory: vec![InventoryVector { inv_type: 2, hash: [99; 32] }] };
+        let response = process_network_message(&NetworkMessage::Inv(inv), &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Ok));
     }
     
     #[test]
     fn test_process_network_message_getdata() {
-        let mut peer_state = PeerState::new();
+        let mut peer_state = connected_peer();
         let chain_state = ChainState::new();
         let getdata = GetDataMessage {
             inventory: vec![InventoryVector {
@@ -714,14 +1729,44 @@ mod tests {
         };
         let message = NetworkMessage::GetData(getdata);
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         // GetData message returns SendMessages (plural) when sending objects
         assert!(matches!(response, NetworkResponse::SendMessages(_)));
     }
+
+    #[test]
+    fn test_getdata_reports_missing_objects() {
+        let mut peer_state = connected_peer();
+        let mut chain_state = ChainState::new();
+        let tx = Transaction { version: 1, inputs: vec![], outputs: vec![], lock_time: 0 };
+        let txid = crate::transaction::calculate_tx_id(&tx);
+        chain_state.transactions.insert(txid, tx.clone());
+
+        let missing = vec![
+            InventoryVector { inv_type: 2, hash: txid }, // A transaction asked for as a block
+            InventoryVector { inv_type: MSG_WTX, hash: [2; 32] },
+        ];
+        let mut inventory = vec![InventoryVector { inv_type: 1, hash: txid }, InventoryVector { inv_type: 99, hash: [3; 32] }];
+        inventory.extend(missing.clone());
+        let message = NetworkMessage::GetData(GetDataMessage { inventory });
+        match process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap() {
+            NetworkResponse::SendMessages(messages) => assert_eq!(messages, vec![
+                NetworkMessage::Tx(tx),
+                NetworkMessage::NotFound(NotFoundMessage { inventory: missing }),
+            ]),
+            _ => panic!("Expected responses"),
+        }
+
+        // A notfound for a requested block stops us waiting for it
+        peer_state.requested_blocks.insert([4; 32]);
+        let notfound = NetworkMessage::NotFound(NotFoundMessage { inventory: vec![InventoryVector { inv_type: 2, hash: [4; 32] }] });
+        process_network_message(&notfound, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(peer_state.requested_blocks.is_empty());
+    }
     
     #[test]
     fn test_process_network_message_getheaders() {
-        let mut peer_state = PeerState::new();
+        let mut peer_state = connected_peer();
         let chain_state = ChainState::new();
         let getheaders = GetHeadersMessage {
             version: 70015,
@@ -730,34 +1775,43 @@ mod tests {
         };
         let message = NetworkMessage::GetHeaders(getheaders);
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         // GetHeaders message returns SendMessage when sending headers
         assert!(matches!(response, NetworkResponse::SendMessage(_)));
     }
     
     #[test]
     fn test_process_network_message_headers() {
-        let mut peer_state = PeerState::new();
-        let chain_state = ChainState::new();
-        let headers = HeadersMessage {
-            headers: vec![BlockHeader {
-                version: 1,
-                prev_block_hash: [0u8; 32],
-                merkle_root: [0u8; 32],
-                timestamp: 1234567890,
-                bits: 0x1d00ffff,
-                nonce: 0,
-            }],
+        let mut peer_state = connected_peer();
+        let mut chain_state = ChainState::new();
+        let header = |prev_block_hash, nonce| BlockHeader {
+            version: 1,
+            prev_block_hash,
+            merkle_root: [0u8; 32],
+            timestamp: 1234567890,
+            bits: 0x1d00ffff,
+            nonce,
         };
-        let message = NetworkMessage::Headers(headers);
+        let known = header([0u8; 32], 0);
+        chain_state.headers.insert(known.block_hash(), known.clone());
+        let first = header(known.block_hash(), 1);
+        let second = header(first.block_hash(), 2);
+        let message = |headers| NetworkMessage::Headers(HeadersMessage { headers });
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message(vec![first.clone(), second.clone()]), &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::Ok));
+        assert_eq!(peer_state.misbehavior_score, 0);
+        
+        // Headers from an unknown parent, or with a gap, do not connect
+        process_network_message(&message(vec![second.clone()]), &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(peer_state.misbehavior_score, Misbehavior::NonConnectingHeaders.penalty());
+        process_network_message(&message(vec![first, header([9; 32], 3)]), &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(peer_state.misbehavior_score, 2 * Misbehavior::NonConnectingHeaders.penalty());
     }
     
     #[test]
     fn test_process_network_message_block() {
-        let mut peer_state = PeerState::new();
+        let mut peer_state = connected_peer();
         let chain_state = ChainState::new();
         let block = Block {
             header: BlockHeader {
@@ -772,13 +1826,13 @@ mod tests {
         };
         let message = NetworkMessage::Block(block);
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::Ok));
     }
     
     #[test]
     fn test_process_network_message_tx() {
-        let mut peer_state = PeerState::new();
+        let mut peer_state = connected_peer();
         let chain_state = ChainState::new();
         let tx = Transaction {
             version: 1,
@@ -791,30 +1845,162 @@ mod tests {
         };
         let message = NetworkMessage::Tx(tx);
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::Ok));
     }
     
     #[test]
     fn test_process_network_message_mempool() {
-        let mut peer_state = PeerState::new();
+        let mut peer_state = connected_peer();
         let chain_state = ChainState::new();
         let message = NetworkMessage::MemPool;
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
         // MemPool message returns SendMessages (plural) when sending transactions
         assert!(matches!(response, NetworkResponse::SendMessages(_)));
     }
     
     #[test]
     fn test_process_network_message_feefilter() {
-        let mut peer_state = PeerState::new();
+        let mut peer_state = connected_peer();
         let chain_state = ChainState::new();
         let feefilter = FeeFilterMessage { feerate: 1000 };
         let message = NetworkMessage::FeeFilter(feefilter);
         
-        let response = process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Ok));
+        assert!(should_relay_tx(1000, &peer_state));
+        assert!(!should_relay_tx(999, &peer_state));
+        assert!(should_relay_tx(0, &PeerState::new()));
+
+        // Peers older than BIP133 cannot set one
+        let mut peer_state = connected_peer();
+        peer_state.version = FEEFILTER_VERSION - 1;
+        process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(peer_state.min_fee_rate, None);
+    }
+
+    #[test]
+    fn test_mempool_response_honors_feefilter() {
+        let mut chain_state = ChainState::new();
+        let txs: Vec<Transaction> = (0..3u8).map(|seed| Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [seed; 32], index: 0 },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: 1000, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        }).collect();
+        for (tx, fee_rate) in txs.iter().zip([500, 5000]) {
+            chain_state.mempool_fee_rates.insert(crate::transaction::calculate_tx_id(tx), fee_rate);
+        }
+        chain_state.mempool = txs.clone();
+
+        // The third transaction has no recorded fee rate, so only peers
+        // without a filter get it
+        let mut peer_state = connected_peer();
+        let response = process_network_message(&NetworkMessage::MemPool, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        match response {
+            NetworkResponse::SendMessages(messages) => assert_eq!(messages.len(), 3),
+            _ => panic!("Expected mempool transactions"),
+        }
+        peer_state.min_fee_rate = Some(1000);
+        let response = process_network_message(&NetworkMessage::MemPool, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        match response {
+            NetworkResponse::SendMessages(messages) => assert_eq!(messages, vec![NetworkMessage::Tx(txs[1].clone())]),
+            _ => panic!("Expected mempool transactions"),
+        }
+
+        // Announcements below the filter are never queued
+        let policy = crate::policy::StandardnessPolicy::default();
+        let recent_rejects = crate::relay::RecentRejects::default();
+        let context = crate::relay::RelayContext::new(&policy, &recent_rejects);
+        let mut standard = txs[0].clone();
+        standard.outputs[0].script_pubkey = [vec![0x00, 0x14], vec![0xab; 20]].concat();
+        peer_state.min_fee_rate = Some(2000);
+        assert_eq!(
+            peer_state.queue_tx_announcement(&standard, &[], 1999, 1000, &context),
+            RelayDecision::Skip(SkipReason::BelowFeeFilter { fee_rate: 1999, fee_filter: 2000 })
+        );
+        assert_eq!(peer_state.inventory.queued(), 0);
+        assert_eq!(peer_state.queue_tx_announcement(&standard, &[], 2000, 1000, &context), RelayDecision::Relay);
+        assert_eq!(peer_state.inventory.queued(), 1);
+
+        // Nor are non-standard ones, or those rejected since the last block
+        assert!(matches!(
+            peer_state.queue_tx_announcement(&txs[1], &[], 5000, 100, &context),
+            RelayDecision::Skip(SkipReason::NonStandard(_))
+        ));
+        let mut rejected = standard.clone();
+        rejected.lock_time = 1;
+        chain_state.record_rejection(crate::transaction::calculate_tx_id(&rejected));
+        let recent_rejects = chain_state.recent_rejects.borrow();
+        let context = crate::relay::RelayContext::new(&policy, &recent_rejects);
+        assert_eq!(
+            peer_state.queue_tx_announcement(&rejected, &[], 5000, 100, &context),
+            RelayDecision::Skip(SkipReason::RecentlyRejected)
+        );
+        assert_eq!(peer_state.inventory.queued(), 1);
+    }
+
+    /// A `ChainState` whose mempool refuses every transaction
+    struct RefusingMempool(ChainState);
+
+    impl ChainStateView for RefusingMempool {
+        fn has_block(&self, hash: &Hash) -> bool { self.0.has_block(hash) }
+        fn get_block(&self, hash: &Hash) -> Option<Block> { self.0.get_block(hash) }
+        fn get_transaction(&self, txid: &Hash) -> Option<Transaction> { self.0.get_transaction(txid) }
+        fn header_of(&self, hash: &Hash) -> Option<BlockHeader> { self.0.header_of(hash) }
+        fn get_headers(&self, locator_hashes: &[Hash], hash_stop: &Hash) -> Vec<BlockHeader> {
+            self.0.get_headers(locator_hashes, hash_stop)
+        }
+        fn process_header(&self, header: &BlockHeader) -> Result<()> { self.0.process_header(header) }
+        fn process_block(&self, block: &Block) -> Result<()> { self.0.process_block(block) }
+        fn process_transaction(&self, _tx: &Transaction) -> Result<()> {
+            Err(crate::error::ConsensusError::TransactionValidation("mempool full".to_string()))
+        }
+        fn get_mempool_transactions(&self) -> Vec<Transaction> { self.0.get_mempool_transactions() }
+        fn mempool_fee_rate(&self, txid: &Hash) -> Option<u64> { self.0.mempool_fee_rate(txid) }
+        fn is_recently_rejected(&self, hash: &Hash) -> bool { self.0.is_recently_rejected(hash) }
+        fn record_rejection(&self, txid: Hash) { self.0.record_rejection(txid) }
+    }
+
+    #[test]
+    fn test_recent_rejects_until_new_tip() {
+        let mut chain = RefusingMempool(ChainState::new());
+        let tx = Transaction {
+            version: 1,
+            inputs: vec![],
+            outputs: vec![TransactionOutput { value: 1, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        };
+        let txid = crate::transaction::calculate_tx_id(&tx);
+        let inv = NetworkMessage::Inv(InvMessage { inventory: vec![InventoryVector { inv_type: 1, hash: txid }] });
+        let mut peer_state = connected_peer();
+        peer_state.handshake = HandshakeState::Complete;
+        assert!(matches!(
+            process_network_message(&inv, &mut peer_state, &chain, std::time::UNIX_EPOCH).unwrap(),
+            NetworkResponse::SendMessage(NetworkMessage::GetData(_))
+        ));
+
+        // A rejection is remembered: the transaction is neither validated
+        // nor requested again
+        let response = process_network_message(&NetworkMessage::Tx(tx.clone()), &mut peer_state, &chain, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Reject(_)));
+        assert!(chain.is_recently_rejected(&txid));
+        let response = process_network_message(&NetworkMessage::Tx(tx), &mut peer_state, &chain, std::time::UNIX_EPOCH).unwrap();
         assert!(matches!(response, NetworkResponse::Ok));
+        assert!(matches!(process_network_message(&inv, &mut peer_state, &chain, std::time::UNIX_EPOCH).unwrap(), NetworkResponse::Ok));
+
+        // Until the tip changes
+        chain.0.extend_main_chain(message_header());
+        assert!(!chain.is_recently_rejected(&txid));
+        assert!(matches!(
+            process_network_message(&inv, &mut peer_state, &chain, std::time::UNIX_EPOCH).unwrap(),
+            NetworkResponse::SendMessage(NetworkMessage::GetData(_))
+        ));
     }
     
     #[test]
@@ -910,14 +2096,78 @@ mod tests {
             bits: 0x1d00ffff,
             nonce: 0,
         };
-        let hash = [1u8; 32];
-        chain_state.headers.insert(hash, header.clone());
-        
-        let headers = chain_state.get_headers(&[], &[0u8; 32]);
-        assert_eq!(headers.len(), 1);
-        assert_eq!(headers[0], header);
+        let mut hashes = Vec::new();
+        for nonce in 0..5 {
+            let mut header = header.clone();
+            header.prev_block_hash = hashes.last().copied().unwrap_or([0u8; 32]);
+            header.nonce = nonce;
+            hashes.push(header.block_hash());
+            chain_state.extend_main_chain(header);
+        }
+        let heights = |chain_state: &ChainState, headers: Vec<BlockHeader>| -> Vec<usize> {
+            headers.iter().map(|h| chain_state.main_chain_height(&h.block_hash()).unwrap()).collect()
+        };
+
+        // From the first known locator hash, up to the stop hash
+        let unknown = [9u8; 32];
+        assert_eq!(heights(&chain_state, chain_state.get_headers(&[unknown, hashes[1], hashes[0]], &[0u8; 32])), vec![2, 3, 4]);
+        assert_eq!(heights(&chain_state, chain_state.get_headers(&[hashes[1]], &hashes[3])), vec![2, 3]);
+        // Nothing known: from after genesis; at the tip: nothing
+        assert_eq!(heights(&chain_state, chain_state.get_headers(&[unknown], &[0u8; 32])), vec![1, 2, 3, 4]);
+        assert!(chain_state.get_headers(&[hashes[4]], &[0u8; 32]).is_empty());
+        // No locator: just the stop header
+        assert_eq!(heights(&chain_state, chain_state.get_headers(&[], &hashes[2])), vec![2]);
+        assert!(chain_state.get_headers(&[], &unknown).is_empty());
+
+        // Off-chain headers are never sent
+        let mut fork = header;
+        fork.nonce = 99;
+        chain_state.headers.insert(fork.block_hash(), fork.clone());
+        assert_eq!(heights(&chain_state, chain_state.get_headers(&[fork.block_hash()], &[0u8; 32])), vec![1, 2, 3, 4]);
     }
     
+    /// Storage that holds nothing and rejects every block
+    struct RejectingChain;
+
+    impl ChainStateView for RejectingChain {
+        fn has_block(&self, _hash: &Hash) -> bool { false }
+        fn get_block(&self, _hash: &Hash) -> Option<Block> { None }
+        fn get_transaction(&self, _txid: &Hash) -> Option<Transaction> { None }
+        fn header_of(&self, _hash: &Hash) -> Option<BlockHeader> { None }
+        fn get_headers(&self, _locator_hashes: &[Hash], _hash_stop: &Hash) -> Vec<BlockHeader> { vec![] }
+        fn process_header(&self, _header: &BlockHeader) -> Result<()> { Ok(()) }
+        fn process_block(&self, _block: &Block) -> Result<()> {
+            Err(crate::error::ConsensusError::BlockValidation("rejected".to_string()))
+        }
+        fn process_transaction(&self, _tx: &Transaction) -> Result<()> { Ok(()) }
+        fn get_mempool_transactions(&self) -> Vec<Transaction> { vec![] }
+        fn mempool_fee_rate(&self, _txid: &Hash) -> Option<u64> { None }
+    }
+
+    #[test]
+    fn test_process_network_message_custom_chain_state() {
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash: [0; 32],
+                merkle_root: [0; 32],
+                timestamp: 1231006505,
+                bits: 0x1d00ffff,
+                nonce: 0,
+            },
+            transactions: vec![],
+        };
+        let mut peer_state = connected_peer();
+        peer_state.requested_blocks.insert(block.header.block_hash());
+        let response = process_network_message(&NetworkMessage::Block(block), &mut peer_state, &RejectingChain, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Reject(_)));
+        assert!(peer_state.should_disconnect());
+
+        // Defaults: no filters are indexed
+        assert!(RejectingChain.filter_range(0, &[0; 32], 10).is_none());
+        assert!(!RejectingChain.has_object(&[0; 32]));
+    }
+
     #[test]
     fn test_chain_state_process_header() {
         let chain_state = ChainState::new();
@@ -1053,7 +2303,7 @@ mod tests {
         peer_state.ping_nonce = Some(12345);
         let pong = PongMessage { nonce: 54321 }; // Wrong nonce
         
-        let response = process_pong_message(&pong, &mut peer_state).unwrap();
+        let response = process_pong_message(&pong, &mut peer_state, std::time::UNIX_EPOCH).unwrap();
         // The current implementation accepts any pong message
         assert!(matches!(response, NetworkResponse::Ok));
     }
@@ -1064,8 +2314,330 @@ mod tests {
         peer_state.ping_nonce = None; // No pending ping
         let pong = PongMessage { nonce: 12345 };
         
-        let response = process_pong_message(&pong, &mut peer_state).unwrap();
+        let response = process_pong_message(&pong, &mut peer_state, std::time::UNIX_EPOCH).unwrap();
         // The current implementation accepts any pong message
         assert!(matches!(response, NetworkResponse::Ok));
     }
+
+    #[test]
+    fn test_process_network_message_compact_block() {
+        let tx = |seed: u8| Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [seed; 32], index: 0 },
+                script_sig: vec![seed],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: 1000, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        };
+        let transactions: Vec<Transaction> = (0..4).map(tx).collect();
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash: [0u8; 32],
+                merkle_root: crate::block::calculate_merkle_root(&transactions).unwrap(),
+                timestamp: 1234567890,
+                bits: 0x1d00ffff,
+                nonce: 0,
+            },
+            transactions,
+        };
+        let mut peer_state = connected_peer();
+        let mut chain_state = ChainState::new();
+        chain_state.mempool = vec![block.transactions[1].clone()];
+
+        // Peers older than BIP152 cannot ask for compact blocks
+        let sendcmpct = NetworkMessage::SendCmpct(SendCmpctMessage { announce: true, version: 1 });
+        peer_state.version = SHORT_IDS_BLOCKS_VERSION - 1;
+        process_network_message(&sendcmpct, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(peer_state.compact_block_version, None);
+        peer_state.version = SHORT_IDS_BLOCKS_VERSION;
+        process_network_message(&sendcmpct, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(peer_state.compact_block_version, Some(1));
+        assert!(peer_state.announce_compact_blocks);
+
+        // Transactions missing from the mempool are requested
+        let compact = NetworkMessage::CmpctBlock(CmpctBlockMessage::from_block(&block, 5, &[]));
+        let response = process_network_message(&compact, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        let NetworkResponse::SendMessage(NetworkMessage::GetBlockTxn(request)) = response else {
+            panic!("expected getblocktxn");
+        };
+        assert_eq!(request.indexes, vec![2, 3]);
+
+        // A peer holding the block answers, and the answer completes it
+        chain_state.blocks.insert(block.header.block_hash(), block.clone());
+        let response = process_network_message(&NetworkMessage::GetBlockTxn(request), &mut connected_peer(), &chain_state, std::time::UNIX_EPOCH).unwrap();
+        let NetworkResponse::SendMessage(blocktxn) = response else {
+            panic!("expected blocktxn");
+        };
+        let response = process_network_message(&blocktxn, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Ok));
+        assert!(peer_state.partial_block.is_none());
+    }
+
+    #[test]
+    fn test_process_network_message_addrv2() {
+        use crate::network::addrv2::AddrV2;
+
+        let mut peer_state = PeerState::new();
+        let chain_state = ChainState::new();
+        peer_state.handshake = HandshakeState::AwaitingVerack;
+        process_network_message(&NetworkMessage::SendAddrV2, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(peer_state.wants_addrv2);
+        process_network_message(&NetworkMessage::VerAck, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+
+        let entry = |addr| AddrV2Entry { time: 0, services: ServiceFlags::NETWORK, addr, port: 8333 };
+        let message = NetworkMessage::AddrV2(AddrV2Message {
+            addresses: vec![entry(AddrV2::Ipv4([10, 0, 0, 1])), entry(AddrV2::TorV3([3; 32]))],
+        });
+        let response = process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Ok));
+        assert_eq!(peer_state.known_addresses.len(), 1);
+        assert_eq!(peer_state.known_addresses_v2.len(), 1);
+
+        // After the handshake sendaddrv2 is a protocol violation
+        let response = process_network_message(&NetworkMessage::SendAddrV2, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Reject(_)));
+    }
+
+    #[test]
+    fn test_process_network_message_getaddr() {
+        use crate::network::addrv2::AddrV2;
+
+        let secs = 1_700_000_000;
+        let now = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs as u64);
+        let mut addrman = AddrMan::new((1, 2));
+        let source = AddrV2::Ipv4([8, 8, 8, 8]);
+        for i in 0..20 {
+            let entry = |addr| AddrV2Entry { time: secs, services: ServiceFlags::NETWORK, addr, port: 8333 };
+            addrman.add(entry(AddrV2::Ipv4([i, 0, 0, 1])), &source, secs);
+            addrman.add(entry(AddrV2::TorV3([i; 32])), &source, secs);
+        }
+        let mut chain_state = ChainState::new();
+        chain_state.addrman = Some(addrman);
+        let mut peer_state = connected_peer();
+
+        // Outbound peers are not answered
+        let response = process_network_message(&NetworkMessage::GetAddr, &mut peer_state, &chain_state, now).unwrap();
+        assert!(matches!(response, NetworkResponse::Ok));
+
+        // Inbound ones are, once, without the addresses addr cannot carry
+        peer_state.inbound = true;
+        match process_network_message(&NetworkMessage::GetAddr, &mut peer_state, &chain_state, now).unwrap() {
+            NetworkResponse::SendMessage(NetworkMessage::Addr(addr)) => {
+                assert!(!addr.addresses.is_empty());
+                assert!(addr.addresses.len() <= ADDRMAN_GETADDR_MAX_PCT * 40 / 100);
+            }
+            _ => panic!("Expected an addr message"),
+        }
+        let response = process_network_message(&NetworkMessage::GetAddr, &mut peer_state, &chain_state, now).unwrap();
+        assert!(matches!(response, NetworkResponse::Ok));
+
+        // Peers that asked for addrv2 get every network
+        let mut peer_state = connected_peer();
+        peer_state.inbound = true;
+        peer_state.wants_addrv2 = true;
+        match process_network_message(&NetworkMessage::GetAddr, &mut peer_state, &chain_state, now).unwrap() {
+            NetworkResponse::SendMessage(NetworkMessage::AddrV2(addrv2)) => {
+                assert_eq!(addrv2.addresses.len(), ADDRMAN_GETADDR_MAX_PCT * chain_state.addrman.as_ref().unwrap().len() / 100);
+            }
+            _ => panic!("Expected an addrv2 message"),
+        }
+    }
+
+    #[test]
+    fn test_process_network_message_wtxid_relay() {
+        let mut chain_state = ChainState::new();
+        let tx = Transaction {
+            version: 2,
+            inputs: vec![],
+            outputs: vec![TransactionOutput { value: 1, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        };
+        let txid = crate::transaction::calculate_tx_id(&tx);
+        let wtxid = [0xee; 32];
+        chain_state.transactions.insert(txid, tx.clone());
+        chain_state.wtxids.insert(wtxid, txid);
+
+        // Old peers cannot negotiate it
+        let mut peer_state = PeerState::new();
+        peer_state.handshake = HandshakeState::AwaitingVerack;
+        peer_state.version = 70015;
+        process_network_message(&NetworkMessage::WtxidRelay, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(!peer_state.wtxid_relay);
+        peer_state.version = WTXID_RELAY_VERSION;
+        process_network_message(&NetworkMessage::WtxidRelay, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(peer_state.wtxid_relay);
+        process_network_message(&NetworkMessage::VerAck, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+
+        // Transactions are served by wtxid
+        let getdata = NetworkMessage::GetData(GetDataMessage {
+            inventory: vec![InventoryVector { inv_type: MSG_WTX, hash: wtxid }],
+        });
+        match process_network_message(&getdata, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap() {
+            NetworkResponse::SendMessages(messages) => assert_eq!(messages, vec![NetworkMessage::Tx(tx)]),
+            _ => panic!("Expected the transaction"),
+        }
+
+        // Unknown wtxids are requested; txid announcements are ignored
+        let unknown = [0xdd; 32];
+        let inv = NetworkMessage::Inv(InvMessage {
+            inventory: vec![
+                InventoryVector { inv_type: 1, hash: unknown },
+                InventoryVector { inv_type: MSG_WTX, hash: wtxid },
+                InventoryVector { inv_type: MSG_WTX, hash: unknown },
+            ],
+        });
+        match process_network_message(&inv, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap() {
+            NetworkResponse::SendMessage(NetworkMessage::GetData(getdata)) => {
+                assert_eq!(getdata.inventory, vec![InventoryVector { inv_type: MSG_WTX, hash: unknown }]);
+            }
+            _ => panic!("Expected a getdata"),
+        }
+
+        // Announcements use the wtxid, except to a peer that announced it
+        let policy = crate::policy::StandardnessPolicy::default();
+        let recent_rejects = crate::relay::RecentRejects::default();
+        let context = crate::relay::RelayContext::new(&policy, &recent_rejects);
+        let segwit = Transaction {
+            version: 2,
+            inputs: vec![TransactionInput { prevout: OutPoint { hash: [1; 32], index: 0 }, script_sig: vec![], sequence: 0 }],
+            outputs: vec![TransactionOutput { value: 1000, script_pubkey: [vec![0x00, 0x14], vec![0xab; 20]].concat() }],
+            lock_time: 0,
+        };
+        let witnesses = vec![vec![vec![0x30; 71], vec![0x02; 33]]];
+        let segwit_wtxid = crate::transaction::calculate_wtxid(&segwit, &witnesses);
+        peer_state.inventory.known.insert(segwit_wtxid);
+        assert_eq!(
+            peer_state.queue_tx_announcement(&segwit, &witnesses, 1000, 110, &context),
+            RelayDecision::Skip(SkipReason::AlreadyKnown)
+        );
+        let mut other = segwit.clone();
+        other.lock_time = 1;
+        assert_eq!(peer_state.queue_tx_announcement(&other, &witnesses, 1000, 110, &context), RelayDecision::Relay);
+        let announced = peer_state.announce_inventory(std::time::SystemTime::UNIX_EPOCH, 0);
+        assert_eq!(announced, Some(NetworkMessage::Inv(InvMessage {
+            inventory: vec![InventoryVector { inv_type: MSG_WTX, hash: crate::transaction::calculate_wtxid(&other, &witnesses) }],
+        })));
+
+        // And after the handshake the message is a protocol violation
+        let response = process_network_message(&NetworkMessage::WtxidRelay, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Reject(_)));
+    }
+
+    #[test]
+    fn test_process_network_message_bloom_filter() {
+        let mut peer_state = connected_peer();
+        let chain_state = ChainState::new();
+
+        // filteradd needs a loaded filter
+        let filteradd = NetworkMessage::FilterAdd(FilterAddMessage { data: vec![0xaa; 20] });
+        let response = process_network_message(&filteradd, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Reject(_)));
+
+        let filter = BloomFilter::new(10, 0.0001, 0, bloom::BLOOM_UPDATE_ALL);
+        let filterload = NetworkMessage::FilterLoad(filter.to_filter_load());
+        process_network_message(&filterload, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        let response = process_network_message(&filteradd, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Ok));
+        assert!(peer_state.bloom_filter.as_ref().unwrap().contains(&[0xaa; 20]));
+
+        let oversized = NetworkMessage::FilterAdd(FilterAddMessage { data: vec![0; MAX_FILTER_ADD_SIZE + 1] });
+        let response = process_network_message(&oversized, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(matches!(response, NetworkResponse::Reject(_)));
+
+        process_network_message(&NetworkMessage::FilterClear, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(peer_state.bloom_filter.is_none());
+    }
+
+    #[test]
+    fn test_process_network_message_block_filters() {
+        use crate::block::BlockUndo;
+        use crate::genesis::genesis_block;
+        use crate::params::Network;
+
+        // Three blocks with their filters and filter headers
+        let mut chain_state = ChainState::new();
+        let mut block = genesis_block(Network::Regtest);
+        let mut prev_filter_header = [0; 32];
+        let mut hashes = Vec::new();
+        for height in 0..3 {
+            if height > 0 {
+                block.header.prev_block_hash = block.header.block_hash();
+                block.header.nonce += 1;
+            }
+            let filter = BlockFilter::basic(&block, &BlockUndo { tx_undo: vec![] }).unwrap();
+            let header = filter.filter_header(&prev_filter_header);
+            let hash = block.header.block_hash();
+            chain_state.headers.insert(hash, block.header.clone());
+            chain_state.block_filters.insert(hash, FilterEntry { height, filter, header });
+            prev_filter_header = header;
+            hashes.push(hash);
+        }
+        let mut peer_state = connected_peer();
+
+        let getcfilters = NetworkMessage::GetCFilters(GetCFiltersMessage { filter_type: 0, start_height: 1, stop_hash: hashes[2] });
+        let NetworkResponse::SendMessages(filters) = process_network_message(&getcfilters, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap() else {
+            panic!("expected cfilters");
+        };
+        assert_eq!(filters.len(), 2);
+        assert!(matches!(&filters[0], NetworkMessage::CFilter(cfilter) if cfilter.block_hash == hashes[1]));
+
+        // The previous header and filter hashes rebuild the stop header
+        let getcfheaders = NetworkMessage::GetCFHeaders(GetCFHeadersMessage { filter_type: 0, start_height: 1, stop_hash: hashes[2] });
+        let NetworkResponse::SendMessage(NetworkMessage::CFHeaders(cfheaders)) =
+            process_network_message(&getcfheaders, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap() else {
+            panic!("expected cfheaders");
+        };
+        assert_eq!(cfheaders.previous_filter_header, chain_state.block_filters[&hashes[0]].header);
+        let rebuilt = cfheaders.filter_hashes.iter().fold(cfheaders.previous_filter_header, |prev, filter_hash| {
+            crate::serialization::double_sha256(&[filter_hash.as_slice(), prev.as_slice()].concat())
+        });
+        assert_eq!(rebuilt, prev_filter_header);
+
+        // Ranges past the stop block or of unknown types are refused
+        let backwards = NetworkMessage::GetCFilters(GetCFiltersMessage { filter_type: 0, start_height: 3, stop_hash: hashes[2] });
+        assert!(matches!(process_network_message(&backwards, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap(), NetworkResponse::Reject(_)));
+        let extended = NetworkMessage::GetCFilters(GetCFiltersMessage { filter_type: 1, start_height: 0, stop_hash: hashes[2] });
+        assert!(matches!(process_network_message(&extended, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap(), NetworkResponse::Reject(_)));
+    }
+
+    #[test]
+    fn test_process_network_message_misbehavior() {
+        let mut peer_state = connected_peer();
+        let chain_state = ChainState::new();
+        let block = Block { header: message_header(), transactions: vec![] };
+
+        // A block we asked for costs nothing; the same block again does
+        let inv = NetworkMessage::Inv(InvMessage {
+            inventory: vec![InventoryVector { inv_type: 2, hash: block.header.block_hash() }],
+        });
+        process_network_message(&inv, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        let message = NetworkMessage::Block(block);
+        process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(peer_state.misbehavior_score, 0);
+        process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(peer_state.misbehavior_score, 10);
+
+        let headers = NetworkMessage::Headers(HeadersMessage { headers: vec![message_header(); 2001] });
+        process_network_message(&headers, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert_eq!(peer_state.misbehavior_score, 30);
+        assert!(!peer_state.should_disconnect());
+
+        let filteradd = NetworkMessage::FilterAdd(FilterAddMessage { data: vec![1] });
+        process_network_message(&filteradd, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
+        assert!(peer_state.should_disconnect());
+    }
+
+    fn message_header() -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block_hash: [0u8; 32],
+            merkle_root: [0u8; 32],
+            timestamp: 1234567890,
+            bits: 0x1d00ffff,
+            nonce: 0,
+        }
+    }
 }
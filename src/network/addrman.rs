@@ -0,0 +1,663 @@
+//! Address manager: the new and tried tables of known peer addresses
+//!
+//! Addresses heard about go into the new table and move to the tried
+//! table once a connection to them succeeds. Each table is split into
+//! buckets chosen by a secret-keyed hash of the address group and, for
+//! new addresses, the group of the peer that sent them, so one source or
+//! one network range can only fill a few buckets. Randomness is supplied
+//! by the caller, which keeps every operation reproducible.
+
+use crate::serialization::siphash24;
+use super::addrv2::{AddrV2, AddrV2Entry};
+use std::collections::HashMap;
+
+/// Buckets of the new table
+pub const ADDRMAN_NEW_BUCKET_COUNT: usize = 1024;
+
+/// Buckets of the tried table
+pub const ADDRMAN_TRIED_BUCKET_COUNT: usize = 256;
+
+/// Entries per bucket
+pub const ADDRMAN_BUCKET_SIZE: usize = 64;
+
+/// Tried buckets one address group can occupy
+pub const ADDRMAN_TRIED_BUCKETS_PER_GROUP: u64 = 8;
+
+/// New buckets the addresses from one source group can occupy
+pub const ADDRMAN_NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 64;
+
+/// Addresses not seen for this many days are terrible
+pub const ADDRMAN_HORIZON_DAYS: u32 = 30;
+
+/// Failed attempts after which a never-connected address is terrible
+pub const ADDRMAN_RETRIES: u32 = 3;
+
+/// Failed attempts after which an address not connected to in
+/// `ADDRMAN_MIN_FAIL_DAYS` is terrible
+pub const ADDRMAN_MAX_FAILURES: u32 = 10;
+
+/// Days without a successful connection before failures count against an
+/// address
+pub const ADDRMAN_MIN_FAIL_DAYS: u32 = 7;
+
+/// Largest share of the known addresses, in percent, given out in
+/// answer to one getaddr
+pub const ADDRMAN_GETADDR_MAX_PCT: usize = 23;
+
+const DAY: u32 = 24 * 60 * 60;
+
+/// An address with its connection history
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddrInfo {
+    pub entry: AddrV2Entry,
+    /// Address of the peer we heard it from
+    pub source: AddrV2,
+    /// Last connection attempt, in seconds since the epoch; 0 if none
+    pub last_try: u32,
+    /// Last successful connection; 0 if none
+    pub last_success: u32,
+    /// Failed attempts since the last success
+    pub attempts: u32,
+    /// New buckets referring to the address
+    ref_count: usize,
+    in_tried: bool,
+}
+
+impl AddrInfo {
+    fn new(entry: AddrV2Entry, source: AddrV2) -> Self {
+        AddrInfo { entry, source, last_try: 0, last_success: 0, attempts: 0, ref_count: 0, in_tried: false }
+    }
+
+    /// IsTerrible: AddrInfo × 𝕋 → {true, false}
+    ///
+    /// 1. Tried in the last minute: never terrible
+    /// 2. Timestamp more than 10 minutes in the future: terrible
+    /// 3. Not seen in `ADDRMAN_HORIZON_DAYS`: terrible
+    /// 4. Never connected and `ADDRMAN_RETRIES` failures: terrible
+    /// 5. Not connected in `ADDRMAN_MIN_FAIL_DAYS` and
+    ///    `ADDRMAN_MAX_FAILURES` failures: terrible
+    pub fn is_terrible(&self, now: u32) -> bool {
+        // 1. Recently tried
+        if self.last_try != 0 && self.last_try >= now.saturating_sub(60) {
+            return false;
+        }
+        // 2. From the future
+        if self.entry.time > now.saturating_add(10 * 60) {
+            return true;
+        }
+        // 3. Stale
+        if now.saturating_sub(self.entry.time) > ADDRMAN_HORIZON_DAYS * DAY {
+            return true;
+        }
+        // 4. Never worked
+        if self.last_success == 0 && self.attempts >= ADDRMAN_RETRIES {
+            return true;
+        }
+        // 5. Stopped working
+        now.saturating_sub(self.last_success) > ADDRMAN_MIN_FAIL_DAYS * DAY && self.attempts >= ADDRMAN_MAX_FAILURES
+    }
+
+    /// Relative chance of picking the address: lower when it was tried in
+    /// the last 10 minutes, and 0.66× per failed attempt, up to 8
+    pub fn chance(&self, now: u32) -> f64 {
+        let mut chance = 1.0;
+        if now.saturating_sub(self.last_try) < 10 * 60 {
+            chance *= 0.01;
+        }
+        chance * 0.66f64.powi(self.attempts.min(8) as i32)
+    }
+}
+
+/// Network group of an address: the range one operator can plausibly
+/// control (/16 for IPv4, /32 for IPv6, the leading 4 bits for overlay
+/// networks)
+pub fn address_group(addr: &AddrV2) -> Vec<u8> {
+    let mut group = vec![addr.network_id()];
+    match addr {
+        AddrV2::Ipv4(ip) => group.extend_from_slice(&ip[..2]),
+        AddrV2::Ipv6(ip) => group.extend_from_slice(&ip[..4]),
+        AddrV2::TorV3(key) | AddrV2::I2p(key) => group.push(key[0] >> 4),
+        AddrV2::Cjdns(ip) => group.push(ip[1] >> 4),
+    }
+    group
+}
+
+/// Bytes identifying an address and port
+fn address_key(addr: &AddrV2, port: u16) -> Vec<u8> {
+    let mut key = vec![addr.network_id()];
+    key.extend_from_slice(addr.as_bytes());
+    key.extend_from_slice(&port.to_be_bytes());
+    key
+}
+
+/// AddrMan: the new and tried tables under a secret key
+#[derive(Debug, Clone)]
+pub struct AddrMan {
+    key: (u64, u64),
+    entries: HashMap<u64, AddrInfo>,
+    ids: HashMap<(AddrV2, u16), u64>,
+    next_id: u64,
+    /// Entry ids, `ADDRMAN_BUCKET_SIZE` per bucket
+    new_table: Vec<Option<u64>>,
+    tried_table: Vec<Option<u64>>,
+    /// Addresses waiting to enter the tried table until the occupant of
+    /// their slot has been tested
+    tried_collisions: Vec<u64>,
+}
+
+impl AddrMan {
+    /// An empty address manager; `key` must be secret and random, or an
+    /// attacker can predict and fill the buckets
+    pub fn new(key: (u64, u64)) -> Self {
+        AddrMan {
+            key,
+            entries: HashMap::new(),
+            ids: HashMap::new(),
+            next_id: 0,
+            new_table: vec![None; ADDRMAN_NEW_BUCKET_COUNT * ADDRMAN_BUCKET_SIZE],
+            tried_table: vec![None; ADDRMAN_TRIED_BUCKET_COUNT * ADDRMAN_BUCKET_SIZE],
+            tried_collisions: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Addresses in the tried table
+    pub fn tried_count(&self) -> usize {
+        self.entries.values().filter(|info| info.in_tried).count()
+    }
+
+    /// Addresses in the new table
+    pub fn new_count(&self) -> usize {
+        self.len() - self.tried_count()
+    }
+
+    pub fn get(&self, addr: &AddrV2, port: u16) -> Option<&AddrInfo> {
+        self.ids.get(&(addr.clone(), port)).map(|id| &self.entries[id])
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> u64 {
+        siphash24(self.key.0, self.key.1, &parts.concat())
+    }
+
+    /// TriedBucket: H(key, address) mod 8 picks one of the group's
+    /// buckets, H(key, group, that) mod 256 the bucket
+    fn tried_bucket(&self, info: &AddrInfo) -> usize {
+        let address = address_key(&info.entry.addr, info.entry.port);
+        let hash1 = self.hash(&[&address]) % ADDRMAN_TRIED_BUCKETS_PER_GROUP;
+        let group = address_group(&info.entry.addr);
+        (self.hash(&[&group, &hash1.to_le_bytes()]) % ADDRMAN_TRIED_BUCKET_COUNT as u64) as usize
+    }
+
+    /// NewBucket: H(key, group, source group) mod 64 picks one of the
+    /// source group's buckets, H(key, source group, that) mod 1024 the
+    /// bucket
+    fn new_bucket(&self, info: &AddrInfo) -> usize {
+        let group = address_group(&info.entry.addr);
+        let source_group = address_group(&info.source);
+        let hash1 = self.hash(&[&group, &source_group]) % ADDRMAN_NEW_BUCKETS_PER_SOURCE_GROUP;
+        (self.hash(&[&source_group, &hash1.to_le_bytes()]) % ADDRMAN_NEW_BUCKET_COUNT as u64) as usize
+    }
+
+    /// Position of the address within a bucket of the given table
+    fn bucket_position(&self, info: &AddrInfo, new: bool, bucket: usize) -> usize {
+        let table = if new { b"N" } else { b"K" };
+        let address = address_key(&info.entry.addr, info.entry.port);
+        (self.hash(&[table, &(bucket as u64).to_le_bytes(), &address]) % ADDRMAN_BUCKET_SIZE as u64) as usize
+    }
+
+    fn slot(&self, id: u64, new: bool) -> usize {
+        let info = &self.entries[&id];
+        let bucket = if new { self.new_bucket(info) } else { self.tried_bucket(info) };
+        bucket * ADDRMAN_BUCKET_SIZE + self.bucket_position(info, new, bucket)
+    }
+
+    /// Empty a new table slot, forgetting its entry once nothing refers to
+    /// it
+    fn clear_new(&mut self, slot: usize) {
+        if let Some(id) = self.new_table[slot].take() {
+            let info = self.entries.get_mut(&id).expect("table entries exist");
+            info.ref_count -= 1;
+            if info.ref_count == 0 && !info.in_tried {
+                let key = (info.entry.addr.clone(), info.entry.port);
+                self.entries.remove(&id);
+                self.ids.remove(&key);
+                self.tried_collisions.retain(|&c| c != id);
+            }
+        }
+    }
+
+    /// Add: AddrMan × AddrV2Entry × AddrV2 × 𝕋 → AddrMan × {true, false}
+    ///
+    /// 1. A known address only has its time and services refreshed
+    /// 2. Otherwise it goes to the slot chosen by its group and the
+    ///    source's group in the new table
+    /// 3. An occupied slot is taken over only if its occupant is terrible
+    ///    or also listed elsewhere; otherwise the address is dropped
+    ///
+    /// Returns whether the address was added.
+    pub fn add(&mut self, entry: AddrV2Entry, source: &AddrV2, now: u32) -> bool {
+        // 1. Known address
+        if let Some(&id) = self.ids.get(&(entry.addr.clone(), entry.port)) {
+            let info = self.entries.get_mut(&id).expect("indexed entries exist");
+            info.entry.time = info.entry.time.max(entry.time);
+            info.entry.services |= entry.services;
+            return false;
+        }
+
+        // 2. Slot in the new table
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.insert(id, AddrInfo::new(entry.clone(), source.clone()));
+        let slot = self.slot(id, true);
+
+        // 3. Occupied slot
+        if let Some(occupant) = self.new_table[slot] {
+            let occupant = &self.entries[&occupant];
+            if !occupant.is_terrible(now) && occupant.ref_count <= 1 {
+                self.entries.remove(&id);
+                return false;
+            }
+            self.clear_new(slot);
+        }
+        self.new_table[slot] = Some(id);
+        self.entries.get_mut(&id).expect("just inserted").ref_count = 1;
+        self.ids.insert((entry.addr, entry.port), id);
+        true
+    }
+
+    /// Record a connection attempt to an address
+    pub fn attempt(&mut self, addr: &AddrV2, port: u16, now: u32) {
+        if let Some(id) = self.ids.get(&(addr.clone(), port)) {
+            let info = self.entries.get_mut(id).expect("indexed entries exist");
+            info.last_try = now;
+            info.attempts += 1;
+        }
+    }
+
+    /// Good: AddrMan × AddrV2 × ℕ × 𝕋 → AddrMan × AddrInfo?
+    ///
+    /// 1. Record the successful connection
+    /// 2. An address already tried stays where it is
+    /// 3. If its tried slot is free, move it there from the new table
+    /// 4. Otherwise queue it as a collision and return the occupant, which
+    ///    the caller should test with `resolve_collision`
+    pub fn good(&mut self, addr: &AddrV2, port: u16, now: u32) -> Option<AddrInfo> {
+        let id = *self.ids.get(&(addr.clone(), port))?;
+
+        // 1. Success
+        let info = self.entries.get_mut(&id).expect("indexed entries exist");
+        info.last_success = now;
+        info.last_try = now;
+        info.attempts = 0;
+
+        // 2. Already tried
+        if info.in_tried {
+            return None;
+        }
+
+        // 3. Free slot
+        let slot = self.slot(id, false);
+        match self.tried_table[slot] {
+            None => {
+                self.make_tried(id);
+                None
+            }
+            // 4. Collision
+            Some(occupant) => {
+                if !self.tried_collisions.contains(&id) {
+                    self.tried_collisions.push(id);
+                }
+                Some(self.entries[&occupant].clone())
+            }
+        }
+    }
+
+    /// Move an entry from the new table into its tried slot, sending any
+    /// occupant back to the new table
+    fn make_tried(&mut self, id: u64) {
+        // The entry can only sit at its own position in each new bucket,
+        // and the search ends once every reference is cleared
+        for bucket in 0..ADDRMAN_NEW_BUCKET_COUNT {
+            let info = &self.entries[&id];
+            if info.ref_count == 0 {
+                break;
+            }
+            let slot = bucket * ADDRMAN_BUCKET_SIZE + self.bucket_position(info, true, bucket);
+            if self.new_table[slot] == Some(id) {
+                self.new_table[slot] = None;
+                self.entries.get_mut(&id).expect("entry exists").ref_count -= 1;
+            }
+        }
+
+        let slot = self.slot(id, false);
+        if let Some(evicted) = self.tried_table[slot].take() {
+            self.entries.get_mut(&evicted).expect("table entries exist").in_tried = false;
+            let new_slot = self.slot(evicted, true);
+            self.clear_new(new_slot);
+            self.new_table[new_slot] = Some(evicted);
+            self.entries.get_mut(&evicted).expect("table entries exist").ref_count = 1;
+        }
+        self.tried_table[slot] = Some(id);
+        self.entries.get_mut(&id).expect("entry exists").in_tried = true;
+    }
+
+    /// Addresses waiting on a tried-table collision
+    pub fn collisions(&self) -> Vec<&AddrInfo> {
+        self.tried_collisions.iter().map(|id| &self.entries[id]).collect()
+    }
+
+    /// ResolveCollision: AddrMan × AddrV2 × ℕ × {true, false} × 𝕋 → AddrMan
+    ///
+    /// Settle the collision of the given address once the occupant of its
+    /// tried slot has been tested at `now`: an occupant that still responds
+    /// records the success and keeps its slot; otherwise it records the
+    /// failed attempt, the address takes the slot and the occupant returns
+    /// to the new table.
+    pub fn resolve_collision(&mut self, addr: &AddrV2, port: u16, occupant_responded: bool, now: u32) {
+        let Some(&id) = self.ids.get(&(addr.clone(), port)) else { return };
+        let Some(index) = self.tried_collisions.iter().position(|&c| c == id) else { return };
+        self.tried_collisions.remove(index);
+
+        if let Some(occupant) = self.tried_table[self.slot(id, false)] {
+            let occupant = self.entries.get_mut(&occupant).expect("table entries exist");
+            occupant.last_try = now;
+            if occupant_responded {
+                occupant.last_success = now;
+                occupant.attempts = 0;
+            } else {
+                occupant.attempts += 1;
+            }
+        }
+        if !occupant_responded {
+            self.make_tried(id);
+        }
+    }
+
+    /// Select: AddrMan × {true, false} × 𝕋 × (() → ℕ) → AddrInfo?
+    ///
+    /// 1. Pick the table: only new if `new_only`, else each with
+    ///    probability 1/2 if both are non-empty
+    /// 2. Pick a random bucket and position, moving on to the next
+    ///    occupied position in the bucket
+    /// 3. Accept the entry with probability chance · factor, where the
+    ///    factor starts at 1 and grows by 1.2 with each rejection
+    pub fn select(&self, new_only: bool, now: u32, rng: &mut impl FnMut() -> u64) -> Option<&AddrInfo> {
+        // 1. Table
+        let tried_count = self.tried_count();
+        let new_count = self.len() - tried_count;
+        let use_tried = if new_only || tried_count == 0 {
+            false
+        } else {
+            new_count == 0 || rng() & 1 == 1
+        };
+        if !use_tried && new_count == 0 {
+            return None;
+        }
+        let table = if use_tried { &self.tried_table } else { &self.new_table };
+        let buckets = table.len() / ADDRMAN_BUCKET_SIZE;
+
+        let mut factor = 1.0;
+        loop {
+            // 2. Bucket and position
+            let bucket = (rng() % buckets as u64) as usize;
+            let start = (rng() % ADDRMAN_BUCKET_SIZE as u64) as usize;
+            let occupied = (0..ADDRMAN_BUCKET_SIZE)
+                .find_map(|i| table[bucket * ADDRMAN_BUCKET_SIZE + (start + i) % ADDRMAN_BUCKET_SIZE]);
+            let Some(id) = occupied else { continue };
+
+            // 3. Accept
+            let info = &self.entries[&id];
+            let draw = (rng() >> 11) as f64 / (1u64 << 53) as f64;
+            if draw < factor * info.chance(now) {
+                return Some(info);
+            }
+            factor *= 1.2;
+        }
+    }
+
+    /// GetAddr: AddrMan × ℕ × ℕ × 𝕋 × (() → ℕ) → [AddrV2Entry]
+    ///
+    /// 1. Limit: `max_pct` percent of the known addresses (no limit if 0),
+    ///    and at most `max_addresses` (no limit if 0)
+    /// 2. Walk the addresses in a uniformly random order, by a partial
+    ///    Fisher-Yates shuffle
+    /// 3. Skip terrible ones, so stale and future timestamps are never
+    ///    passed on, until the limit is reached
+    pub fn get_addr(
+        &self,
+        max_addresses: usize,
+        max_pct: usize,
+        now: u32,
+        rng: &mut impl FnMut() -> u64,
+    ) -> Vec<AddrV2Entry> {
+        // 1. Limit
+        let mut limit = self.len();
+        if max_pct != 0 {
+            limit = max_pct * limit / 100;
+        }
+        if max_addresses != 0 {
+            limit = limit.min(max_addresses);
+        }
+
+        // 2. Random order; sorted first so the result only depends on rng
+        let mut ids: Vec<u64> = self.entries.keys().copied().collect();
+        ids.sort_unstable();
+        let mut addresses = Vec::with_capacity(limit);
+        for i in 0..ids.len() {
+            if addresses.len() >= limit {
+                break;
+            }
+            let j = i + (rng() % (ids.len() - i) as u64) as usize;
+            ids.swap(i, j);
+
+            // 3. Filter
+            let info = &self.entries[&ids[i]];
+            if !info.is_terrible(now) {
+                addresses.push(info.entry.clone());
+            }
+        }
+        addresses
+    }
+
+    /// Whether the tables and the index agree: every entry is referenced
+    /// by as many slots as it counts, and tried entries by exactly one
+    pub fn is_consistent(&self) -> bool {
+        let mut references: HashMap<u64, (usize, usize)> = HashMap::new();
+        for &id in self.new_table.iter().flatten() {
+            references.entry(id).or_default().0 += 1;
+        }
+        for &id in self.tried_table.iter().flatten() {
+            references.entry(id).or_default().1 += 1;
+        }
+        self.entries.len() == self.ids.len()
+            && self.entries.iter().all(|(id, info)| {
+                let (new, tried) = references.get(id).copied().unwrap_or_default();
+                self.ids.get(&(info.entry.addr.clone(), info.entry.port)) == Some(id)
+                    && if info.in_tried { tried == 1 && new == 0 } else { tried == 0 && new == info.ref_count && new > 0 }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ServiceFlags;
+
+    const NOW: u32 = 1_700_000_000;
+
+    fn entry(a: u8, b: u8, c: u8) -> AddrV2Entry {
+        AddrV2Entry { time: NOW - 60, services: ServiceFlags::NETWORK, addr: AddrV2::Ipv4([a, b, c, 1]), port: 8333 }
+    }
+
+    fn xorshift(mut state: u64) -> impl FnMut() -> u64 {
+        move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        }
+    }
+
+    #[test]
+    fn test_addrman_add_good_and_select() {
+        let mut addrman = AddrMan::new((1, 2));
+        let source = AddrV2::Ipv4([8, 8, 8, 8]);
+        assert!(addrman.select(false, NOW, &mut xorshift(7)).is_none());
+
+        for i in 0..50 {
+            addrman.add(entry(i, i, 1), &source, NOW);
+        }
+        assert!(!addrman.add(entry(3, 3, 1), &source, NOW));
+        assert!(addrman.len() > 40);
+        assert_eq!(addrman.tried_count(), 0);
+        assert!(addrman.is_consistent());
+
+        // A successful connection moves the address to the tried table
+        addrman.attempt(&AddrV2::Ipv4([3, 3, 1, 1]), 8333, NOW);
+        assert_eq!(addrman.get(&AddrV2::Ipv4([3, 3, 1, 1]), 8333).unwrap().attempts, 1);
+        assert_eq!(addrman.good(&AddrV2::Ipv4([3, 3, 1, 1]), 8333, NOW), None);
+        assert_eq!(addrman.tried_count(), 1);
+        assert_eq!(addrman.get(&AddrV2::Ipv4([3, 3, 1, 1]), 8333).unwrap().attempts, 0);
+        assert!(addrman.is_consistent());
+
+        // Selection only ever returns known addresses, and new_only
+        // excludes the tried one
+        let mut rng = xorshift(42);
+        for _ in 0..20 {
+            let info = addrman.select(true, NOW + 3600, &mut rng).unwrap();
+            assert_ne!(info.entry.addr, AddrV2::Ipv4([3, 3, 1, 1]));
+            assert!(addrman.get(&info.entry.addr, info.entry.port).is_some());
+        }
+    }
+
+    #[test]
+    fn test_addrman_tried_collision() {
+        let mut addrman = AddrMan::new((3, 4));
+        let source = AddrV2::Ipv4([8, 8, 8, 8]);
+
+        // Fill the tried buckets of one /16 until two addresses collide
+        let mut collision = None;
+        for i in 0..=255u8 {
+            for j in [1u8, 2] {
+                let addr = AddrV2Entry { addr: AddrV2::Ipv4([10, 0, i, j]), ..entry(0, 0, 0) };
+                if !addrman.add(addr.clone(), &source, NOW) {
+                    continue;
+                }
+                if let Some(occupant) = addrman.good(&addr.addr, addr.port, NOW) {
+                    collision = Some((addr, occupant));
+                    break;
+                }
+            }
+            if collision.is_some() {
+                break;
+            }
+        }
+        let (addr, occupant) = collision.expect("one /16 only spans 8 tried buckets");
+        assert_eq!(addrman.collisions().len(), 1);
+        assert!(!addrman.get(&addr.addr, addr.port).unwrap().in_tried);
+
+        // An occupant that still responds keeps its slot, recording the
+        // success
+        addrman.resolve_collision(&addr.addr, addr.port, true, NOW + 10);
+        assert!(addrman.collisions().is_empty());
+        let kept = addrman.get(&occupant.entry.addr, occupant.entry.port).unwrap();
+        assert!(kept.in_tried);
+        assert_eq!((kept.last_try, kept.last_success, kept.attempts), (NOW + 10, NOW + 10, 0));
+
+        // One that does not records the failure and is sent back to the
+        // new table
+        addrman.good(&addr.addr, addr.port, NOW);
+        addrman.resolve_collision(&addr.addr, addr.port, false, NOW + 20);
+        assert!(addrman.get(&addr.addr, addr.port).unwrap().in_tried);
+        if let Some(info) = addrman.get(&occupant.entry.addr, occupant.entry.port) {
+            assert_eq!((info.last_try, info.attempts), (NOW + 20, 1));
+        }
+        assert!(addrman.get(&occupant.entry.addr, occupant.entry.port).is_none_or(|info| !info.in_tried));
+        assert!(addrman.is_consistent());
+    }
+
+    #[test]
+    fn test_addrman_collision_entry_evicted() {
+        let mut addrman = AddrMan::new((3, 4));
+        let source = AddrV2::Ipv4([8, 8, 8, 8]);
+        let old = |i: u8, j: u8| AddrV2Entry { time: NOW - 31 * DAY, addr: AddrV2::Ipv4([10, 0, i, j]), ..entry(0, 0, 0) };
+
+        // Force a tried collision with stale addresses
+        let mut colliding = None;
+        'fill: for i in 0..=255u8 {
+            for j in [1u8, 2] {
+                let addr = old(i, j);
+                if addrman.add(addr.clone(), &source, NOW) && addrman.good(&addr.addr, addr.port, NOW).is_some() {
+                    colliding = Some(addr);
+                    break 'fill;
+                }
+            }
+        }
+        let colliding = colliding.expect("one /16 only spans 8 tried buckets");
+        assert_eq!(addrman.collisions().len(), 1);
+
+        // Once terrible, the waiting address is evicted from its new slot
+        // by later ones from the same source
+        let later = NOW + 120;
+        'evict: for i in 0..=255u8 {
+            for j in 3..=255u8 {
+                addrman.add(AddrV2Entry { time: later, ..old(i, j) }, &source, later);
+                if addrman.get(&colliding.addr, colliding.port).is_none() {
+                    break 'evict;
+                }
+            }
+        }
+        assert!(addrman.get(&colliding.addr, colliding.port).is_none());
+        assert!(addrman.collisions().is_empty());
+        addrman.resolve_collision(&colliding.addr, colliding.port, false, later);
+        assert!(addrman.is_consistent());
+    }
+
+    #[test]
+    fn test_addrman_get_addr() {
+        let mut addrman = AddrMan::new((5, 6));
+        let source = AddrV2::Ipv4([8, 8, 8, 8]);
+        for i in 0..100 {
+            addrman.add(entry(i, 1, 1), &source, NOW);
+        }
+        let stale = AddrV2Entry { time: NOW - 31 * DAY, ..entry(200, 1, 1) };
+        addrman.add(stale.clone(), &source, NOW);
+        let known = addrman.len();
+
+        // 23% at most, never the stale address, and no duplicates
+        let addresses = addrman.get_addr(1000, ADDRMAN_GETADDR_MAX_PCT, NOW, &mut xorshift(9));
+        assert_eq!(addresses.len(), ADDRMAN_GETADDR_MAX_PCT * known / 100);
+        assert!(!addresses.contains(&stale));
+        let unique: std::collections::HashSet<_> = addresses.iter().map(|entry| &entry.addr).collect();
+        assert_eq!(unique.len(), addresses.len());
+        assert_ne!(addresses, addrman.get_addr(1000, ADDRMAN_GETADDR_MAX_PCT, NOW, &mut xorshift(10)));
+
+        // Without a share limit, everything but the stale address fits
+        assert_eq!(addrman.get_addr(10, 0, NOW, &mut xorshift(9)).len(), 10);
+        assert_eq!(addrman.get_addr(0, 0, NOW, &mut xorshift(9)).len(), known - 1);
+    }
+
+    #[test]
+    fn test_addr_info_is_terrible() {
+        let mut info = AddrInfo::new(entry(1, 2, 3), AddrV2::Ipv4([8, 8, 8, 8]));
+        assert!(!info.is_terrible(NOW));
+        assert!(info.is_terrible(NOW + 31 * DAY));
+        info.entry.time = NOW + 3600;
+        assert!(info.is_terrible(NOW));
+        info.entry.time = NOW;
+        info.attempts = ADDRMAN_RETRIES;
+        assert!(info.is_terrible(NOW + 120));
+        // Unless just tried
+        info.last_try = NOW + 100;
+        assert!(!info.is_terrible(NOW + 120));
+        assert!(info.chance(NOW + 120) < 0.01);
+        assert_eq!(address_group(&AddrV2::Ipv4([1, 2, 3, 4])), vec![1, 1, 2]);
+    }
+}
@@ -0,0 +1,174 @@
+//! addrv2 (BIP155): addresses of any length, tagged with their network
+//!
+//! Legacy addr messages carry every address as 16 bytes, which fits IPv4
+//! (as an IPv4-mapped IPv6 address) and IPv6 but not Tor v3 or I2P, and
+//! leaves CJDNS indistinguishable from IPv6.
+//! addrv2 entries name their network, so those can be relayed too.
+
+use super::{NetworkAddress, ServiceFlags};
+
+/// Longest address accepted in an addrv2 entry
+pub const MAX_ADDRV2_SIZE: usize = 512;
+
+/// Prefix of an IPv4 address mapped into IPv6 (::ffff:0:0/96)
+const IPV4_MAPPED_PREFIX: [u8; 12] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff];
+
+/// First byte of every CJDNS address (fc00::/8)
+const CJDNS_PREFIX: u8 = 0xfc;
+
+/// Network identifiers of BIP155
+pub const NET_IPV4: u8 = 1;
+pub const NET_IPV6: u8 = 2;
+pub const NET_TORV3: u8 = 4;
+pub const NET_I2P: u8 = 5;
+pub const NET_CJDNS: u8 = 6;
+
+/// Address on one of the networks addrv2 can describe
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AddrV2 {
+    Ipv4([u8; 4]),
+    Ipv6([u8; 16]),
+    /// Ed25519 public key of a Tor v3 onion service
+    TorV3([u8; 32]),
+    /// SHA256 of an I2P destination
+    I2p([u8; 32]),
+    /// CJDNS address, within fc00::/8
+    Cjdns([u8; 16]),
+}
+
+impl AddrV2 {
+    /// BIP155 network identifier
+    pub fn network_id(&self) -> u8 {
+        match self {
+            AddrV2::Ipv4(_) => NET_IPV4,
+            AddrV2::Ipv6(_) => NET_IPV6,
+            AddrV2::TorV3(_) => NET_TORV3,
+            AddrV2::I2p(_) => NET_I2P,
+            AddrV2::Cjdns(_) => NET_CJDNS,
+        }
+    }
+
+    /// Address bytes as sent on the wire
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            AddrV2::Ipv4(addr) => addr,
+            AddrV2::Ipv6(addr) | AddrV2::Cjdns(addr) => addr,
+            AddrV2::TorV3(addr) | AddrV2::I2p(addr) => addr,
+        }
+    }
+
+    /// Address from its network identifier and bytes
+    ///
+    /// Returns `Ok(None)` for addresses to skip: those on networks this
+    /// implementation does not know, which BIP155 says to ignore, and CJDNS
+    /// addresses outside fc00::/8, which are invalid. A known network with
+    /// an address of the wrong length is an error.
+    pub fn from_network_id(network_id: u8, bytes: &[u8]) -> std::result::Result<Option<AddrV2>, String> {
+        let wrong_length = || format!("Address of {} bytes for network {}", bytes.len(), network_id);
+        let addr = match network_id {
+            NET_IPV4 => AddrV2::Ipv4(bytes.try_into().map_err(|_| wrong_length())?),
+            NET_IPV6 => AddrV2::Ipv6(bytes.try_into().map_err(|_| wrong_length())?),
+            NET_TORV3 => AddrV2::TorV3(bytes.try_into().map_err(|_| wrong_length())?),
+            NET_I2P => AddrV2::I2p(bytes.try_into().map_err(|_| wrong_length())?),
+            NET_CJDNS => {
+                let addr: [u8; 16] = bytes.try_into().map_err(|_| wrong_length())?;
+                if addr[0] != CJDNS_PREFIX {
+                    return Ok(None);
+                }
+                AddrV2::Cjdns(addr)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(addr))
+    }
+
+    /// Address stored in a legacy 16-byte field
+    ///
+    /// IPv4-mapped addresses become IPv4; everything else is IPv6, since a
+    /// legacy address cannot say it is CJDNS.
+    pub fn from_legacy(ip: &[u8; 16]) -> AddrV2 {
+        if ip[..12] == IPV4_MAPPED_PREFIX {
+            AddrV2::Ipv4(ip[12..].try_into().unwrap())
+        } else {
+            AddrV2::Ipv6(*ip)
+        }
+    }
+
+    /// The legacy 16-byte form, if the network has one; Tor v3 and I2P
+    /// addresses do not fit, and CJDNS addresses would read back as IPv6
+    pub fn to_legacy(&self) -> Option<[u8; 16]> {
+        match self {
+            AddrV2::Ipv4(addr) => {
+                let mut ip = [0u8; 16];
+                ip[..12].copy_from_slice(&IPV4_MAPPED_PREFIX);
+                ip[12..].copy_from_slice(addr);
+                Some(ip)
+            }
+            AddrV2::Ipv6(addr) => Some(*addr),
+            AddrV2::TorV3(_) | AddrV2::I2p(_) | AddrV2::Cjdns(_) => None,
+        }
+    }
+}
+
+/// An entry of an addrv2 message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrV2Entry {
+    /// Last time the address was seen, in seconds since the epoch
+    pub time: u32,
+    pub services: ServiceFlags,
+    pub addr: AddrV2,
+    pub port: u16,
+}
+
+impl NetworkAddress {
+    /// The address as an addrv2 entry
+    pub fn to_addr_v2(&self) -> AddrV2Entry {
+        AddrV2Entry { time: self.time, services: self.services, addr: AddrV2::from_legacy(&self.ip), port: self.port }
+    }
+
+    /// The legacy form of an addrv2 entry, if its network has one
+    pub fn from_addr_v2(entry: &AddrV2Entry) -> Option<NetworkAddress> {
+        Some(NetworkAddress { time: entry.time, services: entry.services, ip: entry.addr.to_legacy()?, port: entry.port })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addr_v2_legacy_conversion() {
+        let mut ipv4 = [0u8; 16];
+        ipv4[10..].copy_from_slice(&[0xff, 0xff, 127, 0, 0, 1]);
+        let legacy = NetworkAddress { time: 5, services: ServiceFlags::NETWORK, ip: ipv4, port: 8333 };
+        let entry = legacy.to_addr_v2();
+        assert_eq!(entry.addr, AddrV2::Ipv4([127, 0, 0, 1]));
+        assert_eq!(NetworkAddress::from_addr_v2(&entry), Some(legacy));
+
+        let ipv6 = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert_eq!(AddrV2::from_legacy(&ipv6), AddrV2::Ipv6(ipv6));
+        let mut cjdns = [0u8; 16];
+        cjdns[0] = 0xfc;
+
+        // Overlay networks have no legacy form
+        assert_eq!(AddrV2::Cjdns(cjdns).to_legacy(), None);
+        let onion = AddrV2Entry { time: 5, services: ServiceFlags::NETWORK, addr: AddrV2::TorV3([7; 32]), port: 9050 };
+        assert_eq!(NetworkAddress::from_addr_v2(&onion), None);
+        assert_eq!(AddrV2::I2p([1; 32]).to_legacy(), None);
+    }
+
+    #[test]
+    fn test_addr_v2_from_network_id() {
+        assert_eq!(AddrV2::from_network_id(NET_IPV4, &[1, 2, 3, 4]), Ok(Some(AddrV2::Ipv4([1, 2, 3, 4]))));
+        assert_eq!(AddrV2::from_network_id(NET_I2P, &[9; 32]).unwrap().unwrap().as_bytes(), &[9; 32]);
+        assert!(AddrV2::from_network_id(NET_TORV3, &[0; 16]).is_err());
+        // CJDNS addresses must be within fc00::/8
+        let mut cjdns = [1u8; 16];
+        assert_eq!(AddrV2::from_network_id(NET_CJDNS, &cjdns), Ok(None));
+        cjdns[0] = 0xfc;
+        assert_eq!(AddrV2::from_network_id(NET_CJDNS, &cjdns), Ok(Some(AddrV2::Cjdns(cjdns))));
+        // Tor v2 (3) is retired and unknown networks are skipped
+        assert_eq!(AddrV2::from_network_id(3, &[0; 10]), Ok(None));
+        assert_eq!(AddrV2::from_network_id(42, &[0; 3]), Ok(None));
+    }
+}
@@ -0,0 +1,325 @@
+//! Connection bloom filters (BIP37)
+//!
+//! A light client loads a bloom filter on its peer; the peer then sends
+//! only transactions matching it, and blocks as merkle blocks proving
+//! which of their transactions matched.
+
+use crate::types::*;
+use crate::error::{ConsensusError, Result};
+use crate::merkle::PartialMerkleTree;
+use crate::policy::{classify_script, ScriptType};
+use crate::script::read_op;
+use crate::transaction::calculate_tx_id;
+use super::{FilterLoadMessage, MerkleBlockMessage};
+
+/// Largest filter a peer may load, in bytes
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+
+/// Most hash functions a filter may use
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// Largest element a filteradd may carry: the largest script push
+pub const MAX_FILTER_ADD_SIZE: usize = 520;
+
+/// Never add matched outpoints to the filter
+pub const BLOOM_UPDATE_NONE: u8 = 0;
+/// Add the outpoint of every output whose script matches
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+/// Add the outpoint only of matching pay-to-pubkey and bare multisig outputs
+pub const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+/// Bits of the flags that select the update mode
+pub const BLOOM_UPDATE_MASK: u8 = 3;
+
+const LN2SQUARED: f64 = 0.480_453_013_918_201_4;
+const LN2: f64 = std::f64::consts::LN_2;
+
+/// MurmurHash3 (x86, 32-bit) of `data` with `seed`
+pub fn murmur3_32(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mut h = seed;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let k = u32::from_le_bytes(chunk.try_into().unwrap())
+            .wrapping_mul(C1)
+            .rotate_left(15)
+            .wrapping_mul(C2);
+        h = (h ^ k).rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, &byte) in tail.iter().enumerate() {
+            k |= (byte as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^ (h >> 16)
+}
+
+/// BloomFilter: a bit field with `hash_funcs` seeded MurmurHash3 functions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    pub data: Vec<u8>,
+    pub hash_funcs: u32,
+    pub tweak: u32,
+    pub flags: u8,
+}
+
+impl BloomFilter {
+    /// A filter sized for `elements` entries with false positive rate
+    /// `fp_rate`, within the BIP37 size limits
+    ///
+    /// size = -elements · ln(fp_rate) / ln(2)² bits, and
+    /// hash_funcs = size / elements · ln(2).
+    pub fn new(elements: u32, fp_rate: f64, tweak: u32, flags: u8) -> Self {
+        let elements = elements.max(1);
+        let bits = (-1.0 / LN2SQUARED * elements as f64 * fp_rate.ln()) as u64;
+        let size = (bits.min(MAX_BLOOM_FILTER_SIZE as u64 * 8) / 8) as usize;
+        // As in Core, bits per element is rounded down before scaling
+        let hash_funcs = (((size * 8) as u64 / elements as u64) as f64 * LN2) as u32;
+        BloomFilter { data: vec![0; size], hash_funcs: hash_funcs.min(MAX_HASH_FUNCS), tweak, flags }
+    }
+
+    /// The filter a peer loaded, if within the size limits
+    pub fn from_filter_load(message: &FilterLoadMessage) -> Result<Self> {
+        if message.filter.len() > MAX_BLOOM_FILTER_SIZE || message.hash_funcs > MAX_HASH_FUNCS {
+            return Err(ConsensusError::Serialization("Bloom filter exceeds the size limits".to_string()));
+        }
+        Ok(BloomFilter {
+            data: message.filter.clone(),
+            hash_funcs: message.hash_funcs,
+            tweak: message.tweak,
+            flags: message.flags,
+        })
+    }
+
+    /// The filterload message for this filter
+    pub fn to_filter_load(&self) -> FilterLoadMessage {
+        FilterLoadMessage { filter: self.data.clone(), hash_funcs: self.hash_funcs, tweak: self.tweak, flags: self.flags }
+    }
+
+    /// Bit selected by hash function `n`
+    fn bit_index(&self, n: u32, element: &[u8]) -> usize {
+        let seed = n.wrapping_mul(0xfba4c795).wrapping_add(self.tweak);
+        murmur3_32(seed, element) as usize % (self.data.len() * 8)
+    }
+
+    pub fn insert(&mut self, element: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        for n in 0..self.hash_funcs {
+            let index = self.bit_index(n, element);
+            self.data[index >> 3] |= 1 << (index & 7);
+        }
+    }
+
+    /// Whether `element` may be in the filter; an empty filter matches
+    /// everything
+    pub fn contains(&self, element: &[u8]) -> bool {
+        if self.data.is_empty() {
+            return true;
+        }
+        (0..self.hash_funcs).all(|n| {
+            let index = self.bit_index(n, element);
+            self.data[index >> 3] & (1 << (index & 7)) != 0
+        })
+    }
+
+    pub fn insert_outpoint(&mut self, outpoint: &OutPoint) {
+        self.insert(&serialize_outpoint(outpoint));
+    }
+
+    pub fn contains_outpoint(&self, outpoint: &OutPoint) -> bool {
+        self.contains(&serialize_outpoint(outpoint))
+    }
+
+    /// IsRelevantAndUpdate: BloomFilter × 𝒯𝒳 → {true, false}
+    ///
+    /// 1. The txid matches, or a data push of an output script matches;
+    ///    for matching outputs the outpoint is added to the filter as the
+    ///    update flags say, so spends of it match too
+    /// 2. Otherwise an input's prevout or a data push of its scriptSig
+    ///    matches
+    pub fn is_relevant_and_update(&mut self, tx: &Transaction) -> bool {
+        if self.data.is_empty() {
+            return true;
+        }
+
+        // 1. Txid and outputs
+        let txid = calculate_tx_id(tx);
+        let mut found = self.contains(&txid);
+        for (index, output) in tx.outputs.iter().enumerate() {
+            if !self.any_push_matches(&output.script_pubkey) {
+                continue;
+            }
+            found = true;
+            let update = match self.flags & BLOOM_UPDATE_MASK {
+                BLOOM_UPDATE_ALL => true,
+                BLOOM_UPDATE_P2PUBKEY_ONLY => matches!(
+                    classify_script(&output.script_pubkey),
+                    ScriptType::PubKey | ScriptType::Multisig
+                ),
+                _ => false,
+            };
+            if update {
+                self.insert_outpoint(&OutPoint { hash: txid, index: index as Natural });
+            }
+        }
+        if found {
+            return true;
+        }
+
+        // 2. Inputs
+        tx.inputs.iter().any(|input| {
+            self.contains_outpoint(&input.prevout) || self.any_push_matches(&input.script_sig)
+        })
+    }
+
+    /// Whether any non-empty data push of `script` is in the filter
+    fn any_push_matches(&self, script: &[u8]) -> bool {
+        let mut pc = 0;
+        while let Some((_, data)) = read_op(script, &mut pc) {
+            if !data.is_empty() && self.contains(data) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Outpoint as inserted into filters: txid ‖ index (4 bytes, little-endian)
+fn serialize_outpoint(outpoint: &OutPoint) -> [u8; 36] {
+    let mut bytes = [0u8; 36];
+    bytes[..32].copy_from_slice(&outpoint.hash);
+    bytes[32..].copy_from_slice(&(outpoint.index as u32).to_le_bytes());
+    bytes
+}
+
+/// MerkleBlock: ℬ × BloomFilter → MerkleBlock × (ℕ × 𝒯𝒳)*
+///
+/// The header of `block` with a partial merkle tree proving the
+/// transactions relevant to `filter`, and those transactions with their
+/// positions. Matching updates the filter as `is_relevant_and_update`.
+pub fn merkle_block(block: &Block, filter: &mut BloomFilter) -> Result<(MerkleBlockMessage, Vec<(usize, Transaction)>)> {
+    let txids: Vec<Hash> = block.transactions.iter().map(calculate_tx_id).collect();
+    let matches: Vec<bool> = block.transactions.iter().map(|tx| filter.is_relevant_and_update(tx)).collect();
+    let tree = PartialMerkleTree::from_txids(&txids, &matches)?;
+    let matched = block.transactions.iter()
+        .enumerate()
+        .filter(|(index, _)| matches[*index])
+        .map(|(index, tx)| (index, tx.clone()))
+        .collect();
+    Ok((MerkleBlockMessage { header: block.header.clone(), tree }, matched))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::calculate_merkle_root;
+    use crate::merkle::verify_merkle_proof;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_murmur3_vectors() {
+        // From Bitcoin Core's hash tests
+        assert_eq!(murmur3_32(0, &[]), 0x00000000);
+        assert_eq!(murmur3_32(0xfba4c795, &[]), 0x6a396f08);
+        assert_eq!(murmur3_32(0xffffffff, &[]), 0x81f16f39);
+        assert_eq!(murmur3_32(0, &[0x00]), 0x514e28b7);
+        assert_eq!(murmur3_32(0xfba4c795, &[0x00]), 0xea3f0b17);
+        assert_eq!(murmur3_32(0, &[0xff]), 0xfd6cf10d);
+        assert_eq!(murmur3_32(0, &[0x00, 0x11]), 0x16c6b7ab);
+        assert_eq!(murmur3_32(0, &[0x00, 0x11, 0x22]), 0x8eb51c3d);
+        assert_eq!(murmur3_32(0, &[0x00, 0x11, 0x22, 0x33]), 0xb4471bf8);
+    }
+
+    #[test]
+    fn test_bloom_filter_insert_and_serialize() {
+        // Bitcoin Core's bloom_create_insert_serialize
+        let mut filter = BloomFilter::new(3, 0.01, 0, BLOOM_UPDATE_ALL);
+        filter.insert(&hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8"));
+        assert!(filter.contains(&hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+        assert!(!filter.contains(&hex("19108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+        filter.insert(&hex("b5a2c786d9ef4658287ced5914b37a1b4aa32eee"));
+        filter.insert(&hex("b9300670b4c5366e95b2699e8b18bc75e5f729c5"));
+        assert_eq!(filter.data, vec![0x61, 0x4e, 0x9b]);
+        assert_eq!(filter.hash_funcs, 5);
+
+        // Limits of a loaded filter
+        let mut load = filter.to_filter_load();
+        assert_eq!(BloomFilter::from_filter_load(&load).unwrap(), filter);
+        load.hash_funcs = MAX_HASH_FUNCS + 1;
+        assert!(BloomFilter::from_filter_load(&load).is_err());
+        assert_eq!(BloomFilter::new(1_000_000, 0.0001, 0, 0).data.len(), MAX_BLOOM_FILTER_SIZE);
+    }
+
+    #[test]
+    fn test_merkle_block_matches_and_updates() {
+        let pubkey = vec![0x02; 33];
+        let base = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [1; 32], index: 0 },
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: 1000, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        };
+        let other = |seed: u8| {
+            let mut tx = base.clone();
+            tx.inputs[0].prevout.hash = [seed; 32];
+            tx
+        };
+        let mut paying = other(9);
+        paying.outputs[0].script_pubkey = [vec![0x21], pubkey.clone(), vec![0xac]].concat();
+        let spending = Transaction {
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: calculate_tx_id(&paying), index: 0 },
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }],
+            ..other(0)
+        };
+        let transactions = vec![other(2), paying.clone(), other(3), spending.clone(), other(4)];
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_block_hash: [0; 32],
+                merkle_root: calculate_merkle_root(&transactions).unwrap(),
+                timestamp: 1231006505,
+                bits: 0x1d00ffff,
+                nonce: 0,
+            },
+            transactions,
+        };
+
+        // The key matches the payment, whose outpoint then matches its spend
+        let mut filter = BloomFilter::new(10, 0.000001, 7, BLOOM_UPDATE_P2PUBKEY_ONLY);
+        filter.insert(&pubkey);
+        let (message, matched) = merkle_block(&block, &mut filter).unwrap();
+        assert_eq!(matched.iter().map(|(index, _)| *index).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(
+            verify_merkle_proof(&block.header, &message.tree).unwrap(),
+            vec![calculate_tx_id(&paying), calculate_tx_id(&spending)]
+        );
+
+        // Without updates the spend goes unnoticed
+        let mut filter = BloomFilter::new(10, 0.000001, 7, BLOOM_UPDATE_NONE);
+        filter.insert(&pubkey);
+        assert_eq!(merkle_block(&block, &mut filter).unwrap().1.len(), 1);
+    }
+}
@@ -0,0 +1,284 @@
+//! Compact block relay (BIP152)
+//!
+//! A compact block carries the header, a few prefilled transactions and a
+//! 6-byte short id for every other transaction. The receiver rebuilds the
+//! block from its mempool and asks only for the transactions it lacks.
+//! Short ids are computed from txids (compact block version 1).
+
+use crate::types::*;
+use crate::error::{ConsensusError, Result};
+use crate::block::calculate_merkle_root_with_mutation;
+use crate::serialization::{serialize_header, siphash24};
+use crate::transaction::calculate_tx_id;
+use super::{BlockTxnMessage, CmpctBlockMessage, GetBlockTxnMessage, PrefilledTransaction};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Short ids are the low 48 bits of the SipHash
+pub const SHORT_ID_MASK: u64 = 0xffff_ffff_ffff;
+
+/// Most transactions a compact block may describe; Core caps indexes at
+/// 16 bits
+pub const MAX_COMPACT_BLOCK_TXS: usize = 0xffff;
+
+/// ShortIdKeys: ℋ × ℕ → ℕ × ℕ
+///
+/// The first two little-endian words of SHA256(header ‖ nonce).
+pub fn short_id_keys(header: &BlockHeader, nonce: u64) -> (u64, u64) {
+    let mut data = serialize_header(header);
+    data.extend_from_slice(&nonce.to_le_bytes());
+    let hash = Sha256::digest(&data);
+    (
+        u64::from_le_bytes(hash[0..8].try_into().unwrap()),
+        u64::from_le_bytes(hash[8..16].try_into().unwrap()),
+    )
+}
+
+/// ShortId: (ℕ × ℕ) × ℍ → ℕ
+///
+/// SipHash-2-4(k0, k1, txid), truncated to 48 bits.
+pub fn short_id(keys: (u64, u64), txid: &Hash) -> u64 {
+    siphash24(keys.0, keys.1, txid) & SHORT_ID_MASK
+}
+
+impl CmpctBlockMessage {
+    /// Describe `block` as a compact block keyed by `nonce`
+    ///
+    /// The coinbase is always prefilled, along with the transactions at
+    /// `prefill`; every other transaction is sent as its short id.
+    pub fn from_block(block: &Block, nonce: u64, prefill: &[usize]) -> Self {
+        let keys = short_id_keys(&block.header, nonce);
+        let mut short_ids = Vec::new();
+        let mut prefilled_txs = Vec::new();
+        for (index, tx) in block.transactions.iter().enumerate() {
+            if index == 0 || prefill.contains(&index) {
+                prefilled_txs.push(PrefilledTransaction { index: index as u64, tx: tx.clone() });
+            } else {
+                short_ids.push(short_id(keys, &calculate_tx_id(tx)));
+            }
+        }
+        CmpctBlockMessage { header: block.header.clone(), nonce, short_ids, prefilled_txs }
+    }
+}
+
+/// A block being rebuilt from a compact block: the header and, for every
+/// position, the transaction if it is known yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialBlock {
+    pub header: BlockHeader,
+    pub transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Positions still without a transaction, in increasing order
+    pub fn missing_indexes(&self) -> Vec<u64> {
+        self.transactions.iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u64)
+            .collect()
+    }
+
+    /// The getblocktxn request for the missing transactions
+    pub fn request(&self) -> GetBlockTxnMessage {
+        GetBlockTxnMessage { block_hash: self.header.block_hash(), indexes: self.missing_indexes() }
+    }
+
+    /// FillBlock: PartialBlock × BlockTxn → ℬ
+    ///
+    /// 1. The response must be for this block and hold exactly one
+    ///    transaction per missing position
+    /// 2. Place them at the missing positions in order
+    /// 3. Return the completed block
+    pub fn fill(mut self, response: &BlockTxnMessage) -> Result<Block> {
+        // 1. Match the response
+        let missing = self.missing_indexes();
+        if response.block_hash != self.header.block_hash() {
+            return Err(ConsensusError::BlockValidation("Block transactions are for another block".to_string()));
+        }
+        if response.transactions.len() != missing.len() {
+            return Err(ConsensusError::BlockValidation(format!(
+                "Expected {} block transactions, got {}", missing.len(), response.transactions.len()
+            )));
+        }
+
+        // 2. Fill the gaps
+        for (index, tx) in missing.into_iter().zip(&response.transactions) {
+            self.transactions[index as usize] = Some(tx.clone());
+        }
+
+        // 3. Complete
+        self.into_block()
+    }
+
+    /// The block, once every transaction is known
+    ///
+    /// Fails if a transaction is missing or the transactions do not match
+    /// the header's merkle root, which a short id collision can cause; the
+    /// full block should then be requested instead.
+    pub fn into_block(self) -> Result<Block> {
+        let transactions: Option<Vec<Transaction>> = self.transactions.into_iter().collect();
+        let transactions = transactions.ok_or_else(|| {
+            ConsensusError::BlockValidation("Compact block is missing transactions".to_string())
+        })?;
+        let (root, mutated) = calculate_merkle_root_with_mutation(&transactions)?;
+        if mutated || root != self.header.merkle_root {
+            return Err(ConsensusError::BlockValidation(
+                "Reconstructed block does not match its merkle root".to_string()
+            ));
+        }
+        Ok(Block { header: self.header, transactions })
+    }
+}
+
+/// ReconstructBlock: CmpctBlock × 𝒯𝒳* → PartialBlock
+///
+/// 1. Place the prefilled transactions at their positions
+/// 2. Assign the short ids, in order, to the remaining positions
+/// 3. For each mempool transaction whose short id matches, fill that
+///    position; two candidates for one position leave it empty
+///
+/// Invalid compact blocks (positions out of range or repeated, duplicate
+/// short ids) are errors. Missing positions are reported by
+/// `PartialBlock::missing_indexes`.
+pub fn reconstruct_block(compact: &CmpctBlockMessage, mempool: &[Transaction]) -> Result<PartialBlock> {
+    let total = compact.short_ids.len() + compact.prefilled_txs.len();
+    if total == 0 || total > MAX_COMPACT_BLOCK_TXS {
+        return Err(ConsensusError::BlockValidation(format!("Compact block with {} transactions", total)));
+    }
+
+    // 1. Prefilled transactions
+    let mut transactions: Vec<Option<Transaction>> = vec![None; total];
+    for prefilled in &compact.prefilled_txs {
+        let slot = transactions.get_mut(prefilled.index as usize).ok_or_else(|| {
+            ConsensusError::BlockValidation("Prefilled transaction index out of range".to_string())
+        })?;
+        if slot.is_some() {
+            return Err(ConsensusError::BlockValidation("Prefilled transaction index repeated".to_string()));
+        }
+        *slot = Some(prefilled.tx.clone());
+    }
+
+    // 2. Short ids for the remaining positions
+    let mut positions = HashMap::new();
+    let open = (0..total).filter(|&index| transactions[index].is_none());
+    for (&id, index) in compact.short_ids.iter().zip(open) {
+        if positions.insert(id, index).is_some() {
+            return Err(ConsensusError::BlockValidation("Duplicate short id in compact block".to_string()));
+        }
+    }
+
+    // 3. Mempool matches
+    let keys = short_id_keys(&compact.header, compact.nonce);
+    let mut collided = vec![false; total];
+    for tx in mempool {
+        if let Some(&index) = positions.get(&short_id(keys, &calculate_tx_id(tx))) {
+            if transactions[index].is_some() {
+                collided[index] = true;
+            } else {
+                transactions[index] = Some(tx.clone());
+            }
+        }
+    }
+    for (index, collided) in collided.into_iter().enumerate() {
+        if collided {
+            transactions[index] = None;
+        }
+    }
+
+    Ok(PartialBlock { header: compact.header.clone(), transactions })
+}
+
+/// BlockTransactions: ℬ × GetBlockTxn → BlockTxn
+///
+/// The transactions of `block` a peer asked for; fails if the request is
+/// for another block or an index is out of range.
+pub fn block_transactions(block: &Block, request: &GetBlockTxnMessage) -> Result<BlockTxnMessage> {
+    if request.block_hash != block.header.block_hash() {
+        return Err(ConsensusError::BlockValidation("Block transactions requested for another block".to_string()));
+    }
+    let transactions = request.indexes.iter()
+        .map(|&index| block.transactions.get(index as usize).cloned().ok_or_else(|| {
+            ConsensusError::BlockValidation(format!("Block transaction index {} out of range", index))
+        }))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(BlockTxnMessage { block_hash: request.block_hash, transactions })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::calculate_merkle_root;
+
+    fn tx(seed: u8) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [seed; 32], index: 0 },
+                script_sig: vec![seed],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: 1000, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        }
+    }
+
+    fn block(count: u8) -> Block {
+        let transactions: Vec<Transaction> = (0..count).map(tx).collect();
+        let header = BlockHeader {
+            version: 1,
+            prev_block_hash: [0xab; 32],
+            merkle_root: calculate_merkle_root(&transactions).unwrap(),
+            timestamp: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 0,
+        };
+        Block { header, transactions }
+    }
+
+    #[test]
+    fn test_reconstruct_compact_block() {
+        let block = block(6);
+        let compact = CmpctBlockMessage::from_block(&block, 77, &[3]);
+        assert_eq!(compact.prefilled_txs.iter().map(|p| p.index).collect::<Vec<_>>(), vec![0, 3]);
+        assert_eq!(compact.short_ids.len(), 4);
+        assert!(compact.short_ids.iter().all(|&id| id <= SHORT_ID_MASK));
+
+        // With the whole mempool the block rebuilds at once
+        let mempool: Vec<Transaction> = block.transactions[1..].iter().rev().cloned().collect();
+        let partial = reconstruct_block(&compact, &mempool).unwrap();
+        assert!(partial.missing_indexes().is_empty());
+        assert_eq!(partial.into_block().unwrap(), block);
+
+        // Otherwise the gaps are requested and filled
+        let mempool = vec![block.transactions[1].clone(), block.transactions[4].clone(), tx(99)];
+        let partial = reconstruct_block(&compact, &mempool).unwrap();
+        let request = partial.request();
+        assert_eq!(request.indexes, vec![2, 5]);
+        let response = block_transactions(&block, &request).unwrap();
+        assert_eq!(partial.clone().fill(&response).unwrap(), block);
+
+        // A wrong transaction in a gap fails the merkle check
+        let wrong = BlockTxnMessage { block_hash: request.block_hash, transactions: vec![tx(2), tx(98)] };
+        assert!(partial.clone().fill(&wrong).is_err());
+        let short = BlockTxnMessage { block_hash: request.block_hash, transactions: vec![tx(2)] };
+        assert!(partial.fill(&short).is_err());
+    }
+
+    #[test]
+    fn test_reconstruct_rejects_invalid_compact_blocks() {
+        let block = block(3);
+        let mut compact = CmpctBlockMessage::from_block(&block, 1, &[]);
+        compact.prefilled_txs[0].index = 3;
+        assert!(reconstruct_block(&compact, &[]).is_err());
+
+        let mut compact = CmpctBlockMessage::from_block(&block, 1, &[]);
+        compact.short_ids[1] = compact.short_ids[0];
+        assert!(reconstruct_block(&compact, &[]).is_err());
+
+        // Keys depend on the nonce, so short ids do too
+        let other = CmpctBlockMessage::from_block(&block, 2, &[]);
+        assert_ne!(other.short_ids, CmpctBlockMessage::from_block(&block, 1, &[]).short_ids);
+        assert!(block_transactions(&block, &GetBlockTxnMessage { block_hash: block.header.block_hash(), indexes: vec![3] }).is_err());
+    }
+}
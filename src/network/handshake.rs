@@ -0,0 +1,158 @@
+//! Version handshake
+//!
+//! A connection opens with each side sending version and answering the
+//! other's with verack. Between a peer's version and its verack come the
+//! feature negotiation messages (wtxidrelay, sendaddrv2), which are
+//! meaningless at any other time. sendcmpct must follow version too, but
+//! BIP152 lets it arrive after verack as well, and Bitcoin Core sends it
+//! there. Any other message is ignored until the handshake completes.
+
+use super::NetworkMessage;
+use thiserror::Error;
+
+/// Where a peer is in its half of the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HandshakeState {
+    /// Nothing received; version must come first
+    #[default]
+    AwaitingVersion,
+    /// Version received; feature negotiation is open until verack
+    AwaitingVerack,
+    /// Verack received
+    Complete,
+}
+
+/// Handshake message out of order
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum HandshakeViolation {
+    #[error("Duplicate version message")]
+    DuplicateVersion,
+    #[error("verack before version")]
+    VerackBeforeVersion,
+    #[error("Duplicate verack message")]
+    DuplicateVerack,
+    #[error("{0} before version")]
+    NegotiationBeforeVersion(&'static str),
+    #[error("{0} after verack")]
+    NegotiationAfterVerack(&'static str),
+}
+
+impl HandshakeState {
+    pub fn is_complete(&self) -> bool {
+        *self == HandshakeState::Complete
+    }
+
+    /// Transition: HandshakeState × NetworkMessage → HandshakeState ∪ {violation}
+    ///
+    /// 1. version: AwaitingVersion → AwaitingVerack; a second one is a
+    ///    violation
+    /// 2. verack: AwaitingVerack → Complete; before version or repeated it
+    ///    is a violation
+    /// 3. wtxidrelay, sendaddrv2: only while AwaitingVerack
+    /// 4. sendcmpct: any time after version
+    /// 5. Other messages leave the state unchanged
+    pub fn transition(self, message: &NetworkMessage) -> Result<HandshakeState, HandshakeViolation> {
+        use HandshakeState::*;
+        match (message, self) {
+            // 1. version
+            (NetworkMessage::Version(_), AwaitingVersion) => Ok(AwaitingVerack),
+            (NetworkMessage::Version(_), _) => Err(HandshakeViolation::DuplicateVersion),
+
+            // 2. verack
+            (NetworkMessage::VerAck, AwaitingVerack) => Ok(Complete),
+            (NetworkMessage::VerAck, AwaitingVersion) => Err(HandshakeViolation::VerackBeforeVersion),
+            (NetworkMessage::VerAck, Complete) => Err(HandshakeViolation::DuplicateVerack),
+
+            // 3. Negotiation
+            (NetworkMessage::WtxidRelay | NetworkMessage::SendAddrV2, AwaitingVerack) => Ok(self),
+            (NetworkMessage::WtxidRelay | NetworkMessage::SendAddrV2, AwaitingVersion) => {
+                Err(HandshakeViolation::NegotiationBeforeVersion(message.command()))
+            }
+            (NetworkMessage::WtxidRelay | NetworkMessage::SendAddrV2, Complete) => {
+                Err(HandshakeViolation::NegotiationAfterVerack(message.command()))
+            }
+
+            // 4. sendcmpct
+            (NetworkMessage::SendCmpct(_), AwaitingVersion) => {
+                Err(HandshakeViolation::NegotiationBeforeVersion(message.command()))
+            }
+
+            // 5. Everything else
+            _ => Ok(self),
+        }
+    }
+
+    /// Processes: HandshakeState × NetworkMessage → {true, false}
+    ///
+    /// Whether the message is acted on in this state, as in Bitcoin Core:
+    ///
+    /// 1. Before version, only version
+    /// 2. Before verack, only verack and the negotiation messages
+    /// 3. After verack, everything
+    pub fn processes(&self, message: &NetworkMessage) -> bool {
+        match self {
+            // 1. Before version
+            HandshakeState::AwaitingVersion => matches!(message, NetworkMessage::Version(_)),
+            // 2. Before verack
+            HandshakeState::AwaitingVerack => matches!(
+                message,
+                NetworkMessage::VerAck
+                    | NetworkMessage::WtxidRelay
+                    | NetworkMessage::SendAddrV2
+                    | NetworkMessage::SendCmpct(_)
+            ),
+            // 3. After verack
+            HandshakeState::Complete => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::SendCmpctMessage;
+
+    #[test]
+    fn test_handshake_transitions() {
+        let sendcmpct = NetworkMessage::SendCmpct(SendCmpctMessage { announce: false, version: 1 });
+        let state = HandshakeState::default();
+        assert_eq!(state.transition(&NetworkMessage::VerAck), Err(HandshakeViolation::VerackBeforeVersion));
+        assert_eq!(
+            state.transition(&NetworkMessage::WtxidRelay),
+            Err(HandshakeViolation::NegotiationBeforeVersion("wtxidrelay"))
+        );
+        assert!(state.transition(&sendcmpct).is_err());
+        assert_eq!(state.transition(&NetworkMessage::MemPool), Ok(state));
+
+        let state = HandshakeState::AwaitingVerack;
+        assert_eq!(state.transition(&NetworkMessage::SendAddrV2), Ok(state));
+        assert_eq!(state.transition(&sendcmpct), Ok(state));
+        let state = state.transition(&NetworkMessage::VerAck).unwrap();
+        assert!(state.is_complete());
+
+        assert_eq!(
+            state.transition(&NetworkMessage::SendAddrV2).unwrap_err().to_string(),
+            "sendaddrv2 after verack"
+        );
+        assert_eq!(state.transition(&NetworkMessage::VerAck), Err(HandshakeViolation::DuplicateVerack));
+        assert_eq!(state.transition(&sendcmpct), Ok(state));
+    }
+
+    #[test]
+    fn test_handshake_processes() {
+        let sendcmpct = NetworkMessage::SendCmpct(SendCmpctMessage { announce: false, version: 1 });
+        let state = HandshakeState::AwaitingVersion;
+        assert!(!state.processes(&NetworkMessage::MemPool));
+        assert!(!state.processes(&NetworkMessage::GetAddr));
+
+        let state = HandshakeState::AwaitingVerack;
+        assert!(state.processes(&NetworkMessage::VerAck));
+        assert!(state.processes(&NetworkMessage::WtxidRelay));
+        assert!(state.processes(&NetworkMessage::SendAddrV2));
+        assert!(state.processes(&sendcmpct));
+        assert!(!state.processes(&NetworkMessage::MemPool));
+        assert!(!state.processes(&NetworkMessage::FilterClear));
+
+        assert!(HandshakeState::Complete.processes(&NetworkMessage::MemPool));
+    }
+}
@@ -0,0 +1,254 @@
+//! Inventory announcement: known-inventory tracking and trickling
+//!
+//! Each peer remembers the inventory it already has, either because it
+//! announced it or because we did, so nothing is announced twice. New
+//! inventory is queued and sent in batches at exponentially distributed
+//! intervals, which hides the order and timing in which transactions
+//! reached this node.
+
+use crate::types::*;
+use super::{InvMessage, MSG_WTX, InventoryVector, NetworkMessage, PeerState};
+use crate::relay::{evaluate_tx_for_relay, RelayContext, RelayDecision};
+use crate::segwit::Witness;
+use crate::transaction::{calculate_tx_id, calculate_wtxid};
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, SystemTime};
+
+/// Inventory remembered per peer before the oldest is forgotten
+pub const DEFAULT_MAX_KNOWN_INVENTORY: usize = 50_000;
+
+/// Most items announced in one batch
+pub const INVENTORY_BROADCAST_MAX: usize = 1000;
+
+/// Average time between batches to inbound peers
+pub const INBOUND_INVENTORY_BROADCAST_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Average time between batches to outbound peers, shorter since we chose
+/// them
+pub const OUTBOUND_INVENTORY_BROADCAST_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Set of hashes that forgets the oldest once full
+#[derive(Debug, Clone)]
+pub struct KnownInventory {
+    hashes: HashSet<Hash>,
+    order: VecDeque<Hash>,
+    capacity: usize,
+}
+
+impl KnownInventory {
+    pub fn new(capacity: usize) -> Self {
+        Self { hashes: HashSet::new(), order: VecDeque::new(), capacity }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// Forget every hash
+    pub fn clear(&mut self) {
+        self.hashes.clear();
+        self.order.clear();
+    }
+
+    /// Remember `hash`, forgetting the oldest entry if full; returns
+    /// whether it was new
+    pub fn insert(&mut self, hash: Hash) -> bool {
+        if self.capacity == 0 || !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Exponential delay with the given mean, from a uniform random word
+///
+/// -ln(1 - u) · mean with u in [0, 1) taken from the top 53 bits.
+pub fn trickle_delay(mean: Duration, random: u64) -> Duration {
+    let u = (random >> 11) as f64 / (1u64 << 53) as f64;
+    Duration::from_secs_f64(mean.as_secs_f64() * -(-u).ln_1p())
+}
+
+/// A peer's inventory relay state: what it knows and what is waiting to
+/// be announced to it
+#[derive(Debug, Clone)]
+pub struct InventoryRelay {
+    pub known: KnownInventory,
+    queue: VecDeque<InventoryVector>,
+    queued: HashSet<Hash>,
+    /// When the next batch may be sent; `None` before the first
+    pub next_send: Option<SystemTime>,
+}
+
+impl InventoryRelay {
+    pub fn new() -> Self {
+        Self {
+            known: KnownInventory::new(DEFAULT_MAX_KNOWN_INVENTORY),
+            queue: VecDeque::new(),
+            queued: HashSet::new(),
+            next_send: None,
+        }
+    }
+
+    /// Items waiting to be announced
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Queue `item` for announcement unless the peer knows it or it is
+    /// already queued; returns whether it was queued
+    pub fn queue(&mut self, item: InventoryVector) -> bool {
+        if self.known.contains(&item.hash) || !self.queued.insert(item.hash) {
+            return false;
+        }
+        self.queue.push_back(item);
+        true
+    }
+
+    /// TakeBatch: InventoryRelay × 𝕋 × 𝕋 × ℕ → InvMessage?
+    ///
+    /// 1. Nothing before the scheduled time
+    /// 2. Schedule the next batch an exponential delay with mean `mean`
+    ///    after `now`
+    /// 3. Take up to `INVENTORY_BROADCAST_MAX` queued items in order,
+    ///    skipping any the peer has learnt of since, and mark them known
+    pub fn take_batch(&mut self, now: SystemTime, mean: Duration, random: u64) -> Option<InvMessage> {
+        // 1. Scheduled time
+        if self.next_send.is_some_and(|next| now < next) {
+            return None;
+        }
+
+        // 2. Next batch
+        self.next_send = Some(now + trickle_delay(mean, random));
+
+        // 3. Batch
+        let mut inventory = Vec::new();
+        while inventory.len() < INVENTORY_BROADCAST_MAX {
+            let Some(item) = self.queue.pop_front() else { break };
+            self.queued.remove(&item.hash);
+            if self.known.insert(item.hash) {
+                inventory.push(item);
+            }
+        }
+        if inventory.is_empty() {
+            None
+        } else {
+            Some(InvMessage { inventory })
+        }
+    }
+}
+
+impl Default for InventoryRelay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeerState {
+    /// Queue the announcement of a transaction paying `fee` for `vsize`
+    /// virtual bytes if `evaluate_tx_for_relay` decides to relay it to this
+    /// peer; returns the decision
+    ///
+    /// `context` carries the node's policy and recent rejects; the peer's
+    /// known inventory and fee filter replace its `known` and `fee_filter`.
+    /// The transaction is announced by wtxid to peers that negotiated
+    /// wtxid relay and by txid to the rest, and only once.
+    pub fn queue_tx_announcement(
+        &mut self,
+        tx: &Transaction,
+        witnesses: &[Witness],
+        fee: Integer,
+        vsize: Natural,
+        context: &RelayContext,
+    ) -> RelayDecision {
+        let context = RelayContext { known: Some(&self.inventory.known), fee_filter: self.min_fee_rate, ..context.clone() };
+        let decision = evaluate_tx_for_relay(tx, witnesses, fee, vsize, &context);
+        if decision == RelayDecision::Relay {
+            let item = if self.wtxid_relay {
+                InventoryVector { inv_type: MSG_WTX, hash: calculate_wtxid(tx, witnesses) }
+            } else {
+                InventoryVector { inv_type: 1, hash: calculate_tx_id(tx) }
+            };
+            self.inventory.queue(item);
+        }
+        decision
+    }
+
+    /// The inv message to send to this peer at `now`, if a batch is due
+    /// and anything is queued; `random` draws the delay to the next batch
+    pub fn announce_inventory(&mut self, now: SystemTime, random: u64) -> Option<NetworkMessage> {
+        let mean = if self.inbound {
+            INBOUND_INVENTORY_BROADCAST_INTERVAL
+        } else {
+            OUTBOUND_INVENTORY_BROADCAST_INTERVAL
+        };
+        self.inventory.take_batch(now, mean, random).map(NetworkMessage::Inv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx_inv(seed: u8) -> InventoryVector {
+        InventoryVector { inv_type: 1, hash: [seed; 32] }
+    }
+
+    #[test]
+    fn test_known_inventory_is_bounded() {
+        let mut known = KnownInventory::new(2);
+        assert!(known.insert([1; 32]));
+        assert!(!known.insert([1; 32]));
+        known.insert([2; 32]);
+        known.insert([3; 32]);
+        assert_eq!(known.len(), 2);
+        assert!(!known.contains(&[1; 32]));
+        assert!(known.contains(&[3; 32]));
+    }
+
+    #[test]
+    fn test_inventory_trickle_batches() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut peer_state = PeerState::new();
+        peer_state.inventory.known.insert([1; 32]);
+        assert!(!peer_state.inventory.queue(tx_inv(1)));
+        assert!(peer_state.inventory.queue(tx_inv(2)));
+        assert!(!peer_state.inventory.queue(tx_inv(2)));
+        assert!(peer_state.inventory.queue(tx_inv(3)));
+        peer_state.inventory.known.insert([3; 32]);
+
+        // The first batch goes at once and drops what became known
+        let random = u64::MAX / 2;
+        let sent = peer_state.announce_inventory(start, random);
+        assert_eq!(sent, Some(NetworkMessage::Inv(InvMessage { inventory: vec![tx_inv(2)] })));
+        assert!(!peer_state.inventory.queue(tx_inv(2)));
+
+        // The next waits for the drawn delay
+        let next = peer_state.inventory.next_send.unwrap();
+        assert_eq!(next, start + trickle_delay(OUTBOUND_INVENTORY_BROADCAST_INTERVAL, random));
+        assert!(peer_state.inventory.queue(tx_inv(4)));
+        assert_eq!(peer_state.announce_inventory(next - Duration::from_millis(1), random), None);
+        assert_eq!(peer_state.inventory.queued(), 1);
+        assert!(peer_state.announce_inventory(next, random).is_some());
+        assert_eq!(peer_state.inventory.queued(), 0);
+
+        // Delays are exponential: zero at u = 0, mean · ln 2 at u = 1/2
+        assert_eq!(trickle_delay(INBOUND_INVENTORY_BROADCAST_INTERVAL, 0), Duration::ZERO);
+        let median = trickle_delay(INBOUND_INVENTORY_BROADCAST_INTERVAL, 1 << 63).as_secs_f64();
+        assert!((median - 5.0 * std::f64::consts::LN_2).abs() < 1e-9);
+        assert!(trickle_delay(INBOUND_INVENTORY_BROADCAST_INTERVAL, u64::MAX).as_secs_f64().is_finite());
+    }
+}
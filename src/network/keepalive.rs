@@ -0,0 +1,91 @@
+//! Ping keepalive and timeout
+//!
+//! A ping is sent once the previous one is answered and the ping interval
+//! has passed; a peer that leaves a ping unanswered past the timeout
+//! interval should be disconnected. Every decision takes the current time
+//! as an argument, so callers own the clock.
+
+use super::{NetworkMessage, PeerState, PingMessage};
+use std::time::{Duration, SystemTime};
+
+/// Time between pings to a peer
+pub const PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// Time a peer has to answer a ping
+pub const TIMEOUT_INTERVAL: Duration = Duration::from_secs(20 * 60);
+
+/// Time from `earlier` to `now`, zero if the clock went backwards
+fn elapsed(earlier: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(earlier).unwrap_or_default()
+}
+
+impl PeerState {
+    /// ShouldSendPing: PeerState × 𝕋 → {true, false}
+    ///
+    /// No ping is outstanding and the last one, if any, was sent at least
+    /// the ping interval before `now`.
+    pub fn should_send_ping(&self, now: SystemTime) -> bool {
+        self.ping_nonce.is_none()
+            && self.ping_sent.is_none_or(|sent| elapsed(sent, now) >= PING_INTERVAL)
+    }
+
+    /// Record a ping sent at `now` and return the message to send
+    pub fn start_ping(&mut self, nonce: u64, now: SystemTime) -> NetworkMessage {
+        self.ping_nonce = Some(nonce);
+        self.ping_sent = Some(now);
+        NetworkMessage::Ping(PingMessage { nonce })
+    }
+
+    /// Record a pong received at `now`
+    ///
+    /// Returns whether it answered the outstanding ping; other nonces are
+    /// ignored and leave the ping outstanding.
+    pub fn receive_pong(&mut self, nonce: u64, now: SystemTime) -> bool {
+        if self.ping_nonce != Some(nonce) {
+            return false;
+        }
+        self.ping_nonce = None;
+        self.last_pong = Some(now);
+        true
+    }
+
+    /// HasTimedOut: PeerState × 𝕋 → {true, false}
+    ///
+    /// A ping is outstanding and was sent more than the timeout interval
+    /// before `now`.
+    pub fn has_timed_out(&self, now: SystemTime) -> bool {
+        match (self.ping_nonce, self.ping_sent) {
+            (Some(_), Some(sent)) => elapsed(sent, now) > TIMEOUT_INTERVAL,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_keepalive_and_timeout() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut peer_state = PeerState::new();
+        assert!(peer_state.should_send_ping(start));
+        assert_eq!(peer_state.start_ping(7, start), NetworkMessage::Ping(PingMessage { nonce: 7 }));
+
+        // Outstanding: no new ping, and a timeout only after the interval
+        assert!(!peer_state.should_send_ping(start + PING_INTERVAL));
+        assert!(!peer_state.has_timed_out(start + TIMEOUT_INTERVAL));
+        assert!(peer_state.has_timed_out(start + TIMEOUT_INTERVAL + Duration::from_secs(1)));
+
+        // Answered: the next ping waits for the interval
+        let answered = start + Duration::from_secs(5);
+        assert!(!peer_state.receive_pong(8, answered));
+        assert!(peer_state.receive_pong(7, answered));
+        assert_eq!(peer_state.last_pong, Some(answered));
+        assert!(!peer_state.has_timed_out(start + TIMEOUT_INTERVAL * 2));
+        assert!(!peer_state.should_send_ping(start + Duration::from_secs(60)));
+        assert!(peer_state.should_send_ping(start + PING_INTERVAL));
+        // A clock that went backwards counts as no time passing
+        assert!(!peer_state.should_send_ping(start - Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,95 @@
+//! Peer misbehavior scoring
+//!
+//! Each offense adds a penalty to the peer's score; once the score reaches
+//! the discouragement threshold the peer should be disconnected. Offenses
+//! that can only come from a broken or hostile peer reach it at once;
+//! those an honest peer may commit now and then take several repeats.
+
+use super::PeerState;
+
+/// Score at which a peer is disconnected and discouraged
+pub const DISCOURAGEMENT_THRESHOLD: u32 = 100;
+
+/// Offenses a peer can be penalized for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Misbehavior {
+    /// A header that fails validation
+    InvalidHeader,
+    /// A block that fails validation
+    InvalidBlock,
+    /// A compact block that cannot describe any block
+    InvalidCompactBlock,
+    /// A merkle block whose proof does not hold
+    InvalidMerkleBlock,
+    /// A message with more entries than the protocol allows
+    OversizedMessage,
+    /// A block that was never asked for
+    UnrequestedBlock,
+    /// Headers that do not connect to any known block
+    NonConnectingHeaders,
+    /// A message not allowed in the peer's state, e.g. filteradd without
+    /// a filter or sendaddrv2 after the handshake
+    ProtocolViolation,
+}
+
+impl Misbehavior {
+    /// Score added for the offense
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidHeader
+            | Misbehavior::InvalidBlock
+            | Misbehavior::InvalidCompactBlock
+            | Misbehavior::InvalidMerkleBlock
+            | Misbehavior::ProtocolViolation => DISCOURAGEMENT_THRESHOLD,
+            Misbehavior::OversizedMessage | Misbehavior::NonConnectingHeaders => 20,
+            Misbehavior::UnrequestedBlock => 10,
+        }
+    }
+}
+
+impl PeerState {
+    /// Misbehaving: PeerState × Misbehavior → PeerState × ℕ
+    ///
+    /// Add the offense's penalty to the score, saturating, and return the
+    /// new score.
+    pub fn misbehaving(&mut self, offense: Misbehavior) -> u32 {
+        self.misbehavior_score = self.misbehavior_score.saturating_add(offense.penalty());
+        self.misbehavior_score
+    }
+
+    /// ShouldDisconnect: PeerState → {true, false}
+    ///
+    /// Whether the peer was marked for disconnection or its score has
+    /// reached the discouragement threshold; peers marked `noban` are never
+    /// disconnected for misbehavior.
+    pub fn should_disconnect(&self) -> bool {
+        self.disconnect || (!self.noban && self.misbehavior_score >= DISCOURAGEMENT_THRESHOLD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_misbehavior_scoring() {
+        let mut peer_state = PeerState::new();
+        for _ in 0..4 {
+            peer_state.misbehaving(Misbehavior::OversizedMessage);
+        }
+        assert_eq!(peer_state.misbehavior_score, 80);
+        assert!(!peer_state.should_disconnect());
+        assert_eq!(peer_state.misbehaving(Misbehavior::UnrequestedBlock), 90);
+        assert_eq!(peer_state.misbehaving(Misbehavior::NonConnectingHeaders), 110);
+        assert!(peer_state.should_disconnect());
+
+        // One invalid header is enough, except for noban peers
+        let mut peer_state = PeerState::new();
+        peer_state.misbehaving(Misbehavior::InvalidHeader);
+        assert!(peer_state.should_disconnect());
+        peer_state.noban = true;
+        assert!(!peer_state.should_disconnect());
+        peer_state.misbehavior_score = u32::MAX;
+        assert_eq!(peer_state.misbehaving(Misbehavior::InvalidBlock), u32::MAX);
+    }
+}
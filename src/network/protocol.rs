@@ -0,0 +1,146 @@
+//! Protocol versions and service flags
+//!
+//! Peers announce the protocol version they speak and the services they
+//! offer in their version message; features are only used with peers
+//! whose version and services include them.
+
+use std::fmt;
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+
+/// Protocol version this implementation speaks; a peer's features are
+/// judged by the lower of its version and this one
+pub const PROTOCOL_VERSION: u32 = 70016;
+
+/// Oldest protocol version accepted from a peer
+pub const MIN_PEER_PROTO_VERSION: u32 = 70001;
+
+/// First version that understands feefilter (BIP133)
+pub const FEEFILTER_VERSION: u32 = 70013;
+
+/// First version that understands compact blocks (BIP152)
+pub const SHORT_IDS_BLOCKS_VERSION: u32 = 70014;
+
+/// Lowest protocol version that may negotiate wtxid relay (BIP339)
+pub const WTXID_RELAY_VERSION: u32 = 70016;
+
+/// Services a node offers, as announced in version and address messages
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ServiceFlags(u64);
+
+impl ServiceFlags {
+    /// No services
+    pub const NONE: ServiceFlags = ServiceFlags(0);
+    /// Serves the full block chain
+    pub const NETWORK: ServiceFlags = ServiceFlags(1 << 0);
+    /// Serves bloom-filtered blocks and transactions (BIP111)
+    pub const BLOOM: ServiceFlags = ServiceFlags(1 << 2);
+    /// Serves blocks and transactions with witness data (BIP144)
+    pub const WITNESS: ServiceFlags = ServiceFlags(1 << 3);
+    /// Serves compact block filters (BIP157)
+    pub const COMPACT_FILTERS: ServiceFlags = ServiceFlags(1 << 6);
+    /// Serves only the last 288 blocks (BIP159)
+    pub const NETWORK_LIMITED: ServiceFlags = ServiceFlags(1 << 10);
+    /// Supports the v2 encrypted transport (BIP324)
+    pub const P2P_V2: ServiceFlags = ServiceFlags(1 << 11);
+
+    /// Named flags, for display
+    const NAMES: [(ServiceFlags, &'static str); 6] = [
+        (ServiceFlags::NETWORK, "NETWORK"),
+        (ServiceFlags::BLOOM, "BLOOM"),
+        (ServiceFlags::WITNESS, "WITNESS"),
+        (ServiceFlags::COMPACT_FILTERS, "COMPACT_FILTERS"),
+        (ServiceFlags::NETWORK_LIMITED, "NETWORK_LIMITED"),
+        (ServiceFlags::P2P_V2, "P2P_V2"),
+    ];
+
+    /// Flags from their wire value; unknown bits are kept
+    pub const fn from_bits(bits: u64) -> ServiceFlags {
+        ServiceFlags(bits)
+    }
+
+    /// Wire value of the flags
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Whether every flag in `other` is set
+    pub const fn contains(self, other: ServiceFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: ServiceFlags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: ServiceFlags) {
+        self.0 &= !other.0;
+    }
+}
+
+impl From<u64> for ServiceFlags {
+    fn from(bits: u64) -> Self {
+        ServiceFlags(bits)
+    }
+}
+
+impl From<ServiceFlags> for u64 {
+    fn from(flags: ServiceFlags) -> Self {
+        flags.0
+    }
+}
+
+impl BitOr for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn bitor(self, rhs: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for ServiceFlags {
+    fn bitor_assign(&mut self, rhs: ServiceFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn bitand(self, rhs: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 & rhs.0)
+    }
+}
+
+/// Lists the set flags by name, e.g. `ServiceFlags(NETWORK|WITNESS)`, with
+/// unknown bits in hex
+impl fmt::Debug for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<String> = Self::NAMES.iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| name.to_string())
+            .collect();
+        let known = Self::NAMES.iter().fold(0, |bits, (flag, _)| bits | flag.0);
+        if self.0 & !known != 0 {
+            names.push(format!("{:#x}", self.0 & !known));
+        }
+        write!(f, "ServiceFlags({})", names.join("|"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_flags() {
+        let mut flags = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
+        assert_eq!(flags.bits(), 9);
+        assert!(flags.contains(ServiceFlags::WITNESS));
+        assert!(!flags.contains(ServiceFlags::WITNESS | ServiceFlags::COMPACT_FILTERS));
+        flags.insert(ServiceFlags::P2P_V2);
+        flags.remove(ServiceFlags::NETWORK);
+        assert_eq!(flags, ServiceFlags::from_bits(0x808));
+        assert_eq!(format!("{:?}", flags | ServiceFlags::from(1 << 24)), "ServiceFlags(WITNESS|P2P_V2|0x1000000)");
+        assert_eq!(format!("{:?}", ServiceFlags::NONE), "ServiceFlags()");
+    }
+}
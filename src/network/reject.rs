@@ -0,0 +1,147 @@
+//! Structured rejections and BIP61 reject messages
+//!
+//! Handlers refuse a message with a code, a human-readable reason and,
+//! for blocks and transactions, the hash of the refused object. Peers that
+//! still expect BIP61 can be told the same in a reject message.
+
+use crate::types::*;
+use super::{NetworkMessage, NetworkResponse, PeerState, RejectMessage};
+
+/// First protocol version that understands reject messages
+pub const REJECT_VERSION: u32 = 70002;
+
+/// Longest reason sent in a reject message
+pub const MAX_REJECT_MESSAGE_LENGTH: usize = 111;
+
+/// Reject codes of BIP61
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectCode {
+    Malformed,
+    Invalid,
+    Obsolete,
+    Duplicate,
+    Nonstandard,
+    Dust,
+    InsufficientFee,
+    Checkpoint,
+}
+
+impl RejectCode {
+    /// Code as sent on the wire
+    pub fn code(&self) -> u8 {
+        match self {
+            RejectCode::Malformed => 0x01,
+            RejectCode::Invalid => 0x10,
+            RejectCode::Obsolete => 0x11,
+            RejectCode::Duplicate => 0x12,
+            RejectCode::Nonstandard => 0x40,
+            RejectCode::Dust => 0x41,
+            RejectCode::InsufficientFee => 0x42,
+            RejectCode::Checkpoint => 0x43,
+        }
+    }
+
+    /// Code from its wire value, if BIP61 defines it
+    pub fn from_code(code: u8) -> Option<RejectCode> {
+        Some(match code {
+            0x01 => RejectCode::Malformed,
+            0x10 => RejectCode::Invalid,
+            0x11 => RejectCode::Obsolete,
+            0x12 => RejectCode::Duplicate,
+            0x40 => RejectCode::Nonstandard,
+            0x41 => RejectCode::Dust,
+            0x42 => RejectCode::InsufficientFee,
+            0x43 => RejectCode::Checkpoint,
+            _ => return None,
+        })
+    }
+}
+
+/// Why a message was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub code: RejectCode,
+    pub reason: String,
+    /// Hash of the refused block or transaction
+    pub hash: Option<Hash>,
+}
+
+impl Rejection {
+    pub fn new(code: RejectCode, reason: impl Into<String>) -> Self {
+        Rejection { code, reason: reason.into(), hash: None }
+    }
+
+    pub fn with_hash(mut self, hash: Hash) -> Self {
+        self.hash = Some(hash);
+        self
+    }
+
+    /// The BIP61 reject message refusing a message with `command`; the
+    /// reason is cut to `MAX_REJECT_MESSAGE_LENGTH` bytes
+    pub fn to_message(&self, command: &str) -> NetworkMessage {
+        let mut reason = self.reason.clone();
+        if reason.len() > MAX_REJECT_MESSAGE_LENGTH {
+            let mut end = MAX_REJECT_MESSAGE_LENGTH;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason.truncate(end);
+        }
+        NetworkMessage::Reject(RejectMessage {
+            message: command.to_string(),
+            code: self.code.code(),
+            reason,
+            hash: self.hash,
+        })
+    }
+}
+
+impl NetworkResponse {
+    /// A rejection without a hash
+    pub fn reject(code: RejectCode, reason: impl Into<String>) -> Self {
+        NetworkResponse::Reject(Rejection::new(code, reason))
+    }
+
+    /// The reject message to send for this response to `message`, for
+    /// peers that asked for them and understand them
+    pub fn reject_message(&self, message: &NetworkMessage, peer_state: &PeerState) -> Option<NetworkMessage> {
+        match self {
+            NetworkResponse::Reject(rejection) if peer_state.send_reject && peer_state.version >= REJECT_VERSION => {
+                Some(rejection.to_message(message.command()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::PingMessage;
+
+    #[test]
+    fn test_reject_message_for_legacy_peers() {
+        let rejection = Rejection::new(RejectCode::Invalid, "x".repeat(200)).with_hash([7; 32]);
+        let response = NetworkResponse::Reject(rejection);
+        let message = NetworkMessage::Ping(PingMessage { nonce: 1 });
+
+        let mut peer_state = PeerState::new();
+        peer_state.version = REJECT_VERSION;
+        assert!(response.reject_message(&message, &peer_state).is_none());
+        peer_state.send_reject = true;
+        let Some(NetworkMessage::Reject(reject)) = response.reject_message(&message, &peer_state) else {
+            panic!("expected a reject message");
+        };
+        assert_eq!(reject.message, "ping");
+        assert_eq!(RejectCode::from_code(reject.code), Some(RejectCode::Invalid));
+        assert_eq!(reject.reason.len(), MAX_REJECT_MESSAGE_LENGTH);
+        assert_eq!(reject.hash, Some([7; 32]));
+
+        // Peers from before BIP61, and responses that accept, get nothing
+        peer_state.version = REJECT_VERSION - 1;
+        assert!(response.reject_message(&message, &peer_state).is_none());
+        peer_state.version = REJECT_VERSION;
+        assert!(NetworkResponse::Ok.reject_message(&message, &peer_state).is_none());
+        assert_eq!(RejectCode::from_code(0x02), None);
+    }
+}
@@ -0,0 +1,103 @@
+//! Network-adjusted time
+//!
+//! Each peer's version message carries its clock, and the median offset
+//! between those clocks and ours corrects a local clock that is off by a
+//! little. The correction is bounded, and ignored when larger, so peers
+//! cannot move our idea of the time far enough to make us accept or
+//! reject blocks by their timestamps.
+
+use super::VersionMessage;
+use crate::types::Natural;
+
+/// Largest offset, in seconds, the peers' median may apply to our clock
+pub const DEFAULT_MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+
+/// Samples kept, counting our own zero offset; later peers are ignored
+pub const MAX_TIME_SAMPLES: usize = 200;
+
+/// Samples, counting our own, needed before any offset is applied
+pub const MIN_TIME_SAMPLES: usize = 5;
+
+/// Offset of a peer's clock from ours: its version timestamp minus our
+/// time when the message arrived, in seconds
+pub fn version_time_offset(version: &VersionMessage, now: Natural) -> i64 {
+    version.timestamp.saturating_sub(now.min(i64::MAX as u64) as i64)
+}
+
+/// TimeOffset: [ℤ] → ℤ
+///
+/// `offsets` holds one sample per peer, in the order the peers
+/// connected.
+///
+/// 1. Samples: our own offset of 0 followed by the peers', at most
+///    `MAX_TIME_SAMPLES` in all
+/// 2. The median is only taken over an odd number of samples, so with an
+///    even count the last peer's is left out
+/// 3. Fewer than `MIN_TIME_SAMPLES` samples: no offset
+/// 4. A median beyond ±`DEFAULT_MAX_TIME_ADJUSTMENT` is not applied
+pub fn time_offset(offsets: &[i64]) -> i64 {
+    // 1. Samples
+    let mut samples = vec![0];
+    samples.extend(offsets.iter().take(MAX_TIME_SAMPLES - 1));
+
+    // 2. Odd count
+    if samples.len() % 2 == 0 {
+        samples.pop();
+    }
+
+    // 3. Enough samples
+    if samples.len() < MIN_TIME_SAMPLES {
+        return 0;
+    }
+
+    // 4. Bounded median
+    samples.sort_unstable();
+    let median = samples[samples.len() / 2];
+    if median.abs() <= DEFAULT_MAX_TIME_ADJUSTMENT {
+        median
+    } else {
+        0
+    }
+}
+
+/// AdjustedTime: 𝕋 × ℤ → 𝕋
+///
+/// Our clock corrected by the offset, for `BlockValidationContext`.
+pub fn adjusted_time(now: Natural, offset: i64) -> Natural {
+    now.saturating_add_signed(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_offset() {
+        // Too few samples
+        assert_eq!(time_offset(&[]), 0);
+        assert_eq!(time_offset(&[60, 60, 60]), 0);
+
+        // Median of 0, 60, 60, 60, 60
+        assert_eq!(time_offset(&[60, 60, 60, 60]), 60);
+        // The sixth sample waits for a seventh
+        assert_eq!(time_offset(&[60, 60, 60, 60, -600]), 60);
+        assert_eq!(time_offset(&[60, 60, 60, 60, -600, -600]), 60);
+        assert_eq!(time_offset(&[60, 60, -600, -600]), 0);
+
+        // Beyond the bound nothing is applied
+        assert_eq!(time_offset(&[5000; 4]), 0);
+        assert_eq!(time_offset(&[-4200; 4]), -4200);
+
+        // Only the first peers count
+        let mut offsets = vec![30; MAX_TIME_SAMPLES - 1];
+        offsets.extend([-3000; 300]);
+        assert_eq!(time_offset(&offsets), 30);
+    }
+
+    #[test]
+    fn test_adjusted_time() {
+        assert_eq!(adjusted_time(1_000, -60), 940);
+        assert_eq!(adjusted_time(10, -60), 0);
+        assert_eq!(adjusted_time(1_000, time_offset(&[120; 6])), 1_120);
+    }
+}
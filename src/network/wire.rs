@@ -0,0 +1,1018 @@
+//! P2P wire format: every message is a 24-byte header followed by its
+//! payload
+//!
+//! message_start ‖ command ‖ length ‖ checksum ‖ payload, where the
+//! command is the NUL-padded ASCII name, the length is the payload size
+//! and the checksum is the first four bytes of Hash256(payload).
+
+use crate::types::*;
+use crate::error::ConsensusError;
+use crate::params::ChainParams;
+use crate::serialization::{
+    decode_varint, deserialize_transaction, double_sha256, encode_varint, serialize_block,
+    serialize_header, serialize_transaction, SerializationError,
+};
+use super::*;
+use super::compact_blocks::MAX_COMPACT_BLOCK_TXS;
+use super::addrv2::{AddrV2, AddrV2Entry, MAX_ADDRV2_SIZE};
+use super::bloom::{MAX_BLOOM_FILTER_SIZE, MAX_FILTER_ADD_SIZE};
+use super::reject::MAX_REJECT_MESSAGE_LENGTH;
+use crate::merkle::PartialMerkleTree;
+use thiserror::Error;
+
+/// Size of the message header
+pub const MESSAGE_HEADER_SIZE: usize = 24;
+
+/// Size of the NUL-padded command name
+pub const COMMAND_SIZE: usize = 12;
+
+/// Largest payload accepted from a peer
+pub const MAX_PROTOCOL_MESSAGE_LENGTH: usize = 4_000_000;
+
+/// Largest length prefix accepted for any vector or string
+pub const MAX_SIZE: u64 = 0x0200_0000;
+
+/// Most entries in an inv or getdata message
+pub const MAX_INV_SIZE: usize = 50_000;
+
+/// Most entries in an addr message
+pub const MAX_ADDR_TO_SEND: usize = 1000;
+
+/// Most headers in a headers message
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+
+/// Most hashes in a block locator
+pub const MAX_LOCATOR_SIZE: usize = 101;
+
+/// Longest user agent in a version message
+pub const MAX_SUBVERSION_LENGTH: usize = 256;
+
+/// Why bytes from a peer could not be decoded into a message
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer does not yet hold the whole message
+    #[error("Incomplete message: {needed} more bytes needed")]
+    Incomplete { needed: usize },
+
+    #[error("Message start {0:02x?} is not this network's")]
+    WrongNetwork([u8; 4]),
+
+    #[error("Malformed command name")]
+    InvalidCommand,
+
+    /// A well-formed message this implementation does not know; it can be
+    /// skipped using the length in its header
+    #[error("Unknown command {0:?}")]
+    UnknownCommand(String),
+
+    #[error("Payload of {size} bytes exceeds {max}")]
+    PayloadTooLarge { size: usize, max: usize },
+
+    #[error("Payload checksum mismatch")]
+    BadChecksum,
+
+    #[error("{count} {field} exceed the limit of {max}")]
+    TooManyItems { field: &'static str, count: u64, max: u64 },
+
+    #[error("Non-canonical varint")]
+    NonCanonicalVarint,
+
+    /// The payload ends in the middle of a field
+    #[error("Truncated payload")]
+    Truncated,
+
+    #[error("{0} bytes left over after the payload")]
+    TrailingData(usize),
+
+    #[error("Malformed payload: {0}")]
+    Malformed(String),
+}
+
+impl From<DecodeError> for ConsensusError {
+    fn from(error: DecodeError) -> Self {
+        ConsensusError::Serialization(error.to_string())
+    }
+}
+
+/// MessageHeader: the 24 bytes in front of every payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageHeader {
+    pub command: String,
+    pub length: u32,
+    pub checksum: [u8; 4],
+}
+
+impl NetworkMessage {
+    /// Command name of the message on the wire
+    pub fn command(&self) -> &'static str {
+        match self {
+            NetworkMessage::Version(_) => "version",
+            NetworkMessage::VerAck => "verack",
+            NetworkMessage::GetAddr => "getaddr",
+            NetworkMessage::Addr(_) => "addr",
+            NetworkMessage::Inv(_) => "inv",
+            NetworkMessage::GetData(_) => "getdata",
+            NetworkMessage::NotFound(_) => "notfound",
+            NetworkMessage::GetHeaders(_) => "getheaders",
+            NetworkMessage::Headers(_) => "headers",
+            NetworkMessage::Block(_) => "block",
+            NetworkMessage::Tx(_) => "tx",
+            NetworkMessage::Ping(_) => "ping",
+            NetworkMessage::Pong(_) => "pong",
+            NetworkMessage::MemPool => "mempool",
+            NetworkMessage::FeeFilter(_) => "feefilter",
+            NetworkMessage::Reject(_) => "reject",
+            NetworkMessage::SendCmpct(_) => "sendcmpct",
+            NetworkMessage::CmpctBlock(_) => "cmpctblock",
+            NetworkMessage::GetBlockTxn(_) => "getblocktxn",
+            NetworkMessage::BlockTxn(_) => "blocktxn",
+            NetworkMessage::AddrV2(_) => "addrv2",
+            NetworkMessage::SendAddrV2 => "sendaddrv2",
+            NetworkMessage::WtxidRelay => "wtxidrelay",
+            NetworkMessage::FilterLoad(_) => "filterload",
+            NetworkMessage::FilterAdd(_) => "filteradd",
+            NetworkMessage::FilterClear => "filterclear",
+            NetworkMessage::MerkleBlock(_) => "merkleblock",
+            NetworkMessage::GetCFilters(_) => "getcfilters",
+            NetworkMessage::CFilter(_) => "cfilter",
+            NetworkMessage::GetCFHeaders(_) => "getcfheaders",
+            NetworkMessage::CFHeaders(_) => "cfheaders",
+        }
+    }
+
+    /// Serialize the message payload, without the header
+    ///
+    /// Blocks and transactions are written without witness data.
+    pub fn encode_payload(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            NetworkMessage::Version(version) => {
+                bytes.extend_from_slice(&version.version.to_le_bytes());
+                bytes.extend_from_slice(&version.services.bits().to_le_bytes());
+                bytes.extend_from_slice(&version.timestamp.to_le_bytes());
+                write_address(&mut bytes, &version.addr_recv, false);
+                write_address(&mut bytes, &version.addr_from, false);
+                bytes.extend_from_slice(&version.nonce.to_le_bytes());
+                bytes.extend_from_slice(&encode_varint(version.user_agent.len() as u64));
+                bytes.extend_from_slice(version.user_agent.as_bytes());
+                bytes.extend_from_slice(&version.start_height.to_le_bytes());
+                bytes.push(version.relay as u8);
+            }
+            NetworkMessage::VerAck
+            | NetworkMessage::GetAddr
+            | NetworkMessage::MemPool
+            | NetworkMessage::SendAddrV2
+            | NetworkMessage::WtxidRelay
+            | NetworkMessage::FilterClear => {}
+            NetworkMessage::Addr(addr) => {
+                bytes.extend_from_slice(&encode_varint(addr.addresses.len() as u64));
+                for address in &addr.addresses {
+                    write_address(&mut bytes, address, true);
+                }
+            }
+            NetworkMessage::AddrV2(addrv2) => {
+                bytes.extend_from_slice(&encode_varint(addrv2.addresses.len() as u64));
+                for entry in &addrv2.addresses {
+                    bytes.extend_from_slice(&entry.time.to_le_bytes());
+                    bytes.extend_from_slice(&encode_varint(entry.services.bits()));
+                    bytes.push(entry.addr.network_id());
+                    bytes.extend_from_slice(&encode_varint(entry.addr.as_bytes().len() as u64));
+                    bytes.extend_from_slice(entry.addr.as_bytes());
+                    bytes.extend_from_slice(&entry.port.to_be_bytes());
+                }
+            }
+            NetworkMessage::Inv(InvMessage { inventory })
+            | NetworkMessage::GetData(GetDataMessage { inventory })
+            | NetworkMessage::NotFound(NotFoundMessage { inventory }) => {
+                bytes.extend_from_slice(&encode_varint(inventory.len() as u64));
+                for item in inventory {
+                    bytes.extend_from_slice(&item.inv_type.to_le_bytes());
+                    bytes.extend_from_slice(&item.hash);
+                }
+            }
+            NetworkMessage::GetHeaders(getheaders) => {
+                bytes.extend_from_slice(&getheaders.version.to_le_bytes());
+                bytes.extend_from_slice(&encode_varint(getheaders.block_locator_hashes.len() as u64));
+                for hash in &getheaders.block_locator_hashes {
+                    bytes.extend_from_slice(hash);
+                }
+                bytes.extend_from_slice(&getheaders.hash_stop);
+            }
+            NetworkMessage::Headers(headers) => {
+                bytes.extend_from_slice(&encode_varint(headers.headers.len() as u64));
+                for header in &headers.headers {
+                    bytes.extend_from_slice(&serialize_header(header));
+                    // Transaction count, always zero
+                    bytes.push(0);
+                }
+            }
+            NetworkMessage::Block(block) => bytes = serialize_block(block),
+            NetworkMessage::Tx(tx) => bytes = serialize_transaction(tx),
+            NetworkMessage::Ping(PingMessage { nonce })
+            | NetworkMessage::Pong(PongMessage { nonce }) => {
+                bytes.extend_from_slice(&nonce.to_le_bytes());
+            }
+            NetworkMessage::FeeFilter(feefilter) => {
+                bytes.extend_from_slice(&feefilter.feerate.to_le_bytes());
+            }
+            NetworkMessage::Reject(reject) => {
+                bytes.extend_from_slice(&encode_varint(reject.message.len() as u64));
+                bytes.extend_from_slice(reject.message.as_bytes());
+                bytes.push(reject.code);
+                bytes.extend_from_slice(&encode_varint(reject.reason.len() as u64));
+                bytes.extend_from_slice(reject.reason.as_bytes());
+                if let Some(hash) = reject.hash {
+                    bytes.extend_from_slice(&hash);
+                }
+            }
+            NetworkMessage::SendCmpct(sendcmpct) => {
+                bytes.push(sendcmpct.announce as u8);
+                bytes.extend_from_slice(&sendcmpct.version.to_le_bytes());
+            }
+            NetworkMessage::CmpctBlock(cmpctblock) => {
+                bytes.extend_from_slice(&serialize_header(&cmpctblock.header));
+                bytes.extend_from_slice(&cmpctblock.nonce.to_le_bytes());
+                bytes.extend_from_slice(&encode_varint(cmpctblock.short_ids.len() as u64));
+                for short_id in &cmpctblock.short_ids {
+                    bytes.extend_from_slice(&short_id.to_le_bytes()[..6]);
+                }
+                bytes.extend_from_slice(&encode_varint(cmpctblock.prefilled_txs.len() as u64));
+                let indexes: Vec<u64> = cmpctblock.prefilled_txs.iter().map(|prefilled| prefilled.index).collect();
+                for (diff, prefilled) in differential_indexes(&indexes).into_iter().zip(&cmpctblock.prefilled_txs) {
+                    bytes.extend_from_slice(&encode_varint(diff));
+                    bytes.extend_from_slice(&serialize_transaction(&prefilled.tx));
+                }
+            }
+            NetworkMessage::GetBlockTxn(getblocktxn) => {
+                bytes.extend_from_slice(&getblocktxn.block_hash);
+                bytes.extend_from_slice(&encode_varint(getblocktxn.indexes.len() as u64));
+                for diff in differential_indexes(&getblocktxn.indexes) {
+                    bytes.extend_from_slice(&encode_varint(diff));
+                }
+            }
+            NetworkMessage::FilterLoad(filterload) => {
+                bytes.extend_from_slice(&encode_varint(filterload.filter.len() as u64));
+                bytes.extend_from_slice(&filterload.filter);
+                bytes.extend_from_slice(&filterload.hash_funcs.to_le_bytes());
+                bytes.extend_from_slice(&filterload.tweak.to_le_bytes());
+                bytes.push(filterload.flags);
+            }
+            NetworkMessage::FilterAdd(filteradd) => {
+                bytes.extend_from_slice(&encode_varint(filteradd.data.len() as u64));
+                bytes.extend_from_slice(&filteradd.data);
+            }
+            NetworkMessage::MerkleBlock(merkleblock) => {
+                bytes.extend_from_slice(&serialize_header(&merkleblock.header));
+                bytes.extend_from_slice(&merkleblock.tree.num_transactions.to_le_bytes());
+                bytes.extend_from_slice(&encode_varint(merkleblock.tree.hashes.len() as u64));
+                for hash in &merkleblock.tree.hashes {
+                    bytes.extend_from_slice(hash);
+                }
+                // Flag bits, packed least significant bit first
+                let mut flags = vec![0u8; merkleblock.tree.bits.len().div_ceil(8)];
+                for (i, &bit) in merkleblock.tree.bits.iter().enumerate() {
+                    flags[i / 8] |= (bit as u8) << (i % 8);
+                }
+                bytes.extend_from_slice(&encode_varint(flags.len() as u64));
+                bytes.extend_from_slice(&flags);
+            }
+            NetworkMessage::GetCFilters(GetCFiltersMessage { filter_type, start_height, stop_hash })
+            | NetworkMessage::GetCFHeaders(GetCFHeadersMessage { filter_type, start_height, stop_hash }) => {
+                bytes.push(*filter_type);
+                bytes.extend_from_slice(&start_height.to_le_bytes());
+                bytes.extend_from_slice(stop_hash);
+            }
+            NetworkMessage::CFilter(cfilter) => {
+                bytes.push(cfilter.filter_type);
+                bytes.extend_from_slice(&cfilter.block_hash);
+                bytes.extend_from_slice(&encode_varint(cfilter.filter.len() as u64));
+                bytes.extend_from_slice(&cfilter.filter);
+            }
+            NetworkMessage::CFHeaders(cfheaders) => {
+                bytes.push(cfheaders.filter_type);
+                bytes.extend_from_slice(&cfheaders.stop_hash);
+                bytes.extend_from_slice(&cfheaders.previous_filter_header);
+                bytes.extend_from_slice(&encode_varint(cfheaders.filter_hashes.len() as u64));
+                for hash in &cfheaders.filter_hashes {
+                    bytes.extend_from_slice(hash);
+                }
+            }
+            NetworkMessage::BlockTxn(blocktxn) => {
+                bytes.extend_from_slice(&blocktxn.block_hash);
+                bytes.extend_from_slice(&encode_varint(blocktxn.transactions.len() as u64));
+                for tx in &blocktxn.transactions {
+                    bytes.extend_from_slice(&serialize_transaction(tx));
+                }
+            }
+        }
+        bytes
+    }
+
+    /// EncodeMessage: NetworkMessage × ChainParams → 𝕊
+    ///
+    /// 1. payload = EncodePayload(message)
+    /// 2. header = message_start ‖ command ‖ |payload| ‖ Checksum(payload)
+    /// 3. Return header ‖ payload
+    pub fn encode(&self, params: &ChainParams) -> Vec<u8> {
+        // 1. Payload
+        let payload = self.encode_payload();
+
+        // 2. Header
+        let mut bytes = Vec::with_capacity(MESSAGE_HEADER_SIZE + payload.len());
+        bytes.extend_from_slice(&params.message_start);
+        let mut command = [0u8; COMMAND_SIZE];
+        command[..self.command().len()].copy_from_slice(self.command().as_bytes());
+        bytes.extend_from_slice(&command);
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&checksum(&payload));
+
+        // 3. Payload
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+}
+
+impl MessageHeader {
+    /// DecodeHeader: 𝕊 × ChainParams → MessageHeader
+    ///
+    /// 1. The message must start with the network's magic bytes
+    /// 2. The command is printable ASCII followed only by NUL padding
+    /// 3. The payload length must not exceed MAX_PROTOCOL_MESSAGE_LENGTH
+    ///
+    /// Checking the header first lets a reader refuse an oversized
+    /// message before buffering its payload.
+    pub fn decode(bytes: &[u8], params: &ChainParams) -> std::result::Result<MessageHeader, DecodeError> {
+        if bytes.len() < MESSAGE_HEADER_SIZE {
+            return Err(DecodeError::Incomplete { needed: MESSAGE_HEADER_SIZE - bytes.len() });
+        }
+
+        // 1. Magic
+        let magic: [u8; 4] = bytes[..4].try_into().unwrap();
+        if magic != params.message_start {
+            return Err(DecodeError::WrongNetwork(magic));
+        }
+
+        // 2. Command
+        let command = &bytes[4..4 + COMMAND_SIZE];
+        let name_len = command.iter().position(|&b| b == 0).unwrap_or(COMMAND_SIZE);
+        if name_len == 0
+            || !command[..name_len].iter().all(|b| b.is_ascii_graphic())
+            || command[name_len..].iter().any(|&b| b != 0)
+        {
+            return Err(DecodeError::InvalidCommand);
+        }
+        let command = String::from_utf8(command[..name_len].to_vec()).map_err(|_| DecodeError::InvalidCommand)?;
+
+        // 3. Length
+        let length = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+        if length as usize > MAX_PROTOCOL_MESSAGE_LENGTH {
+            return Err(DecodeError::PayloadTooLarge { size: length as usize, max: MAX_PROTOCOL_MESSAGE_LENGTH });
+        }
+
+        Ok(MessageHeader { command, length, checksum: bytes[20..24].try_into().unwrap() })
+    }
+}
+
+impl NetworkMessage {
+    /// DecodeMessage: 𝕊 × ChainParams → NetworkMessage × ℕ
+    ///
+    /// 1. Decode and check the header
+    /// 2. Wait for the whole payload and check its checksum
+    /// 3. Decode the payload for the header's command
+    ///
+    /// Returns the message and the number of bytes it used. Every count
+    /// read from the peer is checked against its limit and the bytes left
+    /// before anything is allocated for it.
+    pub fn decode(bytes: &[u8], params: &ChainParams) -> std::result::Result<(NetworkMessage, usize), DecodeError> {
+        // 1. Header
+        let header = MessageHeader::decode(bytes, params)?;
+
+        // 2. Payload
+        let end = MESSAGE_HEADER_SIZE + header.length as usize;
+        if bytes.len() < end {
+            return Err(DecodeError::Incomplete { needed: end - bytes.len() });
+        }
+        let payload = &bytes[MESSAGE_HEADER_SIZE..end];
+        if checksum(payload) != header.checksum {
+            return Err(DecodeError::BadChecksum);
+        }
+
+        // 3. Message
+        Ok((Self::decode_payload(&header.command, payload)?, end))
+    }
+
+    /// Decode the payload of a message with the given command, which must
+    /// use every byte
+    pub fn decode_payload(command: &str, payload: &[u8]) -> std::result::Result<NetworkMessage, DecodeError> {
+        let mut reader = Reader { data: payload, pos: 0 };
+        let message = match command {
+            "version" => {
+                let version = reader.u32()?;
+                let services = ServiceFlags::from_bits(reader.u64()?);
+                let timestamp = reader.u64()? as i64;
+                let addr_recv = reader.address(false)?;
+                let addr_from = reader.address(false)?;
+                let nonce = reader.u64()?;
+                let user_agent_len = reader.count("user agent bytes", MAX_SUBVERSION_LENGTH, 1)?;
+                let user_agent = String::from_utf8(reader.bytes(user_agent_len)?.to_vec())
+                    .map_err(|_| DecodeError::Malformed("User agent is not UTF-8".to_string()))?;
+                let start_height = reader.u32()? as i32;
+                // Peers before BIP37 omit the relay flag, which then defaults on
+                let relay = reader.remaining() == 0 || reader.u8()? != 0;
+                NetworkMessage::Version(VersionMessage {
+                    version, services, timestamp, addr_recv, addr_from, nonce, user_agent, start_height, relay,
+                })
+            }
+            "verack" => NetworkMessage::VerAck,
+            "getaddr" => NetworkMessage::GetAddr,
+            "mempool" => NetworkMessage::MemPool,
+            "sendaddrv2" => NetworkMessage::SendAddrV2,
+            "wtxidrelay" => NetworkMessage::WtxidRelay,
+            "filterclear" => NetworkMessage::FilterClear,
+            "filterload" => {
+                let len = reader.count("filter bytes", MAX_BLOOM_FILTER_SIZE, 1)?;
+                let filter = reader.bytes(len)?.to_vec();
+                NetworkMessage::FilterLoad(FilterLoadMessage {
+                    filter,
+                    hash_funcs: reader.u32()?,
+                    tweak: reader.u32()?,
+                    flags: reader.u8()?,
+                })
+            }
+            "filteradd" => {
+                let len = reader.count("filter element bytes", MAX_FILTER_ADD_SIZE, 1)?;
+                NetworkMessage::FilterAdd(FilterAddMessage { data: reader.bytes(len)?.to_vec() })
+            }
+            "getcfilters" => NetworkMessage::GetCFilters(GetCFiltersMessage {
+                filter_type: reader.u8()?,
+                start_height: reader.u32()?,
+                stop_hash: reader.hash()?,
+            }),
+            "getcfheaders" => NetworkMessage::GetCFHeaders(GetCFHeadersMessage {
+                filter_type: reader.u8()?,
+                start_height: reader.u32()?,
+                stop_hash: reader.hash()?,
+            }),
+            "cfilter" => {
+                let filter_type = reader.u8()?;
+                let block_hash = reader.hash()?;
+                let len = reader.count("filter bytes", MAX_PROTOCOL_MESSAGE_LENGTH, 1)?;
+                NetworkMessage::CFilter(CFilterMessage { filter_type, block_hash, filter: reader.bytes(len)?.to_vec() })
+            }
+            "cfheaders" => {
+                let filter_type = reader.u8()?;
+                let stop_hash = reader.hash()?;
+                let previous_filter_header = reader.hash()?;
+                let count = reader.count("filter hashes", MAX_GETCFHEADERS_SIZE, 32)?;
+                let mut filter_hashes = Vec::new();
+                for _ in 0..count {
+                    filter_hashes.push(reader.hash()?);
+                }
+                NetworkMessage::CFHeaders(CFHeadersMessage { filter_type, stop_hash, previous_filter_header, filter_hashes })
+            }
+            "merkleblock" => {
+                let header = reader.header()?;
+                let num_transactions = reader.u32()?;
+                let count = reader.count("merkle hashes", MAX_PROTOCOL_MESSAGE_LENGTH / 32, 32)?;
+                let mut hashes = Vec::new();
+                for _ in 0..count {
+                    hashes.push(reader.hash()?);
+                }
+                let len = reader.count("merkle flag bytes", MAX_PROTOCOL_MESSAGE_LENGTH, 1)?;
+                let bits = reader.bytes(len)?.iter()
+                    .flat_map(|byte| (0..8).map(move |i| byte >> i & 1 == 1))
+                    .collect();
+                NetworkMessage::MerkleBlock(MerkleBlockMessage {
+                    header,
+                    tree: PartialMerkleTree { num_transactions, bits, hashes },
+                })
+            }
+            "addr" => {
+                let count = reader.count("addresses", MAX_ADDR_TO_SEND, 30)?;
+                let mut addresses = Vec::new();
+                for _ in 0..count {
+                    addresses.push(reader.address(true)?);
+                }
+                NetworkMessage::Addr(AddrMessage { addresses })
+            }
+            "addrv2" => {
+                let count = reader.count("addresses", MAX_ADDR_TO_SEND, 9)?;
+                let mut addresses = Vec::new();
+                for _ in 0..count {
+                    let time = reader.u32()?;
+                    let services = ServiceFlags::from_bits(reader.varint()?);
+                    let network_id = reader.u8()?;
+                    let len = reader.count("address bytes", MAX_ADDRV2_SIZE, 1)?;
+                    let addr = AddrV2::from_network_id(network_id, reader.bytes(len)?)
+                        .map_err(DecodeError::Malformed)?;
+                    let port = u16::from_be_bytes(reader.array()?);
+                    // Entries on unknown networks are skipped
+                    if let Some(addr) = addr {
+                        addresses.push(AddrV2Entry { time, services, addr, port });
+                    }
+                }
+                NetworkMessage::AddrV2(AddrV2Message { addresses })
+            }
+            "inv" => NetworkMessage::Inv(InvMessage { inventory: reader.inventory()? }),
+            "getdata" => NetworkMessage::GetData(GetDataMessage { inventory: reader.inventory()? }),
+            "notfound" => NetworkMessage::NotFound(NotFoundMessage { inventory: reader.inventory()? }),
+            "getheaders" => {
+                let version = reader.u32()?;
+                let count = reader.count("locator hashes", MAX_LOCATOR_SIZE, 32)?;
+                let mut block_locator_hashes = Vec::new();
+                for _ in 0..count {
+                    block_locator_hashes.push(reader.hash()?);
+                }
+                let hash_stop = reader.hash()?;
+                NetworkMessage::GetHeaders(GetHeadersMessage { version, block_locator_hashes, hash_stop })
+            }
+            "headers" => {
+                let count = reader.count("headers", MAX_HEADERS_RESULTS, 81)?;
+                let mut headers = Vec::new();
+                for _ in 0..count {
+                    headers.push(reader.header()?);
+                    if reader.varint()? != 0 {
+                        return Err(DecodeError::Malformed("Header with transactions".to_string()));
+                    }
+                }
+                NetworkMessage::Headers(HeadersMessage { headers })
+            }
+            "block" => {
+                let header = reader.header()?;
+                // The smallest transaction is 10 bytes
+                let count = reader.count("transactions", MAX_PROTOCOL_MESSAGE_LENGTH / 10, 10)?;
+                let mut transactions = Vec::new();
+                for _ in 0..count {
+                    transactions.push(reader.transaction()?);
+                }
+                NetworkMessage::Block(Block { header, transactions })
+            }
+            "tx" => NetworkMessage::Tx(reader.transaction()?),
+            "ping" => NetworkMessage::Ping(PingMessage { nonce: reader.u64()? }),
+            "pong" => NetworkMessage::Pong(PongMessage { nonce: reader.u64()? }),
+            "feefilter" => NetworkMessage::FeeFilter(FeeFilterMessage { feerate: reader.u64()? }),
+            "reject" => {
+                let message = reader.string("rejected command", COMMAND_SIZE)?;
+                let code = reader.u8()?;
+                let reason = reader.string("reject reason", MAX_REJECT_MESSAGE_LENGTH)?;
+                // Only block and transaction rejections carry a hash
+                let hash = if reader.remaining() >= 32 { Some(reader.hash()?) } else { None };
+                NetworkMessage::Reject(RejectMessage { message, code, reason, hash })
+            }
+            "sendcmpct" => NetworkMessage::SendCmpct(SendCmpctMessage {
+                announce: reader.u8()? != 0,
+                version: reader.u64()?,
+            }),
+            "cmpctblock" => {
+                let header = reader.header()?;
+                let nonce = reader.u64()?;
+                let count = reader.count("short ids", MAX_COMPACT_BLOCK_TXS, 6)?;
+                let mut short_ids = Vec::new();
+                for _ in 0..count {
+                    let mut id = [0u8; 8];
+                    id[..6].copy_from_slice(reader.bytes(6)?);
+                    short_ids.push(u64::from_le_bytes(id));
+                }
+                let count = reader.count("prefilled transactions", MAX_COMPACT_BLOCK_TXS, 11)?;
+                let mut prefilled_txs = Vec::new();
+                let mut next_index = 0;
+                for _ in 0..count {
+                    let index = reader.differential_index(&mut next_index)?;
+                    prefilled_txs.push(PrefilledTransaction { index, tx: reader.transaction()? });
+                }
+                NetworkMessage::CmpctBlock(CmpctBlockMessage { header, nonce, short_ids, prefilled_txs })
+            }
+            "getblocktxn" => {
+                let block_hash = reader.hash()?;
+                let count = reader.count("block transaction indexes", MAX_COMPACT_BLOCK_TXS, 1)?;
+                let mut indexes = Vec::new();
+                let mut next_index = 0;
+                for _ in 0..count {
+                    indexes.push(reader.differential_index(&mut next_index)?);
+                }
+                NetworkMessage::GetBlockTxn(GetBlockTxnMessage { block_hash, indexes })
+            }
+            "blocktxn" => {
+                let block_hash = reader.hash()?;
+                let count = reader.count("block transactions", MAX_COMPACT_BLOCK_TXS, 10)?;
+                let mut transactions = Vec::new();
+                for _ in 0..count {
+                    transactions.push(reader.transaction()?);
+                }
+                NetworkMessage::BlockTxn(BlockTxnMessage { block_hash, transactions })
+            }
+            other => return Err(DecodeError::UnknownCommand(other.to_string())),
+        };
+
+        if reader.remaining() != 0 {
+            return Err(DecodeError::TrailingData(reader.remaining()));
+        }
+        Ok(message)
+    }
+}
+
+/// Cursor over a payload; every read is bounds-checked
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn bytes(&mut self, len: usize) -> std::result::Result<&'a [u8], DecodeError> {
+        if len > self.remaining() {
+            return Err(DecodeError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> std::result::Result<[u8; N], DecodeError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> std::result::Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> std::result::Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> std::result::Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn hash(&mut self) -> std::result::Result<Hash, DecodeError> {
+        self.array()
+    }
+
+    fn varint(&mut self) -> std::result::Result<u64, DecodeError> {
+        let rest = &self.data[self.pos..];
+        let (value, len) = decode_varint(rest).map_err(|error| match error {
+            SerializationError::NonCanonicalVarint => DecodeError::NonCanonicalVarint,
+            _ => DecodeError::Truncated,
+        })?;
+        self.pos += len;
+        Ok(value)
+    }
+
+    /// A length-prefixed UTF-8 string of at most `max` bytes
+    fn string(&mut self, field: &'static str, max: usize) -> std::result::Result<String, DecodeError> {
+        let len = self.count(field, max, 1)?;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| DecodeError::Malformed(format!("Invalid UTF-8 in {}", field)))
+    }
+
+    /// Read a count of items that each take at least `min_item_size`
+    /// bytes, refusing more than `max` or than the payload could hold
+    fn count(&mut self, field: &'static str, max: usize, min_item_size: usize) -> std::result::Result<usize, DecodeError> {
+        let count = self.varint()?;
+        if count > max as u64 || count > MAX_SIZE {
+            return Err(DecodeError::TooManyItems { field, count, max: max as u64 });
+        }
+        let count = count as usize;
+        if count.saturating_mul(min_item_size) > self.remaining() {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn address(&mut self, with_time: bool) -> std::result::Result<NetworkAddress, DecodeError> {
+        let time = if with_time { self.u32()? } else { 0 };
+        let services = ServiceFlags::from_bits(self.u64()?);
+        let ip = self.array()?;
+        let port = u16::from_be_bytes(self.array()?);
+        Ok(NetworkAddress { time, services, ip, port })
+    }
+
+    fn inventory(&mut self) -> std::result::Result<Vec<InventoryVector>, DecodeError> {
+        let count = self.count("inventory entries", MAX_INV_SIZE, 36)?;
+        let mut inventory = Vec::new();
+        for _ in 0..count {
+            inventory.push(InventoryVector { inv_type: self.u32()?, hash: self.hash()? });
+        }
+        Ok(inventory)
+    }
+
+    fn header(&mut self) -> std::result::Result<BlockHeader, DecodeError> {
+        Ok(BlockHeader {
+            version: self.u32()? as i32 as Integer,
+            prev_block_hash: self.hash()?,
+            merkle_root: self.hash()?,
+            timestamp: self.u32()? as Natural,
+            bits: self.u32()? as Natural,
+            nonce: self.u32()? as Natural,
+        })
+    }
+
+    /// A differentially encoded position (BIP152): each is sent as its gap
+    /// from the one after the previous position
+    fn differential_index(&mut self, next_index: &mut u64) -> std::result::Result<u64, DecodeError> {
+        let index = next_index.checked_add(self.varint()?)
+            .filter(|&index| index < MAX_COMPACT_BLOCK_TXS as u64)
+            .ok_or_else(|| DecodeError::Malformed("Block transaction index out of range".to_string()))?;
+        *next_index = index + 1;
+        Ok(index)
+    }
+
+    /// A transaction in the legacy format
+    fn transaction(&mut self) -> std::result::Result<Transaction, DecodeError> {
+        let rest = &self.data[self.pos..];
+        let (tx, len) = deserialize_transaction(rest).map_err(|error| match error {
+            SerializationError::Truncated => DecodeError::Truncated,
+            SerializationError::NonCanonicalVarint => DecodeError::NonCanonicalVarint,
+            other => DecodeError::Malformed(other.to_string()),
+        })?;
+        self.pos += len;
+        Ok(tx)
+    }
+}
+
+/// Differential encoding of increasing positions (BIP152)
+fn differential_indexes(indexes: &[u64]) -> Vec<u64> {
+    let mut next_index = 0u64;
+    indexes.iter()
+        .map(|&index| {
+            let diff = index.wrapping_sub(next_index);
+            next_index = index.wrapping_add(1);
+            diff
+        })
+        .collect()
+}
+
+/// First four bytes of Hash256(payload)
+pub fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = double_sha256(payload);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// Write an address as services ‖ ip ‖ port, preceded by its time in addr
+/// messages; the port is big-endian
+fn write_address(bytes: &mut Vec<u8>, address: &NetworkAddress, with_time: bool) {
+    if with_time {
+        bytes.extend_from_slice(&address.time.to_le_bytes());
+    }
+    bytes.extend_from_slice(&address.services.bits().to_le_bytes());
+    bytes.extend_from_slice(&address.ip);
+    bytes.extend_from_slice(&address.port.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_verack() {
+        // An empty payload on mainnet
+        let bytes = NetworkMessage::VerAck.encode(&ChainParams::mainnet());
+        assert_eq!(bytes, vec![
+            0xf9, 0xbe, 0xb4, 0xd9,
+            b'v', b'e', b'r', b'a', b'c', b'k', 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0,
+            0x5d, 0xf6, 0xe0, 0xe2,
+        ]);
+        assert_eq!(&NetworkMessage::MemPool.encode(&ChainParams::regtest())[..4], &[0xfa, 0xbf, 0xb5, 0xda]);
+    }
+
+    #[test]
+    fn test_encode_message_layouts() {
+        let address = NetworkAddress { time: 7, services: ServiceFlags::NETWORK, ip: [0xaa; 16], port: 8333 };
+        let version = NetworkMessage::Version(VersionMessage {
+            version: 70016,
+            services: ServiceFlags::NETWORK,
+            timestamp: 1_700_000_000,
+            addr_recv: address.clone(),
+            addr_from: address.clone(),
+            nonce: 42,
+            user_agent: "/test:0.1/".to_string(),
+            start_height: 100,
+            relay: true,
+        });
+        let payload = version.encode_payload();
+        // 4 + 8 + 8 + 26 + 26 + 8 + (1 + 10) + 4 + 1; addresses carry no time
+        assert_eq!(payload.len(), 96);
+        assert_eq!(&payload[44..46], &[0x20, 0x8d]);
+        assert_eq!(payload[95], 1);
+
+        // Addr entries lead with their time
+        let addr = NetworkMessage::Addr(AddrMessage { addresses: vec![address] }).encode_payload();
+        assert_eq!(addr.len(), 1 + 30);
+        assert_eq!(&addr[1..5], &[7, 0, 0, 0]);
+
+        let header = BlockHeader {
+            version: 1,
+            prev_block_hash: [1; 32],
+            merkle_root: [2; 32],
+            timestamp: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 0,
+        };
+        let headers = NetworkMessage::Headers(HeadersMessage { headers: vec![header.clone(); 2] }).encode_payload();
+        assert_eq!(headers.len(), 1 + 2 * 81);
+        assert_eq!(headers[81], 0);
+
+        let block = Block { header, transactions: vec![] };
+        assert_eq!(NetworkMessage::Block(block.clone()).encode_payload(), serialize_block(&block));
+
+        // The header records the payload length and checksum
+        let ping = NetworkMessage::Ping(PingMessage { nonce: 0x0102030405060708 });
+        let bytes = ping.encode(&ChainParams::mainnet());
+        assert_eq!(&bytes[4..8], b"ping");
+        assert_eq!(&bytes[16..20], &[8, 0, 0, 0]);
+        assert_eq!(&bytes[20..24], &checksum(&bytes[24..]));
+        assert_eq!(&bytes[24..], &[8, 7, 6, 5, 4, 3, 2, 1]);
+    }
+
+    fn address(time: u32) -> NetworkAddress {
+        NetworkAddress { time, services: ServiceFlags::NETWORK, ip: [0xaa; 16], port: 8333 }
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        let params = ChainParams::testnet();
+        let header = BlockHeader {
+            version: 0x20000000,
+            prev_block_hash: [1; 32],
+            merkle_root: [2; 32],
+            timestamp: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 9,
+        };
+        let tx = Transaction {
+            version: 2,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [3; 32], index: 1 },
+                script_sig: vec![0x51; 3],
+                sequence: 0xfffffffe,
+            }],
+            outputs: vec![TransactionOutput { value: 5000, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        };
+        let inventory = vec![InventoryVector { inv_type: 2, hash: [4; 32] }];
+        let (header_copy, tx_copy) = (header.clone(), tx.clone());
+        // Flag bits come back padded to whole bytes
+        let mut tree = PartialMerkleTree::from_txids(&[[1; 32], [2; 32], [3; 32]], &[false, true, false]).unwrap();
+        tree.bits.resize(tree.bits.len().div_ceil(8) * 8, false);
+        let messages = vec![
+            NetworkMessage::Version(VersionMessage {
+                version: 70016,
+                services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+                timestamp: 1_700_000_000,
+                addr_recv: address(0),
+                addr_from: address(0),
+                nonce: 42,
+                user_agent: "/test:0.1/".to_string(),
+                start_height: 100,
+                relay: false,
+            }),
+            NetworkMessage::VerAck,
+            NetworkMessage::GetAddr,
+            NetworkMessage::Addr(AddrMessage { addresses: vec![address(7), address(8)] }),
+            NetworkMessage::Inv(InvMessage { inventory: inventory.clone() }),
+            NetworkMessage::NotFound(NotFoundMessage { inventory: inventory.clone() }),
+            NetworkMessage::GetData(GetDataMessage { inventory }),
+            NetworkMessage::GetHeaders(GetHeadersMessage {
+                version: 70016,
+                block_locator_hashes: vec![[5; 32], [6; 32]],
+                hash_stop: [0; 32],
+            }),
+            NetworkMessage::Headers(HeadersMessage { headers: vec![header.clone()] }),
+            NetworkMessage::Block(Block { header, transactions: vec![tx.clone(), tx.clone()] }),
+            NetworkMessage::Tx(tx),
+            NetworkMessage::Ping(PingMessage { nonce: 1 }),
+            NetworkMessage::Pong(PongMessage { nonce: 2 }),
+            NetworkMessage::MemPool,
+            NetworkMessage::FeeFilter(FeeFilterMessage { feerate: 1000 }),
+            NetworkMessage::Reject(RejectMessage { message: "tx".to_string(), code: 0x42, reason: "fee".to_string(), hash: Some([4; 32]) }),
+            NetworkMessage::Reject(RejectMessage { message: "version".to_string(), code: 0x11, reason: String::new(), hash: None }),
+            NetworkMessage::SendCmpct(SendCmpctMessage { announce: true, version: 1 }),
+            NetworkMessage::CmpctBlock(CmpctBlockMessage {
+                header: header_copy.clone(),
+                nonce: 3,
+                short_ids: vec![0xffff_ffff_ffff, 1],
+                prefilled_txs: vec![
+                    PrefilledTransaction { index: 0, tx: tx_copy.clone() },
+                    PrefilledTransaction { index: 3, tx: tx_copy.clone() },
+                ],
+            }),
+            NetworkMessage::GetBlockTxn(GetBlockTxnMessage { block_hash: [7; 32], indexes: vec![1, 2, 10] }),
+            NetworkMessage::BlockTxn(BlockTxnMessage { block_hash: [7; 32], transactions: vec![tx_copy] }),
+            NetworkMessage::SendAddrV2,
+            NetworkMessage::WtxidRelay,
+            NetworkMessage::FilterLoad(FilterLoadMessage { filter: vec![0x61, 0x4e, 0x9b], hash_funcs: 5, tweak: 9, flags: 1 }),
+            NetworkMessage::FilterAdd(FilterAddMessage { data: vec![0xaa; 20] }),
+            NetworkMessage::FilterClear,
+            NetworkMessage::GetCFilters(GetCFiltersMessage { filter_type: 0, start_height: 10, stop_hash: [8; 32] }),
+            NetworkMessage::CFilter(CFilterMessage { filter_type: 0, block_hash: [8; 32], filter: vec![0x01, 0x9d, 0xfc, 0xa8] }),
+            NetworkMessage::GetCFHeaders(GetCFHeadersMessage { filter_type: 0, start_height: 0, stop_hash: [8; 32] }),
+            NetworkMessage::CFHeaders(CFHeadersMessage {
+                filter_type: 0,
+                stop_hash: [8; 32],
+                previous_filter_header: [9; 32],
+                filter_hashes: vec![[1; 32], [2; 32]],
+            }),
+            NetworkMessage::MerkleBlock(MerkleBlockMessage {
+                header: header_copy.clone(),
+                tree,
+            }),
+            NetworkMessage::AddrV2(AddrV2Message { addresses: vec![
+                AddrV2Entry { time: 1, services: ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::NETWORK_LIMITED, addr: AddrV2::Ipv4([1, 2, 3, 4]), port: 8333 },
+                AddrV2Entry { time: 2, services: ServiceFlags::NETWORK, addr: AddrV2::I2p([5; 32]), port: 0 },
+            ] }),
+        ];
+
+        // Messages decode one after another from a single buffer
+        let stream: Vec<u8> = messages.iter().flat_map(|message| message.encode(&params)).collect();
+        let mut pos = 0;
+        for message in &messages {
+            let (decoded, len) = NetworkMessage::decode(&stream[pos..], &params).unwrap();
+            assert_eq!(&decoded, message);
+            pos += len;
+        }
+        assert_eq!(pos, stream.len());
+    }
+
+    #[test]
+    fn test_decode_framing_errors() {
+        let params = ChainParams::mainnet();
+        let bytes = NetworkMessage::Ping(PingMessage { nonce: 1 }).encode(&params);
+
+        assert_eq!(NetworkMessage::decode(&bytes[..10], &params), Err(DecodeError::Incomplete { needed: 14 }));
+        assert_eq!(NetworkMessage::decode(&bytes[..28], &params), Err(DecodeError::Incomplete { needed: 4 }));
+        assert_eq!(
+            NetworkMessage::decode(&bytes, &ChainParams::regtest()),
+            Err(DecodeError::WrongNetwork([0xf9, 0xbe, 0xb4, 0xd9]))
+        );
+
+        let mut corrupted = bytes.clone();
+        corrupted[30] ^= 1;
+        assert_eq!(NetworkMessage::decode(&corrupted, &params), Err(DecodeError::BadChecksum));
+
+        let mut bad_command = bytes.clone();
+        bad_command[9] = b'x';
+        assert_eq!(NetworkMessage::decode(&bad_command, &params), Err(DecodeError::InvalidCommand));
+
+        // An oversized length is refused from the header alone
+        let mut oversized = bytes[..MESSAGE_HEADER_SIZE].to_vec();
+        oversized[16..20].copy_from_slice(&(MAX_PROTOCOL_MESSAGE_LENGTH as u32 + 1).to_le_bytes());
+        assert_eq!(
+            MessageHeader::decode(&oversized, &params),
+            Err(DecodeError::PayloadTooLarge { size: MAX_PROTOCOL_MESSAGE_LENGTH + 1, max: MAX_PROTOCOL_MESSAGE_LENGTH })
+        );
+
+        let mut unknown = bytes.clone();
+        unknown[4..8].copy_from_slice(b"pang");
+        assert_eq!(NetworkMessage::decode(&unknown, &params), Err(DecodeError::UnknownCommand("pang".to_string())));
+    }
+
+    #[test]
+    fn test_decode_payload_limits() {
+        // Counts over the limit fail before any entries are read
+        let too_many = encode_varint(MAX_INV_SIZE as u64 + 1);
+        assert_eq!(
+            NetworkMessage::decode_payload("inv", &too_many),
+            Err(DecodeError::TooManyItems { field: "inventory entries", count: 50_001, max: 50_000 })
+        );
+        assert!(matches!(
+            NetworkMessage::decode_payload("block", &[[0; 80].as_slice(), &encode_varint(u64::MAX)].concat()),
+            Err(DecodeError::TooManyItems { .. })
+        ));
+
+        // A count within the limit must still fit in the payload
+        let mut short = encode_varint(2000);
+        short.extend_from_slice(&[0; 36]);
+        assert_eq!(NetworkMessage::decode_payload("getdata", &short), Err(DecodeError::Truncated));
+
+        assert_eq!(NetworkMessage::decode_payload("addr", &[0xfd, 0x01, 0x00]), Err(DecodeError::NonCanonicalVarint));
+        assert_eq!(NetworkMessage::decode_payload("ping", &[0; 9]), Err(DecodeError::TrailingData(1)));
+        assert_eq!(NetworkMessage::decode_payload("pong", &[0; 7]), Err(DecodeError::Truncated));
+
+        // addrv2 entries on unknown networks are dropped; wrong lengths are errors
+        let mut addrv2 = encode_varint(2);
+        addrv2.extend_from_slice(&[0, 0, 0, 0, 1, 42, 2, 0xaa, 0xbb, 0x20, 0x8d]);
+        addrv2.extend_from_slice(&[0, 0, 0, 0, 1, 1, 4, 10, 0, 0, 1, 0x20, 0x8d]);
+        let NetworkMessage::AddrV2(decoded) = NetworkMessage::decode_payload("addrv2", &addrv2).unwrap() else {
+            panic!("expected addrv2");
+        };
+        assert_eq!(decoded.addresses.len(), 1);
+        assert_eq!(decoded.addresses[0].addr, AddrV2::Ipv4([10, 0, 0, 1]));
+        addrv2[18] = 3;
+        assert!(matches!(NetworkMessage::decode_payload("addrv2", &addrv2), Err(DecodeError::Malformed(_))));
+
+        let mut headers = NetworkMessage::Headers(HeadersMessage { headers: vec![BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp: 0,
+            bits: 0,
+            nonce: 0,
+        }] }).encode_payload();
+        headers[81] = 1;
+        assert!(matches!(NetworkMessage::decode_payload("headers", &headers), Err(DecodeError::Malformed(_))));
+    }
+}
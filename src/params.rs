@@ -0,0 +1,282 @@
+//! Chain parameters: consensus values that differ between Bitcoin networks
+
+use crate::types::*;
+use std::collections::BTreeMap;
+
+/// Bitcoin network
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+/// Soft forks that are enforced from a fixed ("buried") height
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Deployment {
+    /// Height in coinbase, version ≥ 2
+    Bip34,
+    /// OP_CHECKLOCKTIMEVERIFY, version ≥ 4
+    Bip65,
+    /// Strict DER signatures, version ≥ 3
+    Bip66,
+    /// BIP68/112/113 relative lock times and MTP locktime
+    Csv,
+    /// BIP141/143/147 segregated witness
+    Segwit,
+    /// BIP340/341/342 Schnorr and Taproot
+    Taproot,
+}
+
+/// ChainParams: per-network consensus parameters
+///
+/// Validation functions that depend on the network take a `&ChainParams`
+/// instead of reading the mainnet constants directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    pub network: Network,
+    /// Magic bytes that start every P2P message on this network
+    pub message_start: [u8; 4],
+    /// Highest allowed target (lowest difficulty), in compact form
+    pub pow_limit_bits: Natural,
+    /// Testnet rule: a block more than 20 minutes after its parent may use
+    /// the minimum difficulty
+    pub pow_allow_min_difficulty_blocks: bool,
+    /// Regtest rule: difficulty is never adjusted
+    pub pow_no_retargeting: bool,
+    /// Least total work a header chain needs before it is worth syncing;
+    /// zero disables the check
+    pub minimum_chain_work: U256,
+    /// Height from which blocks must be version ≥ 2 and commit to their height
+    pub bip34_height: Natural,
+    /// Height from which blocks must be version ≥ 4 (CHECKLOCKTIMEVERIFY)
+    pub bip65_height: Natural,
+    /// Height from which blocks must be version ≥ 3 (strict DER signatures)
+    pub bip66_height: Natural,
+    /// Height from which BIP68/112/113 (relative lock times, MTP locktime) apply
+    pub csv_height: Natural,
+    /// Height from which segregated witness rules apply
+    pub segwit_height: Natural,
+    /// Height from which Taproot rules apply
+    pub taproot_height: Natural,
+    /// Known-good block hashes by height; headers conflicting with one are
+    /// rejected, and so are reorganizations that fork below the last one
+    pub checkpoints: BTreeMap<Natural, Hash>,
+}
+
+impl ChainParams {
+    /// Parameters for the given network
+    pub fn new(network: Network) -> Self {
+        match network {
+            Network::Mainnet => Self::mainnet(),
+            Network::Testnet => Self::testnet(),
+            Network::Signet => Self::signet(),
+            Network::Regtest => Self::regtest(),
+        }
+    }
+
+    /// Bitcoin mainnet
+    pub fn mainnet() -> Self {
+        ChainParams {
+            network: Network::Mainnet,
+            message_start: [0xf9, 0xbe, 0xb4, 0xd9],
+            pow_limit_bits: 0x1d00ffff,
+            pow_allow_min_difficulty_blocks: false,
+            pow_no_retargeting: false,
+            // As of Bitcoin Core 28.0
+            minimum_chain_work: work_from_be(&[0x88, 0xe1, 0x86, 0xb7, 0x0e, 0x08, 0x62, 0xc1, 0x93, 0xec, 0x44, 0xd6]),
+            bip34_height: 227_931,
+            bip65_height: 388_381,
+            bip66_height: 363_725,
+            csv_height: 419_328,
+            segwit_height: 481_824,
+            taproot_height: 709_632,
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    /// Testnet3
+    pub fn testnet() -> Self {
+        ChainParams {
+            network: Network::Testnet,
+            message_start: [0x0b, 0x11, 0x09, 0x07],
+            pow_limit_bits: 0x1d00ffff,
+            pow_allow_min_difficulty_blocks: true,
+            pow_no_retargeting: false,
+            // As of Bitcoin Core 28.0
+            minimum_chain_work: work_from_be(&[0x15, 0xf5, 0xe0, 0xc9, 0xf1, 0x34, 0x55, 0xb0, 0xeb]),
+            bip34_height: 21_111,
+            bip65_height: 581_885,
+            bip66_height: 330_776,
+            csv_height: 770_112,
+            segwit_height: 834_624,
+            // Activated through version bits rather than buried; as on
+            // mainnet, Taproot script rules are applied to every block
+            taproot_height: 0,
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    /// Default signet (BIP325)
+    pub fn signet() -> Self {
+        ChainParams {
+            network: Network::Signet,
+            message_start: [0x0a, 0x03, 0xcf, 0x40],
+            pow_limit_bits: 0x1e0377ae,
+            pow_allow_min_difficulty_blocks: false,
+            pow_no_retargeting: false,
+            minimum_chain_work: U256::zero(),
+            bip34_height: 1,
+            bip65_height: 1,
+            bip66_height: 1,
+            csv_height: 1,
+            segwit_height: 1,
+            taproot_height: 0,
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    /// Regression test network: trivial proof of work that never adjusts,
+    /// so blocks can be mined instantly
+    pub fn regtest() -> Self {
+        ChainParams {
+            network: Network::Regtest,
+            message_start: [0xfa, 0xbf, 0xb5, 0xda],
+            pow_limit_bits: 0x207fffff,
+            pow_allow_min_difficulty_blocks: true,
+            pow_no_retargeting: true,
+            minimum_chain_work: U256::zero(),
+            bip34_height: 1,
+            bip65_height: 1,
+            bip66_height: 1,
+            csv_height: 1,
+            segwit_height: 0,
+            taproot_height: 0,
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    /// Hash of this network's genesis block
+    pub fn genesis_hash(&self) -> Hash {
+        crate::genesis::genesis_hash(self.network)
+    }
+
+    /// Highest allowed target, expanded from `pow_limit_bits`
+    ///
+    /// A malformed limit yields zero, which no header can meet.
+    pub fn pow_limit(&self) -> U256 {
+        crate::pow::expand_target(self.pow_limit_bits).unwrap_or_default()
+    }
+
+    /// Whether a chain with total work `chain_work` meets `minimum_chain_work`
+    pub fn has_minimum_chain_work(&self, chain_work: &U256) -> bool {
+        *chain_work >= self.minimum_chain_work
+    }
+
+    /// Activation height of a buried deployment
+    pub fn deployment_height(&self, deployment: Deployment) -> Natural {
+        match deployment {
+            Deployment::Bip34 => self.bip34_height,
+            Deployment::Bip65 => self.bip65_height,
+            Deployment::Bip66 => self.bip66_height,
+            Deployment::Csv => self.csv_height,
+            Deployment::Segwit => self.segwit_height,
+            Deployment::Taproot => self.taproot_height,
+        }
+    }
+
+    /// Whether a buried deployment is enforced for a block at `height`
+    pub fn is_deployment_active(&self, deployment: Deployment, height: Natural) -> bool {
+        height >= self.deployment_height(deployment)
+    }
+
+    /// Checkpointed block hash at `height`, if any
+    pub fn checkpoint_at(&self, height: Natural) -> Option<&Hash> {
+        self.checkpoints.get(&height)
+    }
+
+    /// Height of the highest checkpoint, if any
+    pub fn last_checkpoint_height(&self) -> Option<Natural> {
+        self.checkpoints.keys().next_back().copied()
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+/// Chain work from its big-endian bytes, leading zero bytes left out
+fn work_from_be(significant: &[u8]) -> U256 {
+    let mut bytes = [0u8; 32];
+    bytes[32 - significant.len()..].copy_from_slice(significant);
+    U256::from_be_bytes(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_params_per_network() {
+        for network in [Network::Mainnet, Network::Testnet, Network::Signet, Network::Regtest] {
+            assert_eq!(ChainParams::new(network).network, network);
+        }
+
+        assert_eq!(ChainParams::default(), ChainParams::mainnet());
+        assert_eq!(ChainParams::mainnet().pow_limit_bits, crate::constants::MAX_TARGET as Natural);
+    }
+
+    #[test]
+    fn test_checkpoint_lookup() {
+        let mut params = ChainParams::regtest();
+        assert_eq!(params.last_checkpoint_height(), None);
+
+        params.checkpoints.insert(10, [1; 32]);
+        params.checkpoints.insert(20, [2; 32]);
+        assert_eq!(params.checkpoint_at(10), Some(&[1; 32]));
+        assert_eq!(params.checkpoint_at(15), None);
+        assert_eq!(params.last_checkpoint_height(), Some(20));
+    }
+
+    #[test]
+    fn test_deployment_activation() {
+        let params = ChainParams::mainnet();
+        assert!(!params.is_deployment_active(Deployment::Segwit, 481_823));
+        assert!(params.is_deployment_active(Deployment::Segwit, 481_824));
+        assert!(params.is_deployment_active(Deployment::Csv, 481_824));
+        assert!(!params.is_deployment_active(Deployment::Taproot, 481_824));
+
+        let regtest = ChainParams::regtest();
+        assert!(regtest.is_deployment_active(Deployment::Segwit, 0));
+        assert!(!regtest.is_deployment_active(Deployment::Bip34, 0));
+        assert!(regtest.is_deployment_active(Deployment::Bip34, 1));
+    }
+
+    #[test]
+    fn test_minimum_chain_work() {
+        let header = |nonce| BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp: 1231006505,
+            bits: 0x1d00ffff,
+            nonce,
+        };
+        // Regtest has no minimum; the public networks do
+        assert!(ChainParams::regtest().has_minimum_chain_work(&U256::zero()));
+        let mut params = ChainParams::mainnet();
+        assert!(!params.has_minimum_chain_work(&crate::pow::chain_work(&vec![header(0); 10])));
+        assert!(params.minimum_chain_work > ChainParams::testnet().minimum_chain_work);
+        assert!(!ChainParams::testnet().has_minimum_chain_work(&U256::zero()));
+
+        // Require the work of ten difficulty-1 blocks
+        params.minimum_chain_work = crate::pow::chain_work(&vec![header(0); 10]);
+        let nine: Vec<BlockHeader> = (0..9).map(header).collect();
+        let work = crate::pow::chain_work(&nine);
+        assert!(!params.has_minimum_chain_work(&work));
+        assert!(params.has_minimum_chain_work(&crate::pow::accumulate_chain_work(&work, &[header(9)])));
+    }
+}
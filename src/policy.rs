@@ -0,0 +1,547 @@
+//! Standardness policy: rules a node applies before relaying or mining a
+//! transaction, on top of consensus validity
+//!
+//! Nothing here affects block validity. Every limit is a field of
+//! `StandardnessPolicy`, defaulting to Bitcoin Core's relay policy.
+
+use crate::types::*;
+use crate::constants::{MAX_SCRIPT_SIZE, WITNESS_SCALE_FACTOR};
+use crate::script::{is_push_only, read_op};
+use crate::segwit::Witness;
+use crate::serialization::{encode_varint, serialize_transaction};
+use std::collections::HashSet;
+
+/// Largest standard transaction weight
+pub const MAX_STANDARD_TX_WEIGHT: Natural = 400_000;
+
+/// Largest standard scriptSig: a 15-of-15 P2SH multisig spend
+pub const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+
+/// Most keys in a standard bare (non-P2SH) multisig output
+pub const MAX_STANDARD_BARE_MULTISIG_KEYS: usize = 3;
+
+/// Largest standard OP_RETURN output script, in bytes
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+
+/// Most witness stack items, besides the witness script, of a P2WSH spend
+pub const MAX_STANDARD_P2WSH_STACK_ITEMS: usize = 100;
+
+/// Largest witness stack item, besides the witness script, of a P2WSH spend
+pub const MAX_STANDARD_P2WSH_STACK_ITEM_SIZE: usize = 80;
+
+/// Largest standard P2WSH witness script
+pub const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+
+/// Largest witness stack item, besides the script and control block, of a
+/// tapscript spend
+pub const MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE: usize = 80;
+
+/// Virtual bytes charged per unit of sigop cost beyond what the weight
+/// already pays for
+pub const DEFAULT_BYTES_PER_SIGOP: Natural = 20;
+
+/// Leaf version of BIP342 tapscript
+const TAPROOT_LEAF_TAPSCRIPT: u8 = 0xc0;
+
+/// First byte of a taproot annex (BIP341)
+const ANNEX_TAG: u8 = 0x50;
+
+/// Kind of a scriptPubKey, as far as policy is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScriptType {
+    NonStandard,
+    /// <pubkey> OP_CHECKSIG
+    PubKey,
+    /// OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG
+    PubKeyHash,
+    /// OP_HASH160 <20 bytes> OP_EQUAL
+    ScriptHash,
+    /// OP_m <pubkeys...> OP_n OP_CHECKMULTISIG
+    Multisig,
+    /// OP_RETURN followed by pushes
+    NullData,
+    /// OP_0 <20 bytes>
+    WitnessV0KeyHash,
+    /// OP_0 <32 bytes>
+    WitnessV0ScriptHash,
+    /// OP_1 <32 bytes>
+    WitnessV1Taproot,
+    /// Any other witness program, reserved for future soft forks
+    WitnessUnknown,
+}
+
+/// Configurable standardness rules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StandardnessPolicy {
+    /// Largest transaction weight relayed
+    pub max_tx_weight: Natural,
+    /// Largest scriptSig relayed
+    pub max_script_sig_size: usize,
+    /// Output types relayed; `NonStandard` is never accepted
+    pub allowed_script_types: HashSet<ScriptType>,
+    /// Whether bare multisig outputs are relayed at all
+    pub permit_bare_multisig: bool,
+    /// Most keys in a relayed bare multisig output
+    pub max_bare_multisig_keys: usize,
+    /// Largest OP_RETURN output script, or `None` to relay no OP_RETURN outputs
+    pub max_data_carrier_size: Option<usize>,
+    /// Whether inputs may spend witness programs of unknown versions
+    pub permit_unknown_witness_spends: bool,
+    pub max_p2wsh_stack_items: usize,
+    pub max_p2wsh_stack_item_size: usize,
+    pub max_p2wsh_script_size: usize,
+    pub max_tapscript_stack_item_size: usize,
+}
+
+impl Default for StandardnessPolicy {
+    fn default() -> Self {
+        StandardnessPolicy {
+            max_tx_weight: MAX_STANDARD_TX_WEIGHT,
+            max_script_sig_size: MAX_STANDARD_SCRIPTSIG_SIZE,
+            allowed_script_types: [
+                ScriptType::PubKey,
+                ScriptType::PubKeyHash,
+                ScriptType::ScriptHash,
+                ScriptType::Multisig,
+                ScriptType::NullData,
+                ScriptType::WitnessV0KeyHash,
+                ScriptType::WitnessV0ScriptHash,
+                ScriptType::WitnessV1Taproot,
+                ScriptType::WitnessUnknown,
+            ].into_iter().collect(),
+            permit_bare_multisig: true,
+            max_bare_multisig_keys: MAX_STANDARD_BARE_MULTISIG_KEYS,
+            max_data_carrier_size: Some(MAX_OP_RETURN_RELAY),
+            permit_unknown_witness_spends: false,
+            max_p2wsh_stack_items: MAX_STANDARD_P2WSH_STACK_ITEMS,
+            max_p2wsh_stack_item_size: MAX_STANDARD_P2WSH_STACK_ITEM_SIZE,
+            max_p2wsh_script_size: MAX_STANDARD_P2WSH_SCRIPT_SIZE,
+            max_tapscript_stack_item_size: MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE,
+        }
+    }
+}
+
+/// ClassifyScript: 𝒮𝒞 → ScriptType
+pub fn classify_script(script: &ByteString) -> ScriptType {
+    if let Some((version, program)) = witness_program(script) {
+        return match (version, program.len()) {
+            (0, 20) => ScriptType::WitnessV0KeyHash,
+            (0, 32) => ScriptType::WitnessV0ScriptHash,
+            (0, _) => ScriptType::NonStandard,
+            (1, 32) => ScriptType::WitnessV1Taproot,
+            _ => ScriptType::WitnessUnknown,
+        };
+    }
+
+    match script.as_slice() {
+        [0xa9, 0x14, .., 0x87] if script.len() == 23 => ScriptType::ScriptHash,
+        [0x76, 0xa9, 0x14, .., 0x88, 0xac] if script.len() == 25 => ScriptType::PubKeyHash,
+        [0x21, .., 0xac] if script.len() == 35 => ScriptType::PubKey,
+        [0x41, .., 0xac] if script.len() == 67 => ScriptType::PubKey,
+        [0x6a, rest @ ..] if is_push_only(&rest.to_vec()) => ScriptType::NullData,
+        _ if multisig_keys(script).is_some() => ScriptType::Multisig,
+        _ => ScriptType::NonStandard,
+    }
+}
+
+/// Witness version and program of a witness-program scriptPubKey (BIP141):
+/// a version opcode OP_0..OP_16 followed by a single 2-40 byte push
+pub fn witness_program(script: &ByteString) -> Option<(u8, &[u8])> {
+    if script.len() < 4 || script.len() > 42 {
+        return None;
+    }
+    let version = match script[0] {
+        0x00 => 0,
+        0x51..=0x60 => script[0] - 0x50,
+        _ => return None,
+    };
+    (script[1] as usize + 2 == script.len()).then_some((version, &script[2..]))
+}
+
+/// Required signatures and key count of a bare multisig script
+fn multisig_keys(script: &ByteString) -> Option<(usize, usize)> {
+    let small_int = |opcode: u8| (0x51..=0x60).contains(&opcode).then(|| (opcode - 0x50) as usize);
+
+    let mut ops = Vec::new();
+    let mut pc = 0;
+    while pc < script.len() {
+        ops.push(read_op(script, &mut pc)?);
+    }
+    let (&(last, _), rest) = ops.split_last()?;
+    let (&(keys_op, _), rest) = rest.split_last()?;
+    let (&(required_op, _), keys) = rest.split_first()?;
+    if last != 0xae {
+        return None;
+    }
+
+    let (required, key_count) = (small_int(required_op)?, small_int(keys_op)?);
+    let keys_valid = keys.iter().all(|(opcode, data)| {
+        (*opcode == 0x21 || *opcode == 0x41) && data.len() == *opcode as usize
+    });
+    (keys_valid && keys.len() == key_count && required <= key_count).then_some((required, key_count))
+}
+
+/// TransactionWeight: 𝒯𝒳 × 𝒲* → ℕ
+///
+/// 3 × |Serialize(tx ∖ witness)| + |Serialize(tx)|, where `witnesses[i]` is
+/// the witness stack of input i; missing stacks are empty.
+pub fn transaction_weight(tx: &Transaction, witnesses: &[Witness]) -> Natural {
+    let base_size = serialize_transaction(tx).len() as Natural;
+    if witnesses.iter().all(|witness| witness.is_empty()) {
+        return base_size * 4;
+    }
+
+    // Marker and flag, then one stack per input
+    let mut witness_size = 2;
+    for i in 0..tx.inputs.len() {
+        let stack = witnesses.get(i).map(Vec::as_slice).unwrap_or_default();
+        witness_size += encode_varint(stack.len() as u64).len() as Natural;
+        for item in stack {
+            witness_size += (encode_varint(item.len() as u64).len() + item.len()) as Natural;
+        }
+    }
+    base_size * 4 + witness_size
+}
+
+/// VirtualSize: ℕ × ℕ × ℕ → ℕ
+///
+/// ⌈max(weight, sigopCost × bytesPerSigop) / WITNESS_SCALE_FACTOR⌉: the size a
+/// transaction is charged for in fee rates and limits, so that sigop-heavy
+/// transactions pay for the block sigop budget they use.
+pub fn virtual_size(weight: Natural, sigop_cost: Natural, bytes_per_sigop: Natural) -> Natural {
+    let scaled = weight.max(sigop_cost.saturating_mul(bytes_per_sigop));
+    scaled.div_ceil(WITNESS_SCALE_FACTOR)
+}
+
+/// DustThreshold: 𝒯𝒪 × ℝ → ℤ
+///
+/// Smallest value of `output` worth relaying at `dust_relay_fee_rate`
+/// satoshis per virtual byte: the fee for the output plus the input that
+/// would spend it (67 vbytes for witness programs, 148 otherwise).
+/// Unspendable outputs are never dust.
+pub fn dust_threshold(output: &TransactionOutput, dust_relay_fee_rate: f64) -> Integer {
+    let script = &output.script_pubkey;
+    if script.first() == Some(&0x6a) || script.len() > MAX_SCRIPT_SIZE {
+        return 0;
+    }
+
+    let output_size = 8 + encode_varint(script.len() as u64).len() + script.len();
+    let spend_size = if witness_program(script).is_some() {
+        // Outpoint, empty scriptSig and sequence, plus a discounted signature and key
+        32 + 4 + 1 + 107 / WITNESS_SCALE_FACTOR as usize + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    (dust_relay_fee_rate * (output_size + spend_size) as f64) as Integer
+}
+
+/// IsDust: 𝒯𝒪 × ℝ → {true, false}
+pub fn is_dust(output: &TransactionOutput, dust_relay_fee_rate: f64) -> bool {
+    output.value < dust_threshold(output, dust_relay_fee_rate)
+}
+
+/// IsStandardTx: 𝒯𝒳 × 𝒲* × StandardnessPolicy → {valid, invalid}
+///
+/// Rules that need only the transaction itself:
+/// 1. Weight ≤ max_tx_weight
+/// 2. Every scriptSig is push-only and at most max_script_sig_size
+/// 3. Every output type is allowed; bare multisig only if permitted and
+///    with at most max_bare_multisig_keys keys
+/// 4. At most one OP_RETURN output, of at most max_data_carrier_size bytes
+///
+/// Invalid results carry Bitcoin Core's reject reasons.
+pub fn check_standard_tx(tx: &Transaction, witnesses: &[Witness], policy: &StandardnessPolicy) -> ValidationResult {
+    let invalid = |reason: &str| ValidationResult::Invalid(reason.to_string());
+
+    // 1. Weight
+    if transaction_weight(tx, witnesses) > policy.max_tx_weight {
+        return invalid("tx-size");
+    }
+
+    // 2. scriptSigs
+    for input in &tx.inputs {
+        if input.script_sig.len() > policy.max_script_sig_size {
+            return invalid("scriptsig-size");
+        }
+        if !is_push_only(&input.script_sig) {
+            return invalid("scriptsig-not-pushonly");
+        }
+    }
+
+    // 3-4. Outputs
+    let mut data_outputs = 0;
+    for output in &tx.outputs {
+        let script_type = classify_script(&output.script_pubkey);
+        if script_type == ScriptType::NonStandard || !policy.allowed_script_types.contains(&script_type) {
+            return invalid("scriptpubkey");
+        }
+        match script_type {
+            ScriptType::Multisig => {
+                let (_, keys) = multisig_keys(&output.script_pubkey).expect("classified as multisig");
+                if !policy.permit_bare_multisig {
+                    return invalid("bare-multisig");
+                }
+                if keys > policy.max_bare_multisig_keys {
+                    return invalid("scriptpubkey");
+                }
+            }
+            ScriptType::NullData => {
+                match policy.max_data_carrier_size {
+                    Some(max) if output.script_pubkey.len() <= max => data_outputs += 1,
+                    _ => return invalid("scriptpubkey"),
+                }
+            }
+            _ => {}
+        }
+    }
+    if data_outputs > 1 {
+        return invalid("multi-op-return");
+    }
+
+    ValidationResult::Valid
+}
+
+/// AreInputsStandard: 𝒯𝒳 × 𝒲* × 𝒰𝒮 × StandardnessPolicy → {valid, invalid}
+///
+/// Rules on what each input spends, with coins from `coins`:
+/// 1. The spent output is of a standard type
+/// 2. Unknown witness versions are spent only if permitted
+/// 3. P2WSH: at most max_p2wsh_stack_items items of at most
+///    max_p2wsh_stack_item_size bytes, and a witness script of at most
+///    max_p2wsh_script_size bytes
+/// 4. Taproot: no annex, and tapscript stack items of at most
+///    max_tapscript_stack_item_size bytes
+///
+/// Inputs whose coin is missing are left to consensus validation.
+pub fn check_standard_inputs(
+    tx: &Transaction,
+    witnesses: &[Witness],
+    coins: &UtxoSet,
+    policy: &StandardnessPolicy,
+) -> ValidationResult {
+    for (i, input) in tx.inputs.iter().enumerate() {
+        let Some(coin) = coins.get(&input.prevout) else {
+            continue;
+        };
+        let stack = witnesses.get(i).map(Vec::as_slice).unwrap_or_default();
+
+        match classify_script(&coin.script_pubkey) {
+            ScriptType::NonStandard => {
+                return ValidationResult::Invalid("bad-txns-nonstandard-inputs".to_string());
+            }
+            ScriptType::WitnessUnknown if !policy.permit_unknown_witness_spends => {
+                return ValidationResult::Invalid(format!(
+                    "bad-txns-nonstandard-inputs: input {} spends an unknown witness version", i
+                ));
+            }
+            ScriptType::WitnessV0ScriptHash => {
+                if let Some(reason) = p2wsh_witness_violation(stack, policy) {
+                    return ValidationResult::Invalid(format!("bad-witness-nonstandard: input {} {}", i, reason));
+                }
+            }
+            ScriptType::WitnessV1Taproot => {
+                if let Some(reason) = taproot_witness_violation(stack, policy) {
+                    return ValidationResult::Invalid(format!("bad-witness-nonstandard: input {} {}", i, reason));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    ValidationResult::Valid
+}
+
+/// Why a P2WSH witness stack is nonstandard, if it is
+fn p2wsh_witness_violation(stack: &[ByteString], policy: &StandardnessPolicy) -> Option<&'static str> {
+    let (witness_script, items) = stack.split_last()?;
+    if witness_script.len() > policy.max_p2wsh_script_size {
+        return Some("has an oversized witness script");
+    }
+    if items.len() > policy.max_p2wsh_stack_items {
+        return Some("has too many stack items");
+    }
+    if items.iter().any(|item| item.len() > policy.max_p2wsh_stack_item_size) {
+        return Some("has an oversized stack item");
+    }
+    None
+}
+
+/// Why a taproot witness stack is nonstandard, if it is
+fn taproot_witness_violation(stack: &[ByteString], policy: &StandardnessPolicy) -> Option<&'static str> {
+    if stack.len() >= 2 && stack.last().is_some_and(|last| last.first() == Some(&ANNEX_TAG)) {
+        return Some("has an annex");
+    }
+
+    // Script path: <items...> <script> <control block>
+    if let [items @ .., _script, control] = stack {
+        let is_tapscript = control.first().is_some_and(|first| first & 0xfe == TAPROOT_LEAF_TAPSCRIPT);
+        if is_tapscript && items.iter().any(|item| item.len() > policy.max_tapscript_stack_item_size) {
+            return Some("has an oversized tapscript stack item");
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx_with_outputs(scripts: Vec<ByteString>) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [1; 32], index: 0 },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: scripts.into_iter()
+                .map(|script_pubkey| TransactionOutput { value: 1000, script_pubkey })
+                .collect(),
+            lock_time: 0,
+        }
+    }
+
+    fn multisig(required: u8, keys: usize) -> ByteString {
+        let mut script = vec![0x50 + required];
+        for _ in 0..keys {
+            script.push(0x21);
+            script.extend([0x02; 33]);
+        }
+        script.extend([0x50 + keys as u8, 0xae]);
+        script
+    }
+
+    #[test]
+    fn test_classify_script() {
+        let mut p2pkh = vec![0x76, 0xa9, 0x14];
+        p2pkh.extend([0; 20]);
+        p2pkh.extend([0x88, 0xac]);
+        let mut p2sh = vec![0xa9, 0x14];
+        p2sh.extend([0; 20]);
+        p2sh.push(0x87);
+
+        assert_eq!(classify_script(&p2pkh), ScriptType::PubKeyHash);
+        assert_eq!(classify_script(&p2sh), ScriptType::ScriptHash);
+        assert_eq!(classify_script(&[vec![0x21], vec![2; 33], vec![0xac]].concat()), ScriptType::PubKey);
+        assert_eq!(classify_script(&[vec![0x00, 0x14], vec![0; 20]].concat()), ScriptType::WitnessV0KeyHash);
+        assert_eq!(classify_script(&[vec![0x00, 0x20], vec![0; 32]].concat()), ScriptType::WitnessV0ScriptHash);
+        assert_eq!(classify_script(&[vec![0x00, 0x10], vec![0; 16]].concat()), ScriptType::NonStandard);
+        assert_eq!(classify_script(&[vec![0x51, 0x20], vec![0; 32]].concat()), ScriptType::WitnessV1Taproot);
+        assert_eq!(classify_script(&[vec![0x52, 0x20], vec![0; 32]].concat()), ScriptType::WitnessUnknown);
+        assert_eq!(classify_script(&vec![0x6a, 0x04, 1, 2, 3, 4]), ScriptType::NullData);
+        assert_eq!(classify_script(&vec![0x6a, 0xac]), ScriptType::NonStandard);
+        assert_eq!(classify_script(&multisig(2, 3)), ScriptType::Multisig);
+        assert_eq!(classify_script(&multisig(3, 2)), ScriptType::NonStandard);
+        assert_eq!(classify_script(&vec![0x51]), ScriptType::NonStandard);
+        assert_eq!(classify_script(&vec![]), ScriptType::NonStandard);
+    }
+
+    #[test]
+    fn test_check_standard_tx_rules() {
+        let policy = StandardnessPolicy::default();
+        let p2wpkh = [vec![0x00, 0x14], vec![0; 20]].concat();
+        let data = |len: usize| [vec![0x6a, 0x4c, len as u8], vec![0; len]].concat();
+        let check = |tx: &Transaction, policy: &StandardnessPolicy| check_standard_tx(tx, &[], policy);
+        let reason = |result: ValidationResult| match result {
+            ValidationResult::Invalid(reason) => reason,
+            ValidationResult::Valid => "valid".to_string(),
+        };
+
+        assert_eq!(check(&tx_with_outputs(vec![p2wpkh.clone(), data(40)]), &policy), ValidationResult::Valid);
+        assert_eq!(reason(check(&tx_with_outputs(vec![vec![0x51]]), &policy)), "scriptpubkey");
+        assert_eq!(reason(check(&tx_with_outputs(vec![data(81)]), &policy)), "scriptpubkey");
+        assert_eq!(reason(check(&tx_with_outputs(vec![data(10), data(10)]), &policy)), "multi-op-return");
+        assert_eq!(reason(check(&tx_with_outputs(vec![multisig(1, 4)]), &policy)), "scriptpubkey");
+        assert_eq!(check(&tx_with_outputs(vec![multisig(1, 3)]), &policy), ValidationResult::Valid);
+
+        let mut not_push_only = tx_with_outputs(vec![p2wpkh.clone()]);
+        not_push_only.inputs[0].script_sig = vec![0x51, 0x87];
+        assert_eq!(reason(check(&not_push_only, &policy)), "scriptsig-not-pushonly");
+
+        let mut heavy = tx_with_outputs(vec![p2wpkh.clone()]);
+        heavy.outputs = vec![TransactionOutput { value: 1, script_pubkey: p2wpkh.clone() }; 4000];
+        assert_eq!(reason(check(&heavy, &policy)), "tx-size");
+
+        // Each rule can be adjusted on its own
+        let strict = StandardnessPolicy {
+            permit_bare_multisig: false,
+            max_data_carrier_size: None,
+            ..StandardnessPolicy::default()
+        };
+        assert_eq!(reason(check(&tx_with_outputs(vec![multisig(1, 3)]), &strict)), "bare-multisig");
+        assert_eq!(reason(check(&tx_with_outputs(vec![data(4)]), &strict)), "scriptpubkey");
+        let mut no_segwit = StandardnessPolicy::default();
+        no_segwit.allowed_script_types.remove(&ScriptType::WitnessV0KeyHash);
+        assert_eq!(reason(check(&tx_with_outputs(vec![p2wpkh]), &no_segwit)), "scriptpubkey");
+        let loose = StandardnessPolicy { max_tx_weight: Natural::MAX, ..StandardnessPolicy::default() };
+        assert_eq!(check(&heavy, &loose), ValidationResult::Valid);
+    }
+
+    #[test]
+    fn test_check_standard_inputs_witness_limits() {
+        let policy = StandardnessPolicy::default();
+        let tx = tx_with_outputs(vec![[vec![0x00, 0x14], vec![0; 20]].concat()]);
+        let coins_paying = |script_pubkey: ByteString| {
+            let mut coins = UtxoSet::new();
+            coins.insert(tx.inputs[0].prevout.clone(), UTXO { value: 2000, script_pubkey, height: 1, is_coinbase: false });
+            coins
+        };
+        let p2wsh = coins_paying([vec![0x00, 0x20], vec![0; 32]].concat());
+        let taproot = coins_paying([vec![0x51, 0x20], vec![0; 32]].concat());
+        let unknown = coins_paying([vec![0x53, 0x20], vec![0; 32]].concat());
+        let valid = |witness: Witness, coins: &UtxoSet, policy: &StandardnessPolicy| {
+            check_standard_inputs(&tx, &[witness], coins, policy) == ValidationResult::Valid
+        };
+
+        assert!(valid(vec![vec![1; 72], vec![0x51]], &p2wsh, &policy));
+        assert!(!valid(vec![vec![1; 81], vec![0x51]], &p2wsh, &policy));
+        assert!(!valid(vec![vec![1; 1]; 102], &p2wsh, &policy));
+        assert!(!valid(vec![vec![0x51; 3601]], &p2wsh, &policy));
+
+        assert!(valid(vec![vec![1; 64]], &taproot, &policy));
+        assert!(!valid(vec![vec![1; 64], vec![ANNEX_TAG, 0]], &taproot, &policy));
+        assert!(!valid(vec![vec![1; 81], vec![0x51], vec![0xc0; 33]], &taproot, &policy));
+        assert!(valid(vec![vec![1; 81], vec![0x51], vec![0xc2; 33]], &taproot, &policy));
+
+        assert!(!valid(vec![], &unknown, &policy));
+        let permissive = StandardnessPolicy { permit_unknown_witness_spends: true, ..StandardnessPolicy::default() };
+        assert!(valid(vec![], &unknown, &permissive));
+        assert!(!valid(vec![], &coins_paying(vec![0x51]), &policy));
+    }
+
+    #[test]
+    fn test_virtual_size() {
+        assert_eq!(virtual_size(800, 0, DEFAULT_BYTES_PER_SIGOP), 200);
+        assert_eq!(virtual_size(801, 0, DEFAULT_BYTES_PER_SIGOP), 201);
+        // 4 legacy sigops cost 16; 16 × 20 = 320 weight does not exceed 800
+        assert_eq!(virtual_size(800, 16, DEFAULT_BYTES_PER_SIGOP), 200);
+        // A bare 20-key multisig costs 80, charged as 80 × 20 / 4 = 400 vbytes
+        assert_eq!(virtual_size(800, 80, DEFAULT_BYTES_PER_SIGOP), 400);
+    }
+
+    #[test]
+    fn test_dust_threshold() {
+        // Bitcoin Core's well-known limits at 3 sat/vB
+        let mut p2pkh = vec![0x76, 0xa9, 0x14];
+        p2pkh.extend([0; 20]);
+        p2pkh.extend([0x88, 0xac]);
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend([0; 20]);
+        let output = |value, script_pubkey| TransactionOutput { value, script_pubkey };
+        assert_eq!(dust_threshold(&output(0, p2pkh.clone()), 3.0), 546);
+        assert_eq!(dust_threshold(&output(0, p2wpkh.clone()), 3.0), 294);
+        assert!(is_dust(&output(545, p2pkh.clone()), 3.0));
+        assert!(!is_dust(&output(546, p2pkh), 3.0));
+        assert!(!is_dust(&output(294, p2wpkh), 3.0));
+        assert!(!is_dust(&output(0, vec![0x6a, 0x01, 0x00]), 3.0));
+    }
+
+    #[test]
+    fn test_transaction_weight() {
+        let tx = tx_with_outputs(vec![vec![0x51]]);
+        let base = serialize_transaction(&tx).len() as Natural;
+        assert_eq!(transaction_weight(&tx, &[]), base * 4);
+        // Marker, flag, item count, item length and item
+        assert_eq!(transaction_weight(&tx, &[vec![vec![0; 10]]]), base * 4 + 2 + 1 + 1 + 10);
+    }
+}
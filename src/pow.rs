@@ -3,18 +3,85 @@
 use crate::types::*;
 use crate::constants::*;
 use crate::error::{Result, ConsensusError};
-use sha2::{Sha256, Digest};
+use crate::params::ChainParams;
+
+/// GetNextWorkRequiredAt: ℕ × ℕ × ℋ* × ChainParams → ℕ
+///
+/// nBits required for a block at `height` with timestamp `block_time`,
+/// where prev ends with its parent (at height - 1):
+/// 1. If prev = ∅: return powLimit (genesis)
+/// 2. If height mod 2016 ≠ 0:
+///    - If min-difficulty blocks are allowed (testnet) and block_time is
+///      more than 20 minutes after parent.time: return powLimit
+///    - If min-difficulty blocks are allowed: return the bits of the last
+///      header that is at a period start or not at powLimit
+///    - Otherwise return parent.bits (no adjustment)
+/// 3. If retargeting is disabled (regtest): return parent.bits
+/// 4. Let first = the header at height - 2016, i.e. prev[|prev| - 2016]
+/// 5. Return CalculateNextWorkRequired(parent, first.time)
+///
+/// The period's timespan runs from its first to its last block, covering
+/// only 2015 block intervals; this off-by-one is part of consensus.
+pub fn get_next_work_required_at(
+    height: Natural,
+    block_time: Natural,
+    prev_headers: &[BlockHeader],
+    params: &ChainParams,
+) -> Result<Natural> {
+    let last = match prev_headers.last() {
+        Some(last) => last,
+        None => return Ok(params.pow_limit_bits),
+    };
+    
+    if !height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL) {
+        if !params.pow_allow_min_difficulty_blocks {
+            return Ok(last.bits);
+        }
+        
+        // Testnet: a block may be mined at minimum difficulty once the
+        // chain has stalled for twice the target spacing
+        if block_time > last.timestamp.saturating_add(2 * TARGET_TIME_PER_BLOCK) {
+            return Ok(params.pow_limit_bits);
+        }
+        
+        // Otherwise return to the difficulty in force before any such blocks
+        let first_height = height - prev_headers.len() as Natural;
+        let index = (0..prev_headers.len()).rev()
+            .find(|&i| {
+                i == 0
+                    || (first_height + i as Natural).is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL)
+                    || prev_headers[i].bits != params.pow_limit_bits
+            })
+            .unwrap_or(0);
+        return Ok(prev_headers[index].bits);
+    }
+    
+    // Regtest: difficulty never changes
+    if params.pow_no_retargeting {
+        return Ok(last.bits);
+    }
+    
+    let period = DIFFICULTY_ADJUSTMENT_INTERVAL as usize;
+    if prev_headers.len() < period {
+        return Err(ConsensusError::InvalidProofOfWork(
+            format!("Retarget at height {} needs the previous {} headers", height, period)
+        ));
+    }
+    
+    let first = &prev_headers[prev_headers.len() - period];
+    calculate_next_work_required(last, first.timestamp, params)
+}
 
 /// GetNextWorkRequired: ℋ × ℋ* → ℕ
 /// 
-/// Calculate the next work required based on difficulty adjustment.
-/// For block header h and previous headers prev:
-/// 1. If |prev| < 2: return initial difficulty
-/// 2. Let timeSpan = h.time - prev[0].time
-/// 3. Let expectedTime = 14 × 24 × 60 × 60 (2 weeks)
-/// 4. Let adjustment = timeSpan / expectedTime
-/// 5. Let newTarget = h.bits × adjustment
-/// 6. Return min(newTarget, maxTarget)
+/// Retarget for the last header h of a period, given headers prev starting
+/// with the first header of that period:
+/// 1. If |prev| < 2: return error
+/// 2. Return CalculateNextWorkRequired(h, prev[0].time) with mainnet
+///    parameters
+///
+/// Use `get_next_work_required_at` for other networks and to also decide
+/// whether a height is a retarget boundary at all.
 pub fn get_next_work_required(
     current_header: &BlockHeader,
     prev_headers: &[BlockHeader]
@@ -24,241 +91,215 @@ pub fn get_next_work_required(
         return Err(ConsensusError::InvalidProofOfWork("Insufficient headers for difficulty adjustment".to_string()));
     }
     
-    let time_span = current_header.timestamp - prev_headers[0].timestamp;
+    calculate_next_work_required(current_header, prev_headers[0].timestamp, &ChainParams::mainnet())
+}
+
+/// CalculateNextWorkRequired: ℋ × ℕ × ChainParams → ℕ
+///
+/// For the last header h of a period and the time of its first header:
+/// 1. Let timeSpan = h.time - firstTime
+/// 2. Let expectedTime = 2016 × 600 (2 weeks)
+/// 3. Clamp timeSpan to [expectedTime / 4, expectedTime × 4]
+/// 4. Let newTarget = ExpandTarget(h.bits) × timeSpan / expectedTime
+/// 5. Return Compact(min(newTarget, params.pow_limit))
+///
+/// All arithmetic is on 256-bit integers, so the result is exact and the
+/// same on every platform.
+pub fn calculate_next_work_required(
+    last_header: &BlockHeader,
+    first_block_time: Natural,
+    params: &ChainParams,
+) -> Result<Natural> {
     let expected_time = DIFFICULTY_ADJUSTMENT_INTERVAL * TARGET_TIME_PER_BLOCK;
     
-    // Calculate adjustment factor
-    let adjustment = (time_span as f64) / (expected_time as f64);
+    // Limit the adjustment to a factor of 4 either way
+    let time_span = last_header.timestamp
+        .saturating_sub(first_block_time)
+        .clamp(expected_time / 4, expected_time * 4);
     
-    // Clamp adjustment to [0.25, 4.0] - but don't clamp if it would make difficulty go in wrong direction
-    let clamped_adjustment = if adjustment < 0.25 {
-        0.25  // Minimum 4x difficulty increase
-    } else if adjustment > 4.0 {
-        4.0   // Maximum 4x difficulty decrease
-    } else {
-        adjustment
-    };
+    let pow_limit = params.pow_limit();
+    let target = expand_target(last_header.bits)?;
     
-    // Calculate new target (inverse relationship: faster blocks = higher difficulty = lower target)
-    // But we need to ensure the target doesn't exceed MAX_TARGET
-    let new_target = (current_header.bits as f64 / clamped_adjustment) as Natural;
+    // A product that overflows 256 bits is far above the pow limit it is
+    // clamped to anyway
+    let new_target = match target.checked_mul_u64(time_span) {
+        Some(scaled) => scaled.checked_div(&U256::from_u64(expected_time))
+            .expect("expected time is non-zero"),
+        None => pow_limit,
+    };
     
-    // Clamp to maximum target
-    Ok(new_target.min(MAX_TARGET as Natural))
+    Ok(compact_from_target(&new_target.min(pow_limit)))
 }
 
 /// CheckProofOfWork: ℋ → {true, false}
 /// 
-/// Check if the block header satisfies the proof of work requirement.
-/// Formula: SHA256(SHA256(header)) < ExpandTarget(header.bits)
+/// Check if the block header satisfies the mainnet proof of work requirement.
+/// See `check_proof_of_work_with_params` for other networks.
 pub fn check_proof_of_work(header: &BlockHeader) -> Result<bool> {
-    // Serialize header
-    let header_bytes = serialize_header(header);
-    
-    // Double SHA256
-    let hash1 = Sha256::digest(&header_bytes);
-    let hash2 = Sha256::digest(&hash1);
-    
-    // Convert to U256 (big-endian)
-    let mut hash_bytes = [0u8; 32];
-    hash_bytes.copy_from_slice(&hash2);
-    let hash_value = U256::from_bytes(&hash_bytes);
-    
-    // Expand target from compact representation
-    let target = expand_target(header.bits)?;
-    
-    // Check if hash < target
-    Ok(hash_value < target)
+    check_proof_of_work_with_params(header, &ChainParams::mainnet())
 }
 
-/// 256-bit integer for Bitcoin target calculations
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct U256([u64; 4]); // 4 * 64 = 256 bits
-
-impl U256 {
-    fn zero() -> Self {
-        U256([0; 4])
-    }
-    
-    fn from_u32(value: u32) -> Self {
-        U256([value as u64, 0, 0, 0])
-    }
-    
-    fn from_u64(value: u64) -> Self {
-        U256([value, 0, 0, 0])
-    }
-    
-    fn is_zero(&self) -> bool {
-        self.0.iter().all(|&x| x == 0)
-    }
-    
-    fn shl(&self, shift: u32) -> Self {
-        if shift >= 256 {
-            return U256::zero();
-        }
-        
-        let mut result = U256::zero();
-        let word_shift = (shift / 64) as usize;
-        let bit_shift = shift % 64;
-        
-        for i in 0..4 {
-            if i + word_shift < 4 {
-                result.0[i + word_shift] |= self.0[i] << bit_shift;
-                if bit_shift > 0 && i + word_shift + 1 < 4 {
-                    result.0[i + word_shift + 1] |= self.0[i] >> (64 - bit_shift);
-                }
-            }
-        }
-        
-        result
-    }
-    
-    fn shr(&self, shift: u32) -> Self {
-        if shift >= 256 {
-            return U256::zero();
-        }
-        
-        let mut result = U256::zero();
-        let word_shift = (shift / 64) as usize;
-        let bit_shift = shift % 64;
-        
-        for i in 0..4 {
-            if i >= word_shift {
-                result.0[i - word_shift] |= self.0[i] >> bit_shift;
-                if bit_shift > 0 && i - word_shift + 1 < 4 {
-                    result.0[i - word_shift + 1] |= self.0[i] << (64 - bit_shift);
-                }
-            }
-        }
-        
-        result
+/// CheckProofOfWork: ℋ × ChainParams → {true, false}
+/// 
+/// Check if the block header satisfies the proof of work requirement of a chain.
+/// 
+/// 1. target = ExpandTarget(header.bits); malformed encodings are errors
+/// 2. Return false if target = 0 or target > pow_limit
+/// 3. Return SHA256(SHA256(header)) ≤ target
+pub fn check_proof_of_work_with_params(header: &BlockHeader, params: &ChainParams) -> Result<bool> {
+    let target = expand_target(header.bits)?;
+    if target.is_zero() || target > params.pow_limit() {
+        return Ok(false);
     }
+
+    // Double SHA256 of the serialized header, read as a little-endian U256
+    let hash_value = U256::from_le_bytes(&header.block_hash());
+    Ok(hash_value <= target)
+}
+
+/// BlockProof: ℋ → ℕ₂₅₆
+///
+/// Expected number of hashes needed to find a block meeting h.bits:
+/// 2^256 / (target + 1), computed as ~target / (target + 1) + 1 so it fits
+/// in 256 bits. Invalid or zero targets carry no work.
+pub fn block_proof(header: &BlockHeader) -> U256 {
+    let target = match expand_target(header.bits) {
+        Ok(target) if !target.is_zero() => target,
+        _ => return U256::zero(),
+    };
     
-    fn to_bytes(&self) -> [u8; 32] {
-        let mut bytes = [0u8; 32];
-        for (i, &word) in self.0.iter().enumerate() {
-            let word_bytes = word.to_le_bytes();
-            bytes[i * 8..(i + 1) * 8].copy_from_slice(&word_bytes);
-        }
-        bytes
-    }
-    
-    fn from_bytes(bytes: &[u8; 32]) -> Self {
-        let mut words = [0u64; 4];
-        for (i, word) in words.iter_mut().enumerate() {
-            let start = i * 8;
-            let _end = start + 8;
-            *word = u64::from_le_bytes([
-                bytes[start], bytes[start + 1], bytes[start + 2], bytes[start + 3],
-                bytes[start + 4], bytes[start + 5], bytes[start + 6], bytes[start + 7],
-            ]);
-        }
-        U256(words)
+    let denominator = target.wrapping_add(&U256::from_u64(1));
+    match target.not().checked_div(&denominator) {
+        Some(quotient) => quotient.wrapping_add(&U256::from_u64(1)),
+        None => U256::zero(), // target = 2^256 - 1
     }
 }
 
-impl PartialOrd for U256 {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
+/// ChainWork: ℋ* → ℕ₂₅₆
+///
+/// Total work of a header chain: Σ_{h ∈ hs} BlockProof(h). The chain with
+/// the most work, not the most blocks, is the best chain.
+pub fn chain_work(headers: &[BlockHeader]) -> U256 {
+    accumulate_chain_work(&U256::zero(), headers)
 }
 
-impl Ord for U256 {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        for (a, b) in self.0.iter().rev().zip(other.0.iter().rev()) {
-            match a.cmp(b) {
-                std::cmp::Ordering::Equal => continue,
-                other => return other,
-            }
-        }
-        std::cmp::Ordering::Equal
+/// Work of `headers` added to the work of the chain they extend
+pub fn accumulate_chain_work(prev_work: &U256, headers: &[BlockHeader]) -> U256 {
+    headers.iter().fold(*prev_work, |work, header| work.saturating_add(&block_proof(header)))
+}
+
+/// CompactFromTarget: ℕ₂₅₆ → ℕ (GetCompact)
+///
+/// For target t with byte length size:
+/// 1. Let mantissa = the three most significant bytes of t
+/// 2. If mantissa ≥ 0x800000 (the compact sign bit): mantissa >>= 8, size += 1
+/// 3. Return size << 24 | mantissa
+///
+/// The result is the normalized encoding: it never sets the sign bit and
+/// ExpandTarget(CompactFromTarget(t)) is t truncated to its top 3 bytes.
+pub fn compact_from_target(target: &U256) -> Natural {
+    let mut size = target.bits().div_ceil(8);
+    let mut compact = if size <= 3 {
+        target.low_u64() << (8 * (3 - size))
+    } else {
+        target.shr(8 * (size - 3)).low_u64()
+    };
+    
+    if compact & 0x00800000 != 0 {
+        compact >>= 8;
+        size += 1;
     }
+    
+    compact | (size as u64) << 24
 }
 
-/// Expand target from compact representation
+/// ExpandTarget: ℕ → ℕ₂₅₆ (SetCompact)
 /// 
+/// Expand target from compact representation.
 /// Bitcoin uses a compact representation for difficulty targets.
 /// The format is: 0x1d00ffff where:
 /// - 0x1d is the exponent (29)
 /// - 0x00ffff is the mantissa (65535)
 /// 
 /// The actual target is: mantissa * 2^(8 * (exponent - 3))
-fn expand_target(bits: Natural) -> Result<U256> {
-    let exponent = (bits >> 24) as u8;
-    let mantissa = bits & 0x00ffffff;
-    
+///
+/// As in Bitcoin Core's SetCompact, the 0x00800000 bit is a sign bit:
+/// negative targets and targets that do not fit in 256 bits are errors.
+/// Whether a valid target is acceptable for a chain is up to the caller
+/// (see `ChainParams::pow_limit`).
+pub fn expand_target(bits: Natural) -> Result<U256> {
+    let exponent = ((bits >> 24) & 0xff) as u32;
+    let mantissa = bits & 0x007fffff;
+
     // Validate target format
-    if exponent < 3 || exponent > 32 {
+    if exponent < 3 {
         return Err(ConsensusError::InvalidProofOfWork("Invalid target exponent".to_string()));
     }
-    
-    // Check if target is too large (exponent > 29 is usually invalid)
-    if exponent > 29 {
-        return Err(ConsensusError::InvalidProofOfWork("Target too large".to_string()));
-    }
-    
+
     if mantissa == 0 {
         return Ok(U256::zero());
     }
-    
-    if exponent <= 3 {
-        // Target is mantissa >> (8 * (3 - exponent))
-        let shift = 8 * (3 - exponent);
-        let mantissa_u256 = U256::from_u32(mantissa as u32);
-        Ok(mantissa_u256.shr(shift as u32))
-    } else {
-        // Target is mantissa << (8 * (exponent - 3))
-        let shift = 8 * (exponent - 3);
-        if shift >= 255 {
-            return Err(crate::error::ConsensusError::InvalidProofOfWork(
-                "Target too large".to_string()
-            ));
-        }
-        let mantissa_u256 = U256::from_u32(mantissa as u32);
-        Ok(mantissa_u256.shl(shift as u32))
+
+    if bits & 0x00800000 != 0 {
+        return Err(ConsensusError::InvalidProofOfWork("Negative target".to_string()));
+    }
+
+    // The mantissa's significant bytes must fit below 2^256
+    if exponent > 34
+        || (mantissa > 0xff && exponent > 33)
+        || (mantissa > 0xffff && exponent > 32)
+    {
+        return Err(ConsensusError::InvalidProofOfWork("Target too large".to_string()));
     }
+
+    // Target is mantissa << (8 * (exponent - 3))
+    Ok(U256::from_u32(mantissa as u32).shl(8 * (exponent - 3)))
 }
 
-/// Serialize block header to bytes (simplified)
-fn serialize_header(header: &BlockHeader) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    
-    // Version (4 bytes, little-endian)
-    bytes.extend_from_slice(&(header.version as u32).to_le_bytes());
-    
-    // Previous block hash (32 bytes)
-    bytes.extend_from_slice(&header.prev_block_hash);
-    
-    // Merkle root (32 bytes)
-    bytes.extend_from_slice(&header.merkle_root);
-    
-    // Timestamp (4 bytes, little-endian)
-    bytes.extend_from_slice(&(header.timestamp as u32).to_le_bytes());
-    
-    // Bits (4 bytes, little-endian)
-    bytes.extend_from_slice(&(header.bits as u32).to_le_bytes());
-    
-    // Nonce (4 bytes, little-endian)
-    bytes.extend_from_slice(&(header.nonce as u32).to_le_bytes());
-    
-    bytes
+/// IsValidCompactTarget: ℕ × ChainParams → {true, false}
+/// 
+/// Whether `bits` can be a block's difficulty target on the chain:
+/// 1. ExpandTarget(bits) succeeds (not negative, no overflow)
+/// 2. The target is non-zero
+/// 3. The target is at most the chain's pow limit
+pub fn is_valid_compact_target(bits: Natural, params: &ChainParams) -> bool {
+    match expand_target(bits) {
+        Ok(target) => !target.is_zero() && target <= params.pow_limit(),
+        Err(_) => false,
+    }
 }
 
-/// Convert bytes to u256 (simplified to u128)
-fn u256_from_bytes(bytes: &[u8]) -> u128 {
-    let mut value = 0u128;
-    for (i, &byte) in bytes.iter().enumerate() {
-        if i < 16 { // Only use first 16 bytes for u128
-            value |= (byte as u128) << (8 * (15 - i));
-        }
+/// Difficulty: ℕ → ℝ (GetDifficulty)
+/// 
+/// How many times harder `bits` is than the genesis target 0x1d00ffff:
+/// 0xffff / mantissa × 256^(29 - exponent). For display only; consensus
+/// code compares targets and chain work, never this ratio. A zero
+/// mantissa gives 0.
+pub fn difficulty(bits: Natural) -> f64 {
+    let mantissa = bits & 0x00ffffff;
+    if mantissa == 0 {
+        return 0.0;
     }
-    value
+    
+    let exponent = ((bits >> 24) & 0xff) as i32;
+    (0xffff as f64 / mantissa as f64) * 256f64.powi(29 - exponent)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     
+    fn header_with_bits(bits: Natural) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp: 0,
+            bits,
+            nonce: 0,
+        }
+    }
+    
     #[test]
     fn test_get_next_work_required_insufficient_headers() {
         let header = BlockHeader {
@@ -464,20 +505,33 @@ mod tests {
     
     #[test]
     fn test_expand_target_invalid_exponent_too_large() {
-        let result = expand_target(0x2100ffff);
+        let result = expand_target(0x2300ffff);
         assert!(result.is_err());
     }
     
     #[test]
     fn test_expand_target_too_large() {
-        let result = expand_target(0x1f00ffff); // exponent = 31
-        assert!(result.is_err());
+        // Mantissa bytes pushed past 2^256
+        assert!(expand_target(0x2201ffff).is_err());
+        assert!(expand_target(0x21010000).is_err());
+        assert!(expand_target(0x22000100).is_err());
+        // Exactly fitting
+        assert_eq!(expand_target(0x2100ffff).unwrap(), U256::from_u64(0xffff).shl(240));
+        assert_eq!(expand_target(0x22000001).unwrap(), U256::one().shl(248));
     }
     
     #[test]
-    fn test_expand_target_shift_too_large() {
-        let result = expand_target(0x2000ffff); // exponent = 32, would cause shift >= 255
-        assert!(result.is_err());
+    fn test_expand_target_above_mainnet_limit() {
+        // Large but representable targets expand; the chain's pow limit rejects them
+        assert_eq!(expand_target(0x1f00ffff).unwrap(), U256::from_u64(0xffff).shl(224));
+        assert_eq!(expand_target(0x207fffff).unwrap(), U256::from_u64(0x7fffff).shl(232));
+    }
+    
+    #[test]
+    fn test_expand_target_negative() {
+        assert!(expand_target(0x1d80ffff).is_err());
+        // The sign bit alone is a zero target
+        assert!(expand_target(0x1d800000).unwrap().is_zero());
     }
     
     #[test]
@@ -505,12 +559,57 @@ mod tests {
             prev_block_hash: [0; 32],
             merkle_root: [0; 32],
             timestamp: 1231006505,
-            bits: 0x1f00ffff, // Invalid target (exponent = 31)
+            bits: 0x1f00ffff, // Above the mainnet pow limit
             nonce: 0,
         };
         
-        let result = check_proof_of_work(&header);
-        assert!(result.is_err());
+        assert!(!check_proof_of_work(&header).unwrap());
+        
+        let malformed = BlockHeader { bits: 0x2300ffff, ..header };
+        assert!(check_proof_of_work(&malformed).is_err());
+    }
+    
+    #[test]
+    fn test_is_valid_compact_target() {
+        let mainnet = ChainParams::mainnet();
+        let regtest = ChainParams::regtest();
+        assert!(is_valid_compact_target(0x1d00ffff, &mainnet));
+        assert!(is_valid_compact_target(0x1b0404cb, &mainnet));
+        assert!(!is_valid_compact_target(0x1d010000, &mainnet));
+        assert!(!is_valid_compact_target(0x207fffff, &mainnet));
+        assert!(is_valid_compact_target(0x207fffff, &regtest));
+        
+        // Zero, negative and overflowing encodings are never valid
+        for bits in [0x1d000000, 0x1d80ffff, 0x2300ffff, 0x0200ffff] {
+            assert!(!is_valid_compact_target(bits, &regtest));
+        }
+    }
+    
+    #[test]
+    fn test_difficulty() {
+        assert_eq!(difficulty(0x1d00ffff), 1.0);
+        assert_eq!(difficulty(0x1c00ffff), 256.0);
+        assert_eq!(difficulty(0x1d000000), 0.0);
+        assert!((difficulty(0x1b0404cb) - 16307.420938523983).abs() < 1e-6);
+        // Regtest's limit is far easier than difficulty 1
+        assert!((difficulty(0x207fffff) - 4.656542373906925e-10).abs() < 1e-20);
+    }
+    
+    #[test]
+    fn test_check_proof_of_work_uses_chain_pow_limit() {
+        // The regtest genesis block only meets regtest's minimum difficulty
+        let genesis = crate::genesis::genesis_block(crate::params::Network::Regtest).header;
+        assert!(check_proof_of_work_with_params(&genesis, &ChainParams::regtest()).unwrap());
+        assert!(!check_proof_of_work_with_params(&genesis, &ChainParams::mainnet()).unwrap());
+        assert!(!check_proof_of_work(&genesis).unwrap());
+        
+        let mainnet = crate::genesis::genesis_block(crate::params::Network::Mainnet).header;
+        assert!(check_proof_of_work(&mainnet).unwrap());
+        assert!(check_proof_of_work_with_params(&mainnet, &ChainParams::regtest()).unwrap());
+        
+        // A zero target is never met
+        let zero = BlockHeader { bits: 0x1d000000, ..mainnet };
+        assert!(!check_proof_of_work_with_params(&zero, &ChainParams::regtest()).unwrap());
     }
     
     #[test]
@@ -529,127 +628,211 @@ mod tests {
     }
     
     #[test]
-    fn test_u256_zero() {
-        let zero = U256::zero();
-        assert!(zero.is_zero());
-    }
-    
-    #[test]
-    fn test_u256_from_u32() {
-        let value = U256::from_u32(0x12345678);
-        assert!(!value.is_zero());
-    }
-    
-    #[test]
-    fn test_u256_from_u64() {
-        let value = U256::from_u64(0x123456789abcdef0);
-        assert!(!value.is_zero());
-    }
-    
-    #[test]
-    fn test_u256_shl_zero_shift() {
-        let value = U256::from_u32(0x12345678);
-        let result = value.shl(0);
-        assert_eq!(result, value);
-    }
-    
-    #[test]
-    fn test_u256_shl_large_shift() {
-        let value = U256::from_u32(0x12345678);
-        let result = value.shl(300); // > 256
-        assert!(result.is_zero());
-    }
-    
-    #[test]
-    fn test_u256_shr_zero_shift() {
-        let value = U256::from_u32(0x12345678);
-        let result = value.shr(0);
-        assert_eq!(result, value);
-    }
-    
-    #[test]
-    fn test_u256_shr_large_shift() {
-        let value = U256::from_u32(0x12345678);
-        let result = value.shr(300); // > 256
-        assert!(result.is_zero());
-    }
-    
-    #[test]
-    fn test_u256_shl_small_shift() {
-        let value = U256::from_u32(0x12345678);
-        let result = value.shl(8);
-        assert!(!result.is_zero());
-        assert_ne!(result, value);
-    }
-    
-    #[test]
-    fn test_u256_shr_small_shift() {
-        let value = U256::from_u32(0x12345678);
-        let result = value.shr(8);
-        assert!(!result.is_zero());
-        assert_ne!(result, value);
+    fn test_block_proof() {
+        // Difficulty-1 target 0xffff × 2^208: work = 2^256 / (target + 1) = 0x100010001
+        assert_eq!(block_proof(&header_with_bits(0x1d00ffff)), U256::from_u64(0x0000000100010001));
+        // Halving the target doubles the work (up to rounding)
+        assert_eq!(block_proof(&header_with_bits(0x1c7fff80)), U256::from_u64(0x0000000200020002));
+        assert!(block_proof(&header_with_bits(0x1d000000)).is_zero());
+        assert!(block_proof(&header_with_bits(0x2300ffff)).is_zero());
     }
     
     #[test]
-    fn test_u256_to_bytes() {
-        let value = U256::from_u32(0x12345678);
-        let bytes = value.to_bytes();
-        assert_eq!(bytes.len(), 32);
+    fn test_chain_work_accumulates() {
+        let easy = vec![header_with_bits(0x1d00ffff); 3];
+        let hard = vec![header_with_bits(0x1c7fff80); 2];
+        
+        assert!(chain_work(&[]).is_zero());
+        assert_eq!(chain_work(&easy), U256::from_u64(3 * 0x0000000100010001));
+        
+        // Two harder blocks outweigh three easier ones
+        assert!(chain_work(&hard) > chain_work(&easy));
+        
+        let prefix = chain_work(&easy[..1]);
+        assert_eq!(accumulate_chain_work(&prefix, &easy[1..]), chain_work(&easy));
+        
+        assert_eq!(U256::zero().not().saturating_add(&U256::from_u64(1)), U256::zero().not());
     }
     
     #[test]
-    fn test_u256_from_bytes() {
-        let mut bytes = [0u8; 32];
-        bytes[0] = 0x78;
-        bytes[1] = 0x56;
-        bytes[2] = 0x34;
-        bytes[3] = 0x12;
-        let value = U256::from_bytes(&bytes);
-        assert!(!value.is_zero());
+    fn test_compact_from_target_roundtrip() {
+        for bits in [0x1d00ffff, 0x1b0404cb, 0x1c3fffc0, 0x1a05db8b, 0x04123456, 0x03123456] {
+            assert_eq!(compact_from_target(&expand_target(bits).unwrap()), bits);
+        }
+        // A mantissa with its top bit set moves up a byte
+        assert_eq!(compact_from_target(&U256::from_u64(0x80)), 0x02008000);
+        assert_eq!(compact_from_target(&U256::from_u64(0x92340000)), 0x05009234);
+        assert_eq!(compact_from_target(&U256::zero()), 0);
+        
+        // Small targets are left-aligned in the mantissa
+        assert_eq!(compact_from_target(&U256::from_u64(0x12)), 0x01120000);
+        assert_eq!(compact_from_target(&U256::from_u64(0x1234)), 0x02123400);
+        
+        // Precision beyond three bytes is dropped
+        assert_eq!(compact_from_target(&U256::from_u64(0x12345678)), 0x04123456);
+        
+        // The pow limit and the largest 256-bit value
+        assert_eq!(compact_from_target(&expand_target(0x1d00ffff).unwrap()), 0x1d00ffff);
+        assert_eq!(compact_from_target(&U256::zero().not()), 0x2100ffff);
     }
     
     #[test]
-    fn test_u256_ordering() {
-        let small = U256::from_u32(0x12345678);
-        let large = U256::from_u32(0x87654321);
+    fn test_get_next_work_required_integer_math() {
+        let first = BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp: 1000000,
+            bits: 0x1b0404cb,
+            nonce: 0,
+        };
+        let expected_time = DIFFICULTY_ADJUSTMENT_INTERVAL * TARGET_TIME_PER_BLOCK;
+        let last_at = |timestamp: Natural| BlockHeader { timestamp, ..first.clone() };
+        let prev_headers = |last: &BlockHeader| vec![first.clone(), last.clone()];
+        
+        // Twice as fast halves the target exactly: 0x0404cb / 2 = 0x020265 (+ rounding down)
+        let fast = last_at(1000000 + expected_time / 2);
+        assert_eq!(get_next_work_required(&fast, &prev_headers(&fast)).unwrap(), 0x1b020265);
+        
+        // Adjustments are limited to a factor of 4
+        let very_fast = last_at(1000000 + 1);
+        let quarter = last_at(1000000 + expected_time / 4);
+        assert_eq!(
+            get_next_work_required(&very_fast, &prev_headers(&very_fast)).unwrap(),
+            get_next_work_required(&quarter, &prev_headers(&quarter)).unwrap()
+        );
+        assert_eq!(get_next_work_required(&quarter, &prev_headers(&quarter)).unwrap(), 0x1b010132);
         
-        assert!(small < large);
-        assert!(large > small);
-        assert_eq!(small.cmp(&small), std::cmp::Ordering::Equal);
+        let very_slow = last_at(1000000 + expected_time * 10);
+        assert_eq!(get_next_work_required(&very_slow, &prev_headers(&very_slow)).unwrap(), 0x1b10132c);
     }
     
     #[test]
-    fn test_serialize_header() {
-        let header = BlockHeader {
+    fn test_calculate_next_work_required_uses_chain_pow_limit() {
+        let last = BlockHeader {
             version: 1,
-            prev_block_hash: [1; 32],
-            merkle_root: [2; 32],
-            timestamp: 1234567890,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp: 1000000 + DIFFICULTY_ADJUSTMENT_INTERVAL * TARGET_TIME_PER_BLOCK * 4,
             bits: 0x1d00ffff,
-            nonce: 0x12345678,
+            nonce: 0,
         };
         
-        let bytes = serialize_header(&header);
-        assert_eq!(bytes.len(), 80); // 4 + 32 + 32 + 4 + 4 + 4 = 80 bytes
-    }
-    
-    #[test]
-    fn test_u256_from_bytes_simple() {
-        let bytes = [0u8; 32];
-        let value = u256_from_bytes(&bytes);
-        assert_eq!(value, 0);
+        // Four times easier stays within signet's limit but not mainnet's
+        let mainnet = calculate_next_work_required(&last, 1000000, &ChainParams::mainnet()).unwrap();
+        assert_eq!(mainnet, 0x1d00ffff);
+        let signet = calculate_next_work_required(&last, 1000000, &ChainParams::signet()).unwrap();
+        assert_eq!(signet, 0x1d03fffc);
+    }
+    
+    #[test]
+    fn test_get_next_work_required_at_period_boundaries() {
+        // Heights 0..=2015 at exactly 10 minutes apart, except the last
+        // block of the period arrives one week late
+        let mut headers: Vec<BlockHeader> = (0..DIFFICULTY_ADJUSTMENT_INTERVAL)
+            .map(|height| BlockHeader {
+                version: 1,
+                prev_block_hash: [0; 32],
+                merkle_root: [0; 32],
+                timestamp: 1000000 + height * TARGET_TIME_PER_BLOCK,
+                bits: 0x1b0404cb,
+                nonce: 0,
+            })
+            .collect();
+        headers.last_mut().unwrap().timestamp += 7 * 24 * 60 * 60;
+        
+        let params = ChainParams::mainnet();
+        let next = |height: Natural, prev: &[BlockHeader]| {
+            let block_time = prev.last().map_or(0, |last| last.timestamp + TARGET_TIME_PER_BLOCK);
+            get_next_work_required_at(height, block_time, prev, &params)
+        };
+        
+        assert_eq!(next(0, &[]).unwrap(), MAX_TARGET as Natural);
+        
+        // Within a period the parent's bits carry over, however late it was
+        assert_eq!(next(2015, &headers[..2015]).unwrap(), 0x1b0404cb);
+        
+        // At height 2016 the span is measured from height 0 to 2015:
+        // 2015 × 600 + 1 week ≈ 1.4995 × two weeks
+        let last = headers.last().unwrap();
+        let expected = calculate_next_work_required(last, headers[0].timestamp, &params).unwrap();
+        assert_eq!(next(2016, &headers).unwrap(), expected);
+        assert_eq!(expected, 0x1b0606ad);
+        
+        // Extra older headers do not move the start of the period
+        let mut longer = vec![headers[0].clone(); 10];
+        longer.extend(headers.iter().cloned());
+        assert_eq!(next(4032, &longer).unwrap(), expected);
+        
+        assert!(next(2016, &headers[1..]).is_err());
     }
     
     #[test]
-    fn test_u256_from_bytes_with_data() {
-        let mut bytes = [0u8; 32];
-        bytes[0] = 0x78;
-        bytes[1] = 0x56;
-        bytes[2] = 0x34;
-        bytes[3] = 0x12;
-        let value = u256_from_bytes(&bytes);
-        // The function reads bytes in big-endian order from the first 16 bytes
-        // So 0x78, 0x56, 0x34, 0x12 becomes 0x78563412...
-        assert_eq!(value, 0x78563412000000000000000000000000);
+    fn test_testnet_min_difficulty_blocks() {
+        let params = ChainParams::testnet();
+        let normal_bits = 0x1b0404cb;
+        let header = |timestamp: Natural, bits: Natural| BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [0; 32],
+            timestamp,
+            bits,
+            nonce: 0,
+        };
+        
+        // Heights 2016..=2020: a normal block, then two min-difficulty blocks
+        let mut prev = vec![
+            header(1000000, normal_bits),
+            header(1000600, normal_bits),
+            header(1001200, normal_bits),
+        ];
+        let height = 2016 + prev.len() as Natural;
+        
+        // Exactly 20 minutes is not enough, one second more is
+        let parent_time = prev.last().unwrap().timestamp;
+        assert_eq!(get_next_work_required_at(height, parent_time + 1200, &prev, &params).unwrap(), normal_bits);
+        assert_eq!(get_next_work_required_at(height, parent_time + 1201, &prev, &params).unwrap(), params.pow_limit_bits);
+        
+        // After min-difficulty blocks, a timely block returns to the last real target
+        prev.push(header(parent_time + 1201, params.pow_limit_bits));
+        prev.push(header(parent_time + 2402, params.pow_limit_bits));
+        let height = 2016 + prev.len() as Natural;
+        let parent_time = prev.last().unwrap().timestamp;
+        assert_eq!(get_next_work_required_at(height, parent_time + 600, &prev, &params).unwrap(), normal_bits);
+        
+        // A period start at min difficulty is not skipped over
+        let at_period_start = vec![header(1000000, params.pow_limit_bits), header(1000600, params.pow_limit_bits)];
+        assert_eq!(
+            get_next_work_required_at(2018, 1001200, &at_period_start, &params).unwrap(),
+            params.pow_limit_bits
+        );
+        
+        // Mainnet never allows it
+        assert_eq!(
+            get_next_work_required_at(height, parent_time + 1_000_000, &prev, &ChainParams::mainnet()).unwrap(),
+            params.pow_limit_bits // the parent's own bits
+        );
+    }
+    
+    #[test]
+    fn test_regtest_never_retargets() {
+        let params = ChainParams::regtest();
+        let headers: Vec<BlockHeader> = (0..DIFFICULTY_ADJUSTMENT_INTERVAL)
+            .map(|height| BlockHeader {
+                version: 1,
+                prev_block_hash: [0; 32],
+                merkle_root: [0; 32],
+                timestamp: 1000000 + height, // far faster than 10 minutes
+                bits: params.pow_limit_bits,
+                nonce: 0,
+            })
+            .collect();
+        
+        assert_eq!(get_next_work_required_at(0, 1000000, &[], &params).unwrap(), 0x207fffff);
+        assert_eq!(get_next_work_required_at(1000, 1001000, &headers[..1000], &params).unwrap(), 0x207fffff);
+        assert_eq!(get_next_work_required_at(2016, 1002016, &headers, &params).unwrap(), 0x207fffff);
+        
+        // Even with too few headers for a retarget
+        assert_eq!(get_next_work_required_at(4032, 1002016, &headers[..5], &params).unwrap(), 0x207fffff);
     }
 }
@@ -0,0 +1,221 @@
+//! Transaction relay decisions
+//!
+//! Whether a transaction is worth passing on combines several policy
+//! checks: the receiver may already have it, it may have been rejected
+//! recently, it may be non-standard or pay too little for this node or for
+//! the peer's feefilter. `evaluate_tx_for_relay` runs them in order of
+//! cost and names the first that fails. The network layer queues every
+//! transaction announcement through it (`PeerState::queue_tx_announcement`),
+//! and its `ChainState` keeps the `RecentRejects` of `process_transaction`
+//! until the tip changes.
+
+use crate::types::*;
+use crate::mempool::MIN_RELAY_FEE_RATE;
+use crate::network::inventory::KnownInventory;
+use crate::policy::{check_standard_tx, StandardnessPolicy};
+use crate::segwit::Witness;
+use crate::transaction::{calculate_tx_id, calculate_wtxid};
+
+/// Rejected transactions remembered until the next block
+pub const DEFAULT_RECENT_REJECTS_SIZE: usize = 120_000;
+
+/// Transactions rejected since the last block, by wtxid and txid
+///
+/// A block can make a rejected transaction valid (by confirming its
+/// parents), so the cache is cleared whenever the tip changes.
+#[derive(Debug, Clone)]
+pub struct RecentRejects {
+    hashes: KnownInventory,
+}
+
+impl RecentRejects {
+    pub fn new(capacity: usize) -> Self {
+        RecentRejects { hashes: KnownInventory::new(capacity) }
+    }
+
+    pub fn insert(&mut self, hash: Hash) {
+        self.hashes.insert(hash);
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// Forget everything, on a new tip
+    pub fn clear(&mut self) {
+        self.hashes.clear();
+    }
+}
+
+impl Default for RecentRejects {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECENT_REJECTS_SIZE)
+    }
+}
+
+/// Why a transaction is not relayed
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkipReason {
+    /// The receiver already has it
+    AlreadyKnown,
+    /// It was rejected since the last block
+    RecentlyRejected,
+    /// It breaks a standardness rule, with Bitcoin Core's reject reason
+    NonStandard(String),
+    /// It pays less than this node's minimum relay fee rate (sat/vB)
+    BelowMinRelayFee { fee_rate: f64, min_fee_rate: f64 },
+    /// It pays less than the peer's feefilter (sat/kvB)
+    BelowFeeFilter { fee_rate: u64, fee_filter: u64 },
+}
+
+/// Outcome of `evaluate_tx_for_relay`
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelayDecision {
+    Relay,
+    Skip(SkipReason),
+}
+
+/// What a relay decision depends on besides the transaction
+#[derive(Debug, Clone)]
+pub struct RelayContext<'a> {
+    pub policy: &'a StandardnessPolicy,
+    /// This node's minimum relay fee rate in sat/vB
+    pub min_relay_fee_rate: f64,
+    pub recent_rejects: &'a RecentRejects,
+    /// Transactions the receiving peer knows of; `None` for no peer
+    pub known: Option<&'a KnownInventory>,
+    /// The receiving peer's feefilter in sat/kvB, if it sent one
+    pub fee_filter: Option<u64>,
+}
+
+impl<'a> RelayContext<'a> {
+    /// Context with no receiving peer and the default relay fee
+    pub fn new(policy: &'a StandardnessPolicy, recent_rejects: &'a RecentRejects) -> Self {
+        RelayContext { policy, min_relay_fee_rate: MIN_RELAY_FEE_RATE, recent_rejects, known: None, fee_filter: None }
+    }
+}
+
+/// PassesFeeFilter: ℕ × ℕ? → {true, false}
+///
+/// A fee rate in sat/kvB clears a feefilter if it is at least the filter;
+/// no filter lets everything through.
+pub fn passes_fee_filter(fee_rate: u64, fee_filter: Option<u64>) -> bool {
+    fee_filter.is_none_or(|fee_filter| fee_rate >= fee_filter)
+}
+
+/// EvaluateTxForRelay: 𝒯𝒳 × 𝒲* × ℤ × ℕ × RelayContext → RelayDecision
+///
+/// For tx with witnesses, paying `fee` for `vsize` virtual bytes:
+/// 1. Skip if the peer knows its txid or wtxid
+/// 2. Skip if its txid or wtxid was rejected since the last block
+/// 3. Skip if it is not standard
+/// 4. Skip if fee / vsize < min_relay_fee_rate
+/// 5. Skip if 1000 · fee / vsize is below the peer's feefilter
+/// 6. Otherwise relay
+pub fn evaluate_tx_for_relay(
+    tx: &Transaction,
+    witnesses: &[Witness],
+    fee: Integer,
+    vsize: Natural,
+    context: &RelayContext,
+) -> RelayDecision {
+    let txid = calculate_tx_id(tx);
+    let wtxid = calculate_wtxid(tx, witnesses);
+
+    // 1. Known
+    if let Some(known) = context.known {
+        if known.contains(&txid) || known.contains(&wtxid) {
+            return RelayDecision::Skip(SkipReason::AlreadyKnown);
+        }
+    }
+
+    // 2. Recently rejected
+    if context.recent_rejects.contains(&txid) || context.recent_rejects.contains(&wtxid) {
+        return RelayDecision::Skip(SkipReason::RecentlyRejected);
+    }
+
+    // 3. Standardness
+    if let ValidationResult::Invalid(reason) = check_standard_tx(tx, witnesses, context.policy) {
+        return RelayDecision::Skip(SkipReason::NonStandard(reason));
+    }
+
+    // 4. Relay fee floor
+    let vsize = vsize.max(1);
+    let fee = fee.max(0) as u64;
+    let fee_rate = fee as f64 / vsize as f64;
+    if fee_rate < context.min_relay_fee_rate {
+        return RelayDecision::Skip(SkipReason::BelowMinRelayFee { fee_rate, min_fee_rate: context.min_relay_fee_rate });
+    }
+
+    // 5. Feefilter
+    let fee_rate = fee.saturating_mul(1000) / vsize;
+    if let Some(fee_filter) = context.fee_filter {
+        if !passes_fee_filter(fee_rate, Some(fee_filter)) {
+            return RelayDecision::Skip(SkipReason::BelowFeeFilter { fee_rate, fee_filter });
+        }
+    }
+
+    // 6. Relay
+    RelayDecision::Relay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx() -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [1; 32], index: 0 },
+                script_sig: vec![],
+                sequence: 0xfffffffd,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 50_000,
+                script_pubkey: [vec![0x00, 0x14], vec![0xab; 20]].concat(),
+            }],
+            lock_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_evaluate_tx_for_relay() {
+        let policy = StandardnessPolicy::default();
+        let mut recent_rejects = RecentRejects::default();
+        let mut known = KnownInventory::new(10);
+        let tx = tx();
+        let mut context = RelayContext::new(&policy, &recent_rejects);
+        assert_eq!(evaluate_tx_for_relay(&tx, &[], 200, 100, &context), RelayDecision::Relay);
+
+        // Fee floors: this node's minimum, then the peer's feefilter
+        assert_eq!(
+            evaluate_tx_for_relay(&tx, &[], 50, 100, &context),
+            RelayDecision::Skip(SkipReason::BelowMinRelayFee { fee_rate: 0.5, min_fee_rate: 1.0 })
+        );
+        context.fee_filter = Some(3000);
+        assert_eq!(
+            evaluate_tx_for_relay(&tx, &[], 200, 100, &context),
+            RelayDecision::Skip(SkipReason::BelowFeeFilter { fee_rate: 2000, fee_filter: 3000 })
+        );
+
+        // Non-standard outputs
+        let mut bare = tx.clone();
+        bare.outputs[0].script_pubkey = vec![0x99];
+        assert_eq!(
+            evaluate_tx_for_relay(&bare, &[], 1000, 100, &context),
+            RelayDecision::Skip(SkipReason::NonStandard("scriptpubkey".to_string()))
+        );
+
+        // Known to the peer, or rejected since the last block
+        known.insert(calculate_tx_id(&tx));
+        context.known = Some(&known);
+        assert_eq!(evaluate_tx_for_relay(&tx, &[], 1000, 100, &context), RelayDecision::Skip(SkipReason::AlreadyKnown));
+        recent_rejects.insert(calculate_tx_id(&tx));
+        let context = RelayContext::new(&policy, &recent_rejects);
+        assert_eq!(evaluate_tx_for_relay(&tx, &[], 1000, 100, &context), RelayDecision::Skip(SkipReason::RecentlyRejected));
+        recent_rejects.clear();
+        let context = RelayContext::new(&policy, &recent_rejects);
+        assert_eq!(evaluate_tx_for_relay(&tx, &[], 1000, 100, &context), RelayDecision::Relay);
+    }
+}
@@ -78,6 +78,115 @@ pub fn verify_script(
     Ok(stack.len() == 1 && !stack[0].is_empty() && stack[0][0] != 0)
 }
 
+/// CountSigOps: 𝒮𝒞 × {true, false} → ℕ
+/// 
+/// For script s and accuracy flag a, walking the opcodes of s (push data skipped):
+/// 1. OP_CHECKSIG / OP_CHECKSIGVERIFY count 1
+/// 2. OP_CHECKMULTISIG / OP_CHECKMULTISIGVERIFY count n if a and the previous
+///    opcode is OP_n (1 ≤ n ≤ 16), otherwise MAX_PUBKEYS_PER_MULTISIG
+/// 3. Counting stops at the first malformed push
+pub fn count_sigops(script: &ByteString, accurate: bool) -> u32 {
+    let mut count = 0u32;
+    let mut last_opcode = 0xffu8; // OP_INVALIDOPCODE
+    let mut pc = 0;
+    
+    while let Some((opcode, _)) = read_op(script, &mut pc) {
+        match opcode {
+            // OP_CHECKSIG, OP_CHECKSIGVERIFY
+            0xac | 0xad => count += 1,
+            // OP_CHECKMULTISIG, OP_CHECKMULTISIGVERIFY
+            0xae | 0xaf => {
+                if accurate && (0x51..=0x60).contains(&last_opcode) {
+                    count += (last_opcode - 0x50) as u32;
+                } else {
+                    count += MAX_PUBKEYS_PER_MULTISIG;
+                }
+            }
+            _ => {}
+        }
+        last_opcode = opcode;
+    }
+    
+    count
+}
+
+/// Check if a scriptPubKey is pay-to-script-hash: OP_HASH160 <20 bytes> OP_EQUAL
+pub fn is_p2sh(script_pubkey: &ByteString) -> bool {
+    script_pubkey.len() == 23 &&
+    script_pubkey[0] == 0xa9 &&
+    script_pubkey[1] == 0x14 &&
+    script_pubkey[22] == 0x87
+}
+
+/// Check if a script consists only of push operations (OP_0..OP_16 included)
+pub fn is_push_only(script: &ByteString) -> bool {
+    let mut pc = 0;
+    while pc < script.len() {
+        match read_op(script, &mut pc) {
+            Some((opcode, _)) if opcode <= 0x60 => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// CountP2SHSigOps: 𝒮𝒞 × 𝒮𝒞 → ℕ
+/// 
+/// Accurate sigop count of the redeem script (the last push of scriptSig)
+/// when scriptPubKey is P2SH; 0 otherwise or if scriptSig is not push-only.
+pub fn count_p2sh_sigops(script_sig: &ByteString, script_pubkey: &ByteString) -> u32 {
+    if !is_p2sh(script_pubkey) {
+        return 0;
+    }
+    
+    let mut redeem_script: &[u8] = &[];
+    let mut pc = 0;
+    while pc < script_sig.len() {
+        match read_op(script_sig, &mut pc) {
+            // Only pushes (OP_0..OP_16 included) are allowed
+            Some((opcode, data)) if opcode <= 0x60 => redeem_script = data,
+            _ => return 0,
+        }
+    }
+    
+    count_sigops(&redeem_script.to_vec(), true)
+}
+
+/// Read the opcode at `pc` and its push data, advancing `pc` past both
+/// 
+/// Returns `None` at the end of the script or on a truncated push.
+pub(crate) fn read_op<'a>(script: &'a [u8], pc: &mut usize) -> Option<(u8, &'a [u8])> {
+    let opcode = *script.get(*pc)?;
+    *pc += 1;
+    
+    let len = match opcode {
+        0x01..=0x4b => opcode as usize,
+        // OP_PUSHDATA1
+        0x4c => {
+            let len = *script.get(*pc)? as usize;
+            *pc += 1;
+            len
+        }
+        // OP_PUSHDATA2
+        0x4d => {
+            let bytes = script.get(*pc..*pc + 2)?;
+            *pc += 2;
+            u16::from_le_bytes([bytes[0], bytes[1]]) as usize
+        }
+        // OP_PUSHDATA4
+        0x4e => {
+            let bytes = script.get(*pc..*pc + 4)?;
+            *pc += 4;
+            u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+        }
+        _ => 0,
+    };
+    
+    let data = script.get(*pc..pc.checked_add(len)?)?;
+    *pc += len;
+    Some((opcode, data))
+}
+
 /// Execute a single opcode
 fn execute_opcode(opcode: u8, stack: &mut Vec<ByteString>, flags: u32) -> Result<bool> {
     match opcode {
@@ -1134,4 +1243,44 @@ mod tests {
         let result = verify_signature(&secp, &pubkey, &invalid_signature, 0);
         assert!(!result);
     }
+    
+    #[test]
+    fn test_count_sigops_legacy_and_accurate() {
+        // P2PKH: OP_DUP OP_HASH160 <20> OP_EQUALVERIFY OP_CHECKSIG
+        let mut p2pkh = vec![0x76, 0xa9, 0x14];
+        p2pkh.extend_from_slice(&[0u8; 20]);
+        p2pkh.extend_from_slice(&[0x88, 0xac]);
+        assert_eq!(count_sigops(&p2pkh, false), 1);
+        
+        // 2-of-3 multisig counts 20 inexactly, 3 accurately
+        let multisig = vec![0x52, 0x53, 0xae];
+        assert_eq!(count_sigops(&multisig, false), MAX_PUBKEYS_PER_MULTISIG);
+        assert_eq!(count_sigops(&multisig, true), 3);
+        
+        // OP_CHECKSIG bytes inside push data are not opcodes
+        let push = vec![0x02, 0xac, 0xac];
+        assert_eq!(count_sigops(&push, false), 0);
+        
+        // Counting stops at a truncated push
+        let truncated = vec![0xac, 0x4c, 0x05, 0xac];
+        assert_eq!(count_sigops(&truncated, false), 1);
+    }
+    
+    #[test]
+    fn test_count_p2sh_sigops() {
+        let mut p2sh = vec![0xa9, 0x14];
+        p2sh.extend_from_slice(&[0u8; 20]);
+        p2sh.push(0x87);
+        assert!(is_p2sh(&p2sh));
+        
+        // scriptSig: OP_0 <redeem script: OP_1 <pk> <pk> OP_2 OP_CHECKMULTISIG>
+        let redeem = vec![0x51, 0x52, 0xae];
+        let mut script_sig = vec![0x00, redeem.len() as u8];
+        script_sig.extend_from_slice(&redeem);
+        assert_eq!(count_p2sh_sigops(&script_sig, &p2sh), 2);
+        
+        // Non-P2SH outputs and non-push-only scriptSigs contribute nothing
+        assert_eq!(count_p2sh_sigops(&script_sig, &vec![0x51]), 0);
+        assert_eq!(count_p2sh_sigops(&vec![0xac], &p2sh), 0);
+    }
 }
@@ -0,0 +1,461 @@
+//! Consensus serialization of transactions and hashing helpers
+
+use crate::types::*;
+use crate::error::ConsensusError;
+use crate::segwit::Witness;
+use sha2::{Sha256, Digest};
+use thiserror::Error;
+
+/// Why bytes could not be decoded
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SerializationError {
+    /// The data ends in the middle of a field
+    #[error("Unexpected end of data")]
+    Truncated,
+
+    #[error("Non-canonical varint")]
+    NonCanonicalVarint,
+
+    #[error("Length out of range")]
+    LengthOutOfRange,
+}
+
+impl From<SerializationError> for ConsensusError {
+    fn from(error: SerializationError) -> Self {
+        ConsensusError::Serialization(error.to_string())
+    }
+}
+
+type Result<T> = std::result::Result<T, SerializationError>;
+
+/// Encode a number as a Bitcoin varint (CompactSize)
+pub fn encode_varint(value: u64) -> Vec<u8> {
+    if value < 0xfd {
+        vec![value as u8]
+    } else if value <= 0xffff {
+        let mut result = vec![0xfd];
+        result.extend_from_slice(&(value as u16).to_le_bytes());
+        result
+    } else if value <= 0xffffffff {
+        let mut result = vec![0xfe];
+        result.extend_from_slice(&(value as u32).to_le_bytes());
+        result
+    } else {
+        let mut result = vec![0xff];
+        result.extend_from_slice(&value.to_le_bytes());
+        result
+    }
+}
+
+/// Serialize a transaction in the legacy (non-witness) consensus format
+///
+/// version ‖ |ins| ‖ ins ‖ |outs| ‖ outs ‖ lock_time
+pub fn serialize_transaction(tx: &Transaction) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    // Version (4 bytes, little-endian)
+    data.extend_from_slice(&(tx.version as u32).to_le_bytes());
+
+    // Input count (varint)
+    data.extend_from_slice(&encode_varint(tx.inputs.len() as u64));
+
+    // Inputs
+    for input in &tx.inputs {
+        // Previous output hash (32 bytes)
+        data.extend_from_slice(&input.prevout.hash);
+        // Previous output index (4 bytes, little-endian)
+        data.extend_from_slice(&(input.prevout.index as u32).to_le_bytes());
+        // Script length (varint)
+        data.extend_from_slice(&encode_varint(input.script_sig.len() as u64));
+        // Script
+        data.extend_from_slice(&input.script_sig);
+        // Sequence (4 bytes, little-endian)
+        data.extend_from_slice(&(input.sequence as u32).to_le_bytes());
+    }
+
+    // Output count (varint)
+    data.extend_from_slice(&encode_varint(tx.outputs.len() as u64));
+
+    // Outputs
+    for output in &tx.outputs {
+        // Value (8 bytes, little-endian)
+        data.extend_from_slice(&(output.value as u64).to_le_bytes());
+        // Script length (varint)
+        data.extend_from_slice(&encode_varint(output.script_pubkey.len() as u64));
+        // Script
+        data.extend_from_slice(&output.script_pubkey);
+    }
+
+    // Lock time (4 bytes, little-endian)
+    data.extend_from_slice(&(tx.lock_time as u32).to_le_bytes());
+
+    data
+}
+
+/// Serialize a transaction in the BIP144 witness format
+///
+/// version ‖ 0x00 ‖ 0x01 ‖ |ins| ‖ ins ‖ |outs| ‖ outs ‖ witnesses ‖ lock_time,
+/// where `witnesses[i]` is the stack of input i, each written as its item
+/// count followed by length-prefixed items; missing stacks are empty.
+/// Without any witness data this is the legacy serialization.
+pub fn serialize_transaction_with_witness(tx: &Transaction, witnesses: &[Witness]) -> Vec<u8> {
+    let legacy = serialize_transaction(tx);
+    if witnesses.iter().all(|witness| witness.is_empty()) {
+        return legacy;
+    }
+
+    let (version, rest) = legacy.split_at(4);
+    let (inputs_and_outputs, lock_time) = rest.split_at(rest.len() - 4);
+
+    let mut data = Vec::with_capacity(legacy.len() + 2);
+    data.extend_from_slice(version);
+    // Marker and flag
+    data.extend_from_slice(&[0x00, 0x01]);
+    data.extend_from_slice(inputs_and_outputs);
+    for i in 0..tx.inputs.len() {
+        let stack = witnesses.get(i).map(Vec::as_slice).unwrap_or_default();
+        data.extend_from_slice(&encode_varint(stack.len() as u64));
+        for item in stack {
+            data.extend_from_slice(&encode_varint(item.len() as u64));
+            data.extend_from_slice(item);
+        }
+    }
+    data.extend_from_slice(lock_time);
+    data
+}
+
+/// Decode a Bitcoin varint (CompactSize) from the start of `data`
+///
+/// Returns the value and the number of bytes read. Non-canonical encodings,
+/// which use more bytes than needed, are rejected as Bitcoin Core does.
+pub fn decode_varint(data: &[u8]) -> Result<(u64, usize)> {
+    let mut pos = 0;
+    let prefix = take(data, &mut pos, 1)?[0];
+    let (value, min) = match prefix {
+        0xfd => (u16::from_le_bytes(take(data, &mut pos, 2)?.try_into().unwrap()) as u64, 0xfd),
+        0xfe => (u32::from_le_bytes(take(data, &mut pos, 4)?.try_into().unwrap()) as u64, 0x1_0000),
+        0xff => (u64::from_le_bytes(take(data, &mut pos, 8)?.try_into().unwrap()), 0x1_0000_0000),
+        small => return Ok((small as u64, 1)),
+    };
+    if value < min {
+        return Err(SerializationError::NonCanonicalVarint);
+    }
+    Ok((value, pos))
+}
+
+/// Deserialize a transaction in the legacy consensus format from the start
+/// of `data`, returning it and the number of bytes read
+pub fn deserialize_transaction(data: &[u8]) -> Result<(Transaction, usize)> {
+    let mut pos = 0;
+    let read_u32 = |pos: &mut usize| -> Result<u32> {
+        Ok(u32::from_le_bytes(take(data, pos, 4)?.try_into().unwrap()))
+    };
+    let read_varint = |pos: &mut usize| -> Result<usize> {
+        let (value, len) = decode_varint(&data[*pos..])?;
+        *pos += len;
+        usize::try_from(value).map_err(|_| SerializationError::LengthOutOfRange)
+    };
+
+    let version = read_u32(&mut pos)? as Natural;
+
+    let mut inputs = Vec::new();
+    for _ in 0..read_varint(&mut pos)? {
+        let hash: Hash = take(data, &mut pos, 32)?.try_into().unwrap();
+        let index = read_u32(&mut pos)? as Natural;
+        let script_len = read_varint(&mut pos)?;
+        let script_sig = take(data, &mut pos, script_len)?.to_vec();
+        let sequence = read_u32(&mut pos)? as Natural;
+        inputs.push(TransactionInput { prevout: OutPoint { hash, index }, script_sig, sequence });
+    }
+
+    let mut outputs = Vec::new();
+    for _ in 0..read_varint(&mut pos)? {
+        let value = u64::from_le_bytes(take(data, &mut pos, 8)?.try_into().unwrap()) as Integer;
+        let script_len = read_varint(&mut pos)?;
+        let script_pubkey = take(data, &mut pos, script_len)?.to_vec();
+        outputs.push(TransactionOutput { value, script_pubkey });
+    }
+
+    let lock_time = read_u32(&mut pos)? as Natural;
+    Ok((Transaction { version, inputs, outputs, lock_time }, pos))
+}
+
+/// The `len` bytes at `pos`, advancing `pos` past them
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let bytes = pos.checked_add(len)
+        .and_then(|end| data.get(*pos..end))
+        .ok_or(SerializationError::Truncated)?;
+    *pos += len;
+    Ok(bytes)
+}
+
+/// Serialize a block header in the 80-byte consensus format
+///
+/// version ‖ prev_block_hash ‖ merkle_root ‖ timestamp ‖ bits ‖ nonce
+pub fn serialize_header(header: &BlockHeader) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(80);
+    
+    // Version (4 bytes, little-endian)
+    bytes.extend_from_slice(&(header.version as u32).to_le_bytes());
+    
+    // Previous block hash (32 bytes)
+    bytes.extend_from_slice(&header.prev_block_hash);
+    
+    // Merkle root (32 bytes)
+    bytes.extend_from_slice(&header.merkle_root);
+    
+    // Timestamp (4 bytes, little-endian)
+    bytes.extend_from_slice(&(header.timestamp as u32).to_le_bytes());
+    
+    // Bits (4 bytes, little-endian)
+    bytes.extend_from_slice(&(header.bits as u32).to_le_bytes());
+    
+    // Nonce (4 bytes, little-endian)
+    bytes.extend_from_slice(&(header.nonce as u32).to_le_bytes());
+    
+    bytes
+}
+
+/// Serialize a block without witness data
+///
+/// header ‖ |txs| ‖ txs
+pub fn serialize_block(block: &Block) -> Vec<u8> {
+    let mut bytes = serialize_header(&block.header);
+    bytes.extend_from_slice(&encode_varint(block.transactions.len() as u64));
+    for tx in &block.transactions {
+        bytes.extend_from_slice(&serialize_transaction(tx));
+    }
+    bytes
+}
+
+/// Hash256: SHA256(SHA256(data))
+pub fn double_sha256(data: &[u8]) -> Hash {
+    let first = Sha256::digest(data);
+    let second = Sha256::digest(first);
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&second);
+    hash
+}
+
+/// SipHash-2-4 of `data` under the 128-bit key (k0, k1)
+///
+/// Used for short transaction ids (BIP152) and filter hashing (BIP158),
+/// where the key is chosen per block so ids cannot be precomputed.
+pub fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let chunks = data.chunks_exact(8);
+    // The last block holds the leftover bytes and the length in its top byte
+    let mut last = (data.len() as u64) << 56;
+    for (i, &byte) in chunks.remainder().iter().enumerate() {
+        last |= (byte as u64) << (8 * i);
+    }
+    for word in chunks.map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap())).chain(std::iter::once(last)) {
+        v[3] ^= word;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= word;
+    }
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_varint_small() {
+        let encoded = encode_varint(0x42);
+        assert_eq!(encoded, vec![0x42]);
+    }
+
+    #[test]
+    fn test_encode_varint_medium() {
+        let encoded = encode_varint(0x1234);
+        assert_eq!(encoded.len(), 3);
+        assert_eq!(encoded[0], 0xfd);
+    }
+
+    #[test]
+    fn test_encode_varint_large() {
+        let encoded = encode_varint(0x12345678);
+        assert_eq!(encoded.len(), 5);
+        assert_eq!(encoded[0], 0xfe);
+    }
+
+    #[test]
+    fn test_encode_varint_huge() {
+        let encoded = encode_varint(0x123456789abcdef0);
+        assert_eq!(encoded.len(), 9);
+        assert_eq!(encoded[0], 0xff);
+    }
+
+    #[test]
+    fn test_serialize_transaction_layout() {
+        let tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [1; 32], index: 0 },
+                script_sig: vec![0x51],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 1000,
+                script_pubkey: vec![0x51],
+            }],
+            lock_time: 0,
+        };
+
+        let bytes = serialize_transaction(&tx);
+        // 4 + 1 + (32 + 4 + 1 + 1 + 4) + 1 + (8 + 1 + 1) + 4
+        assert_eq!(bytes.len(), 62);
+        assert_eq!(&bytes[0..4], &[1, 0, 0, 0]);
+        assert_eq!(bytes[4], 1);
+    }
+
+    #[test]
+    fn test_serialize_transaction_with_witness() {
+        let tx = Transaction {
+            version: 2,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [1; 32], index: 0 },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput { value: 1000, script_pubkey: vec![0x51] }],
+            lock_time: 7,
+        };
+        let legacy = serialize_transaction(&tx);
+        assert_eq!(serialize_transaction_with_witness(&tx, &[]), legacy);
+        assert_eq!(serialize_transaction_with_witness(&tx, &[vec![]]), legacy);
+
+        let bytes = serialize_transaction_with_witness(&tx, &[vec![vec![0xaa; 3], vec![]]]);
+        // Marker and flag, then one stack of two items: 1 + (1 + 3) + (1 + 0)
+        assert_eq!(bytes.len(), legacy.len() + 2 + 6);
+        assert_eq!(&bytes[4..6], &[0x00, 0x01]);
+        assert_eq!(&bytes[6..legacy.len() - 4 + 2], &legacy[4..legacy.len() - 4]);
+        assert_eq!(&bytes[bytes.len() - 10..], &[2, 3, 0xaa, 0xaa, 0xaa, 0, 7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_decode_varint() {
+        for value in [0, 0xfc, 0xfd, 0xffff, 0x10000, 0xffffffff, 0x100000000, u64::MAX] {
+            let encoded = encode_varint(value);
+            assert_eq!(decode_varint(&encoded).unwrap(), (value, encoded.len()));
+        }
+        assert_eq!(decode_varint(&[]), Err(SerializationError::Truncated));
+        assert_eq!(decode_varint(&[0xfe, 0x01]), Err(SerializationError::Truncated));
+        // 0x10 fits in one byte, so the three-byte form is non-canonical
+        assert_eq!(decode_varint(&[0xfd, 0x10, 0x00]), Err(SerializationError::NonCanonicalVarint));
+    }
+
+    #[test]
+    fn test_deserialize_transaction_round_trip() {
+        let tx = Transaction {
+            version: 2,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [7; 32], index: 3 },
+                script_sig: vec![0x51; 300],
+                sequence: 0xfffffffd,
+            }],
+            outputs: vec![
+                TransactionOutput { value: 1000, script_pubkey: vec![0x51] },
+                TransactionOutput { value: 2_100_000_000_000_000, script_pubkey: vec![] },
+            ],
+            lock_time: 500_000,
+        };
+
+        let mut bytes = serialize_transaction(&tx);
+        assert_eq!(deserialize_transaction(&bytes).unwrap(), (tx.clone(), bytes.len()));
+
+        // Trailing data is left to the caller; truncation is an error
+        bytes.push(0xaa);
+        assert_eq!(deserialize_transaction(&bytes).unwrap().1, bytes.len() - 1);
+        assert_eq!(deserialize_transaction(&bytes[..bytes.len() - 2]), Err(SerializationError::Truncated));
+
+        // A script length given in three bytes when one would do
+        let mut non_canonical = bytes[..41].to_vec();
+        non_canonical.extend_from_slice(&[0xfd, 0x01, 0x00, 0x51]);
+        assert_eq!(deserialize_transaction(&non_canonical), Err(SerializationError::NonCanonicalVarint));
+    }
+
+    #[test]
+    fn test_double_sha256_known_vector() {
+        // Hash256 of the empty string
+        let hash = double_sha256(&[]);
+        assert_eq!(hash[0], 0x5d);
+        assert_eq!(hash[1], 0xf6);
+        assert_eq!(hash[31], 0x56);
+    }
+    
+    #[test]
+    fn test_siphash24_reference_vectors() {
+        // From the SipHash paper: key 00..0f, messages 00..(n-1)
+        let (k0, k1) = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+        let message: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(k0, k1, &[]), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash24(k0, k1, &message[..8]), 0x93f5f5799a932462);
+        assert_eq!(siphash24(k0, k1, &message), 0xa129ca6149be45e5);
+    }
+
+    #[test]
+    fn test_serialize_header() {
+        let header = BlockHeader {
+            version: 1,
+            prev_block_hash: [1; 32],
+            merkle_root: [2; 32],
+            timestamp: 1234567890,
+            bits: 0x1d00ffff,
+            nonce: 0x12345678,
+        };
+        
+        let bytes = serialize_header(&header);
+        assert_eq!(bytes.len(), 80); // 4 + 32 + 32 + 4 + 4 + 4 = 80 bytes
+        assert_eq!(&bytes[76..80], &[0x78, 0x56, 0x34, 0x12]);
+    }
+    
+    #[test]
+    fn test_block_hash_genesis() {
+        let header = BlockHeader {
+            version: 1,
+            prev_block_hash: [0; 32],
+            merkle_root: [
+                0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2, 0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f, 0x61,
+                0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32, 0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e, 0x5e, 0x4a,
+            ],
+            timestamp: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 2083236893,
+        };
+        
+        // 000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f (display order)
+        let expected: Hash = [
+            0x6f, 0xe2, 0x8c, 0x0a, 0xb6, 0xf1, 0xb3, 0x72, 0xc1, 0xa6, 0xa2, 0x46, 0xae, 0x63, 0xf7, 0x4f,
+            0x93, 0x1e, 0x83, 0x65, 0xe1, 0x5a, 0x08, 0x9c, 0x68, 0xd6, 0x19, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(header.block_hash(), expected);
+        assert_eq!(header.block_hash(), double_sha256(&serialize_header(&header)));
+    }
+}
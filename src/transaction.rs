@@ -3,6 +3,9 @@
 use crate::types::*;
 use crate::constants::*;
 use crate::error::Result;
+use crate::serialization::{serialize_transaction, serialize_transaction_with_witness, double_sha256};
+use crate::segwit::Witness;
+use crate::script::{count_sigops, count_p2sh_sigops};
 
 /// CheckTransaction: 𝒯𝒳 → {valid, invalid}
 /// 
@@ -103,6 +106,124 @@ pub fn is_coinbase(tx: &Transaction) -> bool {
     tx.inputs[0].prevout.index == 0xffffffff
 }
 
+/// IsFinalTx: 𝒯𝒳 × ℕ × ℕ → {true, false}
+///
+/// For transaction tx in a block at height h with lock-time cutoff t:
+/// 1. If tx.lock_time = 0: return true
+/// 2. Compare tx.lock_time against h if it is below LOCKTIME_THRESHOLD, else against t;
+///    if tx.lock_time < that value: return true
+/// 3. Otherwise return true iff every input has sequence SEQUENCE_FINAL
+///
+/// The cutoff t is the block timestamp, or the median time past once BIP113 is active.
+pub fn is_final_tx(tx: &Transaction, height: Natural, lock_time_cutoff: Natural) -> bool {
+    if tx.lock_time == 0 {
+        return true;
+    }
+    
+    let limit = if tx.lock_time < LOCKTIME_THRESHOLD as Natural {
+        height
+    } else {
+        lock_time_cutoff
+    };
+    if tx.lock_time < limit {
+        return true;
+    }
+    
+    tx.inputs.iter().all(|input| input.sequence == SEQUENCE_FINAL as Natural)
+}
+
+/// Earliest block a transaction's BIP68 relative locks allow: it must have
+/// height greater than `min_height` and a parent with median time past
+/// greater than `min_time`; -1 means no constraint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceLocks {
+    pub min_height: Integer,
+    pub min_time: Integer,
+}
+
+/// CalculateSequenceLocks: 𝒯𝒳 × ℕ* × (ℕ → ℕ) → SequenceLocks
+///
+/// For transaction tx whose input i spends a coin confirmed at height
+/// prev_heights[i], with mtp(h) the median time past of the block at h:
+/// 1. If tx.version < 2: no constraint
+/// 2. Skip inputs with SEQUENCE_LOCKTIME_DISABLE_FLAG set
+/// 3. Time-based (SEQUENCE_LOCKTIME_TYPE_FLAG): min_time ≥
+///    mtp(max(h - 1, 0)) + (value << SEQUENCE_LOCKTIME_GRANULARITY) − 1
+/// 4. Height-based: min_height ≥ h + value − 1
+///
+/// where value = sequence & SEQUENCE_LOCKTIME_MASK.
+pub fn calculate_sequence_locks(
+    tx: &Transaction,
+    prev_heights: &[Natural],
+    median_time_past_at: impl Fn(Natural) -> Natural,
+) -> SequenceLocks {
+    let mut locks = SequenceLocks { min_height: -1, min_time: -1 };
+    if tx.version < 2 {
+        return locks;
+    }
+
+    for (input, &coin_height) in tx.inputs.iter().zip(prev_heights) {
+        if input.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as Natural != 0 {
+            continue;
+        }
+        let value = (input.sequence & SEQUENCE_LOCKTIME_MASK as Natural) as Integer;
+        if input.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG as Natural != 0 {
+            let coin_time = median_time_past_at(coin_height.saturating_sub(1)) as Integer;
+            locks.min_time = locks.min_time.max(coin_time + (value << SEQUENCE_LOCKTIME_GRANULARITY) - 1);
+        } else {
+            locks.min_height = locks.min_height.max(coin_height as Integer + value - 1);
+        }
+    }
+    locks
+}
+
+/// EvaluateSequenceLocks: SequenceLocks × ℕ × ℕ → {true, false}
+///
+/// Whether a block at `height` whose parent has median time past
+/// `median_time_past` satisfies `locks`.
+pub fn check_sequence_locks(locks: &SequenceLocks, height: Natural, median_time_past: Natural) -> bool {
+    locks.min_height < height as Integer && locks.min_time < median_time_past as Integer
+}
+
+/// TxId: 𝒯𝒳 → ℍ
+///
+/// txid(tx) = SHA256(SHA256(serialize(tx))) over the non-witness serialization
+pub fn calculate_tx_id(tx: &Transaction) -> Hash {
+    double_sha256(&serialize_transaction(tx))
+}
+
+/// WTxId: 𝒯𝒳 × 𝒲* → ℍ
+///
+/// wtxid(tx) = SHA256(SHA256(serialize(tx))) over the BIP144 witness
+/// serialization, with `witnesses[i]` the stack of input i. Equals the txid
+/// for a transaction without witness data.
+pub fn calculate_wtxid(tx: &Transaction, witnesses: &[Witness]) -> Hash {
+    double_sha256(&serialize_transaction_with_witness(tx, witnesses))
+}
+
+/// GetTransactionSigOpCost: 𝒯𝒳 × 𝒰𝒮 → ℕ
+///
+/// For transaction tx spending coins in us:
+/// 1. legacy = Σ CountSigOps(i.script_sig) + Σ CountSigOps(o.script_pubkey), inexact
+/// 2. p2sh = Σ CountP2SHSigOps(i.script_sig, us(i.prevout).script_pubkey) (0 for coinbase)
+/// 3. Return (legacy + p2sh) × WITNESS_SCALE_FACTOR
+///
+/// Inputs whose coin is missing from us contribute no P2SH sigops.
+pub fn get_transaction_sigop_cost(tx: &Transaction, utxo_set: &UtxoSet) -> Natural {
+    let legacy: u32 = tx.inputs.iter().map(|i| count_sigops(&i.script_sig, false)).sum::<u32>()
+        + tx.outputs.iter().map(|o| count_sigops(&o.script_pubkey, false)).sum::<u32>();
+    
+    let p2sh: u32 = if is_coinbase(tx) {
+        0
+    } else {
+        tx.inputs.iter()
+            .filter_map(|i| utxo_set.get(&i.prevout).map(|utxo| count_p2sh_sigops(&i.script_sig, &utxo.script_pubkey)))
+            .sum()
+    };
+    
+    (legacy as Natural + p2sh as Natural) * WITNESS_SCALE_FACTOR
+}
+
 /// Calculate transaction size (simplified)
 fn calculate_transaction_size(tx: &Transaction) -> usize {
     // Simplified size calculation
@@ -113,6 +234,41 @@ fn calculate_transaction_size(tx: &Transaction) -> usize {
     4 // lock_time
 }
 
+/// Bip69Sort: 𝒯𝒳 → 𝒯𝒳
+///
+/// Reorder a transaction's inputs and outputs into BIP69 canonical order:
+/// 1. Inputs ascending by (reversed prevout hash, prevout index)
+/// 2. Outputs ascending by (value, scriptPubKey bytes)
+///
+/// Version, lock time and the contents of each input/output are unchanged.
+pub fn bip69_sort(tx: &Transaction) -> Transaction {
+    let mut sorted = tx.clone();
+    sorted.inputs.sort_by(bip69_input_cmp);
+    sorted.outputs.sort_by(bip69_output_cmp);
+    sorted
+}
+
+/// IsBip69Sorted: 𝒯𝒳 → {true, false}
+///
+/// Check whether inputs and outputs are already in BIP69 canonical order
+pub fn is_bip69_sorted(tx: &Transaction) -> bool {
+    tx.inputs.windows(2).all(|w| bip69_input_cmp(&w[0], &w[1]) != std::cmp::Ordering::Greater) &&
+    tx.outputs.windows(2).all(|w| bip69_output_cmp(&w[0], &w[1]) != std::cmp::Ordering::Greater)
+}
+
+/// BIP69 input ordering: prevout hashes compare in reversed (display) byte order
+fn bip69_input_cmp(a: &TransactionInput, b: &TransactionInput) -> std::cmp::Ordering {
+    a.prevout.hash.iter().rev()
+        .cmp(b.prevout.hash.iter().rev())
+        .then(a.prevout.index.cmp(&b.prevout.index))
+}
+
+/// BIP69 output ordering: value first, then scriptPubKey lexicographically
+fn bip69_output_cmp(a: &TransactionOutput, b: &TransactionOutput) -> std::cmp::Ordering {
+    a.value.cmp(&b.value)
+        .then_with(|| a.script_pubkey.cmp(&b.script_pubkey))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -384,6 +540,7 @@ mod tests {
             value: 1000000000, // 10 BTC
             script_pubkey: vec![],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint, utxo);
         
@@ -441,6 +598,7 @@ mod tests {
             value: 100000000, // 1 BTC
             script_pubkey: vec![],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint, utxo);
         
@@ -474,6 +632,7 @@ mod tests {
             value: 500000000, // 5 BTC
             script_pubkey: vec![],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint1, utxo1);
         
@@ -482,6 +641,7 @@ mod tests {
             value: 300000000, // 3 BTC
             script_pubkey: vec![],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint2, utxo2);
         
@@ -617,4 +777,154 @@ mod tests {
         // The actual calculation includes script_sig and script_pubkey lengths
         assert_eq!(size, 108);
     }
+    
+    #[test]
+    fn test_bip69_sort_inputs_by_reversed_hash_then_index() {
+        let mut hash_a = [0u8; 32];
+        hash_a[0] = 0xff; // Large first byte, small when reversed
+        let mut hash_b = [0u8; 32];
+        hash_b[31] = 0x01; // Larger when compared in display order
+        
+        let tx = Transaction {
+            version: 1,
+            inputs: vec![
+                TransactionInput { prevout: OutPoint { hash: hash_b, index: 0 }, script_sig: vec![], sequence: 0xffffffff },
+                TransactionInput { prevout: OutPoint { hash: hash_a, index: 1 }, script_sig: vec![], sequence: 0xffffffff },
+                TransactionInput { prevout: OutPoint { hash: hash_a, index: 0 }, script_sig: vec![], sequence: 0xffffffff },
+            ],
+            outputs: vec![TransactionOutput { value: 1000, script_pubkey: vec![] }],
+            lock_time: 0,
+        };
+        
+        let sorted = bip69_sort(&tx);
+        assert_eq!(sorted.inputs[0].prevout, OutPoint { hash: hash_a, index: 0 });
+        assert_eq!(sorted.inputs[1].prevout, OutPoint { hash: hash_a, index: 1 });
+        assert_eq!(sorted.inputs[2].prevout, OutPoint { hash: hash_b, index: 0 });
+        assert!(!is_bip69_sorted(&tx));
+        assert!(is_bip69_sorted(&sorted));
+    }
+    
+    #[test]
+    fn test_bip69_sort_outputs_by_value_then_script() {
+        let tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [1; 32], index: 0 },
+                script_sig: vec![],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![
+                TransactionOutput { value: 2000, script_pubkey: vec![0x51] },
+                TransactionOutput { value: 1000, script_pubkey: vec![0x76, 0xa9] },
+                TransactionOutput { value: 1000, script_pubkey: vec![0x00, 0x14] },
+            ],
+            lock_time: 0,
+        };
+        
+        let sorted = bip69_sort(&tx);
+        assert_eq!(sorted.outputs[0].script_pubkey, vec![0x00, 0x14]);
+        assert_eq!(sorted.outputs[1].script_pubkey, vec![0x76, 0xa9]);
+        assert_eq!(sorted.outputs[2].value, 2000);
+        assert!(is_bip69_sorted(&sorted));
+        
+        // Sorting is idempotent
+        assert_eq!(bip69_sort(&sorted), sorted);
+    }
+    
+    #[test]
+    fn test_get_transaction_sigop_cost() {
+        let mut p2sh = vec![0xa9, 0x14];
+        p2sh.extend_from_slice(&[0u8; 20]);
+        p2sh.push(0x87);
+        
+        let mut utxo_set = UtxoSet::new();
+        let prevout = OutPoint { hash: [1; 32], index: 0 };
+        utxo_set.insert(prevout.clone(), UTXO { value: 1000, script_pubkey: p2sh, height: 0, is_coinbase: false });
+        
+        // Redeem script OP_2 OP_CHECKMULTISIG is pushed by the scriptSig
+        let tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout,
+                script_sig: vec![0x02, 0x52, 0xae],
+                sequence: 0xffffffff,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 900,
+                script_pubkey: vec![0xac], // OP_CHECKSIG
+            }],
+            lock_time: 0,
+        };
+        
+        // (1 legacy + 2 P2SH) * 4
+        assert_eq!(get_transaction_sigop_cost(&tx, &utxo_set), 12);
+        
+        // Without the spent coin only the legacy count remains
+        assert_eq!(get_transaction_sigop_cost(&tx, &UtxoSet::new()), 4);
+    }
+    
+    #[test]
+    fn test_is_final_tx() {
+        let mut tx = Transaction {
+            version: 1,
+            inputs: vec![TransactionInput {
+                prevout: OutPoint { hash: [1; 32], index: 0 },
+                script_sig: vec![],
+                sequence: 0,
+            }],
+            outputs: vec![TransactionOutput {
+                value: 1000,
+                script_pubkey: vec![],
+            }],
+            lock_time: 0,
+        };
+        assert!(is_final_tx(&tx, 0, 0));
+        
+        // Height-based lock: final strictly after the lock height
+        tx.lock_time = 100;
+        assert!(!is_final_tx(&tx, 100, 0));
+        assert!(is_final_tx(&tx, 101, 0));
+        
+        // Time-based lock compares against the cutoff, not the height
+        tx.lock_time = LOCKTIME_THRESHOLD as Natural + 10;
+        assert!(!is_final_tx(&tx, 1_000_000, LOCKTIME_THRESHOLD as Natural + 10));
+        assert!(is_final_tx(&tx, 0, LOCKTIME_THRESHOLD as Natural + 11));
+        
+        // Final sequences disable the lock
+        tx.inputs[0].sequence = SEQUENCE_FINAL as Natural;
+        assert!(is_final_tx(&tx, 0, 0));
+    }
+    
+    #[test]
+    fn test_sequence_locks() {
+        let input = |sequence: Natural| TransactionInput {
+            prevout: OutPoint { hash: [1; 32], index: 0 },
+            script_sig: vec![],
+            sequence,
+        };
+        let mut tx = Transaction {
+            version: 2,
+            inputs: vec![
+                input(10),
+                input(SEQUENCE_LOCKTIME_TYPE_FLAG as Natural | 2),
+                input(SEQUENCE_LOCKTIME_DISABLE_FLAG as Natural | 5000),
+            ],
+            outputs: vec![],
+            lock_time: 0,
+        };
+        let mtp = |height: Natural| 1_000_000 + height * 600;
+        
+        // 10 blocks after height 100; 1024 s after the MTP of block 199
+        let locks = calculate_sequence_locks(&tx, &[100, 200, 300], mtp);
+        assert_eq!(locks, SequenceLocks { min_height: 109, min_time: mtp(199) as Integer + 1023 });
+        assert!(!check_sequence_locks(&locks, 109, mtp(199) + 1024));
+        assert!(!check_sequence_locks(&locks, 110, mtp(199) + 1023));
+        assert!(check_sequence_locks(&locks, 110, mtp(199) + 1024));
+        
+        // Version 1 transactions have no relative locks
+        tx.version = 1;
+        let locks = calculate_sequence_locks(&tx, &[100, 200, 300], mtp);
+        assert_eq!(locks, SequenceLocks { min_height: -1, min_time: -1 });
+        assert!(check_sequence_locks(&locks, 0, 0));
+    }
 }
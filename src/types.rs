@@ -57,6 +57,16 @@ pub struct BlockHeader {
     pub nonce: Natural,
 }
 
+impl BlockHeader {
+    /// BlockHash: ℋ → ℍ
+    ///
+    /// SHA256(SHA256(serialize(h))) over the 80-byte header, in internal byte
+    /// order (the order used by `prev_block_hash`); reverse it for display.
+    pub fn block_hash(&self) -> Hash {
+        crate::serialization::double_sha256(&crate::serialization::serialize_header(self))
+    }
+}
+
 /// Block: ℬ = ℋ × 𝒯𝒳*
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Block {
@@ -64,12 +74,15 @@ pub struct Block {
     pub transactions: Vec<Transaction>,
 }
 
-/// UTXO: 𝒰 = ℤ × 𝕊 × ℕ
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// UTXO: 𝒰 = ℤ × 𝕊 × ℕ × {true, false}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UTXO {
     pub value: Integer,
     pub script_pubkey: ByteString,
     pub height: Natural,
+    /// Whether the creating transaction was a coinbase
+    #[serde(default)]
+    pub is_coinbase: bool,
 }
 
 /// UTXO Set: 𝒰𝒮 = 𝒪 → 𝒰
@@ -91,6 +104,244 @@ pub struct ScriptContext {
     pub flags: u32,
 }
 
+/// ℕ₂₅₆: 256-bit unsigned integer for targets, hashes and chain work
+///
+/// Stored as four little-endian 64-bit words. Byte conversions default to
+/// little-endian, the order in which block hashes are compared against
+/// targets; big-endian matches the usual display order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    /// 2^256 - 1
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+    
+    pub fn zero() -> Self {
+        U256([0; 4])
+    }
+    
+    pub fn one() -> Self {
+        U256([1, 0, 0, 0])
+    }
+    
+    pub fn from_u32(value: u32) -> Self {
+        U256([value as u64, 0, 0, 0])
+    }
+    
+    pub fn from_u64(value: u64) -> Self {
+        U256([value, 0, 0, 0])
+    }
+    
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&x| x == 0)
+    }
+    
+    /// Low 64 bits
+    pub fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+    
+    /// Low 128 bits, saturating to u128::MAX if any higher bit is set
+    pub fn saturating_to_u128(&self) -> u128 {
+        if self.0[2] != 0 || self.0[3] != 0 {
+            return u128::MAX;
+        }
+        (self.0[1] as u128) << 64 | self.0[0] as u128
+    }
+    
+    /// Number of significant bits
+    pub fn bits(&self) -> u32 {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i as u32 + 64 - self.0[i].leading_zeros();
+            }
+        }
+        0
+    }
+    
+    fn bit(&self, index: u32) -> bool {
+        (self.0[(index / 64) as usize] >> (index % 64)) & 1 == 1
+    }
+    
+    /// Left shift; shifts of 256 or more give zero
+    pub fn shl(&self, shift: u32) -> Self {
+        if shift >= 256 {
+            return U256::zero();
+        }
+        
+        let mut result = U256::zero();
+        let word_shift = (shift / 64) as usize;
+        let bit_shift = shift % 64;
+        
+        for i in 0..4 {
+            if i + word_shift < 4 {
+                result.0[i + word_shift] |= self.0[i] << bit_shift;
+                if bit_shift > 0 && i + word_shift + 1 < 4 {
+                    result.0[i + word_shift + 1] |= self.0[i] >> (64 - bit_shift);
+                }
+            }
+        }
+        
+        result
+    }
+    
+    /// Right shift; shifts of 256 or more give zero
+    pub fn shr(&self, shift: u32) -> Self {
+        if shift >= 256 {
+            return U256::zero();
+        }
+        
+        let mut result = U256::zero();
+        let word_shift = (shift / 64) as usize;
+        let bit_shift = shift % 64;
+        
+        for i in word_shift..4 {
+            result.0[i - word_shift] |= self.0[i] >> bit_shift;
+            // Low bits spill into the next less significant word
+            if bit_shift > 0 && i > word_shift {
+                result.0[i - word_shift - 1] |= self.0[i] << (64 - bit_shift);
+            }
+        }
+        
+        result
+    }
+    
+    /// Bitwise complement
+    pub fn not(&self) -> Self {
+        U256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+    
+    /// Addition with the carry out of the top word
+    fn overflowing_add(&self, other: &Self) -> (Self, bool) {
+        let mut result = U256::zero();
+        let mut carry = false;
+        for i in 0..4 {
+            let (sum, overflow1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, overflow2) = sum.overflowing_add(carry as u64);
+            result.0[i] = sum;
+            carry = overflow1 || overflow2;
+        }
+        (result, carry)
+    }
+    
+    /// Addition modulo 2^256
+    pub fn wrapping_add(&self, other: &Self) -> Self {
+        self.overflowing_add(other).0
+    }
+    
+    /// Addition; `None` on overflow
+    pub fn checked_add(&self, other: &Self) -> Option<Self> {
+        match self.overflowing_add(other) {
+            (sum, false) => Some(sum),
+            (_, true) => None,
+        }
+    }
+    
+    /// Addition clamped to 2^256 - 1
+    pub fn saturating_add(&self, other: &Self) -> Self {
+        self.checked_add(other).unwrap_or(U256::MAX)
+    }
+    
+    /// Subtraction modulo 2^256
+    pub fn wrapping_sub(&self, other: &Self) -> Self {
+        self.wrapping_add(&other.not().wrapping_add(&U256::one()))
+    }
+    
+    /// Subtraction; `None` if `other` is larger
+    pub fn checked_sub(&self, other: &Self) -> Option<Self> {
+        if other > self {
+            return None;
+        }
+        Some(self.wrapping_sub(other))
+    }
+    
+    /// Multiplication by a 64-bit value; `None` on overflow
+    pub fn checked_mul_u64(&self, factor: u64) -> Option<Self> {
+        let mut result = U256::zero();
+        let mut carry = 0u128;
+        for i in 0..4 {
+            let product = self.0[i] as u128 * factor as u128 + carry;
+            result.0[i] = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 {
+            return None;
+        }
+        Some(result)
+    }
+    
+    /// Integer division; `None` when dividing by zero
+    pub fn checked_div(&self, divisor: &Self) -> Option<Self> {
+        if divisor.is_zero() {
+            return None;
+        }
+        
+        // Binary long division, most significant bit first
+        let mut quotient = U256::zero();
+        let mut remainder = U256::zero();
+        for i in (0..256).rev() {
+            remainder = remainder.shl(1);
+            if self.bit(i) {
+                remainder.0[0] |= 1;
+            }
+            if remainder >= *divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.0[(i / 64) as usize] |= 1 << (i % 64);
+            }
+        }
+        Some(quotient)
+    }
+    
+    pub fn to_le_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, &word) in self.0.iter().enumerate() {
+            bytes[i * 8..(i + 1) * 8].copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+    
+    pub fn from_le_bytes(bytes: &[u8; 32]) -> Self {
+        let mut words = [0u64; 4];
+        for (i, word) in words.iter_mut().enumerate() {
+            let mut chunk = [0u8; 8];
+            chunk.copy_from_slice(&bytes[i * 8..(i + 1) * 8]);
+            *word = u64::from_le_bytes(chunk);
+        }
+        U256(words)
+    }
+    
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        let mut bytes = self.to_le_bytes();
+        bytes.reverse();
+        bytes
+    }
+    
+    pub fn from_be_bytes(bytes: &[u8; 32]) -> Self {
+        let mut le = *bytes;
+        le.reverse();
+        Self::from_le_bytes(&le)
+    }
+}
+
+impl From<u64> for U256 {
+    fn from(value: u64) -> Self {
+        Self::from_u64(value)
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Most significant word first
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
 /// Block validation context
 #[derive(Debug, Clone)]
 pub struct BlockContext {
@@ -98,3 +349,157 @@ pub struct BlockContext {
     pub prev_headers: Vec<BlockHeader>,
     pub utxo_set: UtxoSet,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_u256_zero() {
+        let zero = U256::zero();
+        assert!(zero.is_zero());
+    }
+    
+    #[test]
+    fn test_u256_from_u32() {
+        let value = U256::from_u32(0x12345678);
+        assert!(!value.is_zero());
+    }
+    
+    #[test]
+    fn test_u256_from_u64() {
+        let value = U256::from_u64(0x123456789abcdef0);
+        assert!(!value.is_zero());
+    }
+    
+    #[test]
+    fn test_u256_shl_zero_shift() {
+        let value = U256::from_u32(0x12345678);
+        let result = value.shl(0);
+        assert_eq!(result, value);
+    }
+    
+    #[test]
+    fn test_u256_shl_large_shift() {
+        let value = U256::from_u32(0x12345678);
+        let result = value.shl(300); // > 256
+        assert!(result.is_zero());
+    }
+    
+    #[test]
+    fn test_u256_shr_zero_shift() {
+        let value = U256::from_u32(0x12345678);
+        let result = value.shr(0);
+        assert_eq!(result, value);
+    }
+    
+    #[test]
+    fn test_u256_shr_large_shift() {
+        let value = U256::from_u32(0x12345678);
+        let result = value.shr(300); // > 256
+        assert!(result.is_zero());
+    }
+    
+    #[test]
+    fn test_u256_shl_small_shift() {
+        let value = U256::from_u32(0x12345678);
+        let result = value.shl(8);
+        assert!(!result.is_zero());
+        assert_ne!(result, value);
+    }
+    
+    #[test]
+    fn test_u256_shr_small_shift() {
+        let value = U256::from_u32(0x12345678);
+        let result = value.shr(8);
+        assert!(!result.is_zero());
+        assert_ne!(result, value);
+    }
+    
+    #[test]
+    fn test_u256_to_bytes() {
+        let value = U256::from_u32(0x12345678);
+        let bytes = value.to_le_bytes();
+        assert_eq!(bytes.len(), 32);
+    }
+    
+    #[test]
+    fn test_u256_from_bytes() {
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0x78;
+        bytes[1] = 0x56;
+        bytes[2] = 0x34;
+        bytes[3] = 0x12;
+        let value = U256::from_le_bytes(&bytes);
+        assert!(!value.is_zero());
+    }
+    
+    #[test]
+    fn test_u256_ordering() {
+        let small = U256::from_u32(0x12345678);
+        let large = U256::from_u32(0x87654321);
+        
+        assert!(small < large);
+        assert!(large > small);
+        assert_eq!(small.cmp(&small), std::cmp::Ordering::Equal);
+    }
+    
+    #[test]
+    fn test_u256_from_le_bytes_zero() {
+        let bytes = [0u8; 32];
+        let value = U256::from_le_bytes(&bytes);
+        assert!(value.is_zero());
+    }
+    
+    #[test]
+    fn test_u256_byte_order() {
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0x78;
+        bytes[1] = 0x56;
+        bytes[2] = 0x34;
+        bytes[3] = 0x12;
+        let value = U256::from_le_bytes(&bytes);
+        // Little-endian: the first byte is the least significant
+        assert_eq!(value, U256::from_u64(0x12345678));
+        assert_eq!(value.to_le_bytes(), bytes);
+        
+        let mut be = bytes;
+        be.reverse();
+        assert_eq!(value.to_be_bytes(), be);
+        assert_eq!(U256::from_be_bytes(&be), value);
+    }
+    
+    #[test]
+    fn test_u256_division() {
+        let value = U256::from_u64(1).shl(160);
+        let quotient = value.checked_div(&U256::from_u64(1 << 40)).unwrap();
+        assert_eq!(quotient, U256::from_u64(1).shl(120));
+        assert_eq!(quotient.saturating_to_u128(), 1 << 120);
+        assert_eq!(U256::from_u64(7).checked_div(&U256::from_u64(2)), Some(U256::from_u64(3)));
+        assert!(value.checked_div(&U256::zero()).is_none());
+        assert_eq!(value.bits(), 161);
+        assert_eq!(U256::from_u64(3).checked_mul_u64(5), Some(U256::from_u64(15)));
+        assert!(U256::from_u64(1).shl(255).checked_mul_u64(2).is_none());
+        assert_eq!(U256::from_u64(0x1ff).shl(120).shr(124), U256::from_u64(0x1f));
+        assert_eq!(U256::from_u64(5).wrapping_sub(&U256::from_u64(7)), U256::zero().not().wrapping_sub(&U256::from_u64(1)));
+    }
+    
+    #[test]
+    fn test_u256_checked_arithmetic() {
+        let max = U256::MAX;
+        assert_eq!(max.checked_add(&U256::one()), None);
+        assert_eq!(max.saturating_add(&U256::one()), max);
+        assert_eq!(max.wrapping_add(&U256::one()), U256::zero());
+        assert_eq!(U256::zero().checked_sub(&U256::one()), None);
+        assert_eq!(U256::from_u64(10).checked_sub(&U256::from_u64(3)), Some(U256::from_u64(7)));
+        
+        // Carries propagate across words
+        let carried = U256::from_u64(u64::MAX).checked_add(&U256::one()).unwrap();
+        assert_eq!(carried, U256::one().shl(64));
+        assert_eq!(carried.low_u64(), 0);
+        assert_eq!(carried.bits(), 65);
+        
+        assert_eq!(U256::from(42u64), U256::from_u64(42));
+        assert_eq!(U256::default(), U256::zero());
+    }
+}
@@ -81,6 +81,7 @@ fn test_validate_tx_inputs() {
         value: 2000,
         script_pubkey: vec![0x51],
         height: 100,
+        is_coinbase: false,
     };
     utxo_set.insert(outpoint, utxo);
     
@@ -93,7 +94,7 @@ fn test_validate_tx_inputs() {
 fn test_validate_block() {
     let consensus = ConsensusProof::new();
     
-    let block = Block {
+    let mut block = Block {
         header: BlockHeader {
             version: 1,
             prev_block_hash: [0; 32],
@@ -117,6 +118,8 @@ fn test_validate_block() {
         }],
     };
     
+    block.header.merkle_root = block::calculate_merkle_root(&block.transactions).unwrap();
+    
     let utxo_set = UtxoSet::new();
     let (result, new_utxo_set) = consensus.validate_block(&block, utxo_set, 0).unwrap();
     assert!(matches!(result, ValidationResult::Valid));
@@ -337,7 +340,7 @@ fn test_replacement_checks() {
     };
     
     let mempool = Mempool::new();
-    let result = consensus.replacement_checks(&tx2, &tx1, &mempool).unwrap();
+    let result = consensus.replacement_checks(&tx2, &tx1, &UtxoSet::new(), &mempool).unwrap();
     assert!(result == true || result == false);
 }
 
@@ -365,6 +368,7 @@ fn test_create_new_block() {
         &prev_headers,
         &vec![0x51],
         &vec![0x51],
+        prev_header.timestamp + 600,
     ).unwrap();
     
     assert_eq!(block.transactions.len(), 1); // Only coinbase
@@ -427,6 +431,7 @@ fn test_create_block_template() {
         &prev_headers,
         &vec![0x51],
         &vec![0x51],
+        prev_header.timestamp + 600,
     );
     
     // This might fail due to target expansion issues, which is expected
@@ -470,11 +475,12 @@ fn test_reorganize_chain() {
     }];
     
     let utxo_set = UtxoSet::new();
-    let result = consensus.reorganize_chain(&new_chain, &current_chain, utxo_set, 1);
+    let result = consensus.reorganize_chain(&new_chain, &current_chain, &[Default::default()], utxo_set, 1, U256::zero());
     
     // This might fail due to simplified validation, which is expected
     match result {
         Ok(reorg_result) => {
+            let reorg_result = reorg_result.reorganized().unwrap();
             assert!(reorg_result.new_height >= 0);
             assert!(reorg_result.connected_blocks.len() >= 0);
         },
@@ -522,15 +528,17 @@ fn test_process_network_message() {
     
     let version_msg = VersionMessage {
         version: 70016,
-        services: 0,
+        services: ServiceFlags::NONE,
         timestamp: 0,
         addr_recv: NetworkAddress {
-            services: 0,
+            time: 0,
+            services: ServiceFlags::NONE,
             ip: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 127, 0, 0, 1], // 127.0.0.1
             port: 8333,
         },
         addr_from: NetworkAddress {
-            services: 0,
+            time: 0,
+            services: ServiceFlags::NONE,
             ip: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 127, 0, 0, 1], // 127.0.0.1
             port: 8333,
         },
@@ -544,7 +552,7 @@ fn test_process_network_message() {
     let mut peer_state = PeerState::new();
     let chain_state = ChainState::new();
     
-    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
     assert!(matches!(response, NetworkResponse::Ok | NetworkResponse::SendMessage(_) | NetworkResponse::Reject(_)));
 }
 
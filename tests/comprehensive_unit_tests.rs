@@ -297,12 +297,9 @@ fn test_get_block_subsidy_max_halvings() {
 fn test_total_supply_convergence() {
     // Test that total supply approaches 21M BTC
     let supply_at_halving = total_supply(HALVING_INTERVAL);
-    // At the first halving, we have 210,000 blocks of 50 BTC each
-    let expected_at_halving = (HALVING_INTERVAL as i64) * INITIAL_SUBSIDY;
-    // The difference is due to bit shifting in get_block_subsidy
-    // Allow for significant rounding differences due to bit operations
-    let difference = (supply_at_halving - expected_at_halving).abs();
-    assert!(difference <= 3_000_000_000); // Allow for significant rounding differences
+    // 210,000 blocks of 50 BTC, plus the first 25 BTC block at the halving height
+    let expected_at_halving = (HALVING_INTERVAL as i64) * INITIAL_SUBSIDY + INITIAL_SUBSIDY / 2;
+    assert_eq!(supply_at_halving, expected_at_halving);
 }
 
 #[test]
@@ -335,6 +332,7 @@ fn test_calculate_fee() {
         value: 1000,
         script_pubkey: vec![0x51],
         height: 100,
+        is_coinbase: false,
     };
     utxo_set.insert(outpoint, utxo);
     
@@ -364,6 +362,7 @@ fn test_calculate_fee_negative() {
         value: 500, // Less than output
         script_pubkey: vec![0x51],
         height: 100,
+        is_coinbase: false,
     };
     utxo_set.insert(outpoint, utxo);
     
@@ -393,6 +392,7 @@ fn test_calculate_fee_zero() {
         value: 1000,
         script_pubkey: vec![0x51],
         height: 100,
+        is_coinbase: false,
     };
     utxo_set.insert(outpoint, utxo);
     
@@ -437,11 +437,12 @@ fn test_get_next_work_required_insufficient_headers() {
 
 #[test]
 fn test_get_next_work_required_normal_adjustment() {
+    // Exactly two weeks after the first header of the period
     let current_header = BlockHeader {
         version: 1,
         prev_block_hash: [0; 32],
         merkle_root: [0; 32],
-        timestamp: 1231006505,
+        timestamp: 1231006505 + DIFFICULTY_ADJUSTMENT_INTERVAL * TARGET_TIME_PER_BLOCK,
         bits: 0x1d00ffff,
         nonce: 0,
     };
@@ -464,8 +465,6 @@ fn test_get_next_work_required_normal_adjustment() {
     assert_eq!(result, 0x1d00ffff);
 }
 
-// expand_target is not a public function, so we test it indirectly through check_proof_of_work
-
 #[test]
 fn test_check_proof_of_work_genesis() {
     // Use a reasonable header with valid target
@@ -484,8 +483,6 @@ fn test_check_proof_of_work_genesis() {
     assert!(result == true || result == false);
 }
 
-// expand_target is not a public function, so we test it indirectly through check_proof_of_work
-
 #[test]
 fn test_check_proof_of_work_invalid_target() {
     let header = BlockHeader {
@@ -493,15 +490,24 @@ fn test_check_proof_of_work_invalid_target() {
         prev_block_hash: [0; 32],
         merkle_root: [0; 32],
         timestamp: 1231006505,
-        bits: 0x1f00ffff, // Invalid target
+        bits: 0x1f00ffff, // Above the mainnet pow limit
         nonce: 0,
     };
     
     let result = check_proof_of_work(&header);
-    assert!(result.is_err());
+    assert!(!result.unwrap());
 }
 
-// expand_target is not a public function, so we test it indirectly through check_proof_of_work
+#[test]
+fn test_expand_target_public_api() {
+    let target = expand_target(0x1d00ffff).unwrap();
+    assert_eq!(compact_from_target(&target), 0x1d00ffff);
+    assert!(expand_target(0x1d80ffff).is_err());
+
+    let params = consensus_proof::params::ChainParams::mainnet();
+    assert!(is_valid_compact_target(0x1d00ffff, &params));
+    assert!(!is_valid_compact_target(0x1f00ffff, &params));
+}
 
 // ============================================================================
 // EDGE CASE TESTS
@@ -133,7 +133,7 @@ fn test_reorganization_errors() {
     let current_chain = vec![];
     let utxo_set = UtxoSet::new();
     
-    let result = consensus.reorganize_chain(&new_chain, &current_chain, utxo_set, 0);
+    let result = consensus.reorganize_chain(&new_chain, &current_chain, &[], utxo_set, 0, U256::zero());
     assert!(result.is_err());
 }
 
@@ -144,15 +144,17 @@ fn test_network_message_errors() {
     // Test invalid version message
     let invalid_version = VersionMessage {
         version: 0, // Too old
-        services: 0,
+        services: ServiceFlags::NONE,
         timestamp: 0,
         addr_recv: NetworkAddress {
-            services: 0,
+            time: 0,
+            services: ServiceFlags::NONE,
             ip: [0; 16],
             port: 0,
         },
         addr_from: NetworkAddress {
-            services: 0,
+            time: 0,
+            services: ServiceFlags::NONE,
             ip: [0; 16],
             port: 0,
         },
@@ -166,7 +168,7 @@ fn test_network_message_errors() {
     let mut peer_state = PeerState::new();
     let chain_state = ChainState::new();
     
-    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state);
+    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH);
     assert!(response.is_ok());
     // Should reject due to old version
 }
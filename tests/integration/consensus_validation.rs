@@ -72,6 +72,7 @@ fn test_consensus_proof_utxo_validation() {
         value: 2000,
         script_pubkey: vec![0x51],
         height: 100,
+        is_coinbase: false,
     };
     utxo_set.insert(outpoint, utxo);
     
@@ -103,6 +104,7 @@ fn test_consensus_proof_insufficient_funds() {
         value: 1000, // Less than needed
         script_pubkey: vec![0x51],
         height: 100,
+        is_coinbase: false,
     };
     utxo_set.insert(outpoint, utxo);
     
@@ -66,6 +66,7 @@ fn test_mempool_to_block_integration() {
         &prev_headers,
         &vec![0x51],
         &vec![0x51],
+        prev_header.timestamp + 600,
     ).unwrap();
     
     assert_eq!(block.transactions.len(), 3); // 2 mempool txs + 1 coinbase
@@ -101,6 +102,7 @@ fn test_economic_mining_integration() {
         &prev_headers,
         &vec![0x51],
         &vec![0x51],
+        prev_header.timestamp + 600,
     ).unwrap();
     
     // The coinbase transaction should include the block subsidy
@@ -218,6 +220,7 @@ fn test_cross_system_error_handling() {
         &prev_headers,
         &vec![0x51],
         &vec![0x51],
+        prev_header.timestamp + 600,
     );
     assert!(block_result.is_err());
 }
@@ -270,6 +273,7 @@ fn test_performance_integration() {
         &prev_headers,
         &vec![0x51],
         &vec![0x51],
+        prev_header.timestamp + 600,
     ).unwrap();
     
     assert_eq!(block.transactions.len(), 101); // 100 txs + 1 coinbase
@@ -34,6 +34,7 @@ fn test_mempool_to_block_integration() {
         &prev_headers,
         &coinbase_script,
         &coinbase_address,
+        prev_header.timestamp + 600,
     ).unwrap();
     
     // 4. Verify block structure
@@ -69,6 +70,7 @@ fn test_economic_mining_integration() {
             &vec![create_valid_block_header()],
             &coinbase_script,
             &coinbase_address,
+            1231006505 + 600,
         ).unwrap();
         
         // 3. Verify coinbase output matches subsidy
@@ -97,6 +99,7 @@ fn test_script_transaction_integration() {
         value: 10000,
         script_pubkey: vec![0x51], // OP_1
         height: 0,
+        is_coinbase: false,
     };
     utxo_set.insert(outpoint, utxo);
     
@@ -169,6 +172,7 @@ fn test_cross_system_error_handling() {
         &vec![create_valid_block_header()],
         &vec![0x51],
         &vec![0x51],
+        1231006505 + 600,
     );
     
     // Should succeed but create block without invalid transactions
@@ -190,6 +194,7 @@ fn test_performance_integration() {
             value: 1000,
             script_pubkey: vec![0x51],
             height: 0,
+            is_coinbase: false,
         };
         utxo_set.insert(outpoint, utxo);
     }
@@ -228,6 +233,7 @@ fn test_performance_integration() {
         &vec![create_valid_block_header()],
         &vec![0x51],
         &vec![0x51],
+        1231006505 + 600,
     ).unwrap();
     
     let duration = start.elapsed();
@@ -274,6 +280,7 @@ fn create_test_utxo_set() -> UtxoSet {
         value: 10000,
         script_pubkey: vec![0x51],
         height: 0,
+        is_coinbase: false,
     };
     utxo_set.insert(outpoint, utxo);
     utxo_set
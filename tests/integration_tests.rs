@@ -97,6 +97,7 @@ fn test_consensus_proof_utxo_validation() {
         value: 1000,
         script_pubkey: vec![],
         height: 0,
+        is_coinbase: false,
     });
     
     let (result, fee) = consensus.validate_tx_inputs(&tx, &utxo_set, 1).unwrap();
@@ -131,6 +132,7 @@ fn test_consensus_proof_insufficient_funds() {
         value: 1000, // Less than output
         script_pubkey: vec![],
         height: 0,
+        is_coinbase: false,
     });
     
     let (result, _fee) = consensus.validate_tx_inputs(&tx, &utxo_set, 1).unwrap();
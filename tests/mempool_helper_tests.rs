@@ -22,11 +22,13 @@ fn test_mempool_basic_operations() {
     
     let mut mempool = Mempool::new();
     let tx_id = calculate_tx_id(&tx);
-    let result = mempool.insert(tx_id);
-    assert!(result); // HashSet::insert returns bool
+    let result = mempool.insert(MempoolEntry::new(tx.clone(), 1000, 0, 0, 100));
+    assert!(result); // true for a new transaction
     
     // Test that transaction ID is in mempool
     assert!(mempool.contains(&tx_id));
+    assert_eq!(mempool.get(&tx_id).unwrap().fee, 1000);
+    assert!(!mempool.insert(MempoolEntry::new(tx, 1000, 0, 0, 100)));
 }
 
 #[test]
@@ -61,12 +63,11 @@ fn test_mempool_conflict_detection() {
     
     let mut mempool = Mempool::new();
     let tx1_id = calculate_tx_id(&tx1);
-    mempool.insert(tx1_id);
+    mempool.insert(MempoolEntry::new(tx1, 0, 0, 0, 100));
     
-    let tx2_id = calculate_tx_id(&tx2);
-    // tx2 should conflict with tx1 (same input) but different transaction IDs
-    let result = mempool.insert(tx2_id);
-    assert!(result); // HashSet::insert returns bool (true if new element)
+    // tx2 conflicts with tx1 (same input) but has a different transaction ID
+    assert_ne!(calculate_tx_id(&tx2), tx1_id);
+    assert_eq!(mempool.spender_of(&tx2.inputs[0].prevout).unwrap().txid, tx1_id);
 }
 
 #[test]
@@ -126,6 +127,7 @@ fn test_mempool_fee_calculation() {
         value: 1000,
         script_pubkey: vec![0x51],
         height: 100,
+        is_coinbase: false,
     };
     utxo_set.insert(outpoint, utxo);
     
@@ -168,12 +170,14 @@ fn test_mempool_dependency_creation() {
     
     let mut mempool = Mempool::new();
     let tx1_id = calculate_tx_id(&tx1);
-    mempool.insert(tx1_id);
+    mempool.insert(MempoolEntry::new(tx1, 0, 0, 0, 100));
     
     let tx2_id = calculate_tx_id(&tx2);
-    // tx2 depends on tx1 (spends tx1's output) but has different transaction ID
-    let result = mempool.insert(tx2_id);
-    assert!(result); // Should succeed since tx2_id is different from tx1_id
+    // tx2 depends on tx1 (spends tx1's output) and is linked to it
+    let result = mempool.insert(MempoolEntry::new(tx2, 100, 0, 0, 100));
+    assert!(result);
+    assert!(mempool.get(&tx2_id).unwrap().ancestors.contains(&tx1_id));
+    assert!(mempool.get(&tx1_id).unwrap().descendants.contains(&tx2_id));
 }
 
 #[test]
@@ -9,7 +9,8 @@ fn test_process_addr_message() {
     
     let addr_msg = AddrMessage {
         addresses: vec![NetworkAddress {
-            services: 0,
+            time: 0,
+            services: ServiceFlags::NONE,
             ip: [127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
             port: 8333,
         }],
@@ -17,9 +18,10 @@ fn test_process_addr_message() {
     
     let message = NetworkMessage::Addr(addr_msg);
     let mut peer_state = PeerState::new();
+    peer_state.handshake = handshake::HandshakeState::Complete;
     let chain_state = ChainState::new();
     
-    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
     assert!(matches!(response, NetworkResponse::Ok));
 }
 
@@ -36,9 +38,10 @@ fn test_process_inv_message() {
     
     let message = NetworkMessage::Inv(inv_msg);
     let mut peer_state = PeerState::new();
+    peer_state.handshake = handshake::HandshakeState::Complete;
     let chain_state = ChainState::new();
     
-    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
     // Since chain_state is empty, it should request the data
     assert!(matches!(response, NetworkResponse::SendMessage(_)));
 }
@@ -56,9 +59,10 @@ fn test_process_getdata_message() {
     
     let message = NetworkMessage::GetData(getdata_msg);
     let mut peer_state = PeerState::new();
+    peer_state.handshake = handshake::HandshakeState::Complete;
     let chain_state = ChainState::new();
     
-    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
     // GetData should return SendMessages
     assert!(matches!(response, NetworkResponse::SendMessages(_)));
 }
@@ -80,9 +84,10 @@ fn test_process_headers_message() {
     
     let message = NetworkMessage::Headers(headers_msg);
     let mut peer_state = PeerState::new();
+    peer_state.handshake = handshake::HandshakeState::Complete;
     let chain_state = ChainState::new();
     
-    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
     assert!(matches!(response, NetworkResponse::Ok));
 }
 
@@ -104,9 +109,10 @@ fn test_process_block_message() {
     
     let message = NetworkMessage::Block(block);
     let mut peer_state = PeerState::new();
+    peer_state.handshake = handshake::HandshakeState::Complete;
     let chain_state = ChainState::new();
     
-    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
     assert!(matches!(response, NetworkResponse::Ok));
 }
 
@@ -123,9 +129,10 @@ fn test_process_tx_message() {
     
     let message = NetworkMessage::Tx(tx);
     let mut peer_state = PeerState::new();
+    peer_state.handshake = handshake::HandshakeState::Complete;
     let chain_state = ChainState::new();
     
-    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
     assert!(matches!(response, NetworkResponse::Ok));
 }
 
@@ -215,9 +222,10 @@ fn test_process_mempool_message() {
     
     let message = NetworkMessage::MemPool;
     let mut peer_state = PeerState::new();
+    peer_state.handshake = handshake::HandshakeState::Complete;
     let chain_state = ChainState::new();
     
-    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
     // MemPool should return SendMessages
     assert!(matches!(response, NetworkResponse::SendMessages(_)));
 }
@@ -231,8 +239,9 @@ fn test_process_feefilter_message() {
     };
     let message = NetworkMessage::FeeFilter(feefilter_msg);
     let mut peer_state = PeerState::new();
+    peer_state.handshake = handshake::HandshakeState::Complete;
     let chain_state = ChainState::new();
     
-    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state).unwrap();
+    let response = consensus.process_network_message(&message, &mut peer_state, &chain_state, std::time::UNIX_EPOCH).unwrap();
     assert!(matches!(response, NetworkResponse::Ok));
 }
@@ -42,7 +42,7 @@ fn test_calculate_fee_negative() {
     // Create UTXO with less value than transaction output
     utxo.insert(
         OutPoint { hash: [1; 32], index: 0 },
-        consensus_proof::UTXO { value: 500, script_pubkey: vec![0x51], height: 1 }
+        consensus_proof::UTXO { value: 500, script_pubkey: vec![0x51], height: 1, is_coinbase: false }
     );
     
     let fee = economic::calculate_fee(&tx, &utxo);
@@ -4,7 +4,7 @@ fn utxo(value: i64) -> (UtxoSet, OutPoint) {
     let mut set = UtxoSet::new();
     let txid = [1u8;32];
     let op = OutPoint { hash: txid, index: 0 };
-    set.insert(op.clone(), consensus_proof::UTXO { value, script_pubkey: vec![0x51], height: 1 });
+    set.insert(op.clone(), consensus_proof::UTXO { value, script_pubkey: vec![0x51], height: 1, is_coinbase: false });
     (set, op)
 }
 
@@ -1,6 +1,6 @@
-use consensus_proof::{mempool, Transaction, TransactionInput, TransactionOutput, OutPoint, UtxoSet};
+use consensus_proof::{mempool, Transaction, TransactionInput, TransactionOutput, OutPoint, UtxoSet, UTXO, SEQUENCE_RBF};
 
-fn create_rbf_tx(sequence: u32) -> Transaction {
+fn create_rbf_tx(sequence: u64) -> Transaction {
     Transaction {
         version: 1,
         inputs: vec![TransactionInput {
@@ -16,15 +16,23 @@ fn create_rbf_tx(sequence: u32) -> Transaction {
 #[test]
 fn test_rbf_sequence_checks() {
     let pool = mempool::Mempool::new();
+    let mut utxo = UtxoSet::new();
+    utxo.insert(
+        OutPoint { hash: [1; 32], index: 0 },
+        UTXO { value: 10000, script_pubkey: vec![0x51], height: 0, is_coinbase: false },
+    );
     
-    // RBF transaction (sequence < 0xffffffff)
-    let rbf_tx = create_rbf_tx(0xfffffffe);
-    let non_rbf_tx = create_rbf_tx(0xffffffff);
+    // A higher-fee spend of the same coin replaces one that signals
+    let mut replacement = create_rbf_tx(SEQUENCE_RBF as u64);
+    replacement.outputs[0].value = 500;
+    let rbf_tx = create_rbf_tx(SEQUENCE_RBF as u64);
+    assert!(mempool::replacement_checks(&replacement, &rbf_tx, &utxo, &pool).unwrap());
     
-    // Test RBF replacement logic
-    let can_replace = mempool::replacement_checks(&rbf_tx, &non_rbf_tx, &pool);
-    // Whether it succeeds depends on implementation, just exercise the path
-    let _ = can_replace;
+    // 0xfffffffe enables nLockTime but does not signal replaceability
+    let locktime_tx = create_rbf_tx(0xfffffffe);
+    assert!(!mempool::replacement_checks(&replacement, &locktime_tx, &utxo, &pool).unwrap());
+    let final_tx = create_rbf_tx(0xffffffff);
+    assert!(!mempool::replacement_checks(&replacement, &final_tx, &utxo, &pool).unwrap());
 }
 
 #[test]
@@ -62,6 +62,7 @@ fn test_block_template_creation() {
         &prev_headers,
         &coinbase_script,
         &coinbase_address,
+        prev_header.timestamp + 600,
     );
     
     assert!(template.is_ok());